@@ -35,31 +35,27 @@
 //! ```rust no_run
 //! use std::error::Error;
 //! use log::error;
-//! use easy_upnp::{add_ports, delete_ports, Ipv4Cidr, PortMappingProtocol, UpnpConfig};
+//! use easy_upnp::{add_ports, delete_ports, Ipv4Cidr, LeaseDuration, PortMappingProtocol, UpnpConfig};
 //!
 //! fn get_configs() -> Result<[UpnpConfig; 3], Box<dyn Error>> {
 //!     let config_no_address = UpnpConfig {
-//!         address: None,
-//!         port: 80,
-//!         protocol: PortMappingProtocol::TCP,
-//!         duration: 3600,
+//!         duration: LeaseDuration::Seconds(3600),
 //!         comment: "Webserver".to_string(),
+//!         ..UpnpConfig::new(80, PortMappingProtocol::TCP)
 //!     };
 //!
 //!     let config_specific_address = UpnpConfig {
-//!         address: Some(Ipv4Cidr::from_str("192.168.0.10/24")?),
-//!         port: 8080,
-//!         protocol: PortMappingProtocol::TCP,
-//!         duration: 3600,
+//!         address: Some(Ipv4Cidr::from_str("192.168.0.10/24")?.into()),
+//!         duration: LeaseDuration::Seconds(3600),
 //!         comment: "Webserver alternative".to_string(),
+//!         ..UpnpConfig::new(8080, PortMappingProtocol::TCP)
 //!     };
 //!
 //!     let config_address_range = UpnpConfig {
-//!         address: Some(Ipv4Cidr::from_str("192.168.0")?),
-//!         port: 8081,
-//!         protocol: PortMappingProtocol::TCP,
-//!         duration: 3600,
+//!         address: Some(Ipv4Cidr::from_str("192.168.0")?.into()),
+//!         duration: LeaseDuration::Seconds(3600),
 //!         comment: "Webserver second alternative".to_string(),
+//!         ..UpnpConfig::new(8081, PortMappingProtocol::TCP)
 //!     };
 //!
 //!     Ok([
@@ -70,13 +66,13 @@
 //! }
 //!
 //! fn main() -> Result<(), Box<dyn Error>> {
-//!     for result in add_ports(get_configs()?) {
+//!     for result in add_ports(get_configs()?, None) {
 //!         if let Err(err) = result {
 //!             error!("{}", err);
 //!         }
 //!     }
 //!
-//!     for result in delete_ports(get_configs()?) {
+//!     for result in delete_ports(get_configs()?, None) {
 //!         if let Err(err) = result {
 //!             error!("{}", err);
 //!         }
@@ -85,21 +81,80 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! ## Keeping Ports Open
+//!
+//! Port mappings with a limited lease duration need to be renewed regularly. Instead of calling
+//! [add_ports] in a loop, you can let [keepalive] do this in a background thread, which is stopped
+//! again with [KeepaliveHandle::stop]. To show the progress, for example in a user interface, use
+//! [keepalive_with_events], which additionally reports each renewal over a channel. For a single
+//! port mapping, [keep_port_open] is the shortest way to do the same.
+//!
+//! If ports are only needed for a limited task, like a test, a [PortSession] opens them and closes
+//! exactly the ones that could be opened again, when it is closed or dropped.
+//!
+//! ## Custom Gateway Discovery
+//!
+//! By default, the gateway and the local address of a port mapping are found as described for
+//! [`UpnpConfig::address`]. If your application knows better, for example from a service
+//! discovery, implement [AddressResolver] and set it as
+//! [`DiscoveryOptions::resolver`]. To search only once for a batch of port mappings,
+//! [prefetch_gateway] finds the gateway ahead of time, and can be set as the resolver itself.
+//!
+//! ## Comparing with the Gateway
+//!
+//! To build your own policies, for example to reconcile the gateway with a set of configurations,
+//! [diff] compares the configurations with the port mappings of a gateway, without changing
+//! anything. The resulting [MappingDiff] lists the configurations to add, the port mappings to
+//! remove, and the port mappings that exist but differ from their configuration.
+//!
+//! ## Config Files
+//!
+//! Instead of building the configurations by hand, they can also be read from CSV or JSON, in the
+//! same format that [`upnp-daemon`] uses for its config files. See [parse_configs_csv] and
+//! [parse_configs_json] for details. With the features `yaml` and `toml`, the config files can
+//! also be given in YAML and TOML, see `parse_configs_yaml` and `parse_configs_toml`. Likewise,
+//! configurations can be written to CSV or JSON with [write_configs_csv] and [write_configs_json].
 
 #![deny(missing_docs)]
 
-use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 pub use cidr_utils::cidr::Ipv4Cidr;
-use igd::{Gateway, SearchOptions};
+pub use csv::StringRecord;
+pub use igd::Gateway;
+use igd::SearchOptions;
 use log::{debug, error, info, warn};
-use serde::Deserialize;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{
+    self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Unexpected, Visitor,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+pub use serde_json::Value as JsonValue;
 use thiserror::Error;
+use wildmatch::WildMatch;
+
+mod nat_pmp;
+mod soap;
 
 /// Convenience wrapper over all possible Errors
 #[allow(missing_docs)]
 #[derive(Debug, Error)]
-pub enum Error {
+pub enum UpnpError {
     #[error("No matching gateway found")]
     NoMatchingGateway,
 
@@ -109,301 +164,4729 @@ pub enum Error {
     #[error("Error adding port: {0}")]
     IgdAddPortError(#[from] igd::AddPortError),
 
+    #[error("Error adding any port: {0}")]
+    IgdAddAnyPortError(#[from] igd::AddAnyPortError),
+
     #[error("Error searching for gateway: {0}")]
     IgdSearchError(#[from] igd::SearchError),
+
+    #[error("Lease duration \"auto\" has not been resolved")]
+    UnresolvedAutoDuration,
+
+    #[error("Permanent port mappings are not supported by the {0} backend")]
+    PermanentLeaseUnsupported(Backend),
+
+    #[error("Error parsing CSV config: {0}")]
+    CsvError(#[from] csv::Error),
+
+    #[error("Error parsing JSON config: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("Input is not a JSON array")]
+    NotAJsonArray,
+
+    #[error("Error reading config: {0}")]
+    ReadError(#[from] std::io::Error),
+
+    #[error("Error writing config: {0}")]
+    WriteError(#[source] std::io::Error),
+
+    #[cfg(feature = "yaml")]
+    #[error("Error parsing YAML config: {0}")]
+    YamlError(#[from] serde_yaml::Error),
+
+    #[cfg(feature = "yaml")]
+    #[error("Input is not a YAML sequence")]
+    NotAYamlSequence,
+
+    #[cfg(feature = "toml")]
+    #[error("Error parsing TOML config: {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    #[cfg(feature = "toml")]
+    #[error("Input has no array of tables named \"{}\"", TOML_TABLE_NAME)]
+    NoTomlMappings,
+
+    #[error("Error communicating with gateway: {0}")]
+    HttpError(#[from] attohttpc::Error),
+
+    #[error("Invalid response from gateway: {0}")]
+    InvalidResponse(String),
+
+    /// A record of [record_soap] that was given to [replay_soap] could not be parsed.
+    #[error("Invalid SOAP record: {0}")]
+    InvalidRecord(String),
+
+    /// While replaying a record with [replay_soap], there is no recorded response left for a
+    /// request.
+    #[error("No recorded response left for {0}")]
+    NotRecorded(String),
+
+    #[error("Gateway reported error {0}: {1}")]
+    GatewayError(u16, String),
+
+    #[error("Error getting port mapping entry: {0}")]
+    IgdGetPortMappingEntryError(#[from] igd::GetGenericPortMappingEntryError),
+
+    /// The gateway does not list the action in its service description, see [USED_ACTIONS].
+    #[error("Gateway does not support the {0} action")]
+    UnsupportedAction(&'static str),
+
+    #[error("{original}, retried after deleting the existing mapping: {retry}")]
+    Retried {
+        original: Box<UpnpError>,
+        #[source]
+        retry: Box<UpnpError>,
+    },
+
+    #[error("{original}, and the existing mapping could not be deleted: {removal}")]
+    ReplaceFailed {
+        original: Box<UpnpError>,
+        #[source]
+        removal: Box<UpnpError>,
+    },
+
+    #[error("Error removing port: {0}")]
+    IgdRemovePortError(#[from] igd::RemovePortError),
+
+    #[error("Error getting external IP address: {0}")]
+    IgdGetExternalIpError(#[from] igd::GetExternalIpError),
+
+    #[error("Address command \"{0}\" failed: {1}")]
+    AddressCommandError(String, String),
+
+    #[error("Invalid address \"{0}\": {1}")]
+    InvalidAddress(String, String),
+
+    #[error("Invalid gateway URL \"{0}\": {1}")]
+    InvalidGatewayUrl(String, &'static str),
+
+    /// The [`bind_ip`](DiscoveryOptions::bind_ip) is not the address of an interface, or not in
+    /// the [`address`](UpnpConfig::address) range of the port mapping.
+    #[error("Bind IP {0} is not the address of any matching interface")]
+    BindIpNotFound(Ipv4Addr),
+
+    /// The [`gateway`](UpnpConfig::gateway) of a port mapping could not be found.
+    #[error("Gateway \"{0}\" not found")]
+    GatewayNotFound(String),
+
+    #[error("NAT-PMP error: {0}")]
+    NatpmpError(#[from] natpmp::Error),
+
+    #[error("Operation is not supported by the {0} backend")]
+    UnsupportedByBackend(Backend),
+
+    #[error("Fields \"{0}\" and \"{1}\" cannot be combined")]
+    ConflictingFields(&'static str, &'static str),
+
+    /// The gateway understood the request, but does not allow this port mapping.
+    ///
+    /// This usually means that UPnP is enabled on the router, but the requesting device or the
+    /// requested port is not allowed to create port mappings.
+    #[error(
+        "Router accepted the request but refused to authorize the mapping, \
+         check the UPnP permissions of the router"
+    )]
+    NotAuthorized,
+
+    /// The gateway acknowledged the removal of a port mapping twice, but still has it, see
+    /// [`DiscoveryOptions::verify_removal`].
+    #[error("Port mapping {1} {0} still exists after removing it twice")]
+    RemovalIgnored(u16, PortMappingProtocol),
+
+    /// The port is already in use by another port mapping, which is protected from deletion.
+    #[error("Port {0} is in use by another mapping and protected, not deleting it: {1}")]
+    ProtectedPort(u16, #[source] Box<UpnpError>),
+
+    /// Processing the port mapping panicked, with the given message.
+    ///
+    /// This is a bug, either in this crate or in one of its dependencies. The panic is caught, so
+    /// that the other port mappings are still processed.
+    #[error("Processing the port mapping panicked: {0}")]
+    Panicked(String),
 }
 
-type Result<R> = std::result::Result<R, Error>;
+/// Former name of [UpnpError].
+#[deprecated(note = "Use UpnpError instead")]
+pub type Error = UpnpError;
+
+/// Convenience alias for results with this crate's [UpnpError] type.
+pub type Result<R> = std::result::Result<R, UpnpError>;
 
 /// The protocol for which the given port will be opened. Possible values are
 /// [`UDP`](PortMappingProtocol::UDP) and [`TCP`](PortMappingProtocol::TCP).
 #[allow(missing_docs)]
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum PortMappingProtocol {
     TCP,
     UDP,
 }
 
-impl From<PortMappingProtocol> for igd::PortMappingProtocol {
-    fn from(proto: PortMappingProtocol) -> Self {
-        match proto {
-            PortMappingProtocol::TCP => igd::PortMappingProtocol::TCP,
-            PortMappingProtocol::UDP => igd::PortMappingProtocol::UDP,
-        }
+impl fmt::Display for PortMappingProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PortMappingProtocol::TCP => "TCP",
+            PortMappingProtocol::UDP => "UDP",
+        })
     }
 }
 
-fn find_gateway_with_bind_addr(bind_addr: SocketAddr) -> Result<Gateway> {
-    let options = SearchOptions {
-        bind_addr,
-        ..Default::default()
-    };
-    Ok(igd::search_gateway(options)?)
-}
+/// An IP address family, see [`DiscoveryOptions::prefer_family`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    /// IPv4.
+    #[default]
+    V4,
 
-fn find_gateway_and_addr(cidr: &Option<Ipv4Cidr>) -> Result<(Gateway, SocketAddr)> {
-    let ifaces = get_if_addrs::get_if_addrs().map_err(Error::CannotGetInterfaceAddress)?;
+    /// IPv6.
+    V6,
+}
 
-    let (gateway, address) = ifaces
-        .iter()
-        .filter_map(|iface| {
-            if iface.is_loopback() || !iface.ip().is_ipv4() {
-                None
-            } else {
-                let iface_ip = match iface.ip() {
-                    IpAddr::V4(ip) => ip,
-                    IpAddr::V6(_) => unreachable!(),
-                };
+impl AddressFamily {
+    fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => AddressFamily::V4,
+            IpAddr::V6(_) => AddressFamily::V6,
+        }
+    }
+}
 
-                match cidr {
-                    Some(cidr) if !cidr.contains(iface_ip) => None,
-                    Some(_) => {
-                        let addr = SocketAddr::new(IpAddr::V4(iface_ip), 0);
+/// The order of equally ranked interfaces, see [`DiscoveryOptions::match_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MatchPolicy {
+    /// Order the interfaces by their name, and interfaces with the same name by their address.
+    #[default]
+    Name,
 
-                        let gateway = find_gateway_with_bind_addr(addr);
+    /// Order the interfaces by their address, lowest first.
+    LowestIp,
+}
 
-                        Some((gateway, addr))
-                    }
-                    _ => {
-                        let options = SearchOptions {
-                            // Unwrap is okay here, IP is correctly generated
-                            bind_addr: format!("{}:0", iface.addr.ip()).parse().unwrap(),
-                            ..Default::default()
-                        };
-                        igd::search_gateway(options).ok().and_then(|gateway| {
-                            if let get_if_addrs::IfAddr::V4(addr) = &iface.addr {
-                                Some((Ok(gateway), SocketAddr::V4(SocketAddrV4::new(addr.ip, 0))))
-                            } else {
-                                // Anything other than V4 has been ruled out by the first if
-                                // condition.
-                                unreachable!()
-                            }
-                        })
-                    }
-                }
-            }
+impl fmt::Display for MatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MatchPolicy::Name => "name",
+            MatchPolicy::LowestIp => "lowest address",
         })
-        .next()
-        .ok_or_else(|| Error::NoMatchingGateway)?;
+    }
+}
 
-    Ok((gateway?, address))
+/// The protocol that is used to talk to the gateway.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Universal Plug and Play, via an Internet Gateway Device.
+    #[default]
+    Upnp,
+
+    /// NAT Port Mapping Protocol, as described in [RFC 6886].
+    ///
+    /// NAT-PMP can only map ports for the requesting host, via its default gateway. Therefore,
+    /// the [`address`](UpnpConfig::address) of a configuration is ignored with this backend.
+    ///
+    /// [RFC 6886]: https://datatracker.ietf.org/doc/html/rfc6886
+    NatPmp,
 }
 
-fn get_gateway_and_address_from_options(
-    address: &Option<Ipv4Cidr>,
-    port: u16,
-) -> Result<(Gateway, SocketAddrV4)> {
-    Ok(match address {
-        Some(addr) if addr.get_bits() == 32 => {
-            let addr = SocketAddr::new(IpAddr::V4(addr.get_prefix_as_ipv4_addr()), port);
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Upnp => "UPnP",
+            Backend::NatPmp => "NAT-PMP",
+        })
+    }
+}
 
-            let gateway = find_gateway_with_bind_addr(addr)?;
+/// The lease duration for a port mapping.
+///
+/// In config files, this is given either as a number of seconds or as the string `"auto"`. If it
+/// is left out or empty, the [`RouterDefault`](LeaseDuration::RouterDefault) is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LeaseDuration {
+    /// Lease the port mapping for the given number of seconds.
+    ///
+    /// A duration of 0 requests a permanent port mapping, which is never reduced to the maximum
+    /// lease duration of the gateway. NAT-PMP has no permanent port mappings, so this is rejected
+    /// with [UpnpError::PermanentLeaseUnsupported] for the [`NatPmp`](Backend::NatPmp) backend.
+    Seconds(u32),
 
-            let addr = match addr {
-                SocketAddr::V4(addr) => addr,
-                _ => panic!("No IPv4 given"),
-            };
+    /// Leave the lease duration to the gateway.
+    ///
+    /// With UPnP, the maximum lease duration that the gateway advertises is requested. Gateways
+    /// that do not advertise a maximum are asked for [`NAT_PMP_DEFAULT_LIFETIME`] instead, so that
+    /// only [`Seconds(0)`](LeaseDuration::Seconds) ever requests a permanent port mapping. With
+    /// NAT-PMP, the same lifetime is requested, as recommended by the protocol.
+    #[default]
+    RouterDefault,
 
-            (gateway, addr)
+    /// Lease the port mapping until shortly after the next refresh.
+    ///
+    /// Since only the caller knows when it will refresh the port mappings, this has to be
+    /// translated into actual seconds with [`LeaseDuration::resolve`] before the port can be
+    /// opened.
+    Auto,
+}
+
+impl LeaseDuration {
+    /// Translate [`Auto`](LeaseDuration::Auto) into the given number of seconds.
+    ///
+    /// A duration given in [`Seconds`](LeaseDuration::Seconds) is returned unchanged.
+    pub fn resolve(self, auto: u32) -> Self {
+        match self {
+            LeaseDuration::Auto => LeaseDuration::Seconds(auto),
+            duration => duration,
         }
+    }
+}
 
-        _ => {
-            let (gateway, mut addr) = find_gateway_and_addr(address)?;
-            addr.set_port(port);
+impl<'de> Deserialize<'de> for LeaseDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct LeaseDurationVisitor;
 
-            let addr = match addr {
-                SocketAddr::V4(addr) => addr,
-                _ => panic!("No IPv4 given"),
-            };
+        impl<'de> Visitor<'de> for LeaseDurationVisitor {
+            type Value = LeaseDuration;
 
-            (gateway, addr)
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number of seconds or \"auto\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+                u32::try_from(v)
+                    .map(LeaseDuration::Seconds)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+                u32::try_from(v)
+                    .map(LeaseDuration::Seconds)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                if v.eq_ignore_ascii_case("auto") {
+                    Ok(LeaseDuration::Auto)
+                } else {
+                    v.parse()
+                        .map(LeaseDuration::Seconds)
+                        .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+                }
+            }
         }
-    })
+
+        deserializer.deserialize_any(LeaseDurationVisitor)
+    }
 }
 
-/// This struct defines a configuration for a port mapping.
-///
-/// The configuration consists of all necessary pieces of information for a proper port opening.
-///
-/// # Examples
-///
-/// ```
-/// use easy_upnp::{Ipv4Cidr, PortMappingProtocol, UpnpConfig};
-///
-/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-/// let config_no_address = UpnpConfig {
-///     address: None,
-///     port: 80,
-///     protocol: PortMappingProtocol::TCP,
-///     duration: 3600,
-///     comment: "Webserver".to_string(),
-/// };
+impl Serialize for LeaseDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            LeaseDuration::Seconds(seconds) => serializer.serialize_u32(*seconds),
+            LeaseDuration::RouterDefault => serializer.serialize_none(),
+            LeaseDuration::Auto => serializer.serialize_str("auto"),
+        }
+    }
+}
+
+/// The lifetime in seconds that a [`RouterDefault`](LeaseDuration::RouterDefault) lease requests
+/// from a NAT-PMP gateway, as recommended by [RFC 6886], or from a UPnP gateway without a maximum
+/// lease duration.
 ///
-/// let config_specific_address = UpnpConfig {
-///     address: Some(Ipv4Cidr::from_str("192.168.0.10/24")?),
-///     port: 80,
-///     protocol: PortMappingProtocol::TCP,
-///     duration: 3600,
-///     comment: "Webserver".to_string(),
-/// };
+/// [RFC 6886]: https://datatracker.ietf.org/doc/html/rfc6886#section-3.3
+pub const NAT_PMP_DEFAULT_LIFETIME: u32 = 7200;
+
+/// The address for which a port mapping should be added.
 ///
-/// let config_address_range = UpnpConfig {
-///     address: Some(Ipv4Cidr::from_str("192.168.0")?),
-///     port: 80,
-///     protocol: PortMappingProtocol::TCP,
-///     duration: 3600,
-///     comment: "Webserver".to_string(),
-/// };
-/// #
-/// # Ok(())
-/// # }
-/// ```
-#[derive(Debug, Deserialize)]
-pub struct UpnpConfig {
-    /// The IP address for which the port mapping should be added.
-    ///
-    /// This field can be [None], in which case every connected interface will be tried, until one
-    /// gateway reports success. Useful if the IP address is dynamic and not consistent over
-    /// reboots.
-    ///
-    /// Fill in an IP address if you want to add a port mapping for a foreign device, or if you
-    /// know your machine's address and want to slightly speed up the process.
+/// In config files, this is given as a string. Strings of the form `exec:<command>` are taken as a
+/// [`Command`](Address::Command), strings with commas as [`Candidates`](Address::Candidates),
+/// everything else is parsed as an [Ipv4Cidr]. Candidates can also be given as a list of strings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// A fixed IP address or range.
+    Cidr(Ipv4Cidr),
+
+    /// Several IP addresses or ranges, which are tried in the given order until one of them finds
+    /// a gateway.
     ///
-    /// For examples how to specify IP addresses, check the documentation of [Ipv4Cidr].
-    pub address: Option<Ipv4Cidr>,
+    /// This is useful for devices that move between networks, like laptops.
+    Candidates(Vec<Ipv4Cidr>),
 
-    /// The port number to open for the given IP address.
+    /// A command whose output is the IP address or range.
     ///
-    /// Note that we are greedy at the moment, if a port mapping is already in place, it will be
-    /// deleted and re-added with the given IP address. This might be configurable in a future
-    /// release.
-    pub port: u16,
+    /// The command is split at whitespace into the program and its arguments, it is not run in a
+    /// shell. The first line of its output is parsed as an [Ipv4Cidr]. This is useful if the
+    /// address is assigned dynamically, for example by a VPN.
+    Command(String),
+}
 
-    /// The protocol for which the given port will be opened. Possible values are
-    /// [`UDP`](PortMappingProtocol::UDP) and [`TCP`](PortMappingProtocol::TCP).
-    pub protocol: PortMappingProtocol,
+impl Address {
+    /// The prefix that marks an address as a [`Command`](Address::Command) in config files.
+    pub const COMMAND_PREFIX: &'static str = "exec:";
 
-    /// The lease duration for the port mapping in seconds.
+    /// Get the IP address or range, running the command if necessary.
     ///
-    /// Please note that some UPnP capable routers might choose to ignore this value, so do not
-    /// exclusively rely on this.
-    pub duration: u32,
+    /// If the command cannot be run, exits unsuccessfully, or its output is not a valid address,
+    /// [UpnpError::AddressCommandError] is returned. Of [`Candidates`](Address::Candidates), only
+    /// the first one is returned, adding and removing port mappings tries all of them.
+    pub fn resolve(&self) -> Result<Ipv4Cidr> {
+        let command = match self {
+            Address::Cidr(cidr) => return Ok(*cidr),
+            Address::Candidates(candidates) => {
+                return candidates
+                    .first()
+                    .copied()
+                    .ok_or(UpnpError::NoMatchingGateway)
+            }
+            Address::Command(command) => command,
+        };
+        let error = |reason: String| UpnpError::AddressCommandError(command.clone(), reason);
 
-    /// A comment about the reason for the port mapping.
-    ///
-    /// Will be stored together with the mapping in the router.
-    pub comment: String,
+        let mut args = command.split_whitespace();
+        let program = args
+            .next()
+            .ok_or_else(|| error("Command is empty".to_string()))?;
+
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .map_err(|e| error(e.to_string()))?;
+
+        if !output.status.success() {
+            return Err(error(output.status.to_string()));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout.lines().next().unwrap_or_default().trim();
+
+        debug!("Address command \"{}\" returned \"{}\"", command, line);
+
+        Ipv4Cidr::from_str(line).map_err(|e| error(format!("Invalid address \"{}\": {}", line, e)))
+    }
 }
 
-impl UpnpConfig {
-    fn remove_port(&self) -> Result<()> {
-        let port = self.port;
-        let protocol = self.protocol.into();
+impl From<Ipv4Cidr> for Address {
+    fn from(cidr: Ipv4Cidr) -> Self {
+        Address::Cidr(cidr)
+    }
+}
 
-        let (gateway, _) = get_gateway_and_address_from_options(&self.address, port)?;
+fn parse_cidr(s: &str) -> Result<Ipv4Cidr> {
+    Ipv4Cidr::from_str(s.trim())
+        .map_err(|e| UpnpError::InvalidAddress(s.to_string(), e.to_string()))
+}
 
-        gateway.remove_port(protocol, port).unwrap_or_else(|e| {
-            warn!(
-                "The following, non-fatal error appeared while deleting port {}:",
-                port
-            );
-            warn!("{}", e);
-        });
+impl FromStr for Address {
+    type Err = UpnpError;
 
-        Ok(())
+    /// Parse an address in the same way as in config files.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(command) = s.strip_prefix(Address::COMMAND_PREFIX) {
+            Ok(Address::Command(command.trim().to_string()))
+        } else if s.contains(',') {
+            s.split(',')
+                .map(parse_cidr)
+                .collect::<Result<_>>()
+                .map(Address::Candidates)
+        } else {
+            parse_cidr(s).map(Address::Cidr)
+        }
     }
+}
 
-    fn add_port(&self) -> Result<()> {
-        let port = self.port;
-        let protocol = self.protocol.into();
-        let duration = self.duration;
-        let comment = &self.comment;
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct AddressVisitor;
 
-        let (gateway, addr) = get_gateway_and_address_from_options(&self.address, port)?;
+        impl<'de> Visitor<'de> for AddressVisitor {
+            type Value = Address;
 
-        let f = || gateway.add_port(protocol, port, addr, duration, comment);
-        f().or_else(|e| match e {
-            igd::AddPortError::PortInUse => {
-                debug!("Port already in use. Delete mapping.");
-                gateway.remove_port(protocol, port).unwrap();
-                debug!("Retry port mapping.");
-                f()
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an address, a command or a list of addresses")
             }
-            e => Err(e),
-        })?;
 
-        Ok(())
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let mut candidates = Vec::new();
+                while let Some(candidate) = seq.next_element::<String>()? {
+                    candidates.push(parse_cidr(&candidate).map_err(de::Error::custom)?);
+                }
+
+                if candidates.is_empty() {
+                    return Err(de::Error::invalid_length(0, &self));
+                }
+
+                Ok(Address::Candidates(candidates))
+            }
+        }
+
+        deserializer.deserialize_any(AddressVisitor)
     }
 }
 
-/// Add port mappings.
-///
-/// This function takes an iterable of [UpnpConfig]s and opens all configures ports.
-///
-/// Errors are logged, but otherwise ignored. An error during opening a port will not stop the
-/// processing of the other ports.
-///
-/// # Example
-///
-/// ```no_run
-/// use log::error;
-/// use easy_upnp::{add_ports, PortMappingProtocol, UpnpConfig};
-///
-/// let config = UpnpConfig {
-///     address: None,
-///     port: 80,
-///     protocol: PortMappingProtocol::TCP,
-///     duration: 3600,
-///     comment: "Webserver".to_string(),
-/// };
-///
-/// for result in add_ports([config]) {
-///     if let Err(err) = result {
-///         error!("{}", err);
-///     }
-/// }
-/// ```
-pub fn add_ports(
-    configs: impl IntoIterator<Item = UpnpConfig>,
-) -> impl Iterator<Item = Result<()>> {
-    configs.into_iter().map(|config| {
-        info!("Add port: {:?}", config);
-        config.add_port()
-    })
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Address::Cidr(cidr) => serializer.collect_str(cidr),
+            Address::Candidates(candidates) => {
+                let candidates: Vec<_> = candidates.iter().map(ToString::to_string).collect();
+                serializer.collect_str(&candidates.join(","))
+            }
+            Address::Command(command) => {
+                serializer.collect_str(&format_args!("{}{}", Address::COMMAND_PREFIX, command))
+            }
+        }
+    }
 }
 
-/// Delete port mappings.
-///
-/// This function takes an iterable of [UpnpConfig]s and closes all configures ports.
-///
-/// Errors are logged, but otherwise ignored. An error during closing a port will not stop the
-/// processing of the other ports.
-///
-/// # Example
-///
-/// ```no_run
-/// use log::error;
-/// use easy_upnp::{delete_ports, PortMappingProtocol, UpnpConfig};
-///
-/// let config = UpnpConfig {
-///     address: None,
-///     port: 80,
-///     protocol: PortMappingProtocol::TCP,
-///     duration: 3600,
-///     comment: "Webserver".to_string(),
-/// };
+/// A process that has to be running for a port mapping, see [`UpnpConfig::requires_process`].
 ///
-/// for result in delete_ports([config]) {
-///     if let Err(err) = result {
-///         error!("{}", err);
-///     }
-/// }
-/// ```
-pub fn delete_ports(
-    configs: impl IntoIterator<Item = UpnpConfig>,
-) -> impl Iterator<Item = Result<()>> {
-    configs.into_iter().map(|config| {
-        info!("Remove port: {:?}", config);
-        config.remove_port()
-    })
+/// In config files, this is given as a string. Strings of the form `pidfile:<path>` are taken as
+/// a [`PidFile`](RequiredProcess::PidFile), everything else as a process
+/// [`Name`](RequiredProcess::Name).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RequiredProcess {
+    /// The name of the process, as reported by the operating system.
+    Name(String),
+
+    /// A file that contains the PID of the process.
+    PidFile(PathBuf),
+}
+
+impl RequiredProcess {
+    /// The prefix that marks a required process as a [`PidFile`](RequiredProcess::PidFile) in
+    /// config files.
+    pub const PID_FILE_PREFIX: &'static str = "pidfile:";
+}
+
+impl<'de> Deserialize<'de> for RequiredProcess {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+
+        Ok(match value.strip_prefix(RequiredProcess::PID_FILE_PREFIX) {
+            Some(path) => RequiredProcess::PidFile(PathBuf::from(path.trim())),
+            None => RequiredProcess::Name(value),
+        })
+    }
+}
+
+impl Serialize for RequiredProcess {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            RequiredProcess::Name(name) => serializer.serialize_str(name),
+            RequiredProcess::PidFile(path) => serializer.collect_str(&format_args!(
+                "{}{}",
+                RequiredProcess::PID_FILE_PREFIX,
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Deserialize an optional value, falling back to its default for empty CSV fields or JSON nulls.
+fn default_if_empty<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+/// Deserialize an optional remote host, rejecting addresses that cannot belong to a remote peer.
+fn deserialize_remote_host<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Ipv4Addr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Ipv4Addr>::deserialize(deserializer)? {
+        Some(ip)
+            if ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_broadcast()
+                || ip.is_multicast() =>
+        {
+            Err(de::Error::invalid_value(
+                Unexpected::Str(&ip.to_string()),
+                &"the address of a remote host",
+            ))
+        }
+        remote_host => Ok(remote_host),
+    }
+}
+
+impl From<PortMappingProtocol> for igd::PortMappingProtocol {
+    fn from(proto: PortMappingProtocol) -> Self {
+        match proto {
+            PortMappingProtocol::TCP => igd::PortMappingProtocol::TCP,
+            PortMappingProtocol::UDP => igd::PortMappingProtocol::UDP,
+        }
+    }
+}
+
+impl From<igd::PortMappingProtocol> for PortMappingProtocol {
+    fn from(proto: igd::PortMappingProtocol) -> Self {
+        match proto {
+            igd::PortMappingProtocol::TCP => PortMappingProtocol::TCP,
+            igd::PortMappingProtocol::UDP => PortMappingProtocol::UDP,
+        }
+    }
+}
+
+/// A port mapping as reported by the gateway.
+#[derive(Clone, Debug)]
+pub struct PortMappingEntry {
+    /// The remote host for which the port mapping is valid. Empty for all hosts.
+    pub remote_host: String,
+
+    /// The external port of the port mapping.
+    pub external_port: u16,
+
+    /// The protocol of the port mapping.
+    pub protocol: PortMappingProtocol,
+
+    /// The port on the internal client to which the traffic is forwarded.
+    pub internal_port: u16,
+
+    /// The internal client to which the traffic is forwarded.
+    pub internal_client: String,
+
+    /// Whether the port mapping is enabled.
+    pub enabled: bool,
+
+    /// The description of the port mapping.
+    pub description: String,
+
+    /// The lease duration of the port mapping in seconds, as reported by the gateway.
+    ///
+    /// Most gateways report the remaining lease time here. A value of 0 means that the port
+    /// mapping is permanent.
+    pub lease_duration: u32,
+}
+
+impl PortMappingEntry {
+    /// Whether the description matches the given pattern.
+    ///
+    /// If the pattern contains the wildcards `*` or `?`, it is matched as a glob, where `*`
+    /// stands for any number of characters and `?` for exactly one. Otherwise, the description
+    /// has to be equal to the pattern.
+    pub fn description_matches(&self, pattern: &str) -> bool {
+        if pattern.contains(['*', '?']) {
+            WildMatch::new(pattern).matches(&self.description)
+        } else {
+            self.description == pattern
+        }
+    }
+
+    /// The remote host as an address, or [None] if the port mapping is valid for all remote hosts.
+    fn remote_host_addr(&self) -> Result<Option<Ipv4Addr>> {
+        match self.remote_host.as_str() {
+            "" => Ok(None),
+            remote_host => remote_host.parse().map(Some).map_err(|_| {
+                UpnpError::InvalidResponse(format!("Invalid remote host \"{}\"", remote_host))
+            }),
+        }
+    }
+
+    /// Whether the port mapping forwards to the given local address.
+    fn forwards_to(&self, addr: SocketAddrV4) -> bool {
+        self.internal_port == addr.port() && self.internal_client == addr.ip().to_string()
+    }
+}
+
+impl From<igd::PortMappingEntry> for PortMappingEntry {
+    fn from(entry: igd::PortMappingEntry) -> Self {
+        Self {
+            remote_host: entry.remote_host,
+            external_port: entry.external_port,
+            protocol: entry.protocol.into(),
+            internal_port: entry.internal_port,
+            internal_client: entry.internal_client,
+            enabled: entry.enabled,
+            description: entry.port_mapping_description,
+            lease_duration: entry.lease_duration,
+        }
+    }
+}
+
+/// The time to wait for a gateway to answer the search, if [`DiscoveryOptions::timeout`] is not
+/// set.
+pub const DEFAULT_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a search without an answer is repeated, if [`DiscoveryOptions::retries`] is not set.
+pub const DEFAULT_DISCOVERY_RETRIES: u32 = 2;
+
+/// The shortest time to wait before a search is repeated, a random delay of up to the same length
+/// is added.
+const DISCOVERY_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// The address that the search for gateways is sent to, if [`DiscoveryOptions::ssdp_address`] is
+/// not set. This is the SSDP multicast address.
+pub const DEFAULT_SSDP_ADDRESS: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// Options for the discovery of gateways.
+///
+/// The [Default] options are suitable for most networks: the search is sent from an ephemeral
+/// port to [DEFAULT_SSDP_ADDRESS], waits up to [DEFAULT_SEARCH_TIMEOUT] for an answer, repeats it
+/// up to [DEFAULT_DISCOVERY_RETRIES] times if there is none, and tries all interfaces in the order
+/// of the operating system. Options can be set directly, or with the builder methods of the same
+/// names.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use easy_upnp::DiscoveryOptions;
+///
+/// let options = DiscoveryOptions::new()
+///     .bind_port(1901)
+///     .timeout(Duration::from_secs(3))
+///     .gateway_name("FRITZ!Box");
+///
+/// assert_eq!(options.bind_port, 1901);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DiscoveryOptions {
+    /// The local port of the socket that searches for gateways.
+    ///
+    /// The default of 0 lets the operating system choose an ephemeral port. A fixed port can be
+    /// useful if a firewall only allows the discovery from a known port. If the port is already in
+    /// use, the discovery fails with [UpnpError::IgdSearchError].
+    pub bind_port: u16,
+
+    /// The address that the search for gateways is sent to.
+    ///
+    /// By default, the search is sent to the SSDP multicast address [DEFAULT_SSDP_ADDRESS], so
+    /// that any gateway in the network can answer. If the multicast search is blocked in your
+    /// network, the address of a known gateway can be given instead.
+    pub ssdp_address: Option<SocketAddrV4>,
+
+    /// The order in which the interfaces are tried.
+    ///
+    /// If a port mapping has no [`address`](UpnpConfig::address), or an address range, the
+    /// interfaces are tried until one of them finds a gateway. Interfaces that match an earlier
+    /// entry of this list are tried first, interfaces that match none of the entries are tried
+    /// last. Interfaces with the same rank are tried in the order of the
+    /// [`match_policy`](DiscoveryOptions::match_policy). By default, this list is empty.
+    pub interface_priority: Vec<InterfaceSelector>,
+
+    /// The order in which equally ranked interfaces are tried.
+    ///
+    /// If several interfaces match the [`address`](UpnpConfig::address) of a port mapping, and
+    /// they have the same rank in the
+    /// [`interface_priority`](DiscoveryOptions::interface_priority), this decides which one is
+    /// tried first. Unlike the order of the operating system, it is the same on all platforms. The
+    /// default is [`Name`](MatchPolicy::Name).
+    pub match_policy: MatchPolicy,
+
+    /// Interfaces that are never tried.
+    ///
+    /// This applies to port mappings without an [`address`](UpnpConfig::address), or with an
+    /// address range, a single address is always used as given. By default, this list is empty.
+    pub interface_excludes: Vec<InterfaceSelector>,
+
+    /// How long to wait for a gateway to answer the search.
+    ///
+    /// If this is [None], [DEFAULT_SEARCH_TIMEOUT] is used. Port mappings can override this with
+    /// [`UpnpConfig::timeout`].
+    pub timeout: Option<Duration>,
+
+    /// How often the search is repeated on an interface if no gateway answers in time.
+    ///
+    /// Search requests and their answers are sent via UDP and get lost now and then, so a search
+    /// that times out is repeated after a short random delay before the interface is given up.
+    /// Other errors are not retried. If this is [None], [DEFAULT_DISCOVERY_RETRIES] is used. Port
+    /// mappings can override this with [`UpnpConfig::retries`].
+    pub retries: Option<u32>,
+
+    /// Whether to log the options of each search for a gateway.
+    ///
+    /// If this is set, the bind address, the broadcast address and the timeout that are handed to
+    /// [igd] for each attempt on each interface are logged at debug level, to be included in bug
+    /// reports about gateways that cannot be found. Off by default.
+    pub log_search_options: bool,
+
+    /// Only use gateways whose friendly name contains this string.
+    ///
+    /// Gateways that do not match are treated as if they did not answer, so that other interfaces
+    /// are tried. By default, any gateway is used.
+    pub gateway_name: Option<String>,
+
+    /// Ports whose existing port mappings are never deleted.
+    ///
+    /// If a port is already in use by another port mapping, [add_ports] deletes the existing one
+    /// and tries again. For the ports in this list, the existing port mapping is kept instead and
+    /// [UpnpError::ProtectedPort] is returned. By default, this list is empty.
+    pub protected_ports: Vec<u16>,
+
+    /// The address family of the interfaces that are tried first.
+    ///
+    /// Interfaces with the same [`interface_priority`](DiscoveryOptions::interface_priority) are
+    /// tried in the preferred family first. Since port mappings are only supported for IPv4 so far,
+    /// interfaces with only an IPv6 address are skipped anyway, so this has no effect yet. It
+    /// exists to keep the choice stable once IPv6 is supported. The default is
+    /// [`V4`](AddressFamily::V4).
+    pub prefer_family: AddressFamily,
+
+    /// The URL of the device description of a known gateway, to use it without a search.
+    ///
+    /// This is for networks where the search does not reach the gateway at all, like when
+    /// multicast is blocked. The URL is the `LOCATION` that the gateway announces, like
+    /// `http://192.168.0.1:5000/rootDesc.xml`, and its host has to be an IPv4 address. If this is
+    /// set, the gateway is used for all port mappings, regardless of their
+    /// [`address`](UpnpConfig::address), and the port mappings forward to the address of the
+    /// local interface that faces the gateway. All other options of the search are ignored.
+    pub gateway_url: Option<String>,
+
+    /// The local address to search for gateways from, and to forward the ports to.
+    ///
+    /// If a port mapping has no [`address`](UpnpConfig::address), or an address range that
+    /// matches several addresses of this machine, the first interface that finds a gateway is
+    /// used. With this option, the given address is used instead, if it is the address of an
+    /// interface and within the range of the port mapping. Otherwise, the port mapping fails with
+    /// [UpnpError::BindIpNotFound]. The
+    /// [`interface_priority`](DiscoveryOptions::interface_priority) and
+    /// [`interface_excludes`](DiscoveryOptions::interface_excludes) are not considered then. By
+    /// default, no address is forced.
+    pub bind_ip: Option<Ipv4Addr>,
+
+    /// Whether to check that a removed port mapping is really gone.
+    ///
+    /// Some gateways acknowledge the removal of a port mapping, but keep it anyway. If this is
+    /// set, [delete_ports] asks the gateway for the port mapping after removing it. If it still
+    /// exists, a warning is logged and the removal is tried once more, after which
+    /// [UpnpError::RemovalIgnored] is reported. This only applies to the
+    /// [`Upnp`](Backend::Upnp) backend and is off by default, since it costs another request per
+    /// port mapping.
+    pub verify_removal: bool,
+
+    /// A custom way to find the gateway and the local address of port mappings.
+    ///
+    /// If this is set, the resolver replaces the built-in search, see [AddressResolver]. All
+    /// other options of the search only apply if the resolver passes them on to the
+    /// [DefaultResolver]. By default, the [DefaultResolver] is used.
+    pub resolver: Option<Arc<dyn AddressResolver>>,
+}
+
+impl DiscoveryOptions {
+    /// Create the [Default] options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set [`bind_port`](DiscoveryOptions::bind_port).
+    pub fn bind_port(mut self, bind_port: u16) -> Self {
+        self.bind_port = bind_port;
+        self
+    }
+
+    /// Set [`ssdp_address`](DiscoveryOptions::ssdp_address).
+    pub fn ssdp_address(mut self, ssdp_address: SocketAddrV4) -> Self {
+        self.ssdp_address = Some(ssdp_address);
+        self
+    }
+
+    /// Set [`interface_priority`](DiscoveryOptions::interface_priority).
+    pub fn interface_priority(mut self, interface_priority: Vec<InterfaceSelector>) -> Self {
+        self.interface_priority = interface_priority;
+        self
+    }
+
+    /// Set [`interface_excludes`](DiscoveryOptions::interface_excludes).
+    pub fn interface_excludes(mut self, interface_excludes: Vec<InterfaceSelector>) -> Self {
+        self.interface_excludes = interface_excludes;
+        self
+    }
+
+    /// Set [`match_policy`](DiscoveryOptions::match_policy).
+    pub fn match_policy(mut self, match_policy: MatchPolicy) -> Self {
+        self.match_policy = match_policy;
+        self
+    }
+
+    /// Set [`timeout`](DiscoveryOptions::timeout).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set [`retries`](DiscoveryOptions::retries).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Set [`log_search_options`](DiscoveryOptions::log_search_options).
+    pub fn log_search_options(mut self, log_search_options: bool) -> Self {
+        self.log_search_options = log_search_options;
+        self
+    }
+
+    /// Set [`gateway_name`](DiscoveryOptions::gateway_name).
+    pub fn gateway_name(mut self, gateway_name: impl Into<String>) -> Self {
+        self.gateway_name = Some(gateway_name.into());
+        self
+    }
+
+    /// Set [`protected_ports`](DiscoveryOptions::protected_ports).
+    pub fn protected_ports(mut self, protected_ports: Vec<u16>) -> Self {
+        self.protected_ports = protected_ports;
+        self
+    }
+
+    /// Set [`prefer_family`](DiscoveryOptions::prefer_family).
+    pub fn prefer_family(mut self, prefer_family: AddressFamily) -> Self {
+        self.prefer_family = prefer_family;
+        self
+    }
+
+    /// Set [`gateway_url`](DiscoveryOptions::gateway_url).
+    pub fn gateway_url(mut self, gateway_url: impl Into<String>) -> Self {
+        self.gateway_url = Some(gateway_url.into());
+        self
+    }
+
+    /// Set [`bind_ip`](DiscoveryOptions::bind_ip).
+    pub fn bind_ip(mut self, bind_ip: Ipv4Addr) -> Self {
+        self.bind_ip = Some(bind_ip);
+        self
+    }
+
+    /// Set [`verify_removal`](DiscoveryOptions::verify_removal).
+    pub fn verify_removal(mut self, verify_removal: bool) -> Self {
+        self.verify_removal = verify_removal;
+        self
+    }
+
+    /// Set [`resolver`](DiscoveryOptions::resolver).
+    pub fn resolver(mut self, resolver: impl AddressResolver + 'static) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+}
+
+/// Finds the gateway for a port mapping, and the local address that the port is forwarded to.
+///
+/// The [DefaultResolver] searches for gateways as described for [`UpnpConfig::address`]. Set
+/// another resolver as [`DiscoveryOptions::resolver`] to replace it, for example with an address
+/// from a service discovery. The resolver is only asked for port mappings of the
+/// [`Upnp`](Backend::Upnp) backend, since NAT-PMP always uses the default route.
+///
+/// # Example
+///
+/// A resolver that forwards all ports to a fixed address, but keeps the default search for the
+/// gateway:
+///
+/// ```no_run
+/// use std::net::{Ipv4Addr, SocketAddrV4};
+///
+/// use easy_upnp::{
+///     add_ports, AddressResolver, DefaultResolver, DiscoveryOptions, Gateway, Ipv4Cidr,
+///     UpnpConfig,
+/// };
+///
+/// #[derive(Debug)]
+/// struct FixedAddress(Ipv4Addr);
+///
+/// impl AddressResolver for FixedAddress {
+///     fn resolve(
+///         &self,
+///         address: &Option<Ipv4Cidr>,
+///         port: u16,
+///         options: &DiscoveryOptions,
+///     ) -> easy_upnp::Result<(Gateway, SocketAddrV4)> {
+///         let (gateway, _) = DefaultResolver.resolve(address, port, options)?;
+///         Ok((gateway, SocketAddrV4::new(self.0, port)))
+///     }
+/// }
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let options = DiscoveryOptions::new().resolver(FixedAddress(Ipv4Addr::new(192, 168, 0, 10)));
+/// let config: UpnpConfig = serde_json::from_str(r#"{"port": 80, "protocol": "TCP"}"#)?;
+///
+/// for result in add_ports([config], Some(&options)) {
+///     result?;
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub trait AddressResolver: fmt::Debug + Send + Sync {
+    /// Find the gateway and the local address for a port mapping.
+    ///
+    /// The `address` is the resolved [`address`](UpnpConfig::address) of the port mapping, or
+    /// each of its candidates in turn, and the port of the returned address should be `port`. If
+    /// no gateway can be found, [UpnpError::NoMatchingGateway] is the error of choice.
+    fn resolve(
+        &self,
+        address: &Option<Ipv4Cidr>,
+        port: u16,
+        options: &DiscoveryOptions,
+    ) -> Result<(Gateway, SocketAddrV4)>;
+}
+
+/// The built-in [AddressResolver], which searches for gateways as configured by the
+/// [DiscoveryOptions].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultResolver;
+
+impl AddressResolver for DefaultResolver {
+    fn resolve(
+        &self,
+        address: &Option<Ipv4Cidr>,
+        port: u16,
+        options: &DiscoveryOptions,
+    ) -> Result<(Gateway, SocketAddrV4)> {
+        match &options.gateway_url {
+            Some(url) => get_gateway_and_address_from_url(url, port),
+            None => search_gateway_and_address(address, port, options),
+        }
+    }
+}
+
+/// Selects network interfaces, see [`DiscoveryOptions::interface_priority`] and
+/// [`DiscoveryOptions::interface_excludes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InterfaceSelector {
+    /// Select the interface with the given name, like `eth0`.
+    Name(String),
+
+    /// Select the interfaces whose address is in the given range.
+    Cidr(Ipv4Cidr),
+}
+
+impl InterfaceSelector {
+    fn matches(&self, iface: &get_if_addrs::Interface, ip: Ipv4Addr) -> bool {
+        match self {
+            InterfaceSelector::Name(name) => &iface.name == name,
+            InterfaceSelector::Cidr(cidr) => cidr.contains(ip),
+        }
+    }
+}
+
+impl FromStr for InterfaceSelector {
+    type Err = std::convert::Infallible;
+
+    /// Parse an [Ipv4Cidr], or fall back to an interface name.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match Ipv4Cidr::from_str(s) {
+            Ok(cidr) => InterfaceSelector::Cidr(cidr),
+            Err(_) => InterfaceSelector::Name(s.to_string()),
+        })
+    }
+}
+
+/// A random number, good enough for jitter and port choice, but not for cryptography.
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// The time to wait before the search is repeated, with some jitter so that several instances do
+/// not repeat their searches in lockstep.
+fn discovery_retry_delay() -> Duration {
+    let jitter = random_u64() % DISCOVERY_RETRY_DELAY.as_millis() as u64;
+    DISCOVERY_RETRY_DELAY + Duration::from_millis(jitter)
+}
+
+/// Search for a gateway from the given address, and repeat the search if it times out.
+fn search_gateway(ip: IpAddr, options: &DiscoveryOptions) -> Result<Gateway> {
+    let retries = options.retries.unwrap_or(DEFAULT_DISCOVERY_RETRIES);
+    let mut attempt = 0;
+
+    loop {
+        let search_options = SearchOptions {
+            bind_addr: SocketAddr::new(ip, options.bind_port),
+            broadcast_address: SocketAddr::V4(options.ssdp_address.unwrap_or(DEFAULT_SSDP_ADDRESS)),
+            timeout: Some(options.timeout.unwrap_or(DEFAULT_SEARCH_TIMEOUT)),
+        };
+
+        if options.log_search_options {
+            // igd does not set the TTL of its search requests, so the default of the system
+            // applies, which is 1 for multicast on most systems.
+            debug!(
+                "Search options of attempt {} of {}: bind address {}, broadcast address {}, \
+                 timeout {:?}, TTL of the system",
+                attempt + 1,
+                retries + 1,
+                search_options.bind_addr,
+                search_options.broadcast_address,
+                search_options.timeout.unwrap_or_default()
+            );
+        }
+
+        match igd::search_gateway(search_options) {
+            Err(igd::SearchError::IoError(e))
+                if attempt < retries
+                    && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                attempt += 1;
+                let delay = discovery_retry_delay();
+                debug!(
+                    "No gateway answered the search from {}, retry {} of {} in {:?}",
+                    ip, attempt, retries, delay
+                );
+                thread::sleep(delay);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+fn find_gateway_with_bind_addr(ip: IpAddr, options: &DiscoveryOptions) -> Result<Gateway> {
+    let gateway = search_gateway(ip, options)?;
+
+    if let Some(name) = &options.gateway_name {
+        let friendly_name = soap::get_friendly_name(&gateway)?;
+        if !friendly_name.contains(name.as_str()) {
+            debug!(
+                "Ignore gateway \"{}\" at {}, it does not match \"{}\"",
+                friendly_name, gateway.addr, name
+            );
+            return Err(UpnpError::NoMatchingGateway);
+        }
+    }
+
+    Ok(gateway)
+}
+
+/// Sort the interfaces in the order in which they are tried, see
+/// [`DiscoveryOptions::interface_priority`].
+fn order_interfaces(ifaces: &mut [get_if_addrs::Interface], options: &DiscoveryOptions) {
+    // The sorts below are stable, so each one only decides between equally ranked interfaces of
+    // the next one.
+    ifaces.sort_by(|a, b| match options.match_policy {
+        MatchPolicy::Name => (&a.name, a.ip()).cmp(&(&b.name, b.ip())),
+        MatchPolicy::LowestIp => (a.ip(), &a.name).cmp(&(b.ip(), &b.name)),
+    });
+
+    if options.prefer_family == AddressFamily::V6 {
+        debug!("IPv6 is not supported yet, searching for gateways via IPv4");
+    }
+    ifaces.sort_by_key(|iface| AddressFamily::of(iface.ip()) != options.prefer_family);
+
+    if !options.interface_priority.is_empty() {
+        ifaces.sort_by_key(|iface| match iface.ip() {
+            IpAddr::V4(ip) => options
+                .interface_priority
+                .iter()
+                .position(|selector| selector.matches(iface, ip))
+                .unwrap_or(options.interface_priority.len()),
+            IpAddr::V6(_) => options.interface_priority.len(),
+        });
+    }
+}
+
+/// Interfaces that a gateway has been found on, by their name and address.
+static USED_INTERFACES: Mutex<BTreeSet<(String, Ipv4Addr)>> = Mutex::new(BTreeSet::new());
+
+/// Forget the used interfaces that are not among `ifaces` anymore, and return them.
+fn forget_missing_interfaces(
+    used: &mut BTreeSet<(String, Ipv4Addr)>,
+    ifaces: &[get_if_addrs::Interface],
+) -> Vec<(String, Ipv4Addr)> {
+    let (present, missing): (BTreeSet<_>, BTreeSet<_>) =
+        std::mem::take(used).into_iter().partition(|(name, ip)| {
+            ifaces
+                .iter()
+                .any(|iface| &iface.name == name && iface.ip() == IpAddr::V4(*ip))
+        });
+    *used = present;
+    missing.into_iter().collect()
+}
+
+fn find_gateway_and_addr(
+    cidr: &Option<Ipv4Cidr>,
+    options: &DiscoveryOptions,
+) -> Result<(Gateway, SocketAddr)> {
+    let mut ifaces = get_if_addrs::get_if_addrs().map_err(UpnpError::CannotGetInterfaceAddress)?;
+
+    // Interfaces come and go on laptops, so tell why another one is used from now on.
+    let missing = forget_missing_interfaces(
+        &mut USED_INTERFACES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        &ifaces,
+    );
+    for (name, ip) in missing {
+        warn!(
+            "Interface {} ({}) is no longer present, re-selecting",
+            name, ip
+        );
+    }
+
+    ifaces.retain(|iface| match iface.ip() {
+        IpAddr::V4(ip) => !options
+            .interface_excludes
+            .iter()
+            .any(|selector| selector.matches(iface, ip)),
+        IpAddr::V6(_) => true,
+    });
+
+    order_interfaces(&mut ifaces, options);
+
+    let candidates: Vec<_> = ifaces
+        .iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match (iface.ip(), cidr) {
+            (IpAddr::V4(ip), Some(cidr)) if !cidr.contains(ip) => None,
+            (IpAddr::V4(ip), _) => Some((iface, ip)),
+            _ => None,
+        })
+        .collect();
+
+    let (iface, ip, gateway) = match cidr {
+        // A given address range only decides about the interface, errors of its search count.
+        Some(_) => candidates.first().map(|&(iface, ip)| {
+            (
+                iface,
+                ip,
+                find_gateway_with_bind_addr(IpAddr::V4(ip), options),
+            )
+        }),
+        None => candidates.iter().find_map(|&(iface, ip)| {
+            find_gateway_with_bind_addr(IpAddr::V4(ip), options)
+                .ok()
+                .map(|gateway| (iface, ip, Ok(gateway)))
+        }),
+    }
+    .ok_or(UpnpError::NoMatchingGateway)?;
+    let gateway = gateway?;
+
+    USED_INTERFACES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert((iface.name.clone(), ip));
+
+    if candidates.len() > 1 {
+        debug!(
+            "Use interface {} ({}), the first of {} matching interfaces to find a gateway, \
+             ordered by interface priority and {}",
+            iface.name,
+            ip,
+            candidates.len(),
+            options.match_policy
+        );
+    }
+
+    Ok((gateway, SocketAddr::new(IpAddr::V4(ip), 0)))
+}
+
+/// Use the gateway of [`DiscoveryOptions::gateway_url`], and the local address that faces it.
+fn get_gateway_and_address_from_url(url: &str, port: u16) -> Result<(Gateway, SocketAddrV4)> {
+    let gateway = soap::gateway_from_url(url)?;
+
+    // Connecting a UDP socket sends nothing, it only lets the system pick the route.
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect(gateway.addr).map(|()| socket))
+        .map_err(UpnpError::CannotGetInterfaceAddress)?;
+    let ip = match socket
+        .local_addr()
+        .map_err(UpnpError::CannotGetInterfaceAddress)?
+        .ip()
+    {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => unreachable!("The socket is bound to an IPv4 address"),
+    };
+
+    Ok((gateway, SocketAddrV4::new(ip, port)))
+}
+
+fn get_gateway_and_address_from_options(
+    address: &Option<Ipv4Cidr>,
+    port: u16,
+    options: &DiscoveryOptions,
+) -> Result<(Gateway, SocketAddrV4)> {
+    if let Some(replayed) = soap::replayed_gateway(port) {
+        return Ok(replayed);
+    }
+
+    let (gateway, addr) = match &options.resolver {
+        Some(resolver) => resolver.resolve(address, port, options)?,
+        None => DefaultResolver.resolve(address, port, options)?,
+    };
+    report_gateway(&gateway);
+
+    Ok((gateway, addr))
+}
+
+/// The actions of the connection service that this crate relies on.
+pub const USED_ACTIONS: [&str; 6] = [
+    "GetExternalIPAddress",
+    "AddPortMapping",
+    "AddAnyPortMapping",
+    "DeletePortMapping",
+    "GetSpecificPortMappingEntry",
+    "GetGenericPortMappingEntry",
+];
+
+/// The [USED_ACTIONS] that the service description of the gateway does not list.
+fn missing_actions(gateway: &Gateway) -> Vec<String> {
+    USED_ACTIONS
+        .iter()
+        .filter(|action| !gateway.control_schema.contains_key(**action))
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Fail early with a clear error if the gateway does not support the action.
+fn require_action(gateway: &Gateway, action: &'static str) -> Result<()> {
+    if gateway.control_schema.contains_key(action) {
+        Ok(())
+    } else {
+        Err(UpnpError::UnsupportedAction(action))
+    }
+}
+
+/// Gateways that have been reported, by the URL of their service description.
+static REPORTED_GATEWAYS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Log once per gateway which device it is, and warn about the [USED_ACTIONS] that it does not
+/// support.
+fn report_gateway(gateway: &Gateway) {
+    let url = format!("{}{}", gateway.addr, gateway.control_schema_url);
+    let first_time = REPORTED_GATEWAYS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(url);
+    if !first_time {
+        return;
+    }
+
+    match soap::get_device_identity(gateway) {
+        Ok(identity) => info!(
+            "Using gateway \"{}\" at {}, manufacturer: {}, model: {}",
+            identity.friendly_name,
+            gateway.addr,
+            identity.manufacturer.as_deref().unwrap_or("unknown"),
+            identity.model_name.as_deref().unwrap_or("unknown")
+        ),
+        Err(e) => {
+            info!("Using gateway at {}", gateway.addr);
+            debug!("Could not read device description of gateway: {}", e);
+        }
+    }
+    debug!(
+        "Control URL of gateway: http://{}{}",
+        gateway.addr, gateway.control_url
+    );
+
+    let missing = missing_actions(gateway);
+    if !missing.is_empty() {
+        warn!(
+            "Gateway at {} does not support the actions {}, features that need them are disabled",
+            gateway.addr,
+            missing.join(", ")
+        );
+    }
+}
+
+/// Make sure that the [`DiscoveryOptions::bind_ip`] belongs to an interface and is within the
+/// address range of the port mapping.
+fn check_bind_ip(bind_ip: Ipv4Addr, address: &Option<Ipv4Cidr>) -> Result<()> {
+    if address.as_ref().is_some_and(|cidr| !cidr.contains(bind_ip)) {
+        return Err(UpnpError::BindIpNotFound(bind_ip));
+    }
+
+    let ifaces = get_if_addrs::get_if_addrs().map_err(UpnpError::CannotGetInterfaceAddress)?;
+    if ifaces.iter().any(|iface| iface.ip() == IpAddr::V4(bind_ip)) {
+        Ok(())
+    } else {
+        Err(UpnpError::BindIpNotFound(bind_ip))
+    }
+}
+
+fn search_gateway_and_address(
+    address: &Option<Ipv4Cidr>,
+    port: u16,
+    options: &DiscoveryOptions,
+) -> Result<(Gateway, SocketAddrV4)> {
+    if let Some(bind_ip) = options.bind_ip {
+        check_bind_ip(bind_ip, address)?;
+        let gateway = find_gateway_with_bind_addr(IpAddr::V4(bind_ip), options)?;
+
+        return Ok((gateway, SocketAddrV4::new(bind_ip, port)));
+    }
+
+    Ok(match address {
+        Some(addr) if addr.get_bits() == 32 => {
+            let addr = SocketAddr::new(IpAddr::V4(addr.get_prefix_as_ipv4_addr()), port);
+
+            let gateway = find_gateway_with_bind_addr(addr.ip(), options)?;
+
+            let addr = match addr {
+                SocketAddr::V4(addr) => addr,
+                _ => panic!("No IPv4 given"),
+            };
+
+            (gateway, addr)
+        }
+
+        _ => {
+            let (gateway, mut addr) = find_gateway_and_addr(address, options)?;
+            addr.set_port(port);
+
+            let addr = match addr {
+                SocketAddr::V4(addr) => addr,
+                _ => panic!("No IPv4 given"),
+            };
+
+            (gateway, addr)
+        }
+    })
+}
+
+/// This struct defines a configuration for a port mapping.
+///
+/// The configuration consists of all necessary pieces of information for a proper port opening.
+///
+/// # Examples
+///
+/// ```
+/// use easy_upnp::{Ipv4Cidr, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let config_no_address = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let config_specific_address = UpnpConfig {
+///     address: Some(Ipv4Cidr::from_str("192.168.0.10/24")?.into()),
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let config_address_range = UpnpConfig {
+///     address: Some(Ipv4Cidr::from_str("192.168.0")?.into()),
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(remote = "Self")]
+pub struct UpnpConfig {
+    /// The IP address for which the port mapping should be added.
+    ///
+    /// This field can be [None], in which case every connected interface will be tried, until one
+    /// gateway reports success. Useful if the IP address is dynamic and not consistent over
+    /// reboots.
+    ///
+    /// Fill in an IP address if you want to add a port mapping for a foreign device, or if you
+    /// know your machine's address and want to slightly speed up the process.
+    ///
+    /// For examples how to specify IP addresses, check the documentation of [Ipv4Cidr]. The
+    /// address can also be determined by a command, see [Address].
+    pub address: Option<Address>,
+
+    /// The port number to open for the given IP address.
+    ///
+    /// In config files, the port can also be given together with the protocol, like `"tcp/8080"`
+    /// or `"udp/53"`. The [`protocol`](UpnpConfig::protocol) can then be left out.
+    ///
+    /// Note that we are greedy at the moment, if a port mapping is already in place, it will be
+    /// deleted and re-added with the given IP address. This might be configurable in a future
+    /// release.
+    pub port: u16,
+
+    /// The protocol for which the given port will be opened. Possible values are
+    /// [`UDP`](PortMappingProtocol::UDP) and [`TCP`](PortMappingProtocol::TCP).
+    pub protocol: PortMappingProtocol,
+
+    /// The lease duration for the port mapping.
+    ///
+    /// Please note that some UPnP capable routers might choose to ignore this value, so do not
+    /// exclusively rely on this. If the router advertises a maximum lease duration in its service
+    /// description, longer durations are reduced to that maximum. If a gateway rejects a UDP port
+    /// mapping because it only supports permanent leases, it is requested again as permanent, and
+    /// so are all later UDP port mappings on that gateway. A duration of 0 requests a permanent
+    /// port mapping, and if the duration is left out, the gateway's default is used, see
+    /// [LeaseDuration].
+    #[serde(default, deserialize_with = "default_if_empty")]
+    pub duration: LeaseDuration,
+
+    /// A comment about the reason for the port mapping.
+    ///
+    /// Will be stored together with the mapping in the router. Defaults to an empty string in
+    /// config files.
+    #[serde(default)]
+    pub comment: String,
+
+    /// The backend that is used to add the port mapping.
+    ///
+    /// This field can be [None], in which case [`Backend::Upnp`] is used.
+    #[serde(default)]
+    pub backend: Option<Backend>,
+
+    /// The priority of the port mapping.
+    ///
+    /// Port mappings with a higher priority are added first by [add_ports], port mappings with
+    /// the same priority are added in the given order. This has no effect on the router itself,
+    /// it only determines the order in which the port mappings are processed. Defaults to 0.
+    #[serde(default, deserialize_with = "default_if_empty")]
+    pub priority: i32,
+
+    /// The remote host for which the port mapping should be valid.
+    ///
+    /// This field can be [None], in which case the port mapping is valid for all remote hosts.
+    /// Otherwise, only traffic from the given external address is forwarded. Please note that
+    /// not all UPnP capable routers honor this value, some reject such port mappings, others
+    /// silently open the port for everyone. This is not supported by the
+    /// [`NatPmp`](Backend::NatPmp) backend.
+    #[serde(default, deserialize_with = "deserialize_remote_host")]
+    pub remote_host: Option<Ipv4Addr>,
+
+    /// Whether the gateway may choose any free external port.
+    ///
+    /// If this is true, [`port`](UpnpConfig::port) is only used as the internal port, and the
+    /// external port is chosen by the gateway. The chosen port is returned by [add_ports]. When
+    /// the port mapping is renewed, the existing port mapping for the internal port is reused, so
+    /// the external port stays the same as long as the port mapping does not expire. This cannot
+    /// be combined with [`remote_host`](UpnpConfig::remote_host). Defaults to false.
+    #[serde(default, deserialize_with = "default_if_empty")]
+    pub any_port: bool,
+
+    /// The IP address of the device to which the traffic is forwarded.
+    ///
+    /// This field can be [None], in which case the traffic is forwarded to the local interface
+    /// that found the gateway, see [`address`](UpnpConfig::address). Otherwise, the traffic is
+    /// forwarded to the given device, which allows to open ports for other devices in the local
+    /// network. The gateway is still searched for via the local interfaces. A warning is logged if
+    /// the address is not a private address. This is not supported by the
+    /// [`NatPmp`](Backend::NatPmp) backend.
+    #[serde(default)]
+    pub internal_client: Option<Ipv4Addr>,
+
+    /// A process that has to be running for the port mapping to be open.
+    ///
+    /// This field can be [None], in which case the port mapping is always opened. This library
+    /// does not check the process itself, [add_ports] opens the port regardless. It is up to the
+    /// caller to only pass configurations whose process is running, and to delete the port
+    /// mappings of processes that have ended, like [`upnp-daemon`] does.
+    ///
+    /// [`upnp-daemon`]: https://github.com/FloGa/upnp-daemon
+    #[serde(default)]
+    pub requires_process: Option<RequiredProcess>,
+
+    /// The hostname of the device to which the traffic is forwarded.
+    ///
+    /// This field can be [None], in which case [`internal_client`](UpnpConfig::internal_client)
+    /// is used as is. This library does not look up the hostname, [add_ports] only uses the
+    /// internal client. It is up to the caller to look up the current address of the hostname,
+    /// for example in a DHCP lease file with [parse_dnsmasq_leases], and to fill it in as the
+    /// internal client, like [`upnp-daemon`] does.
+    ///
+    /// [`upnp-daemon`]: https://github.com/FloGa/upnp-daemon
+    #[serde(default)]
+    pub internal_hostname: Option<String>,
+
+    /// How often the search for the gateway of this port mapping is repeated if no gateway
+    /// answers in time.
+    ///
+    /// This field can be [None], in which case [`DiscoveryOptions::retries`] applies. Otherwise,
+    /// it overrides the retries of the [DiscoveryOptions] for this port mapping only, for example
+    /// to try harder for a critical port.
+    #[serde(default)]
+    pub retries: Option<u32>,
+
+    /// How many seconds to wait for a gateway to answer the search for this port mapping.
+    ///
+    /// This field can be [None], in which case [`DiscoveryOptions::timeout`] applies. Otherwise,
+    /// it overrides the timeout of the [DiscoveryOptions] for this port mapping only.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    /// The gateway to use for this port mapping, if there are several.
+    ///
+    /// This field can be [None], in which case the gateway is chosen as described for
+    /// [`address`](UpnpConfig::address). Otherwise, it is either the URL of the device
+    /// description of the gateway, like `http://192.168.0.1:5000/rootDesc.xml`, which is used like
+    /// [`DiscoveryOptions::gateway_url`], or a part of the friendly name of the gateway, which is
+    /// used like [`DiscoveryOptions::gateway_name`]. Either way, it takes precedence over the
+    /// [DiscoveryOptions] for this port mapping only. If no such gateway is found, the port mapping
+    /// fails with [UpnpError::GatewayNotFound].
+    #[serde(default)]
+    pub gateway: Option<String>,
+}
+
+/// The `port` of a config file, either a number or combined with the protocol, like `"tcp/8080"`.
+#[derive(Clone, Copy)]
+enum PortSpec {
+    Number(u16),
+    WithProtocol(PortMappingProtocol, u16),
+}
+
+impl<'de> Deserialize<'de> for PortSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct PortSpecVisitor;
+
+        impl<'de> Visitor<'de> for PortSpecVisitor {
+            type Value = PortSpec;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a port number, or a protocol and port like \"tcp/8080\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(PortSpec::Number)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(PortSpec::Number)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                let invalid = || E::invalid_value(Unexpected::Str(v), &self);
+
+                let Some((protocol, port)) = v.split_once('/') else {
+                    return v
+                        .trim()
+                        .parse()
+                        .map(PortSpec::Number)
+                        .map_err(|_| invalid());
+                };
+
+                let protocol = match protocol.trim() {
+                    protocol if protocol.eq_ignore_ascii_case("tcp") => PortMappingProtocol::TCP,
+                    protocol if protocol.eq_ignore_ascii_case("udp") => PortMappingProtocol::UDP,
+                    _ => return Err(invalid()),
+                };
+                let port = port.trim().parse().map_err(|_| invalid())?;
+
+                Ok(PortSpec::WithProtocol(protocol, port))
+            }
+        }
+
+        deserializer.deserialize_any(PortSpecVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for UpnpConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct UpnpConfigVisitor;
+
+        impl<'de> Visitor<'de> for UpnpConfigVisitor {
+            type Value = UpnpConfig;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a port mapping configuration")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let mut map = PortSpecMap {
+                    map,
+                    exhausted: false,
+                    next_is_port: false,
+                    port: None,
+                    has_protocol: false,
+                    added_protocol: None,
+                };
+
+                // The derived implementation, see `#[serde(remote = "Self")]`
+                let config = UpnpConfig::deserialize(MapAccessDeserializer::new(&mut map))?;
+
+                if let Some(PortSpec::WithProtocol(protocol, port)) = map.port {
+                    if protocol != config.protocol {
+                        return Err(de::Error::custom(format!(
+                            "port {}/{} contradicts protocol {}",
+                            protocol, port, config.protocol
+                        )));
+                    }
+                }
+
+                if config.timeout == Some(0) {
+                    return Err(de::Error::custom("timeout must be at least 1 second"));
+                }
+
+                Ok(config)
+            }
+        }
+
+        deserializer.deserialize_struct("UpnpConfig", UpnpConfig::FIELDS, UpnpConfigVisitor)
+    }
+}
+
+impl Serialize for UpnpConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        // The derived implementation, see `#[serde(remote = "Self")]`
+        UpnpConfig::serialize(self, serializer)
+    }
+}
+
+/// The entries of a config file, with the [PortSpec] of the `port` expanded.
+///
+/// The port is passed on as a number. If the port includes the protocol and the entries do not
+/// name one, the protocol is added as an entry of its own.
+struct PortSpecMap<A> {
+    map: A,
+    exhausted: bool,
+    next_is_port: bool,
+    port: Option<PortSpec>,
+    has_protocol: bool,
+    added_protocol: Option<PortMappingProtocol>,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for PortSpecMap<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> std::result::Result<Option<K::Value>, Self::Error> {
+        if !self.exhausted {
+            if let Some(key) = self.map.next_key::<String>()? {
+                self.next_is_port = key == "port";
+                self.has_protocol |= key == "protocol";
+                return seed.deserialize(key.into_deserializer()).map(Some);
+            }
+            self.exhausted = true;
+        }
+
+        match self.port {
+            Some(PortSpec::WithProtocol(protocol, _)) if !self.has_protocol => {
+                self.has_protocol = true;
+                self.added_protocol = Some(protocol);
+                seed.deserialize("protocol".into_deserializer()).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        if let Some(protocol) = self.added_protocol.take() {
+            return seed.deserialize(protocol.to_string().into_deserializer());
+        }
+
+        if !self.next_is_port {
+            return self.map.next_value_seed(seed);
+        }
+
+        let port = self.map.next_value()?;
+        self.port = Some(port);
+        match port {
+            PortSpec::Number(port) | PortSpec::WithProtocol(_, port) => {
+                seed.deserialize(port.into_deserializer())
+            }
+        }
+    }
+}
+
+impl UpnpConfig {
+    /// The names of all fields, as used in config files.
+    pub const FIELDS: &'static [&'static str] = &[
+        "address",
+        "port",
+        "protocol",
+        "duration",
+        "comment",
+        "backend",
+        "priority",
+        "remote_host",
+        "any_port",
+        "internal_client",
+        "requires_process",
+        "internal_hostname",
+        "retries",
+        "timeout",
+        "gateway",
+    ];
+
+    /// A port mapping of the given port and protocol, with the defaults of a config file for all
+    /// other fields.
+    ///
+    /// Use the struct update syntax to set other fields, so that code keeps compiling when new
+    /// fields are added:
+    ///
+    /// ```
+    /// use easy_upnp::{PortMappingProtocol, UpnpConfig};
+    ///
+    /// let config = UpnpConfig {
+    ///     comment: "Webserver".to_string(),
+    ///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+    /// };
+    /// ```
+    pub fn new(port: u16, protocol: PortMappingProtocol) -> Self {
+        Self {
+            address: None,
+            port,
+            protocol,
+            duration: LeaseDuration::default(),
+            comment: String::new(),
+            backend: None,
+            priority: 0,
+            remote_host: None,
+            any_port: false,
+            internal_client: None,
+            requires_process: None,
+            internal_hostname: None,
+            retries: None,
+            timeout: None,
+            gateway: None,
+        }
+    }
+
+    /// Get the remaining lease time in seconds of the port mapping for this configuration.
+    ///
+    /// Returns [None] if the gateway has no port mapping for this configuration, or if the
+    /// existing port mapping is disabled or forwards to another address. A remaining lease time
+    /// of 0 means that the port mapping is permanent.
+    ///
+    /// This is only supported by the [`Upnp`](Backend::Upnp) backend. If no `options` are given,
+    /// the [Default] [DiscoveryOptions] are used.
+    pub fn remaining_lease(&self, options: Option<&DiscoveryOptions>) -> Result<Option<u32>> {
+        self.check_backend(Backend::Upnp)?;
+
+        let options = options.cloned().unwrap_or_default();
+        let (gateway, addr) = self.gateway_and_address(&options)?;
+        let addr = self.internal_addr(addr);
+
+        let entry = self.existing_mapping(&gateway, addr)?;
+
+        Ok(entry
+            .filter(|entry| entry.enabled && entry.forwards_to(addr))
+            .map(|entry| entry.lease_duration))
+    }
+
+    /// Find out what [add_ports] would do for this configuration, without changing anything.
+    ///
+    /// The gateway is searched for and asked for an existing port mapping, just like when adding
+    /// the port. The same errors are returned that adding the port would run into before talking
+    /// to the gateway. Since NAT-PMP cannot be asked for existing port mappings, configurations of
+    /// the [`NatPmp`](Backend::NatPmp) backend are always planned to be added, without a gateway.
+    ///
+    /// If no `options` are given, the [Default] [DiscoveryOptions] are used.
+    pub fn plan(&self, options: Option<&DiscoveryOptions>) -> Result<PortMappingPlan> {
+        self.check_upnp_fields()?;
+        self.check_any_port()?;
+
+        if self.backend() == Backend::NatPmp {
+            return Ok(PortMappingPlan {
+                gateway: None,
+                local_addr: None,
+                action: PlannedAction::Add,
+            });
+        }
+
+        let options = options.cloned().unwrap_or_default();
+        let (gateway, addr) = self.gateway_and_address(&options)?;
+        let addr = self.internal_addr(addr);
+
+        let entry = self.existing_mapping(&gateway, addr)?;
+
+        let action = match entry {
+            None => PlannedAction::Add,
+            Some(entry) if entry.forwards_to(addr) => {
+                if entry.enabled {
+                    PlannedAction::Refresh
+                } else {
+                    PlannedAction::Enable
+                }
+            }
+            Some(_) => PlannedAction::Replace,
+        };
+
+        Ok(PortMappingPlan {
+            gateway: Some(gateway_info(&gateway)?),
+            local_addr: Some(addr),
+            action,
+        })
+    }
+
+    /// The [DiscoveryOptions] to find the gateway of this configuration with.
+    ///
+    /// These are the given `options`, where the [`retries`](UpnpConfig::retries),
+    /// [`timeout`](UpnpConfig::timeout) and [`gateway`](UpnpConfig::gateway) of this configuration
+    /// take precedence.
+    pub fn discovery_options(&self, options: &DiscoveryOptions) -> DiscoveryOptions {
+        let mut options = DiscoveryOptions {
+            retries: self.retries.or(options.retries),
+            timeout: self.timeout.map(Duration::from_secs).or(options.timeout),
+            ..options.clone()
+        };
+        match &self.gateway {
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                options.gateway_url = Some(url.clone());
+            }
+            Some(name) => {
+                options.gateway_url = None;
+                options.gateway_name = Some(name.clone());
+            }
+            None => {}
+        }
+
+        options
+    }
+
+    /// Get the IP address or range of this configuration, see [Address::resolve].
+    pub fn resolve_address(&self) -> Result<Option<Ipv4Cidr>> {
+        self.address.as_ref().map(Address::resolve).transpose()
+    }
+
+    /// Parse a single configuration from a CSV record.
+    ///
+    /// The `headers` name the field of each column of the `record`, valid names are listed in
+    /// [`UpnpConfig::FIELDS`]. Just like in [parse_configs_csv], trailing fields that are missing
+    /// from the record get their default values.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use easy_upnp::{StringRecord, UpnpConfig};
+    ///
+    /// # fn main() -> Result<(), easy_upnp::UpnpError> {
+    /// let headers = StringRecord::from(vec!["port", "protocol", "duration", "comment"]);
+    /// let record = StringRecord::from(vec!["80", "TCP", "3600"]);
+    ///
+    /// let config = UpnpConfig::from_csv_record(&record, &headers)?;
+    /// assert_eq!(config.port, 80);
+    /// assert_eq!(config.comment, "");
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_csv_record(record: &StringRecord, headers: &StringRecord) -> Result<Self> {
+        // Leave out the headers of missing trailing fields, so that they get their default values.
+        let headers: StringRecord = headers.iter().take(record.len()).collect();
+
+        // The CSV deserializer takes some addresses like `10.10` for numbers, so the address is
+        // parsed by hand.
+        let Some(index) = headers.iter().position(|header| header == "address") else {
+            return Ok(record.deserialize(Some(&headers))?);
+        };
+
+        let without_address: StringRecord = record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| if i == index { "" } else { field })
+            .collect();
+
+        let mut config: Self = without_address.deserialize(Some(&headers))?;
+        config.address = match &record[index] {
+            "" => None,
+            address => Some(address.parse()?),
+        };
+
+        Ok(config)
+    }
+
+    /// Parse a single configuration from a JSON value, like one entry of the array that
+    /// [parse_configs_json] reads.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use easy_upnp::{JsonValue, UpnpConfig};
+    ///
+    /// # fn main() -> Result<(), easy_upnp::UpnpError> {
+    /// let value: JsonValue = serde_json::json!({
+    ///     "port": 80,
+    ///     "protocol": "TCP",
+    ///     "duration": 3600,
+    ///     "comment": "Webserver",
+    /// });
+    ///
+    /// let config = UpnpConfig::from_json_value(&value)?;
+    /// assert_eq!(config.port, 80);
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_json_value(value: &JsonValue) -> Result<Self> {
+        Ok(<Self as Deserialize>::deserialize(value)?)
+    }
+
+    /// Create a configuration that describes an existing port mapping.
+    ///
+    /// The configuration opens the [`external_port`](PortMappingEntry::external_port) of the
+    /// entry for its [`internal_client`](UpnpConfig::internal_client), via any interface, with the
+    /// remaining lease time as duration. Since a
+    /// configuration always uses the same port internally and externally, the
+    /// [`internal_port`](PortMappingEntry::internal_port) is not taken over. If the internal
+    /// client or the remote host is not a valid IP address, [UpnpError::InvalidResponse] is
+    /// returned.
+    pub fn from_port_mapping_entry(entry: &PortMappingEntry) -> Result<Self> {
+        let internal_client = entry.internal_client.parse().map_err(|_| {
+            UpnpError::InvalidResponse(format!(
+                "Invalid internal client \"{}\"",
+                entry.internal_client
+            ))
+        })?;
+
+        let remote_host = entry.remote_host_addr()?;
+
+        Ok(UpnpConfig {
+            duration: LeaseDuration::Seconds(entry.lease_duration),
+            comment: entry.description.clone(),
+            remote_host,
+            internal_client: Some(internal_client),
+            ..UpnpConfig::new(entry.external_port, entry.protocol)
+        })
+    }
+
+    fn backend(&self) -> Backend {
+        self.backend.unwrap_or_default()
+    }
+
+    fn check_backend(&self, supported: Backend) -> Result<()> {
+        match self.backend() {
+            backend if backend == supported => Ok(()),
+            backend => Err(UpnpError::UnsupportedByBackend(backend)),
+        }
+    }
+
+    /// Check that fields which only the UPnP backend supports are not used with another backend.
+    fn check_upnp_fields(&self) -> Result<()> {
+        match (self.remote_host, self.internal_client) {
+            (None, None) => Ok(()),
+            _ => self.check_backend(Backend::Upnp),
+        }
+    }
+
+    /// Find the gateway and the address of the local interface for this configuration.
+    ///
+    /// [`Candidates`](Address::Candidates) are tried in order, until one of them finds a gateway.
+    fn gateway_and_address(&self, options: &DiscoveryOptions) -> Result<(Gateway, SocketAddrV4)> {
+        let started = Instant::now();
+        let result = self.find_gateway_and_address(options);
+        debug!(
+            "Finding the gateway for port {} took {:.2?}",
+            self.port,
+            started.elapsed()
+        );
+
+        result
+    }
+
+    fn find_gateway_and_address(
+        &self,
+        options: &DiscoveryOptions,
+    ) -> Result<(Gateway, SocketAddrV4)> {
+        let overridden;
+        let options = if self.retries.is_some() || self.timeout.is_some() || self.gateway.is_some()
+        {
+            overridden = self.discovery_options(options);
+            &overridden
+        } else {
+            options
+        };
+
+        match (self.find_gateway_for_options(options), &self.gateway) {
+            (Err(UpnpError::NoMatchingGateway), Some(gateway)) => {
+                Err(UpnpError::GatewayNotFound(gateway.clone()))
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn find_gateway_for_options(
+        &self,
+        options: &DiscoveryOptions,
+    ) -> Result<(Gateway, SocketAddrV4)> {
+        let Some(Address::Candidates(candidates)) = &self.address else {
+            return get_gateway_and_address_from_options(
+                &self.resolve_address()?,
+                self.port,
+                options,
+            );
+        };
+
+        let mut last_error = UpnpError::NoMatchingGateway;
+        for candidate in candidates {
+            match get_gateway_and_address_from_options(&Some(*candidate), self.port, options) {
+                Ok(found) => {
+                    info!(
+                        "Found gateway for port {} with address candidate {}",
+                        self.port, candidate
+                    );
+                    return Ok(found);
+                }
+                Err(e) => {
+                    debug!(
+                        "Address candidate {} for port {} failed: {}",
+                        candidate, self.port, e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Replace the address of the local interface by the internal client, if there is one.
+    fn internal_addr(&self, addr: SocketAddrV4) -> SocketAddrV4 {
+        match self.internal_client {
+            Some(ip) => SocketAddrV4::new(ip, addr.port()),
+            None => addr,
+        }
+    }
+
+    fn check_internal_client(&self) {
+        if let Some(ip) = self.internal_client.filter(|ip| !ip.is_private()) {
+            warn!(
+                "Internal client {} of port {} is not a private address",
+                ip, self.port
+            );
+        }
+    }
+
+    fn check_any_port(&self) -> Result<()> {
+        match self.remote_host {
+            Some(_) if self.any_port => {
+                Err(UpnpError::ConflictingFields("any_port", "remote_host"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Find the port mapping that an earlier call with [`any_port`](UpnpConfig::any_port) created.
+    fn find_any_port_mapping(
+        &self,
+        gateway: &Gateway,
+        addr: SocketAddrV4,
+    ) -> Result<Option<PortMappingEntry>> {
+        Ok(get_port_mappings(gateway)?.into_iter().find(|entry| {
+            entry.protocol == self.protocol
+                && entry.internal_port == addr.port()
+                && entry.internal_client == addr.ip().to_string()
+        }))
+    }
+
+    /// Get the port mapping on the gateway that this configuration would add or renew.
+    fn existing_mapping(
+        &self,
+        gateway: &Gateway,
+        addr: SocketAddrV4,
+    ) -> Result<Option<PortMappingEntry>> {
+        if self.any_port {
+            self.find_any_port_mapping(gateway, addr)
+        } else {
+            soap::get_specific_port_mapping_entry(
+                gateway,
+                self.remote_host,
+                self.protocol,
+                self.port,
+            )
+        }
+    }
+
+    fn add_any_port_mapping(
+        &self,
+        gateway: &Gateway,
+        addr: SocketAddrV4,
+        duration: u32,
+    ) -> Result<ApplyOutcome> {
+        let protocol = self.protocol.into();
+
+        match self.find_any_port_mapping(gateway, addr)? {
+            Some(entry) => {
+                debug!(
+                    "Renew existing port mapping on port {}",
+                    entry.external_port
+                );
+                if soap::is_intercepted() {
+                    soap::add_port_mapping(
+                        gateway,
+                        None,
+                        self.protocol,
+                        entry.external_port,
+                        addr,
+                        duration,
+                        &self.comment,
+                    )
+                } else {
+                    Ok(gateway.add_port(
+                        protocol,
+                        entry.external_port,
+                        addr,
+                        duration,
+                        &self.comment,
+                    )?)
+                }
+                .map_err(not_authorized)?;
+                Ok(ApplyOutcome::Refreshed {
+                    port: entry.external_port,
+                })
+            }
+            None if soap::is_intercepted() => {
+                soap::add_any_port_mapping(gateway, self.protocol, addr, duration, &self.comment)
+                    .map_err(not_authorized)
+                    .map(|port| ApplyOutcome::Created { port })
+            }
+            None => gateway
+                .add_any_port(protocol, addr, duration, &self.comment)
+                .map_err(|e| not_authorized(e.into()))
+                .map(|port| ApplyOutcome::Created { port }),
+        }
+    }
+
+    fn add_mapping(&self, gateway: &Gateway, addr: SocketAddrV4, duration: u32) -> Result<()> {
+        // While recording or replaying, the port mapping is added by hand, so that the request is
+        // part of the record.
+        let result = if self.remote_host.is_some() || soap::is_intercepted() {
+            soap::add_port_mapping(
+                gateway,
+                self.remote_host,
+                self.protocol,
+                self.port,
+                addr,
+                duration,
+                &self.comment,
+            )
+        } else {
+            Ok(gateway.add_port(
+                self.protocol.into(),
+                self.port,
+                addr,
+                duration,
+                &self.comment,
+            )?)
+        };
+
+        result.map_err(not_authorized)
+    }
+
+    fn remove_mapping(&self, gateway: &Gateway) -> Result<()> {
+        if self.remote_host.is_some() || soap::is_intercepted() {
+            soap::delete_port_mapping(gateway, self.remote_host, self.protocol, self.port)
+        } else {
+            Ok(gateway.remove_port(self.protocol.into(), self.port)?)
+        }
+    }
+
+    /// Remove the port mapping with the given external port, and check that the gateway really
+    /// removed it if the `options` ask for it.
+    fn remove_external_port(
+        &self,
+        gateway: &Gateway,
+        external_port: u16,
+        options: &DiscoveryOptions,
+    ) -> Result<()> {
+        let remove = || {
+            if self.any_port && soap::is_intercepted() {
+                soap::delete_port_mapping(gateway, None, self.protocol, external_port)
+            } else if self.any_port {
+                Ok(gateway.remove_port(self.protocol.into(), external_port)?)
+            } else {
+                self.remove_mapping(gateway)
+            }
+        };
+        let still_exists = || {
+            soap::get_specific_port_mapping_entry(
+                gateway,
+                self.remote_host,
+                self.protocol,
+                external_port,
+            )
+            .map(|entry| entry.is_some())
+        };
+
+        remove()?;
+        if !options.verify_removal || !still_exists()? {
+            return Ok(());
+        }
+
+        warn!(
+            "Port mapping {} {} still exists after removing it, trying again",
+            self.protocol, external_port
+        );
+        remove()?;
+        if still_exists()? {
+            return Err(UpnpError::RemovalIgnored(external_port, self.protocol));
+        }
+
+        Ok(())
+    }
+
+    fn remove_port(&self, options: &DiscoveryOptions) -> Result<()> {
+        let port = self.port;
+        self.check_upnp_fields()?;
+        self.check_any_port()?;
+
+        if self.backend() == Backend::NatPmp {
+            return nat_pmp::remove_port(self.protocol, port);
+        }
+
+        let (gateway, addr) = self.gateway_and_address(options)?;
+        let addr = self.internal_addr(addr);
+
+        let result = if self.any_port {
+            match self.find_any_port_mapping(&gateway, addr)? {
+                Some(entry) => self.remove_external_port(&gateway, entry.external_port, options),
+                None => {
+                    debug!("No port mapping found for internal port {}", port);
+                    Ok(())
+                }
+            }
+        } else {
+            self.remove_external_port(&gateway, port, options)
+        };
+
+        match result {
+            Err(e) if no_such_port_mapping(&e) => {
+                debug!("Port mapping {} {} did not exist", self.protocol, port);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn add_port(&self, options: &DiscoveryOptions) -> Result<u16> {
+        self.check_addable()?;
+
+        if self.backend() == Backend::NatPmp {
+            return self.add_nat_pmp_port();
+        }
+
+        let (gateway, addr) = self.gateway_and_address(options)?;
+        self.add_upnp_port(&gateway, self.internal_addr(addr), options, false)
+            .map(|outcome| outcome.port())
+    }
+
+    /// Add the port mapping like [UpnpConfig::add_port], but look up the existing port mapping
+    /// first, to tell what has changed.
+    ///
+    /// Port mappings with [`any_port`](UpnpConfig::any_port) are looked up while adding them
+    /// anyway, so they are not looked up twice.
+    fn apply_port(&self, options: &DiscoveryOptions) -> Result<ApplyOutcome> {
+        self.check_addable()?;
+
+        if self.backend() == Backend::NatPmp {
+            return self
+                .add_nat_pmp_port()
+                .map(|port| ApplyOutcome::Created { port });
+        }
+
+        let (gateway, addr) = self.gateway_and_address(options)?;
+        let addr = self.internal_addr(addr);
+
+        if self.any_port {
+            return self.add_upnp_port(&gateway, addr, options, false);
+        }
+
+        let entry = self
+            .existing_mapping(&gateway, addr)
+            .unwrap_or_else(|e| {
+                debug!(
+                    "Could not look up the port mapping of port {}, add it anyway: {}",
+                    self.port, e
+                );
+                None
+            })
+            .filter(|entry| entry.forwards_to(addr));
+
+        match entry {
+            Some(entry)
+                if entry.enabled
+                    && entry.lease_duration == 0
+                    && self.duration == LeaseDuration::Seconds(0)
+                    && entry.description == self.comment =>
+            {
+                Ok(ApplyOutcome::Unchanged {
+                    port: entry.external_port,
+                })
+            }
+            entry => self.add_upnp_port(&gateway, addr, options, entry.is_some()),
+        }
+    }
+
+    /// Check everything that can be checked before talking to the gateway.
+    fn check_addable(&self) -> Result<()> {
+        if self.duration == LeaseDuration::Auto {
+            return Err(UpnpError::UnresolvedAutoDuration);
+        }
+        self.check_upnp_fields()?;
+        self.check_any_port()?;
+        self.check_internal_client();
+
+        Ok(())
+    }
+
+    fn add_nat_pmp_port(&self) -> Result<u16> {
+        // A lifetime of 0 would delete the port mapping instead.
+        let lifetime = match self.duration {
+            LeaseDuration::Seconds(0) => {
+                return Err(UpnpError::PermanentLeaseUnsupported(Backend::NatPmp))
+            }
+            LeaseDuration::Seconds(duration) => duration,
+            _ => NAT_PMP_DEFAULT_LIFETIME,
+        };
+        let public_port = if self.any_port { 0 } else { self.port };
+        nat_pmp::add_port(self.protocol, self.port, public_port, lifetime)
+    }
+
+    fn add_upnp_port(
+        &self,
+        gateway: &Gateway,
+        addr: SocketAddrV4,
+        options: &DiscoveryOptions,
+        existed: bool,
+    ) -> Result<ApplyOutcome> {
+        let port = self.port;
+        let permanent_udp = self.protocol == PortMappingProtocol::UDP && permanent_udp(gateway);
+        let duration = match (self.duration, max_lease_duration(gateway)) {
+            _ if permanent_udp => {
+                debug!(
+                    "Request a permanent port mapping for UDP port {}, the gateway rejects others",
+                    port
+                );
+                0
+            }
+            (LeaseDuration::Seconds(0), _) => {
+                debug!("Request a permanent port mapping for port {}", port);
+                0
+            }
+            (LeaseDuration::Seconds(duration), Some(max)) if max > 0 && duration > max => {
+                info!(
+                    "Reduce lease duration of port {} from {} to {} seconds, the maximum of the \
+                    gateway",
+                    port, duration, max
+                );
+                max
+            }
+            (LeaseDuration::Seconds(duration), _) => duration,
+            (_, Some(max)) if max > 0 => {
+                debug!(
+                    "Use the maximum lease duration of {} seconds for port {}",
+                    max, port
+                );
+                max
+            }
+            _ => {
+                debug!(
+                    "Gateway has no maximum lease duration, use {} seconds for port {}",
+                    NAT_PMP_DEFAULT_LIFETIME, port
+                );
+                NAT_PMP_DEFAULT_LIFETIME
+            }
+        };
+
+        match self.add_or_replace_mapping(gateway, addr, duration, options, existed) {
+            Err(e)
+                if self.protocol == PortMappingProtocol::UDP
+                    && duration > 0
+                    && only_permanent_leases(&e) =>
+            {
+                info!(
+                    "Gateway rejected the lease of {} seconds for UDP port {}, request permanent \
+                    UDP port mappings from now on",
+                    duration, port
+                );
+                remember_permanent_udp(gateway);
+                self.add_or_replace_mapping(gateway, addr, 0, options, existed)
+            }
+            result => result,
+        }
+    }
+
+    /// Add the port mapping, and replace an existing one of another address unless it is
+    /// protected.
+    ///
+    /// Whether a port mapping with a fixed port is [`Refreshed`](ApplyOutcome::Refreshed) cannot
+    /// be told from the response, so the caller has to say whether it `existed`.
+    fn add_or_replace_mapping(
+        &self,
+        gateway: &Gateway,
+        addr: SocketAddrV4,
+        duration: u32,
+        options: &DiscoveryOptions,
+        existed: bool,
+    ) -> Result<ApplyOutcome> {
+        let port = self.port;
+        if self.any_port {
+            return self.add_any_port_mapping(gateway, addr, duration);
+        }
+
+        match self.add_mapping(gateway, addr, duration) {
+            Err(
+                original @ (UpnpError::IgdAddPortError(igd::AddPortError::PortInUse)
+                | UpnpError::GatewayError(soap::CONFLICT_IN_MAPPING_ENTRY, _)),
+            ) if options.protected_ports.contains(&port) => {
+                Err(UpnpError::ProtectedPort(port, Box::new(original)))
+            }
+            Err(
+                original @ (UpnpError::IgdAddPortError(igd::AddPortError::PortInUse)
+                | UpnpError::GatewayError(soap::CONFLICT_IN_MAPPING_ENTRY, _)),
+            ) => {
+                debug!("Port already in use. Delete mapping.");
+                if let Err(removal) = self.remove_mapping(gateway) {
+                    return Err(UpnpError::ReplaceFailed {
+                        original: Box::new(original),
+                        removal: Box::new(removal),
+                    });
+                }
+
+                debug!("Retry port mapping.");
+                self.add_mapping(gateway, addr, duration)
+                    .map_err(|retry| UpnpError::Retried {
+                        original: Box::new(original),
+                        retry: Box::new(retry),
+                    })
+                    .map(|()| ApplyOutcome::Created { port })
+            }
+            Ok(()) if existed => Ok(ApplyOutcome::Refreshed { port }),
+            Ok(()) => Ok(ApplyOutcome::Created { port }),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Record the requests that are sent to gateways, together with their responses.
+///
+/// This is meant for debugging gateways that behave unexpectedly. From the time of this call on,
+/// each request is written to `writer` as soon as its response has arrived, with the URL, the
+/// full request body, the HTTP status and the full response body. Nothing is redacted, so the
+/// record contains the addresses and descriptions of the port mappings.
+///
+/// While recording, all requests to gateways are sent by this crate instead of [igd], so that they
+/// are part of the record. Only the search for gateways is not recorded.
+pub fn record_soap(writer: impl Write + Send + 'static) {
+    soap::set_recorder(Box::new(writer));
+}
+
+/// Answer the requests to gateways with the responses of a record, instead of sending them.
+///
+/// The `reader` has to provide a record of [record_soap]. From the time of this call on, no
+/// gateway is searched for. All port mappings use the gateway of the record, and forward to the
+/// internal client of the first recorded port mapping, unless they have their own
+/// [`internal_client`](UpnpConfig::internal_client). Each request is answered with the next
+/// recorded response to the same action and URL, in the recorded order. If there is none left,
+/// [UpnpError::NotRecorded] is returned. Documents like the device description are reused once
+/// their last recorded response has been reached.
+///
+/// This is meant for reproducing the behavior of a specific gateway, for example in tests. No
+/// request is sent to any gateway while replaying, the requests of the [`NatPmp`](Backend::NatPmp)
+/// backend fail with [UpnpError::NotRecorded] as well.
+pub fn replay_soap(mut reader: impl Read) -> Result<()> {
+    let mut record = String::new();
+    reader.read_to_string(&mut record)?;
+    soap::set_replay(soap::Replay::parse(&record)?);
+
+    Ok(())
+}
+
+/// Maximum lease durations of the gateways seen so far, by the URL of their service description.
+static MAX_LEASE_DURATIONS: Mutex<BTreeMap<String, Option<u32>>> = Mutex::new(BTreeMap::new());
+
+/// Get the maximum lease duration of the gateway, if it advertises one.
+///
+/// The service description is only queried once per gateway. If that fails, the gateway is
+/// treated as having no maximum.
+fn max_lease_duration(gateway: &Gateway) -> Option<u32> {
+    let url = format!("{}{}", gateway.addr, gateway.control_schema_url);
+    let mut max_lease_durations = MAX_LEASE_DURATIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    *max_lease_durations.entry(url).or_insert_with(|| {
+        soap::get_max_lease_duration(gateway).unwrap_or_else(|e| {
+            debug!("Could not get the maximum lease duration: {}", e);
+            None
+        })
+    })
+}
+
+/// Gateways that only accept permanent UDP port mappings, by the URL of their service description.
+static PERMANENT_UDP_GATEWAYS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Whether the gateway has rejected a UDP port mapping with a lease time before.
+fn permanent_udp(gateway: &Gateway) -> bool {
+    let url = format!("{}{}", gateway.addr, gateway.control_schema_url);
+    PERMANENT_UDP_GATEWAYS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .contains(&url)
+}
+
+/// Remember that the gateway only accepts permanent UDP port mappings, see [permanent_udp].
+fn remember_permanent_udp(gateway: &Gateway) {
+    let url = format!("{}{}", gateway.addr, gateway.control_schema_url);
+    PERMANENT_UDP_GATEWAYS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(url);
+}
+
+/// Whether the gateway rejected a port mapping because it has a lease time.
+fn only_permanent_leases(error: &UpnpError) -> bool {
+    matches!(
+        error,
+        UpnpError::IgdAddPortError(igd::AddPortError::OnlyPermanentLeasesSupported)
+            | UpnpError::IgdAddAnyPortError(igd::AddAnyPortError::OnlyPermanentLeasesSupported)
+            | UpnpError::GatewayError(soap::ONLY_PERMANENT_LEASES_SUPPORTED, _)
+    )
+}
+
+/// Replace errors of gateways that refuse to authorize a port mapping by a clearer error.
+fn not_authorized(error: UpnpError) -> UpnpError {
+    match error {
+        UpnpError::IgdAddPortError(igd::AddPortError::ActionNotAuthorized)
+        | UpnpError::IgdAddAnyPortError(igd::AddAnyPortError::ActionNotAuthorized)
+        | UpnpError::GatewayError(soap::ACTION_NOT_AUTHORIZED, _) => UpnpError::NotAuthorized,
+        error => error,
+    }
+}
+
+/// Run the processing of a single port mapping, and turn a panic into an error, so that the
+/// other port mappings of a batch are still processed.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(ToString::to_string)
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        Err(UpnpError::Panicked(message))
+    })
+}
+
+/// Whether the gateway could not delete a port mapping because it does not exist.
+fn no_such_port_mapping(error: &UpnpError) -> bool {
+    matches!(
+        error,
+        UpnpError::IgdRemovePortError(igd::RemovePortError::NoSuchPortMapping)
+            | UpnpError::GatewayError(soap::NO_SUCH_ENTRY_IN_ARRAY, _)
+    )
+}
+
+/// Add port mappings.
+///
+/// This function takes an iterable of [UpnpConfig]s and opens all configures ports. The ports are
+/// opened in the order of their [`priority`](UpnpConfig::priority), highest first, and the results
+/// are returned in the same order. Port mappings with the same priority keep their given order.
+///
+/// Each successful result holds the external port of the port mapping. This is the configured
+/// [`port`](UpnpConfig::port), unless the gateway chose another one, for example because of
+/// [`any_port`](UpnpConfig::any_port).
+///
+/// An existing port mapping of the same address is updated in place, which renews its lease and
+/// also enables it again if it has been disabled, for example in the web interface of the router.
+/// Only if the gateway refuses to update the port mapping, because the port is in use by another
+/// address or because the gateway does not support updates, it is deleted and added again.
+///
+/// Errors are logged, but otherwise ignored. An error during opening a port will not stop the
+/// processing of the other ports, and neither will a panic, which is returned as
+/// [UpnpError::Panicked].
+///
+/// # Example
+///
+/// ```no_run
+/// use log::error;
+/// use easy_upnp::{add_ports, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// for result in add_ports([config], None) {
+///     if let Err(err) = result {
+///         error!("{}", err);
+///     }
+/// }
+/// ```
+pub fn add_ports(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    options: Option<&DiscoveryOptions>,
+) -> impl Iterator<Item = Result<u16>> {
+    let mut configs: Vec<_> = configs.into_iter().collect();
+    configs.sort_by_key(|config| Reverse(config.priority));
+
+    let options = options.cloned().unwrap_or_default();
+    configs.into_iter().map(move |config| {
+        info!("Add port: {:?}", config);
+        let started = Instant::now();
+        let result = catch_panic(|| config.add_port(&options));
+        debug!(
+            "Adding port {} {} took {:.2?}",
+            config.protocol,
+            config.port,
+            started.elapsed()
+        );
+        result
+    })
+}
+
+/// What [apply_ports] has done for a configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyOutcome {
+    /// There was no port mapping for the configuration, it has been added.
+    ///
+    /// This also covers port mappings that replaced the one of another address, and all port
+    /// mappings of the [`NatPmp`](Backend::NatPmp) backend, since NAT-PMP cannot be asked for
+    /// existing port mappings.
+    Created {
+        /// The external port of the port mapping.
+        port: u16,
+    },
+
+    /// The port mapping already existed, its lease has been renewed, and it has been enabled
+    /// again if it was disabled.
+    Refreshed {
+        /// The external port of the port mapping.
+        port: u16,
+    },
+
+    /// The port mapping already existed as a permanent port mapping with the same description,
+    /// so nothing had to be sent to the gateway.
+    Unchanged {
+        /// The external port of the port mapping.
+        port: u16,
+    },
+}
+
+impl ApplyOutcome {
+    /// The external port of the port mapping.
+    pub fn port(&self) -> u16 {
+        match *self {
+            ApplyOutcome::Created { port }
+            | ApplyOutcome::Refreshed { port }
+            | ApplyOutcome::Unchanged { port } => port,
+        }
+    }
+}
+
+impl fmt::Display for ApplyOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ApplyOutcome::Created { .. } => "created",
+            ApplyOutcome::Refreshed { .. } => "refreshed",
+            ApplyOutcome::Unchanged { .. } => "unchanged",
+        })
+    }
+}
+
+/// Add port mappings, and tell for each one what has changed.
+///
+/// This works like [add_ports], but asks the gateway for the existing port mapping of each
+/// configuration first, which costs another request per port mapping with a fixed port. Port
+/// mappings with [`any_port`](UpnpConfig::any_port) are looked up by [add_ports] as well, so they
+/// cost nothing extra. Each successful result tells whether the port mapping has been
+/// [`Created`](ApplyOutcome::Created), [`Refreshed`](ApplyOutcome::Refreshed), or left
+/// [`Unchanged`](ApplyOutcome::Unchanged). If the gateway cannot be asked, the port mapping is
+/// added anyway and reported as created.
+///
+/// # Example
+///
+/// ```no_run
+/// use log::{error, info};
+/// use easy_upnp::{apply_ports, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// for result in apply_ports([config], None) {
+///     match result {
+///         Ok(outcome) => info!("Port {}: {}", outcome.port(), outcome),
+///         Err(err) => error!("{}", err),
+///     }
+/// }
+/// ```
+pub fn apply_ports(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    options: Option<&DiscoveryOptions>,
+) -> impl Iterator<Item = Result<ApplyOutcome>> {
+    let mut configs: Vec<_> = configs.into_iter().collect();
+    configs.sort_by_key(|config| Reverse(config.priority));
+
+    let options = options.cloned().unwrap_or_default();
+    configs.into_iter().map(move |config| {
+        info!("Add port: {:?}", config);
+        let started = Instant::now();
+        let result = catch_panic(|| config.apply_port(&options));
+        debug!(
+            "Adding port {} {} took {:.2?}",
+            config.protocol,
+            config.port,
+            started.elapsed()
+        );
+        let outcome = result?;
+        info!(
+            "Port mapping {} {} {}",
+            config.protocol,
+            outcome.port(),
+            outcome
+        );
+        Ok(outcome)
+    })
+}
+
+/// Delete port mappings.
+///
+/// This function takes an iterable of [UpnpConfig]s and closes all configures ports.
+///
+/// A port mapping that does not exist counts as deleted. An error during closing a port will not
+/// stop the processing of the other ports, and neither will a panic, which is returned as
+/// [UpnpError::Panicked].
+///
+/// # Example
+///
+/// ```no_run
+/// use log::error;
+/// use easy_upnp::{delete_ports, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// for result in delete_ports([config], None) {
+///     if let Err(err) = result {
+///         error!("{}", err);
+///     }
+/// }
+/// ```
+pub fn delete_ports(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    options: Option<&DiscoveryOptions>,
+) -> impl Iterator<Item = Result<()>> {
+    let options = options.cloned().unwrap_or_default();
+    configs.into_iter().map(move |config| {
+        info!("Remove port: {:?}", config);
+        let started = Instant::now();
+        let result = catch_panic(|| config.remove_port(&options));
+        debug!(
+            "Removing port {} {} took {:.2?}",
+            config.protocol,
+            config.port,
+            started.elapsed()
+        );
+        result
+    })
+}
+
+/// Minimal time that an [`Auto`](LeaseDuration::Auto) lease outlasts the interval between two
+/// renewals.
+const AUTO_DURATION_MIN_MARGIN: Duration = Duration::from_secs(30);
+
+/// The lease duration in seconds that [`Auto`](LeaseDuration::Auto) stands for, if the port
+/// mappings are renewed after each `interval`.
+///
+/// The lease outlasts the interval by a tenth of it, but at least by 30 seconds, so that the port
+/// mappings do not expire between two renewals.
+pub fn auto_duration(interval: Duration) -> u32 {
+    let margin = (interval / 10).max(AUTO_DURATION_MIN_MARGIN);
+    u32::try_from(interval.saturating_add(margin).as_secs()).unwrap_or(u32::MAX)
+}
+
+/// A handle to the background thread that was started by [keepalive].
+///
+/// Dropping the handle stops the thread after its current iteration, without closing the ports.
+#[derive(Debug)]
+pub struct KeepaliveHandle {
+    stop: mpsc::Sender<bool>,
+    thread: JoinHandle<()>,
+}
+
+impl KeepaliveHandle {
+    /// Stop renewing the port mappings and wait for the background thread to finish.
+    ///
+    /// If `close_ports` is true, the port mappings are deleted before this function returns.
+    /// Otherwise, they stay open until their lease expires.
+    pub fn stop(self, close_ports: bool) {
+        // If the thread is already gone, there is nobody left to tell.
+        let _ = self.stop.send(close_ports);
+
+        if self.thread.join().is_err() {
+            error!("Keepalive thread panicked");
+        }
+    }
+}
+
+/// Keep port mappings open in the background.
+///
+/// This function spawns a thread that opens all given ports with [add_ports], and opens them
+/// again after each `interval`, so that their leases are renewed. It returns immediately with a
+/// [KeepaliveHandle] that is used to stop the thread. Errors are logged, but otherwise ignored,
+/// the affected ports are simply tried again in the next iteration.
+///
+/// An [`Auto`](LeaseDuration::Auto) lease duration is resolved to slightly more than the
+/// interval, so that the port mappings expire shortly after the thread is stopped.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use easy_upnp::{keepalive, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Auto,
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let handle = keepalive([config], Duration::from_secs(60), None);
+///
+/// // Serve some requests ...
+///
+/// handle.stop(true);
+/// ```
+pub fn keepalive(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    interval: Duration,
+    options: Option<&DiscoveryOptions>,
+) -> KeepaliveHandle {
+    spawn_keepalive(configs, interval, options, None)
+}
+
+/// Keep a single port mapping open in the background.
+///
+/// This is the shortest way to keep one port open for as long as the application runs. It works
+/// like [keepalive] with only the given configuration and the default [DiscoveryOptions], so no
+/// config file is ever read. Failures are logged and the port mapping is tried again after the
+/// next `interval`.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use easy_upnp::{keep_port_open, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Auto,
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let handle = keep_port_open(config, Duration::from_secs(60));
+///
+/// // Serve some requests ...
+///
+/// handle.stop(true);
+/// ```
+pub fn keep_port_open(config: UpnpConfig, interval: Duration) -> KeepaliveHandle {
+    keepalive([config], interval, None)
+}
+
+/// A status update of [keepalive_with_events].
+#[derive(Debug)]
+pub enum MappingEvent {
+    /// A port mapping was added for the first time.
+    Added {
+        /// The external port of the port mapping.
+        port: u16,
+
+        /// The protocol of the port mapping.
+        protocol: PortMappingProtocol,
+    },
+
+    /// The lease of a port mapping that was added before was renewed.
+    Renewed {
+        /// The external port of the port mapping.
+        port: u16,
+
+        /// The protocol of the port mapping.
+        protocol: PortMappingProtocol,
+    },
+
+    /// A port mapping could not be added. It is tried again in the next iteration.
+    Failed {
+        /// The configured port of the port mapping.
+        port: u16,
+
+        /// The protocol of the port mapping.
+        protocol: PortMappingProtocol,
+
+        /// The reason of the failure.
+        error: UpnpError,
+    },
+
+    /// A port mapping was deleted, because the keepalive was stopped with `close_ports`.
+    Removed {
+        /// The configured port of the port mapping.
+        port: u16,
+
+        /// The protocol of the port mapping.
+        protocol: PortMappingProtocol,
+    },
+
+    /// The external IP address of the gateway was seen for the first time, or it has changed.
+    ExternalIpChanged {
+        /// The external IP address that was seen before, if any.
+        previous: Option<Ipv4Addr>,
+
+        /// The current external IP address.
+        current: Ipv4Addr,
+    },
+}
+
+/// Keep port mappings open in the background and report on the progress.
+///
+/// This works like [keepalive], but additionally sends a [MappingEvent] to `events` for each
+/// port mapping in each iteration. After each iteration with at least one renewed UPnP port
+/// mapping, the external IP address of its gateway is queried as well, and reported whenever it
+/// changes.
+///
+/// Dropping the receiving end of the channel does not stop the thread. The port mappings are still
+/// renewed, only the events are discarded.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::sync::mpsc;
+/// use std::thread;
+/// use std::time::Duration;
+/// use easy_upnp::{keepalive_with_events, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Auto,
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let (events, received) = mpsc::channel();
+/// let handle = keepalive_with_events([config], Duration::from_secs(60), None, events);
+///
+/// thread::spawn(move || {
+///     for event in received {
+///         println!("{:?}", event);
+///     }
+/// });
+///
+/// // Serve some requests ...
+///
+/// handle.stop(true);
+/// ```
+pub fn keepalive_with_events(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    interval: Duration,
+    options: Option<&DiscoveryOptions>,
+    events: mpsc::Sender<MappingEvent>,
+) -> KeepaliveHandle {
+    spawn_keepalive(configs, interval, options, Some(events))
+}
+
+/// Send an event, if anybody listens.
+fn send_event(events: &Option<mpsc::Sender<MappingEvent>>, event: MappingEvent) {
+    if let Some(events) = events {
+        // A dropped receiver only means that nobody is interested in the events anymore.
+        let _ = events.send(event);
+    }
+}
+
+/// Get the external IP address of the gateway that is responsible for the given configuration.
+fn external_ip(config: &UpnpConfig, options: &DiscoveryOptions) -> Result<Ipv4Addr> {
+    let (gateway, _) = config.gateway_and_address(options)?;
+    get_external_ip(&gateway)
+}
+
+fn spawn_keepalive(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    interval: Duration,
+    options: Option<&DiscoveryOptions>,
+    events: Option<mpsc::Sender<MappingEvent>>,
+) -> KeepaliveHandle {
+    let auto_duration = auto_duration(interval);
+
+    let mut configs: Vec<_> = configs
+        .into_iter()
+        .map(|mut config| {
+            config.duration = config.duration.resolve(auto_duration);
+            config
+        })
+        .collect();
+    // Sort like add_ports does, so that its results line up with the configs.
+    configs.sort_by_key(|config| Reverse(config.priority));
+    let options = options.cloned();
+
+    let (stop, stopped) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        let mut added = vec![false; configs.len()];
+        let mut last_external_ip = None;
+
+        let close_ports = loop {
+            let mut renewed_upnp = None;
+
+            for ((config, added), result) in configs
+                .iter()
+                .zip(&mut added)
+                .zip(add_ports(configs.clone(), options.as_ref()))
+            {
+                let protocol = config.protocol;
+                let event = match result {
+                    Ok(port) if *added => MappingEvent::Renewed { port, protocol },
+                    Ok(port) => {
+                        *added = true;
+                        MappingEvent::Added { port, protocol }
+                    }
+                    Err(error) => {
+                        error!("{}", error);
+                        MappingEvent::Failed {
+                            port: config.port,
+                            protocol,
+                            error,
+                        }
+                    }
+                };
+
+                if matches!(
+                    event,
+                    MappingEvent::Added { .. } | MappingEvent::Renewed { .. }
+                ) && config.backend() == Backend::Upnp
+                {
+                    renewed_upnp.get_or_insert(config);
+                }
+
+                send_event(&events, event);
+            }
+
+            if let (Some(_), Some(config)) = (&events, renewed_upnp) {
+                match external_ip(config, &options.clone().unwrap_or_default()) {
+                    Ok(current) if last_external_ip != Some(current) => {
+                        send_event(
+                            &events,
+                            MappingEvent::ExternalIpChanged {
+                                previous: last_external_ip,
+                                current,
+                            },
+                        );
+                        last_external_ip = Some(current);
+                    }
+                    Ok(_) => {}
+                    Err(e) => debug!("Could not get the external IP address: {}", e),
+                }
+            }
+
+            match stopped.recv_timeout(interval) {
+                Ok(close_ports) => break close_ports,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break false,
+            }
+        };
+
+        if close_ports {
+            for (config, result) in configs
+                .iter()
+                .zip(delete_ports(configs.clone(), options.as_ref()))
+            {
+                match result {
+                    Ok(()) => send_event(
+                        &events,
+                        MappingEvent::Removed {
+                            port: config.port,
+                            protocol: config.protocol,
+                        },
+                    ),
+                    Err(err) => error!("{}", err),
+                }
+            }
+        }
+    });
+
+    KeepaliveHandle { stop, thread }
+}
+
+/// Port mappings that are open for as long as the session lives.
+///
+/// A session remembers which of the given port mappings could be opened, and closes exactly those
+/// again, either with [PortSession::close] or when it is dropped. This is useful to open ports
+/// for a limited task, like a test, without having to keep the configuration around for closing
+/// them, and without closing ports that have never been opened by this session.
+///
+/// # Example
+///
+/// ```no_run
+/// use log::error;
+/// use easy_upnp::{LeaseDuration, PortMappingProtocol, PortSession, UpnpConfig};
+///
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(600),
+///     comment: "Webserver test".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let session = PortSession::open([config], None);
+/// for (config, err) in session.failures() {
+///     error!("Could not open port {}: {}", config.port, err);
+/// }
+///
+/// // Run some checks ...
+///
+/// for result in session.close() {
+///     if let Err(err) = result {
+///         error!("{}", err);
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PortSession {
+    opened: Vec<UpnpConfig>,
+    failures: Vec<(UpnpConfig, UpnpError)>,
+    options: DiscoveryOptions,
+}
+
+impl PortSession {
+    /// Open the given port mappings with [add_ports].
+    ///
+    /// Port mappings that cannot be opened are not retried, they are available from
+    /// [PortSession::failures]. An [`Auto`](LeaseDuration::Auto) lease duration has to be
+    /// resolved beforehand, since a session does not renew its port mappings.
+    pub fn open(
+        configs: impl IntoIterator<Item = UpnpConfig>,
+        options: Option<&DiscoveryOptions>,
+    ) -> Self {
+        let mut configs: Vec<_> = configs.into_iter().collect();
+        // Sort like add_ports does, so that its results line up with the configs.
+        configs.sort_by_key(|config| Reverse(config.priority));
+
+        let mut opened = Vec::new();
+        let mut failures = Vec::new();
+        for (config, result) in configs.clone().into_iter().zip(add_ports(configs, options)) {
+            match result {
+                Ok(_) => opened.push(config),
+                Err(err) => failures.push((config, err)),
+            }
+        }
+
+        PortSession {
+            opened,
+            failures,
+            options: options.cloned().unwrap_or_default(),
+        }
+    }
+
+    /// The port mappings that have been opened by this session.
+    pub fn opened(&self) -> &[UpnpConfig] {
+        &self.opened
+    }
+
+    /// The port mappings that could not be opened, together with the reason.
+    pub fn failures(&self) -> &[(UpnpConfig, UpnpError)] {
+        &self.failures
+    }
+
+    /// Close the port mappings that have been opened by this session, and return the results in
+    /// the same order as [PortSession::opened].
+    pub fn close(mut self) -> Vec<Result<()>> {
+        self.close_opened()
+    }
+
+    fn close_opened(&mut self) -> Vec<Result<()>> {
+        delete_ports(std::mem::take(&mut self.opened), Some(&self.options)).collect()
+    }
+}
+
+impl Drop for PortSession {
+    /// Close the port mappings that are still open, logging any errors.
+    fn drop(&mut self) {
+        for result in self.close_opened() {
+            if let Err(err) = result {
+                error!("{}", err);
+            }
+        }
+    }
+}
+
+/// List port mappings.
+///
+/// This function finds the gateway that is responsible for the given address, in the same way as
+/// for the [`address`](UpnpConfig::address) of a [UpnpConfig], and returns all of its port
+/// mappings. Please note that some gateways only report the port mappings that have been created
+/// by the requesting device.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_upnp::list_port_mappings;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// for entry in list_port_mappings(&None, None)? {
+///     println!("{} {}: {}", entry.protocol, entry.external_port, entry.description);
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn list_port_mappings(
+    address: &Option<Ipv4Cidr>,
+    options: Option<&DiscoveryOptions>,
+) -> Result<Vec<PortMappingEntry>> {
+    let options = options.cloned().unwrap_or_default();
+    let (gateway, _) = get_gateway_and_address_from_options(address, 0, &options)?;
+
+    get_port_mappings(&gateway)
+}
+
+fn get_port_mappings(gateway: &Gateway) -> Result<Vec<PortMappingEntry>> {
+    require_action(gateway, "GetGenericPortMappingEntry")?;
+
+    let mut entries = Vec::new();
+    if soap::is_intercepted() {
+        for index in 0.. {
+            match soap::get_generic_port_mapping_entry(gateway, index)? {
+                Some(entry) => entries.push(entry),
+                None => break,
+            }
+        }
+        return Ok(entries);
+    }
+
+    for index in 0.. {
+        match gateway.get_generic_port_mapping_entry(index) {
+            Ok(entry) => entries.push(entry.into()),
+            Err(igd::GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Delete port mappings, as returned by [list_port_mappings].
+///
+/// The gateway is searched for only once, in the same way as for [list_port_mappings]. Each entry
+/// is returned together with the result of its deletion, an error during deleting one port mapping
+/// will not stop the processing of the others. The port mappings do not need to be described by
+/// any [UpnpConfig], so this can be used to clean up stale port mappings.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_upnp::{delete_port_mappings, list_port_mappings};
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let entries = list_port_mappings(&None, None)?;
+///
+/// for (entry, result) in delete_port_mappings(&None, entries, None)? {
+///     if let Err(err) = result {
+///         eprintln!("Could not delete port {}: {}", entry.external_port, err);
+///     }
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn delete_port_mappings(
+    address: &Option<Ipv4Cidr>,
+    entries: impl IntoIterator<Item = PortMappingEntry>,
+    options: Option<&DiscoveryOptions>,
+) -> Result<impl Iterator<Item = (PortMappingEntry, Result<()>)>> {
+    let options = options.cloned().unwrap_or_default();
+    let (gateway, _) = get_gateway_and_address_from_options(address, 0, &options)?;
+
+    Ok(entries.into_iter().map(move |entry| {
+        info!("Remove port mapping: {:?}", entry);
+        let result = entry.remote_host_addr().and_then(|remote_host| {
+            // Port mappings for a remote host are only found together with it.
+            if remote_host.is_some() || soap::is_intercepted() {
+                soap::delete_port_mapping(
+                    &gateway,
+                    remote_host,
+                    entry.protocol,
+                    entry.external_port,
+                )
+            } else {
+                Ok(gateway.remove_port(entry.protocol.into(), entry.external_port)?)
+            }
+        });
+        (entry, result)
+    }))
+}
+
+/// Delete all port mappings whose description matches the given pattern.
+///
+/// The port mappings are listed like with [list_port_mappings], and matched with
+/// [PortMappingEntry::description_matches]. This way, port mappings can be removed by the comment
+/// they have been created with, without knowing their ports. Each matching entry is returned
+/// together with the result of its deletion, so the number of removed port mappings can be told
+/// from the results. If no port mapping matches, the result is empty.
+pub fn delete_port_mappings_by_comment(
+    address: &Option<Ipv4Cidr>,
+    pattern: &str,
+    options: Option<&DiscoveryOptions>,
+) -> Result<Vec<(PortMappingEntry, Result<()>)>> {
+    let entries = list_port_mappings(address, options)?
+        .into_iter()
+        .filter(|entry| entry.description_matches(pattern));
+
+    Ok(delete_port_mappings(address, entries, options)?.collect())
+}
+
+/// The differences between configurations and the port mappings of a gateway, as found by [diff].
+#[derive(Clone, Debug, Default)]
+pub struct MappingDiff {
+    /// The configurations that have no port mapping on the gateway yet.
+    pub to_add: Vec<UpnpConfig>,
+
+    /// The port mappings on the gateway that no configuration describes.
+    pub to_remove: Vec<PortMappingEntry>,
+
+    /// The configurations whose port mapping exists, but is disabled, forwards to another address,
+    /// or has another description, together with that port mapping.
+    pub to_update: Vec<(UpnpConfig, PortMappingEntry)>,
+}
+
+impl MappingDiff {
+    /// Whether the gateway already matches the configurations.
+    pub fn is_empty(&self) -> bool {
+        self.to_add.is_empty() && self.to_remove.is_empty() && self.to_update.is_empty()
+    }
+
+    fn new(
+        configs: impl IntoIterator<Item = UpnpConfig>,
+        mut entries: Vec<PortMappingEntry>,
+        local_ip: Ipv4Addr,
+        pattern: Option<&str>,
+    ) -> Self {
+        let mut diff = MappingDiff::default();
+
+        for config in configs {
+            let addr = config.internal_addr(SocketAddrV4::new(local_ip, config.port));
+            let remote_host = config
+                .remote_host
+                .map(|ip| ip.to_string())
+                .unwrap_or_default();
+            let position = entries.iter().position(|entry| {
+                entry.protocol == config.protocol
+                    && if config.any_port {
+                        entry.forwards_to(addr)
+                    } else {
+                        entry.external_port == config.port && entry.remote_host == remote_host
+                    }
+            });
+
+            match position.map(|position| entries.remove(position)) {
+                None => diff.to_add.push(config),
+                Some(entry)
+                    if entry.enabled
+                        && entry.forwards_to(addr)
+                        && entry.description == config.comment => {}
+                Some(entry) => diff.to_update.push((config, entry)),
+            }
+        }
+
+        diff.to_remove = entries
+            .into_iter()
+            .filter(|entry| match pattern {
+                Some(pattern) => entry.description_matches(pattern),
+                None => true,
+            })
+            .collect();
+
+        diff
+    }
+}
+
+/// Compare configurations with the port mappings of a gateway, without changing anything.
+///
+/// The gateway is searched for only once, in the same way as for [list_port_mappings], and all
+/// configurations are compared with its port mappings, regardless of their own
+/// [`address`](UpnpConfig::address). Port mappings that no configuration describes are only
+/// reported for removal if their description matches the `pattern`, see
+/// [PortMappingEntry::description_matches], so that port mappings of other clients can be left
+/// out. Without a pattern, all of them are reported. Configurations of the
+/// [`NatPmp`](Backend::NatPmp) backend are left out, since NAT-PMP cannot list port mappings.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_upnp::{diff, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "[host-a] Web".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let diff = diff([config], &None, Some("[host-a]*"), None)?;
+/// for entry in &diff.to_remove {
+///     println!("Port {} is not configured anymore", entry.external_port);
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn diff(
+    configs: impl IntoIterator<Item = UpnpConfig>,
+    address: &Option<Ipv4Cidr>,
+    pattern: Option<&str>,
+    options: Option<&DiscoveryOptions>,
+) -> Result<MappingDiff> {
+    let options = options.cloned().unwrap_or_default();
+    let (gateway, addr) = get_gateway_and_address_from_options(address, 0, &options)?;
+
+    let configs = configs
+        .into_iter()
+        .filter(|config| config.backend() == Backend::Upnp);
+
+    Ok(MappingDiff::new(
+        configs,
+        get_port_mappings(&gateway)?,
+        *addr.ip(),
+        pattern,
+    ))
+}
+
+/// Information about a gateway, as found by [probe_gateway].
+#[derive(Clone, Debug, Serialize)]
+pub struct GatewayInfo {
+    /// The address of the gateway's control endpoint.
+    pub addr: SocketAddrV4,
+
+    /// The friendly name from the gateway's device description, if it could be read.
+    pub friendly_name: Option<String>,
+
+    /// The external IP address of the gateway.
+    pub external_ip: Ipv4Addr,
+
+    /// The [USED_ACTIONS] that the gateway does not support.
+    ///
+    /// Features that need these actions fail with [UpnpError::UnsupportedAction] on this gateway.
+    pub missing_actions: Vec<String>,
+}
+
+/// Find a gateway, without adding or removing any port mappings.
+///
+/// The gateway is searched for in the same way as for the [`address`](UpnpConfig::address) of a
+/// [UpnpConfig]. This is useful to check whether UPnP is available at all. If no gateway can be
+/// found, [UpnpError::NoMatchingGateway] is returned.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_upnp::probe_gateway;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let info = probe_gateway(&None, None)?;
+/// println!("Found gateway at {} with external IP {}", info.addr, info.external_ip);
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn probe_gateway(
+    address: &Option<Ipv4Cidr>,
+    options: Option<&DiscoveryOptions>,
+) -> Result<GatewayInfo> {
+    let options = options.cloned().unwrap_or_default();
+    let (gateway, _) = get_gateway_and_address_from_options(address, 0, &options)?;
+
+    gateway_info(&gateway)
+}
+/// Get the external IP address of the gateway.
+fn get_external_ip(gateway: &Gateway) -> Result<Ipv4Addr> {
+    if soap::is_intercepted() {
+        soap::get_external_ip(gateway)
+    } else {
+        Ok(gateway.get_external_ip()?)
+    }
+}
+
+fn gateway_info(gateway: &Gateway) -> Result<GatewayInfo> {
+    let friendly_name = soap::get_friendly_name(gateway)
+        .map_err(|e| debug!("Could not read friendly name of gateway: {}", e))
+        .ok();
+
+    Ok(GatewayInfo {
+        addr: gateway.addr,
+        friendly_name,
+        external_ip: get_external_ip(gateway)?,
+        missing_actions: missing_actions(gateway),
+    })
+}
+
+/// A gateway and its external IP address, found ahead of time by [prefetch_gateway].
+///
+/// As an [AddressResolver], it hands out the prefetched gateway for port mappings of the same
+/// address, without searching again. Port mappings of other addresses, or with another
+/// [`gateway`](UpnpConfig::gateway), are resolved by the [`resolver`](DiscoveryOptions::resolver)
+/// of the original [DiscoveryOptions] as usual.
+#[derive(Clone, Debug)]
+pub struct PrefetchedGateway {
+    address: Option<Ipv4Cidr>,
+    gateway: Gateway,
+    local_ip: Ipv4Addr,
+    external_ip: Ipv4Addr,
+    gateway_url: Option<String>,
+    gateway_name: Option<String>,
+    fallback: Option<Arc<dyn AddressResolver>>,
+}
+
+impl PrefetchedGateway {
+    /// The prefetched gateway.
+    pub fn gateway(&self) -> &Gateway {
+        &self.gateway
+    }
+
+    /// The external IP address of the gateway, as it was when the gateway was prefetched.
+    pub fn external_ip(&self) -> Ipv4Addr {
+        self.external_ip
+    }
+}
+
+impl AddressResolver for PrefetchedGateway {
+    fn resolve(
+        &self,
+        address: &Option<Ipv4Cidr>,
+        port: u16,
+        options: &DiscoveryOptions,
+    ) -> Result<(Gateway, SocketAddrV4)> {
+        if *address == self.address
+            && options.gateway_url == self.gateway_url
+            && options.gateway_name == self.gateway_name
+        {
+            return Ok((self.gateway.clone(), SocketAddrV4::new(self.local_ip, port)));
+        }
+
+        match &self.fallback {
+            Some(resolver) => resolver.resolve(address, port, options),
+            None => DefaultResolver.resolve(address, port, options),
+        }
+    }
+}
+
+/// Find a gateway and fetch its external IP address once, to reuse them for later port mappings.
+///
+/// The gateway is searched for in the same way as for [probe_gateway], so connectivity problems
+/// show up right away. To use the prefetched gateway, set it as the
+/// [`resolver`](DiscoveryOptions::resolver) of the [DiscoveryOptions]. Since the gateway is not
+/// searched for again, this is best suited for a single batch of port mappings, like the first
+/// one after the start of a program.
+///
+/// # Example
+///
+/// ```no_run
+/// use easy_upnp::{add_ports, prefetch_gateway, DiscoveryOptions, UpnpConfig};
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let prefetched = prefetch_gateway(&None, None)?;
+/// println!("External IP: {}", prefetched.external_ip());
+///
+/// let options = DiscoveryOptions::new().resolver(prefetched);
+/// let config: UpnpConfig = serde_json::from_str(r#"{"port": 80, "protocol": "TCP"}"#)?;
+/// for result in add_ports([config], Some(&options)) {
+///     result?;
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn prefetch_gateway(
+    address: &Option<Ipv4Cidr>,
+    options: Option<&DiscoveryOptions>,
+) -> Result<PrefetchedGateway> {
+    let options = options.cloned().unwrap_or_default();
+    let (gateway, addr) = get_gateway_and_address_from_options(address, 0, &options)?;
+    let external_ip = get_external_ip(&gateway)?;
+
+    Ok(PrefetchedGateway {
+        address: *address,
+        gateway,
+        local_ip: *addr.ip(),
+        external_ip,
+        gateway_url: options.gateway_url,
+        gateway_name: options.gateway_name,
+        fallback: options.resolver,
+    })
+}
+
+/// What adding a port mapping would do, see [UpnpConfig::plan].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlannedAction {
+    /// There is no port mapping for the configuration yet, it would be added.
+    Add,
+
+    /// The port mapping already exists, its lease would be renewed.
+    Refresh,
+
+    /// The port mapping already exists, but is disabled, it would be enabled again and renewed.
+    Enable,
+
+    /// The port is mapped to another address, that port mapping would be replaced.
+    Replace,
+}
+
+/// The result of [UpnpConfig::plan].
+#[derive(Clone, Debug, Serialize)]
+pub struct PortMappingPlan {
+    /// The gateway that would be asked to add the port mapping, if it is known beforehand.
+    pub gateway: Option<GatewayInfo>,
+
+    /// The local address that the port would be forwarded to, if it is known beforehand.
+    pub local_addr: Option<SocketAddrV4>,
+
+    /// What would be done.
+    pub action: PlannedAction,
+}
+
+/// Parse a DHCP lease file of dnsmasq, and return the current IPv4 address of each hostname.
+///
+/// Each line of the file describes one lease, with the expiry time, the MAC address, the IP
+/// address, the hostname and the client ID, separated by spaces. Leases of IPv6 addresses and
+/// leases without a hostname, which dnsmasq marks as `*`, are skipped, as well as lines that do
+/// not look like a lease at all.
+///
+/// # Example
+///
+/// ```
+/// use std::net::Ipv4Addr;
+///
+/// use easy_upnp::parse_dnsmasq_leases;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let input = "1700000000 00:11:22:33:44:55 192.168.0.20 nas 01:00:11:22:33:44:55\n";
+///
+/// let leases = parse_dnsmasq_leases(input.as_bytes())?;
+/// assert_eq!(leases["nas"], Ipv4Addr::new(192, 168, 0, 20));
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_dnsmasq_leases(mut reader: impl Read) -> Result<BTreeMap<String, Ipv4Addr>> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+
+    Ok(input
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, _, ip, hostname, ..] if *hostname != "*" => {
+                    Some((hostname.to_string(), ip.parse().ok()?))
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Parse port mappings from CSV.
+///
+/// The first line of the input must be a header with the names of the fields. The fields are
+/// separated by the given delimiter. Records may end early, if the missing fields are optional.
+///
+/// Each record is parsed independently, so a badly formatted record results in an error for this
+/// record only and does not stop the processing of the other records.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::parse_configs_csv;
+///
+/// let input = "address;port;protocol;duration;comment\n;80;TCP;3600;Webserver\n";
+///
+/// for result in parse_configs_csv(input.as_bytes(), b';') {
+///     let config = result.unwrap();
+///     assert_eq!(config.port, 80);
+/// }
+/// ```
+pub fn parse_configs_csv(
+    reader: impl Read,
+    delimiter: u8,
+) -> impl Iterator<Item = Result<UpnpConfig>> {
+    let mut headers = None;
+
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(reader)
+        .into_records()
+        .filter_map(move |result| match (result, &headers) {
+            (Err(e), _) => Some(Err(e.into())),
+            (Ok(record), None) => {
+                headers = Some(record);
+                None
+            }
+            (Ok(record), Some(headers)) => Some(UpnpConfig::from_csv_record(&record, headers)),
+        })
+}
+
+/// Parse port mappings from CSV without a header line.
+///
+/// Instead of reading the names of the fields from the first line, the given `columns` name the
+/// field of each column, in order. Valid names are listed in [`UpnpConfig::FIELDS`]. All lines of
+/// the input are treated as records.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::parse_configs_csv_with_columns;
+///
+/// let input = "80,TCP,,3600,Webserver\n";
+/// let columns = ["port", "protocol", "address", "duration", "comment"];
+///
+/// for result in parse_configs_csv_with_columns(input.as_bytes(), b',', &columns) {
+///     let config = result.unwrap();
+///     assert_eq!(config.port, 80);
+/// }
+/// ```
+pub fn parse_configs_csv_with_columns(
+    reader: impl Read,
+    delimiter: u8,
+    columns: &[impl AsRef<str>],
+) -> impl Iterator<Item = Result<UpnpConfig>> {
+    let headers: StringRecord = columns.iter().map(AsRef::as_ref).collect();
+
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(reader)
+        .into_records()
+        .map(move |result| UpnpConfig::from_csv_record(&result?, &headers))
+}
+
+/// Parse port mappings from JSON.
+///
+/// The input must be a JSON array of port mappings. If it is not, [UpnpError::NotAJsonArray] is
+/// returned.
+///
+/// Each entry of the array is parsed independently, so a badly formatted entry results in an
+/// error for this entry only and does not stop the processing of the other entries.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::parse_configs_json;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let input = r#"[{"port": 80, "protocol": "TCP", "duration": 3600, "comment": "Webserver"}]"#;
+///
+/// for result in parse_configs_json(input.as_bytes())? {
+///     let config = result?;
+///     assert_eq!(config.port, 80);
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_configs_json(reader: impl Read) -> Result<impl Iterator<Item = Result<UpnpConfig>>> {
+    json_array_configs(serde_json::from_reader(reader)?)
+}
+
+/// Parse port mappings from JSON, ignoring any content after the array.
+///
+/// This works like [parse_configs_json], but only the first JSON value of the input is read. Any
+/// content after it is ignored, with a warning, which tolerates files that another program
+/// appends to. The array itself must still be complete and valid.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::parse_configs_json_lenient;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let input = r#"[{"port": 80, "protocol": "TCP", "duration": 3600}]{"port": "#;
+///
+/// assert_eq!(parse_configs_json_lenient(input.as_bytes())?.count(), 1);
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_configs_json_lenient(
+    mut reader: impl Read,
+) -> Result<impl Iterator<Item = Result<UpnpConfig>>> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+
+    let mut values = serde_json::Deserializer::from_slice(&input).into_iter::<JsonValue>();
+    let value = match values.next() {
+        Some(value) => value?,
+        None => return Err(UpnpError::NotAJsonArray),
+    };
+
+    let trailing = input[values.byte_offset()..].trim_ascii();
+    if !trailing.is_empty() {
+        warn!(
+            "Ignore {} bytes of trailing content after the JSON array",
+            trailing.len()
+        );
+    }
+
+    json_array_configs(value)
+}
+
+fn json_array_configs(value: JsonValue) -> Result<impl Iterator<Item = Result<UpnpConfig>>> {
+    match value {
+        JsonValue::Array(values) => Ok(values
+            .into_iter()
+            .map(|value| UpnpConfig::from_json_value(&value))),
+        _ => Err(UpnpError::NotAJsonArray),
+    }
+}
+
+/// Parse port mappings from YAML.
+///
+/// The input must be a YAML sequence of port mappings. If it is not,
+/// [UpnpError::NotAYamlSequence] is returned.
+///
+/// Each entry of the sequence is parsed independently, so a badly formatted entry results in an
+/// error for this entry only and does not stop the processing of the other entries.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::parse_configs_yaml;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let input = "- port: 80\n  protocol: TCP\n  duration: 3600\n  comment: Webserver\n";
+///
+/// for result in parse_configs_yaml(input.as_bytes())? {
+///     let config = result?;
+///     assert_eq!(config.port, 80);
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "yaml")]
+pub fn parse_configs_yaml(reader: impl Read) -> Result<impl Iterator<Item = Result<UpnpConfig>>> {
+    match serde_yaml::from_reader(reader)? {
+        serde_yaml::Value::Sequence(values) => Ok(values
+            .into_iter()
+            .map(|value| serde_yaml::from_value(value).map_err(UpnpError::from))),
+        _ => Err(UpnpError::NotAYamlSequence),
+    }
+}
+
+/// The name of the array of tables that holds the port mappings in TOML.
+#[cfg(feature = "toml")]
+pub const TOML_TABLE_NAME: &str = "mapping";
+
+/// Parse port mappings from TOML.
+///
+/// The input must contain an array of tables named [`mapping`](TOML_TABLE_NAME), with one table
+/// per port mapping. If it does not, [UpnpError::NoTomlMappings] is returned. Other keys are
+/// ignored.
+///
+/// Each table of the array is parsed independently, so a badly formatted table results in an
+/// error for this table only and does not stop the processing of the other tables.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::parse_configs_toml;
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let input = r#"
+/// [[mapping]]
+/// port = 80
+/// protocol = "TCP"
+/// duration = 3600
+/// comment = "Webserver"
+/// "#;
+///
+/// for result in parse_configs_toml(input.as_bytes())? {
+///     let config = result?;
+///     assert_eq!(config.port, 80);
+/// }
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "toml")]
+pub fn parse_configs_toml(
+    mut reader: impl Read,
+) -> Result<impl Iterator<Item = Result<UpnpConfig>>> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+
+    let mut table: toml::Table = toml::from_str(&input)?;
+    match table.remove(TOML_TABLE_NAME) {
+        Some(toml::Value::Array(values)) => Ok(values
+            .into_iter()
+            .map(|value| value.try_into().map_err(UpnpError::from))),
+        _ => Err(UpnpError::NoTomlMappings),
+    }
+}
+
+/// Write port mappings as CSV, in the format that [parse_configs_csv] understands.
+///
+/// The first line of the output is a header with the names of all [fields](UpnpConfig::FIELDS).
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::{parse_configs_csv, write_configs_csv, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let mut output = Vec::new();
+/// write_configs_csv(&mut output, [&config], b';')?;
+///
+/// let parsed = parse_configs_csv(output.as_slice(), b';').next().unwrap()?;
+/// assert_eq!(parsed, config);
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn write_configs_csv<'a>(
+    writer: impl Write,
+    configs: impl IntoIterator<Item = &'a UpnpConfig>,
+    delimiter: u8,
+) -> Result<()> {
+    let error = |e: csv::Error| UpnpError::WriteError(e.into());
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+
+    for config in configs {
+        writer.serialize(config).map_err(error)?;
+    }
+
+    writer.flush().map_err(UpnpError::WriteError)
+}
+
+/// Write port mappings as JSON, in the format that [parse_configs_json] understands.
+///
+/// # Example
+///
+/// ```
+/// use easy_upnp::{parse_configs_json, write_configs_json, LeaseDuration, PortMappingProtocol, UpnpConfig};
+///
+/// # fn main() -> Result<(), easy_upnp::UpnpError> {
+/// let config = UpnpConfig {
+///     duration: LeaseDuration::Seconds(3600),
+///     comment: "Webserver".to_string(),
+///     ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+/// };
+///
+/// let mut output = Vec::new();
+/// write_configs_json(&mut output, [&config])?;
+///
+/// let parsed = parse_configs_json(output.as_slice())?.next().unwrap()?;
+/// assert_eq!(parsed, config);
+/// #
+/// # Ok(())
+/// # }
+/// ```
+pub fn write_configs_json<'a>(
+    mut writer: impl Write,
+    configs: impl IntoIterator<Item = &'a UpnpConfig>,
+) -> Result<()> {
+    let configs: Vec<_> = configs.into_iter().collect();
+
+    serde_json::to_writer_pretty(&mut writer, &configs)
+        .map_err(|e| UpnpError::WriteError(e.into()))?;
+    writeln!(writer).map_err(UpnpError::WriteError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retried_error_reports_both_attempts() {
+        let error = UpnpError::Retried {
+            original: Box::new(igd::AddPortError::PortInUse.into()),
+            retry: Box::new(igd::AddPortError::ActionNotAuthorized.into()),
+        };
+
+        let message = error.to_string();
+        assert!(message.contains(&igd::AddPortError::PortInUse.to_string()));
+        assert!(message.contains(&igd::AddPortError::ActionNotAuthorized.to_string()));
+    }
+
+    #[test]
+    fn written_configs_can_be_parsed() {
+        let configs = [
+            UpnpConfig {
+                address: Some(Ipv4Cidr::from_str("192.168.0.10").unwrap().into()),
+                duration: LeaseDuration::Auto,
+                comment: "Game server; with delimiter".to_string(),
+                backend: Some(Backend::NatPmp),
+                priority: 5,
+                any_port: true,
+                ..UpnpConfig::new(8080, PortMappingProtocol::UDP)
+            },
+            UpnpConfig {
+                address: Some(Address::Command("get-address --vpn".to_string())),
+                duration: LeaseDuration::Seconds(0),
+                remote_host: Some(Ipv4Addr::new(198, 51, 100, 7)),
+                internal_client: Some(Ipv4Addr::new(192, 168, 0, 20)),
+                requires_process: Some(RequiredProcess::PidFile(PathBuf::from("/run/app.pid"))),
+                ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+            },
+        ];
+
+        let mut csv = Vec::new();
+        write_configs_csv(&mut csv, &configs, b';').unwrap();
+        let parsed: Vec<_> = parse_configs_csv(csv.as_slice(), b';')
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(parsed, configs);
+
+        let mut json = Vec::new();
+        write_configs_json(&mut json, &configs).unwrap();
+        let parsed: Vec<_> = parse_configs_json(json.as_slice())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(parsed, configs);
+    }
+
+    #[test]
+    fn keepalive_stops_promptly() {
+        let config = UpnpConfig {
+            address: Some(Ipv4Cidr::from_str("203.0.113.0/24").unwrap().into()),
+            duration: LeaseDuration::Auto,
+            ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+        };
+
+        let start = std::time::Instant::now();
+        keepalive([config], Duration::from_secs(3600), None).stop(true);
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn interface_selector_is_name_or_cidr() {
+        assert_eq!(
+            InterfaceSelector::from_str("eth0"),
+            Ok(InterfaceSelector::Name("eth0".to_string()))
+        );
+        assert_eq!(
+            InterfaceSelector::from_str("192.168.1.0/24"),
+            Ok(InterfaceSelector::Cidr(
+                Ipv4Cidr::from_str("192.168.1.0/24").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn interfaces_are_ordered_by_match_policy() {
+        let iface = |name: &str, ip| get_if_addrs::Interface {
+            name: name.to_string(),
+            addr: get_if_addrs::IfAddr::V4(get_if_addrs::Ifv4Addr {
+                ip,
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                broadcast: None,
+            }),
+        };
+        let order = |options: &DiscoveryOptions| {
+            let mut ifaces = vec![
+                iface("wlan0", Ipv4Addr::new(192, 168, 1, 20)),
+                iface("eth1", Ipv4Addr::new(192, 168, 1, 30)),
+                iface("eth0", Ipv4Addr::new(192, 168, 1, 40)),
+                iface("eth0", Ipv4Addr::new(192, 168, 1, 10)),
+            ];
+            order_interfaces(&mut ifaces, options);
+            ifaces
+                .into_iter()
+                .map(|iface| (iface.name.clone(), iface.ip()))
+                .collect::<Vec<_>>()
+        };
+        let entry = |name: &str, last| (name.to_string(), IpAddr::from([192, 168, 1, last]));
+
+        assert_eq!(
+            order(&DiscoveryOptions::new()),
+            [
+                entry("eth0", 10),
+                entry("eth0", 40),
+                entry("eth1", 30),
+                entry("wlan0", 20)
+            ]
+        );
+        assert_eq!(
+            order(&DiscoveryOptions::new().match_policy(MatchPolicy::LowestIp)),
+            [
+                entry("eth0", 10),
+                entry("wlan0", 20),
+                entry("eth1", 30),
+                entry("eth0", 40)
+            ]
+        );
+        assert_eq!(
+            order(
+                &DiscoveryOptions::new()
+                    .match_policy(MatchPolicy::LowestIp)
+                    .interface_priority(vec![InterfaceSelector::Name("eth1".to_string())])
+            ),
+            [
+                entry("eth1", 30),
+                entry("eth0", 10),
+                entry("wlan0", 20),
+                entry("eth0", 40)
+            ]
+        );
+    }
+
+    #[test]
+    fn vanished_interfaces_are_reported_once() {
+        let iface = |name: &str, ip| get_if_addrs::Interface {
+            name: name.to_string(),
+            addr: get_if_addrs::IfAddr::V4(get_if_addrs::Ifv4Addr {
+                ip,
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                broadcast: None,
+            }),
+        };
+        let wlan = ("wlan0".to_string(), Ipv4Addr::new(192, 168, 1, 20));
+        let eth = ("eth0".to_string(), Ipv4Addr::new(192, 168, 1, 10));
+        let mut used = BTreeSet::from([wlan.clone(), eth.clone()]);
+
+        let before = [iface("eth0", eth.1), iface("wlan0", wlan.1)];
+        assert_eq!(forget_missing_interfaces(&mut used, &before), []);
+
+        // The Wi-Fi drops between two runs, and comes back with another address.
+        let after = [
+            iface("eth0", eth.1),
+            iface("wlan0", Ipv4Addr::new(10, 0, 0, 5)),
+        ];
+        assert_eq!(forget_missing_interfaces(&mut used, &after), [wlan]);
+        assert_eq!(used, BTreeSet::from([eth]));
+        assert_eq!(forget_missing_interfaces(&mut used, &after), []);
+    }
+
+    #[test]
+    fn address_candidates_are_parsed() {
+        let cidr = |s| Ipv4Cidr::from_str(s).unwrap();
+        let candidates = Address::Candidates(vec![cidr("192.168.1.0/24"), cidr("10.0.0.0/8")]);
+
+        let value = serde_json::json!({
+            "address": ["192.168.1.0/24", "10.0.0.0/8"],
+            "port": 80,
+            "protocol": "TCP",
+            "duration": 3600,
+        });
+        let config = UpnpConfig::from_json_value(&value).unwrap();
+        assert_eq!(config.address, Some(candidates.clone()));
+
+        let headers = StringRecord::from(vec!["address", "port", "protocol", "duration"]);
+        let record = StringRecord::from(vec!["192.168.1.0/24,10.0.0.0/8", "80", "TCP", "3600"]);
+        let config = UpnpConfig::from_csv_record(&record, &headers).unwrap();
+        assert_eq!(config.address, Some(candidates));
+
+        // Looks like a number to the CSV deserializer.
+        let record = StringRecord::from(vec!["172.20", "80", "TCP", "3600"]);
+        let config = UpnpConfig::from_csv_record(&record, &headers).unwrap();
+        assert_eq!(config.address, Some(Address::Cidr(cidr("172.20"))));
+    }
+
+    #[test]
+    fn dnsmasq_leases_are_parsed() {
+        let input = "\
+            1700000000 00:11:22:33:44:55 192.168.0.20 nas 01:00:11:22:33:44:55\n\
+            1700000000 00:11:22:33:44:66 192.168.0.21 * *\n\
+            duid 00:01:00:01:2b:2c:2d:2e:00:11:22:33:44:55\n\
+            1700000000 1234 fd00::20 nas 00:01:00:01\n";
+
+        let leases = parse_dnsmasq_leases(input.as_bytes()).unwrap();
+        assert_eq!(
+            leases,
+            BTreeMap::from([("nas".to_string(), Ipv4Addr::new(192, 168, 0, 20))])
+        );
+    }
+
+    #[test]
+    fn search_defaults_match_igd() {
+        let defaults = SearchOptions::default();
+        assert_eq!(defaults.timeout, Some(DEFAULT_SEARCH_TIMEOUT));
+        assert_eq!(
+            defaults.broadcast_address,
+            SocketAddr::V4(DEFAULT_SSDP_ADDRESS)
+        );
+    }
+
+    #[test]
+    fn description_is_matched_exactly_or_as_glob() {
+        let entry = PortMappingEntry {
+            remote_host: String::new(),
+            external_port: 80,
+            protocol: PortMappingProtocol::TCP,
+            internal_port: 80,
+            internal_client: "192.168.0.10".to_string(),
+            enabled: true,
+            description: "[host-a] Web".to_string(),
+            lease_duration: 3600,
+        };
+
+        assert!(entry.description_matches("[host-a] Web"));
+        assert!(!entry.description_matches("[host-a]"));
+        assert!(entry.description_matches("[host-a]*"));
+        assert!(entry.description_matches("*We?"));
+        assert!(!entry.description_matches("[host-b]*"));
+    }
+
+    #[test]
+    fn diff_compares_configs_with_port_mappings() {
+        let config = |port, comment: &str| UpnpConfig {
+            duration: LeaseDuration::Seconds(3600),
+            comment: comment.to_string(),
+            ..UpnpConfig::new(port, PortMappingProtocol::TCP)
+        };
+        let entry = |port, client: &str, description: &str| PortMappingEntry {
+            remote_host: String::new(),
+            external_port: port,
+            protocol: PortMappingProtocol::TCP,
+            internal_port: port,
+            internal_client: client.to_string(),
+            enabled: true,
+            description: description.to_string(),
+            lease_duration: 3600,
+        };
+        let local_ip = Ipv4Addr::new(192, 168, 0, 10);
+
+        let configs = vec![
+            config(80, "[host-a] Web"),
+            config(443, "[host-a] TLS"),
+            config(8080, "[host-a] Proxy"),
+            config(9000, "[host-a] Game"),
+        ];
+        let entries = vec![
+            entry(80, "192.168.0.10", "[host-a] Web"),
+            entry(443, "192.168.0.10", "[host-a] Old"),
+            entry(8080, "192.168.0.20", "[host-a] Proxy"),
+            entry(22, "192.168.0.10", "[host-a] SSH"),
+            entry(25, "192.168.0.30", "[host-b] Mail"),
+        ];
+
+        let diff = MappingDiff::new(
+            configs.clone(),
+            entries.clone(),
+            local_ip,
+            Some("[host-a]*"),
+        );
+        assert_eq!(diff.to_add, [config(9000, "[host-a] Game")]);
+        let updated: Vec<_> = diff
+            .to_update
+            .iter()
+            .map(|(config, _)| config.port)
+            .collect();
+        assert_eq!(updated, [443, 8080]);
+        let removed: Vec<_> = diff
+            .to_remove
+            .iter()
+            .map(|entry| entry.external_port)
+            .collect();
+        assert_eq!(removed, [22]);
+
+        let diff = MappingDiff::new(configs, entries, local_ip, None);
+        let removed: Vec<_> = diff
+            .to_remove
+            .iter()
+            .map(|entry| entry.external_port)
+            .collect();
+        assert_eq!(removed, [22, 25]);
+
+        let diff = MappingDiff::new(
+            [config(80, "[host-a] Web")],
+            vec![entry(80, "192.168.0.10", "[host-a] Web")],
+            local_ip,
+            None,
+        );
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn max_lease_duration_is_parsed() {
+        let description = r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<serviceStateTable>
+<stateVariable sendEvents="no">
+<name>ExternalPort</name>
+<dataType>ui2</dataType>
+</stateVariable>
+<stateVariable sendEvents="no">
+<name>PortMappingLeaseDuration</name>
+<dataType>ui4</dataType>
+<allowedValueRange><minimum>0</minimum><maximum>604800</maximum></allowedValueRange>
+</stateVariable>
+</serviceStateTable>
+</scpd>"#;
+        assert_eq!(
+            soap::parse_max_lease_duration(description).unwrap(),
+            Some(604800)
+        );
+
+        let unlimited = description.replace("<maximum>604800</maximum>", "");
+        assert_eq!(soap::parse_max_lease_duration(&unlimited).unwrap(), None);
+    }
+
+    #[test]
+    fn implausible_remote_host_is_rejected() {
+        let input = "port;protocol;duration;remote_host\n\
+                     80;TCP;3600;198.51.100.7\n\
+                     80;TCP;3600;\n\
+                     80;TCP;3600;0.0.0.0\n\
+                     80;TCP;3600;127.0.0.1\n\
+                     80;TCP;3600;example.com\n";
+
+        let remote_hosts: Vec<_> = parse_configs_csv(input.as_bytes(), b';')
+            .map(|result| result.map(|config| config.remote_host).ok())
+            .collect();
+
+        assert_eq!(
+            remote_hosts,
+            [
+                Some(Some(Ipv4Addr::new(198, 51, 100, 7))),
+                Some(None),
+                None,
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn keepalive_reports_failures_and_survives_dropped_receiver() {
+        let config = UpnpConfig {
+            address: Some(Ipv4Cidr::from_str("203.0.113.0/24").unwrap().into()),
+            duration: LeaseDuration::Auto,
+            ..UpnpConfig::new(8080, PortMappingProtocol::UDP)
+        };
+
+        let (events, received) = mpsc::channel();
+        let handle = keepalive_with_events([config], Duration::from_millis(10), None, events);
+
+        match received.recv_timeout(Duration::from_secs(10)) {
+            Ok(MappingEvent::Failed {
+                port,
+                protocol,
+                error,
+            }) => {
+                assert_eq!(port, 8080);
+                assert_eq!(protocol, PortMappingProtocol::UDP);
+                assert!(matches!(error, UpnpError::NoMatchingGateway));
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        drop(received);
+        thread::sleep(Duration::from_millis(50));
+
+        handle.stop(false);
+    }
+
+    #[test]
+    fn gateway_urls_are_validated() {
+        let (addr, path) = soap::parse_gateway_url("http://192.168.0.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(addr, SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 5000));
+        assert_eq!(path, "/rootDesc.xml");
+
+        let (addr, path) = soap::parse_gateway_url("http://192.168.0.1").unwrap();
+        assert_eq!(addr, SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 80));
+        assert_eq!(path, "/");
+
+        for url in [
+            "https://192.168.0.1/",
+            "http://router.local/",
+            "192.168.0.1:5000",
+        ] {
+            assert!(matches!(
+                soap::parse_gateway_url(url),
+                Err(UpnpError::InvalidGatewayUrl(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn omitted_duration_is_the_router_default() {
+        let headers = StringRecord::from(vec!["port", "protocol", "duration"]);
+        let config =
+            UpnpConfig::from_csv_record(&StringRecord::from(vec!["80", "TCP", ""]), &headers)
+                .unwrap();
+        assert_eq!(config.duration, LeaseDuration::RouterDefault);
+
+        let value = serde_json::json!({"port": 80, "protocol": "TCP"});
+        let config = UpnpConfig::from_json_value(&value).unwrap();
+        assert_eq!(config.duration, LeaseDuration::RouterDefault);
+
+        let value = serde_json::json!({"port": 80, "protocol": "TCP", "duration": 0});
+        let config = UpnpConfig::from_json_value(&value).unwrap();
+        assert_eq!(config.duration, LeaseDuration::Seconds(0));
+    }
+
+    #[test]
+    fn port_session_only_closes_opened_ports() {
+        let config = UpnpConfig {
+            address: Some(Ipv4Cidr::from_str("203.0.113.0/24").unwrap().into()),
+            duration: LeaseDuration::Seconds(600),
+            ..UpnpConfig::new(8080, PortMappingProtocol::TCP)
+        };
+
+        let session = PortSession::open([config], None);
+        assert!(session.opened().is_empty());
+        assert_eq!(session.failures().len(), 1);
+        assert!(matches!(
+            session.failures()[0].1,
+            UpnpError::NoMatchingGateway
+        ));
+        assert!(session.close().is_empty());
+    }
+
+    #[test]
+    fn search_is_repeated_when_nobody_answers() {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        silent
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let SocketAddr::V4(ssdp_address) = silent.local_addr().unwrap() else {
+            unreachable!("The socket is bound to an IPv4 address");
+        };
+
+        let options = DiscoveryOptions::new()
+            .ssdp_address(ssdp_address)
+            .timeout(Duration::from_millis(100))
+            .retries(2);
+        assert!(search_gateway(IpAddr::V4(Ipv4Addr::LOCALHOST), &options).is_err());
+
+        let mut buf = [0; 1024];
+        let searches = std::iter::from_fn(|| silent.recv_from(&mut buf).ok()).count();
+        assert_eq!(searches, 3);
+    }
+
+    #[test]
+    fn bind_ip_has_to_match() {
+        assert!(check_bind_ip(Ipv4Addr::LOCALHOST, &None).is_ok());
+        assert!(check_bind_ip(Ipv4Addr::LOCALHOST, &Some("127.0.0.0/8".parse().unwrap())).is_ok());
+        assert!(matches!(
+            check_bind_ip(Ipv4Addr::LOCALHOST, &Some("10.0.0.0/8".parse().unwrap())),
+            Err(UpnpError::BindIpNotFound(_))
+        ));
+        assert!(matches!(
+            check_bind_ip(Ipv4Addr::new(203, 0, 113, 7), &None),
+            Err(UpnpError::BindIpNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn custom_resolver_replaces_the_search() {
+        #[derive(Debug, Default)]
+        struct Unreachable {
+            asked: Mutex<Vec<Option<Ipv4Cidr>>>,
+        }
+
+        impl AddressResolver for Arc<Unreachable> {
+            fn resolve(
+                &self,
+                address: &Option<Ipv4Cidr>,
+                _port: u16,
+                _options: &DiscoveryOptions,
+            ) -> Result<(Gateway, SocketAddrV4)> {
+                self.asked.lock().unwrap().push(*address);
+                Err(UpnpError::NoMatchingGateway)
+            }
+        }
+
+        let resolver = Arc::new(Unreachable::default());
+        let options = DiscoveryOptions::new().resolver(Arc::clone(&resolver));
+        let config = UpnpConfig {
+            address: Some(Ipv4Cidr::from_str("10.0.0.0/8").unwrap().into()),
+            duration: LeaseDuration::Seconds(600),
+            ..UpnpConfig::new(8080, PortMappingProtocol::TCP)
+        };
+
+        let results: Vec<_> = add_ports([config], Some(&options)).collect();
+        assert!(matches!(results[..], [Err(UpnpError::NoMatchingGateway)]));
+        assert_eq!(
+            *resolver.asked.lock().unwrap(),
+            [Some(Ipv4Cidr::from_str("10.0.0.0/8").unwrap())]
+        );
+    }
+
+    #[test]
+    fn panic_of_one_port_mapping_does_not_stop_the_others() {
+        #[derive(Debug)]
+        struct PanicsOn8081;
+
+        impl AddressResolver for PanicsOn8081 {
+            fn resolve(
+                &self,
+                _address: &Option<Ipv4Cidr>,
+                port: u16,
+                _options: &DiscoveryOptions,
+            ) -> Result<(Gateway, SocketAddrV4)> {
+                if port == 8081 {
+                    panic!("resolver failed on port {}", port);
+                }
+                Err(UpnpError::NoMatchingGateway)
+            }
+        }
+
+        let options = DiscoveryOptions::new().resolver(PanicsOn8081);
+        let config = |port| UpnpConfig {
+            address: Some(Ipv4Cidr::from_str("10.0.0.0/8").unwrap().into()),
+            duration: LeaseDuration::Seconds(600),
+            ..UpnpConfig::new(port, PortMappingProtocol::TCP)
+        };
+        let configs = [config(8080), config(8081), config(8082)];
+
+        let results: Vec<_> = add_ports(configs.clone(), Some(&options)).collect();
+        assert!(matches!(
+            &results[..],
+            [
+                Err(UpnpError::NoMatchingGateway),
+                Err(UpnpError::Panicked(message)),
+                Err(UpnpError::NoMatchingGateway),
+            ] if message == "resolver failed on port 8081"
+        ));
+
+        let results: Vec<_> = apply_ports(configs.clone(), Some(&options)).collect();
+        assert!(matches!(results[1], Err(UpnpError::Panicked(_))));
+        assert_eq!(results.len(), 3);
+
+        let results: Vec<_> = delete_ports(configs, Some(&options)).collect();
+        assert!(matches!(results[1], Err(UpnpError::Panicked(_))));
+        assert_eq!(results.len(), 3);
+    }
+
+    #[test]
+    fn prefetched_gateway_is_only_used_for_its_address() {
+        #[derive(Debug)]
+        struct Unreachable;
+
+        impl AddressResolver for Unreachable {
+            fn resolve(
+                &self,
+                _address: &Option<Ipv4Cidr>,
+                _port: u16,
+                _options: &DiscoveryOptions,
+            ) -> Result<(Gateway, SocketAddrV4)> {
+                Err(UpnpError::NoMatchingGateway)
+            }
+        }
+
+        let address = Some(Ipv4Cidr::from_str("192.168.0.0/24").unwrap());
+        let prefetched = PrefetchedGateway {
+            address,
+            gateway: Gateway {
+                addr: SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 5000),
+                root_url: "/rootDesc.xml".to_string(),
+                control_url: "/ctl/IPConn".to_string(),
+                control_schema_url: "/WANIPCn.xml".to_string(),
+                control_schema: Default::default(),
+            },
+            local_ip: Ipv4Addr::new(192, 168, 0, 10),
+            external_ip: Ipv4Addr::new(203, 0, 113, 7),
+            gateway_url: None,
+            gateway_name: None,
+            fallback: Some(Arc::new(Unreachable)),
+        };
+        let options = DiscoveryOptions::new();
+
+        let (gateway, addr) = prefetched.resolve(&address, 8080, &options).unwrap();
+        assert_eq!(gateway.addr, prefetched.gateway().addr);
+        assert_eq!(
+            addr,
+            SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 10), 8080)
+        );
+
+        assert!(matches!(
+            prefetched.resolve(&None, 8080, &options),
+            Err(UpnpError::NoMatchingGateway)
+        ));
+        assert!(matches!(
+            prefetched.resolve(&address, 8080, &options.gateway_name("Other")),
+            Err(UpnpError::NoMatchingGateway)
+        ));
+    }
+
+    #[test]
+    fn port_can_include_the_protocol() {
+        let headers = StringRecord::from(vec!["port", "duration"]);
+        let config =
+            UpnpConfig::from_csv_record(&StringRecord::from(vec!["udp/53", "600"]), &headers)
+                .unwrap();
+        assert_eq!(config.port, 53);
+        assert_eq!(config.protocol, PortMappingProtocol::UDP);
+
+        let value = serde_json::json!({"port": "TCP/8080", "protocol": "TCP"});
+        let config = UpnpConfig::from_json_value(&value).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.protocol, PortMappingProtocol::TCP);
+
+        let value = serde_json::json!({"port": 8080, "protocol": "UDP"});
+        assert!(UpnpConfig::from_json_value(&value).is_ok());
+
+        for value in [
+            serde_json::json!({"port": "sctp/8080"}),
+            serde_json::json!({"port": "tcp/70000"}),
+            serde_json::json!({"port": "tcp/8080", "protocol": "UDP"}),
+            serde_json::json!({"port": 8080}),
+        ] {
+            assert!(UpnpConfig::from_json_value(&value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn discovery_can_be_overridden_per_config() {
+        let headers = StringRecord::from(vec!["port", "protocol", "retries", "timeout"]);
+        let config =
+            UpnpConfig::from_csv_record(&StringRecord::from(vec!["80", "TCP", "5", ""]), &headers)
+                .unwrap();
+        assert_eq!(config.retries, Some(5));
+        assert_eq!(config.timeout, None);
+
+        let value = serde_json::json!({"port": 80, "protocol": "TCP", "timeout": 0});
+        assert!(UpnpConfig::from_json_value(&value).is_err());
+    }
+
+    #[test]
+    fn recorded_exchange_is_answered_in_order() {
+        let record = "\
+>>> GET http://192.168.0.1:5000/rootDesc.xml
+<<< 200
+<root><device/></root>
+
+>>> POST http://192.168.0.1:5000/ctl/IPConn AddPortMapping
+<NewInternalClient>192.168.0.10</NewInternalClient>
+<<< 500
+<errorCode>606</errorCode>
+";
+        let mut replay = soap::Replay::parse(record).unwrap();
+
+        let (gateway, local_addr) = replay.gateway(8080);
+        assert_eq!(gateway.addr.to_string(), "192.168.0.1:5000");
+        assert_eq!(gateway.control_url, "/ctl/IPConn");
+        assert_eq!(local_addr.to_string(), "192.168.0.10:8080");
+
+        let url = "http://192.168.0.1:5000/ctl/IPConn";
+        let document = "http://192.168.0.1:5000/rootDesc.xml";
+        assert!(replay.respond("GET", document, None).is_ok());
+        assert!(replay.respond("GET", document, None).is_ok());
+        assert!(replay
+            .respond("POST", url, Some("AddPortMapping"))
+            .unwrap()
+            .contains("606"));
+        assert!(matches!(
+            replay.respond("POST", url, Some("AddPortMapping")),
+            Err(UpnpError::NotRecorded(_))
+        ));
+
+        assert!(matches!(
+            soap::Replay::parse("GET / HTTP/1.1"),
+            Err(UpnpError::InvalidRecord(_))
+        ));
+    }
+
+    #[test]
+    fn auto_duration_outlasts_interval() {
+        assert_eq!(auto_duration(Duration::from_secs(60)), 90);
+        assert_eq!(auto_duration(Duration::from_secs(3600)), 3960);
+        assert_eq!(auto_duration(Duration::MAX), u32::MAX);
+    }
 }
@@ -0,0 +1,86 @@
+//! Port mappings via [NAT-PMP](https://datatracker.ietf.org/doc/html/rfc6886).
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use natpmp::{Natpmp, Protocol, Response};
+
+use crate::{soap, PortMappingProtocol, Result, UpnpError};
+
+/// Time to wait between polls for the response of the gateway.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time to wait for the response of the gateway before giving up.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl From<PortMappingProtocol> for Protocol {
+    fn from(proto: PortMappingProtocol) -> Self {
+        match proto {
+            PortMappingProtocol::TCP => Protocol::TCP,
+            PortMappingProtocol::UDP => Protocol::UDP,
+        }
+    }
+}
+
+/// Send a mapping request to the default gateway and return the public port it chose.
+fn request_mapping(
+    protocol: PortMappingProtocol,
+    private_port: u16,
+    public_port: u16,
+    lifetime: u32,
+) -> Result<u16> {
+    // A record only holds the requests to UPnP gateways, so there is nothing to answer this with.
+    if soap::is_replaying() {
+        return Err(UpnpError::NotRecorded(format!(
+            "NAT-PMP request for {protocol} {private_port}"
+        )));
+    }
+
+    let mut client = Natpmp::new()?;
+    client.send_port_mapping_request(protocol.into(), private_port, public_port, lifetime)?;
+
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    loop {
+        match client.read_response_or_retry() {
+            Ok(Response::TCP(response)) | Ok(Response::UDP(response)) => {
+                return Ok(response.public_port())
+            }
+            Ok(Response::Gateway(_)) => {}
+            Err(natpmp::Error::NATPMP_TRYAGAIN) if Instant::now() < deadline => {}
+            Err(natpmp::Error::NATPMP_TRYAGAIN) => {
+                return Err(natpmp::Error::NATPMP_ERR_NOGATEWAYSUPPORT.into())
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Map the given port of this host to the suggested public port and return the actual one.
+///
+/// A suggested public port of 0 lets the gateway choose any port.
+pub(crate) fn add_port(
+    protocol: PortMappingProtocol,
+    port: u16,
+    suggested_port: u16,
+    lifetime: u32,
+) -> Result<u16> {
+    let public_port = request_mapping(protocol, port, suggested_port, lifetime)?;
+
+    if suggested_port != 0 && public_port != suggested_port {
+        warn!(
+            "Gateway mapped port {} to public port {} instead",
+            port, public_port
+        );
+    }
+
+    Ok(public_port)
+}
+
+/// Remove the mapping for the given port of this host.
+pub(crate) fn remove_port(protocol: PortMappingProtocol, port: u16) -> Result<()> {
+    request_mapping(protocol, port, 0, 0)?;
+    Ok(())
+}
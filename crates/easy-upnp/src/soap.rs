@@ -0,0 +1,708 @@
+//! Requests and SOAP actions that are not (fully) covered by [igd].
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use igd::Gateway;
+use log::warn;
+use xmltree::Element;
+
+use crate::{
+    require_action, PortMappingEntry, PortMappingProtocol, Result, UpnpError, USED_ACTIONS,
+};
+
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+/// The service types that [igd] accepts for port mappings.
+const CONNECTION_SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+];
+
+/// UPnP error code for an action that the gateway does not allow.
+pub(crate) const ACTION_NOT_AUTHORIZED: u16 = 606;
+
+/// UPnP error code for an index past the end of the port mapping table.
+const SPECIFIED_ARRAY_INDEX_INVALID: u16 = 713;
+
+/// UPnP error code for a port mapping that does not exist.
+pub(crate) const NO_SUCH_ENTRY_IN_ARRAY: u16 = 714;
+
+/// UPnP error code for a port mapping that is already in use by another client.
+pub(crate) const CONFLICT_IN_MAPPING_ENTRY: u16 = 718;
+
+/// UPnP error code for a gateway that only accepts port mappings without a lease time.
+pub(crate) const ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+
+/// Destination of the recorded requests, see [crate::record_soap].
+static RECORDER: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+pub(crate) fn set_recorder(writer: Box<dyn Write + Send>) {
+    *RECORDER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(writer);
+}
+
+pub(crate) fn is_recording() -> bool {
+    RECORDER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .is_some()
+}
+
+/// Whether the requests to gateways are recorded or replayed, so that they all have to be made
+/// here instead of by [igd].
+pub(crate) fn is_intercepted() -> bool {
+    is_recording() || is_replaying()
+}
+
+/// Write a request and its response to the recorder, if there is one.
+fn record(request: &str, body: Option<&str>, status: u16, response: &str) {
+    let mut recorder = RECORDER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let Some(writer) = recorder.as_mut() else {
+        return;
+    };
+
+    let mut entry = format!(">>> {request}\n");
+    if let Some(body) = body {
+        entry.push_str(body);
+        entry.push('\n');
+    }
+    entry.push_str(&format!("<<< {status}\n{response}\n\n"));
+
+    if let Err(e) = writer
+        .write_all(entry.as_bytes())
+        .and_then(|()| writer.flush())
+    {
+        warn!("Could not record SOAP request: {}", e);
+    }
+}
+
+/// A recorded exchange with a gateway, to answer requests with instead of the gateway, see
+/// [crate::replay_soap].
+#[derive(Debug)]
+pub(crate) struct Replay {
+    /// The gateway that the requests were sent to.
+    gateway: Gateway,
+
+    /// The internal client of the first recorded port mapping, as the address of this host.
+    local_ip: Ipv4Addr,
+
+    /// The recorded responses to each request, in the recorded order.
+    responses: HashMap<String, VecDeque<String>>,
+}
+
+impl Replay {
+    /// Parse a record of [crate::record_soap].
+    pub(crate) fn parse(record: &str) -> Result<Self> {
+        let invalid = |reason: &str| UpnpError::InvalidRecord(reason.to_string());
+
+        let record = record
+            .strip_prefix(">>> ")
+            .ok_or_else(|| invalid("it does not start with a request"))?;
+
+        let mut gateway = None;
+        let mut local_ip = None;
+        let mut root_url = None;
+        let mut control_schema = None;
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+
+        for entry in record.split("\n>>> ") {
+            let (request, rest) = entry.split_once('\n').unwrap_or((entry, ""));
+            let (body, response) = match rest.strip_prefix("<<< ") {
+                Some(response) => ("", response),
+                None => rest
+                    .split_once("\n<<< ")
+                    .ok_or_else(|| invalid(&format!("{} has no response", request)))?,
+            };
+            let (_status, response) = response.split_once('\n').unwrap_or((response, ""));
+            let response = response.trim_end_matches('\n').to_string();
+
+            let mut words = request.split_whitespace();
+            let (method, url, action) = (words.next(), words.next(), words.next());
+            let (addr, path) = parse_gateway_url(url.unwrap_or_default())?;
+            match (method, action) {
+                (Some("GET"), None) => {
+                    if let Ok(schema) = parse_control_schema(&response) {
+                        control_schema.get_or_insert((path.clone(), schema));
+                    } else if Element::parse(response.as_bytes())
+                        .is_ok_and(|xml| xml.get_child("device").is_some())
+                    {
+                        root_url.get_or_insert(path.clone());
+                    }
+                }
+                (Some("POST"), Some(action)) => {
+                    gateway.get_or_insert((addr, path.clone()));
+                    if action == "AddPortMapping" && local_ip.is_none() {
+                        local_ip = arg(body, "NewInternalClient").and_then(|ip| ip.parse().ok());
+                    }
+                }
+                _ => return Err(invalid(&format!("unknown request {}", request))),
+            }
+
+            responses
+                .entry(request_key(method.unwrap_or_default(), &path, action))
+                .or_default()
+                .push_back(response);
+        }
+
+        let (addr, control_url) = gateway.ok_or_else(|| invalid("it has no SOAP request"))?;
+        let (control_schema_url, control_schema) = control_schema.unwrap_or_else(|| {
+            let actions = USED_ACTIONS
+                .iter()
+                .map(|action| (action.to_string(), Vec::new()));
+            (String::new(), actions.collect())
+        });
+
+        Ok(Replay {
+            gateway: Gateway {
+                addr,
+                root_url: root_url.unwrap_or_default(),
+                control_url,
+                control_schema_url,
+                control_schema,
+            },
+            local_ip: local_ip.unwrap_or(Ipv4Addr::LOCALHOST),
+            responses,
+        })
+    }
+
+    /// The recorded gateway, and the local address for the given port.
+    pub(crate) fn gateway(&self, port: u16) -> (Gateway, SocketAddrV4) {
+        (self.gateway.clone(), SocketAddrV4::new(self.local_ip, port))
+    }
+
+    /// Take the next recorded response to the request.
+    ///
+    /// Documents are reused once their last response is reached, since they do not change,
+    /// actions are only answered as often as they have been recorded.
+    pub(crate) fn respond(
+        &mut self,
+        method: &str,
+        url: &str,
+        action: Option<&str>,
+    ) -> Result<String> {
+        let path = parse_gateway_url(url).map_or_else(|_| url.to_string(), |(_, path)| path);
+        let key = request_key(method, &path, action);
+
+        match self.responses.get_mut(&key) {
+            Some(responses) if action.is_none() && responses.len() == 1 => {
+                Some(responses[0].clone())
+            }
+            Some(responses) => responses.pop_front(),
+            None => None,
+        }
+        .ok_or(UpnpError::NotRecorded(key))
+    }
+}
+
+fn request_key(method: &str, path: &str, action: Option<&str>) -> String {
+    match action {
+        Some(action) => format!("{method} {path} {action}"),
+        None => format!("{method} {path}"),
+    }
+}
+
+/// The value of an argument in a request body.
+fn arg<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + body[start..].find(&format!("</{name}>"))?;
+    Some(&body[start..end])
+}
+
+/// The record to answer requests with, see [crate::replay_soap].
+static REPLAY: Mutex<Option<Replay>> = Mutex::new(None);
+
+pub(crate) fn set_replay(replay: Replay) {
+    *REPLAY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(replay);
+}
+
+pub(crate) fn is_replaying() -> bool {
+    REPLAY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .is_some()
+}
+
+/// The gateway of the record and the local address for the given port, if there is a record.
+pub(crate) fn replayed_gateway(port: u16) -> Option<(Gateway, SocketAddrV4)> {
+    REPLAY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()
+        .map(|replay| replay.gateway(port))
+}
+
+/// Answer a request from the record, if there is one.
+fn replay(method: &str, url: &str, action: Option<&str>) -> Option<Result<String>> {
+    REPLAY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_mut()
+        .map(|replay| replay.respond(method, url, action))
+}
+
+/// Get a document from the gateway, like its device or service description.
+fn get(url: String) -> Result<String> {
+    if let Some(response) = replay("GET", &url, None) {
+        return response;
+    }
+
+    let response = attohttpc::get(&url).send()?;
+    let status = response.status().as_u16();
+    let text = response.text()?;
+
+    record(&format!("GET {url}"), None, status, &text);
+
+    Ok(text)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn perform_request(gateway: &Gateway, action: &str, args: &[(&str, String)]) -> Result<Element> {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+        .collect();
+
+    let body = format!(
+        r#"<?xml version="1.0"?>
+<s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body>
+<u:{action} xmlns:u="{SERVICE_TYPE}">{args}</u:{action}>
+</s:Body>
+</s:Envelope>"#
+    );
+
+    let url = format!("http://{}{}", gateway.addr, gateway.control_url);
+    if let Some(response) = replay("POST", &url, Some(action)) {
+        return parse_response(&response?, &format!("{action}Response"));
+    }
+
+    let response = attohttpc::post(&url)
+        .header("SOAPAction", format!("\"{SERVICE_TYPE}#{action}\""))
+        .header("Content-Type", "text/xml")
+        .text(&body)
+        .send()?;
+    let status = response.status().as_u16();
+    let text = response.text()?;
+
+    record(&format!("POST {url} {action}"), Some(&body), status, &text);
+
+    parse_response(&text, &format!("{action}Response"))
+}
+
+fn parse_response(text: &str, ok: &str) -> Result<Element> {
+    let invalid = || UpnpError::InvalidResponse(text.to_string());
+
+    let mut xml = Element::parse(text.as_bytes()).map_err(|_| invalid())?;
+    let body = xml.get_mut_child("Body").ok_or_else(invalid)?;
+
+    if let Some(response) = body.take_child(ok) {
+        return Ok(response);
+    }
+
+    let upnp_error = body
+        .get_child("Fault")
+        .and_then(|e| e.get_child("detail"))
+        .and_then(|e| e.get_child("UPnPError"))
+        .ok_or_else(invalid)?;
+
+    let code = upnp_error
+        .get_child("errorCode")
+        .and_then(|e| e.get_text())
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let description = upnp_error
+        .get_child("errorDescription")
+        .and_then(|e| e.get_text())
+        .unwrap_or_default()
+        .into_owned();
+
+    Err(UpnpError::GatewayError(code, description))
+}
+
+fn text(xml: &Element, field: &str) -> Result<String> {
+    xml.get_child(field)
+        .map(|e| e.get_text().unwrap_or_default().into_owned())
+        .ok_or_else(|| UpnpError::InvalidResponse(format!("{} is missing", field)))
+}
+
+fn parse<T: FromStr>(xml: &Element, field: &str) -> Result<T> {
+    text(xml, field)?
+        .trim()
+        .parse()
+        .map_err(|_| UpnpError::InvalidResponse(format!("{} is invalid", field)))
+}
+
+/// Split an URL like `http://192.168.0.1:5000/rootDesc.xml` into the address and the path.
+pub(crate) fn parse_gateway_url(url: &str) -> Result<(SocketAddrV4, String)> {
+    let invalid = |reason| UpnpError::InvalidGatewayUrl(url.to_string(), reason);
+
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("only http:// is supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+
+    let addr = match authority.parse() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddrV4::new(
+            authority
+                .parse()
+                .map_err(|_| invalid("the host must be an IPv4 address, with an optional port"))?,
+            80,
+        ),
+    };
+
+    Ok((addr, path.to_string()))
+}
+
+/// The child elements of the named child, if there is one.
+fn child_elements<'a>(xml: &'a Element, name: &str) -> impl Iterator<Item = &'a Element> {
+    xml.get_child(name)
+        .into_iter()
+        .flat_map(|child| child.children.iter().filter_map(|node| node.as_element()))
+}
+
+/// Find the SCPD URL and the control URL of the first connection service of the device.
+fn find_connection_service(device: &Element) -> Option<(String, String)> {
+    for service in child_elements(device, "serviceList") {
+        if service
+            .get_child("serviceType")
+            .and_then(|e| e.get_text())
+            .is_some_and(|t| CONNECTION_SERVICE_TYPES.contains(&t.trim()))
+        {
+            return Some((
+                text(service, "SCPDURL").ok()?,
+                text(service, "controlURL").ok()?,
+            ));
+        }
+    }
+
+    child_elements(device, "deviceList").find_map(find_connection_service)
+}
+
+/// Parse the input arguments of each action from a service description.
+fn parse_control_schema(description: &str) -> Result<HashMap<String, Vec<String>>> {
+    let xml = Element::parse(description.as_bytes())
+        .map_err(|_| UpnpError::InvalidResponse(description.to_string()))?;
+    if xml.get_child("actionList").is_none() {
+        return Err(UpnpError::InvalidResponse(
+            "actionList is missing".to_string(),
+        ));
+    }
+
+    let schema = child_elements(&xml, "actionList")
+        .filter_map(|action| {
+            let arguments = child_elements(action, "argumentList")
+                .filter(|argument| {
+                    text(argument, "direction").is_ok_and(|direction| direction.trim() == "in")
+                })
+                .filter_map(|argument| text(argument, "name").ok())
+                .collect();
+            Some((text(action, "name").ok()?, arguments))
+        })
+        .collect();
+
+    Ok(schema)
+}
+
+/// Build a gateway from the URL of its device description, without searching for it.
+pub(crate) fn gateway_from_url(url: &str) -> Result<Gateway> {
+    let (addr, root_url) = parse_gateway_url(url)?;
+
+    let description = get(format!("http://{addr}{root_url}"))?;
+    let xml = Element::parse(description.as_bytes())
+        .map_err(|_| UpnpError::InvalidResponse(description.clone()))?;
+    let (control_schema_url, control_url) = xml
+        .get_child("device")
+        .and_then(find_connection_service)
+        .ok_or_else(|| {
+            UpnpError::InvalidResponse("No WAN connection service in device description".into())
+        })?;
+
+    let control_schema = parse_control_schema(&get(format!("http://{addr}{control_schema_url}"))?)?;
+
+    Ok(Gateway {
+        addr,
+        root_url,
+        control_url,
+        control_schema_url,
+        control_schema,
+    })
+}
+
+/// What the device description of a gateway tells about the device itself.
+pub(crate) struct DeviceIdentity {
+    pub(crate) friendly_name: String,
+    pub(crate) manufacturer: Option<String>,
+    pub(crate) model_name: Option<String>,
+}
+
+/// Get the root device from the device description of the gateway.
+fn get_device(gateway: &Gateway) -> Result<Element> {
+    let description = get(format!("http://{}{}", gateway.addr, gateway.root_url))?;
+
+    let mut xml = Element::parse(description.as_bytes())
+        .map_err(|_| UpnpError::InvalidResponse(description.clone()))?;
+    xml.take_child("device")
+        .ok_or_else(|| UpnpError::InvalidResponse("device is missing".to_string()))
+}
+
+/// Get the friendly name of the gateway from its device description.
+pub(crate) fn get_friendly_name(gateway: &Gateway) -> Result<String> {
+    text(&get_device(gateway)?, "friendlyName")
+}
+
+/// Get the friendly name, manufacturer and model of the gateway from its device description.
+pub(crate) fn get_device_identity(gateway: &Gateway) -> Result<DeviceIdentity> {
+    let device = get_device(gateway)?;
+
+    Ok(DeviceIdentity {
+        friendly_name: text(&device, "friendlyName")?,
+        manufacturer: text(&device, "manufacturer").ok(),
+        model_name: text(&device, "modelName").ok(),
+    })
+}
+
+/// Get the maximum lease duration that the service description of the gateway allows, if any.
+pub(crate) fn get_max_lease_duration(gateway: &Gateway) -> Result<Option<u32>> {
+    let description = get(format!(
+        "http://{}{}",
+        gateway.addr, gateway.control_schema_url
+    ))?;
+
+    parse_max_lease_duration(&description)
+}
+
+pub(crate) fn parse_max_lease_duration(description: &str) -> Result<Option<u32>> {
+    let xml = Element::parse(description.as_bytes())
+        .map_err(|_| UpnpError::InvalidResponse(description.to_string()))?;
+
+    let maximum = xml
+        .get_child("serviceStateTable")
+        .into_iter()
+        .flat_map(|table| table.children.iter().filter_map(|node| node.as_element()))
+        .find(|variable| {
+            variable
+                .get_child("name")
+                .and_then(|e| e.get_text())
+                .is_some_and(|name| name.trim() == "PortMappingLeaseDuration")
+        })
+        .and_then(|variable| variable.get_child("allowedValueRange"))
+        .and_then(|range| range.get_child("maximum"))
+        .and_then(|e| e.get_text())
+        .and_then(|maximum| maximum.trim().parse().ok());
+
+    Ok(maximum)
+}
+
+fn remote_host_arg(remote_host: Option<Ipv4Addr>) -> String {
+    remote_host.map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+/// Add a port mapping that is only valid for the given remote host, or for all remote hosts.
+///
+/// [igd] always adds port mappings for all remote hosts, so this is done by hand.
+pub(crate) fn add_port_mapping(
+    gateway: &Gateway,
+    remote_host: Option<Ipv4Addr>,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+) -> Result<()> {
+    perform_request(
+        gateway,
+        "AddPortMapping",
+        &[
+            ("NewRemoteHost", remote_host_arg(remote_host)),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+            ("NewInternalPort", local_addr.port().to_string()),
+            ("NewInternalClient", local_addr.ip().to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", description.to_string()),
+            ("NewLeaseDuration", lease_duration.to_string()),
+        ],
+    )
+    .map(|_| ())
+}
+
+/// Delete a port mapping that is only valid for the given remote host, or for all remote hosts.
+pub(crate) fn delete_port_mapping(
+    gateway: &Gateway,
+    remote_host: Option<Ipv4Addr>,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> Result<()> {
+    perform_request(
+        gateway,
+        "DeletePortMapping",
+        &[
+            ("NewRemoteHost", remote_host_arg(remote_host)),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+        ],
+    )
+    .map(|_| ())
+}
+
+/// Get the port mapping for the given remote host, external port and protocol, if there is one.
+pub(crate) fn get_specific_port_mapping_entry(
+    gateway: &Gateway,
+    remote_host: Option<Ipv4Addr>,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> Result<Option<PortMappingEntry>> {
+    require_action(gateway, "GetSpecificPortMappingEntry")?;
+
+    let response = match perform_request(
+        gateway,
+        "GetSpecificPortMappingEntry",
+        &[
+            ("NewRemoteHost", remote_host_arg(remote_host)),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+        ],
+    ) {
+        Ok(response) => response,
+        Err(UpnpError::GatewayError(NO_SUCH_ENTRY_IN_ARRAY, _)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    Ok(Some(PortMappingEntry {
+        remote_host: remote_host_arg(remote_host),
+        external_port,
+        protocol,
+        internal_port: parse(&response, "NewInternalPort")?,
+        internal_client: text(&response, "NewInternalClient")?,
+        enabled: parse::<u8>(&response, "NewEnabled")? == 1,
+        description: text(&response, "NewPortMappingDescription")?,
+        lease_duration: parse(&response, "NewLeaseDuration")?,
+    }))
+}
+
+/// Add a port mapping on any free external port, and return that port.
+///
+/// Like [igd], gateways without the action `AddAnyPortMapping` are asked for random ports with
+/// `AddPortMapping` until one of them is free.
+pub(crate) fn add_any_port_mapping(
+    gateway: &Gateway,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+) -> Result<u16> {
+    if gateway.control_schema.contains_key("AddAnyPortMapping") {
+        let response = perform_request(
+            gateway,
+            "AddAnyPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", local_addr.port().to_string()),
+                ("NewProtocol", protocol.to_string()),
+                ("NewInternalPort", local_addr.port().to_string()),
+                ("NewInternalClient", local_addr.ip().to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", description.to_string()),
+                ("NewLeaseDuration", lease_duration.to_string()),
+            ],
+        )?;
+        return parse(&response, "NewReservedPort");
+    }
+
+    let mut result = Err(UpnpError::GatewayError(
+        CONFLICT_IN_MAPPING_ENTRY,
+        String::new(),
+    ));
+    for _ in 0..RANDOM_PORT_ATTEMPTS {
+        let external_port = random_port();
+        result = add_port_mapping(
+            gateway,
+            None,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .map(|()| external_port);
+        if !matches!(
+            result,
+            Err(UpnpError::GatewayError(CONFLICT_IN_MAPPING_ENTRY, _))
+        ) {
+            break;
+        }
+    }
+    result
+}
+
+/// How many random ports are tried by [add_any_port_mapping], like [igd] does.
+const RANDOM_PORT_ATTEMPTS: usize = 20;
+
+/// A random port from the range of dynamic ports.
+fn random_port() -> u16 {
+    32768 + (crate::random_u64() % 32768) as u16
+}
+
+/// Get the external IP address of the gateway.
+pub(crate) fn get_external_ip(gateway: &Gateway) -> Result<Ipv4Addr> {
+    let response = perform_request(gateway, "GetExternalIPAddress", &[])?;
+    parse(&response, "NewExternalIPAddress")
+}
+
+/// Get the port mapping at the given index of the table, or [None] past the end of the table.
+pub(crate) fn get_generic_port_mapping_entry(
+    gateway: &Gateway,
+    index: u32,
+) -> Result<Option<PortMappingEntry>> {
+    let response = match perform_request(
+        gateway,
+        "GetGenericPortMappingEntry",
+        &[("NewPortMappingIndex", index.to_string())],
+    ) {
+        Ok(response) => response,
+        Err(UpnpError::GatewayError(SPECIFIED_ARRAY_INDEX_INVALID, _)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let protocol = match text(&response, "NewProtocol")?.trim() {
+        "TCP" => PortMappingProtocol::TCP,
+        "UDP" => PortMappingProtocol::UDP,
+        _ => {
+            return Err(UpnpError::InvalidResponse(
+                "NewProtocol is invalid".to_string(),
+            ))
+        }
+    };
+
+    Ok(Some(PortMappingEntry {
+        remote_host: text(&response, "NewRemoteHost")?,
+        external_port: parse(&response, "NewExternalPort")?,
+        protocol,
+        internal_port: parse(&response, "NewInternalPort")?,
+        internal_client: text(&response, "NewInternalClient")?,
+        enabled: parse::<u8>(&response, "NewEnabled")? == 1,
+        description: text(&response, "NewPortMappingDescription")?,
+        lease_duration: parse(&response, "NewLeaseDuration")?,
+    }))
+}
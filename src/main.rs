@@ -30,7 +30,10 @@
 //! and send them to your router. The main usage will be that you start it once
 //! and let it run as a background service forever. The file with the port
 //! mappings will be newly read in on each iteration, so you can add new mappings
-//! on the fly.
+//! on the fly. If the file cannot be read, for example because it does not exist
+//! yet or is being rewritten by another process, the error is logged and the
+//! file is tried again on the next iteration. Only in oneshot mode, such an error
+//! ends the program unsuccessfully.
 //!
 //! ## Installation
 //!
@@ -55,20 +58,82 @@
 //! ## Usage
 //!
 //! ```text
-//! Usage: upnp-daemon [OPTIONS] --file <FILE>
+//! Usage: upnp-daemon [OPTIONS]
+//!        upnp-daemon [OPTIONS] <COMMAND>
+//!
+//! Commands:
+//!   probe       Check whether a UPnP gateway is available, without opening any ports
+//!   remove-all  Remove all port mappings of the gateway, regardless of any configuration
+//!   renew       Renew the lease of a single port mapping once, or create it if it does not exist
+//!   export      Write the current port mappings of the gateway to a config file
+//!   help        Print this message or the help of the given subcommand(s)
 //!
 //! Options:
-//!   -f, --file <FILE>                    The file (or "-" for stdin) with the port descriptions
-//!       --format <FORMAT>                The format of the configuration file [default: csv] [possible values: csv, json]
-//!   -d, --csv-delimiter <CSV_DELIMITER>  Field delimiter when using CSV files [default: ;]
-//!   -F, --foreground                     Run in foreground instead of forking to background
-//!   -1, --oneshot                        Run just one time instead of continuously
-//!   -n, --interval <INTERVAL>            Specify update interval in seconds [default: 60]
-//!       --close-ports-on-exit            Close specified ports on program exit
-//!       --only-close-ports               Only close specified ports and exit
-//!       --pid-file <PID_FILE>            Absolute path to PID file for daemon mode [default: /tmp/upnp-daemon.pid]
-//!   -h, --help                           Print help
-//!   -V, --version                        Print version
+//!   -f, --file <FILE>                     The file (or "-" for stdin) with the port descriptions, can be given multiple times
+//!       --from-env                        Read one more port mapping from the UPNP_* environment variables
+//!       --format <FORMAT>                 The format of the configuration files, once for all or once per file [default: by file extension, or auto] [possible values: auto, csv, json, yaml, toml]
+//!   -d, --csv-delimiter <CSV_DELIMITER>   Field delimiter when using CSV files [default: ;]
+//!       --no-header                       Treat the first line of CSV files as data instead of a header
+//!       --csv-columns <SPEC>              Comma separated names of the columns in CSV files without a header [possible values: address, port, protocol, duration, comment, backend, priority, remote_host, any_port, internal_client, requires_process, internal_hostname, retries, timeout, gateway]
+//!       --protocol-backend <BACKEND>      The backend for port mappings that do not specify one [default: upnp] [possible values: upnp, natpmp]
+//!   -F, --foreground                      Run in foreground instead of forking to background
+//!   -1, --oneshot                         Run just one time instead of continuously
+//!   -n, --interval <INTERVAL>             Specify update interval in seconds [default: 60]
+//!       --cron <EXPR>                     Run at the times of this cron expression instead of every interval, like "*/5 * * * *"
+//!       --filter-comment <PATTERN>        Only process port mappings whose comment matches the given pattern
+//!       --select <SPEC>                   Comma separated positions like "2,4-6", or "comment:PATTERN", to only process those entries
+//!       --lenient-json                    Ignore content after the array in JSON files, instead of rejecting the whole file
+//!       --dhcp-leases <FILE>              The dnsmasq lease file to look up the hostnames of internal clients in [default: /var/lib/misc/dnsmasq.leases]
+//!       --comment-prefix <PREFIX>         Prefix for the comments of all port mappings, like the name of this host
+//!       --description-template <FORMAT>   Template for the descriptions on the router, with {comment}, {date}, {host} and {version}
+//!       --duration-override <SECONDS>     Use this lease duration in seconds for all port mappings, instead of the configured ones
+//!       --protocols <PROTOCOLS>           Comma separated protocols to handle, port mappings of other protocols are skipped [default: tcp udp] [possible values: tcp, udp]
+//!       --allowed-ports <RANGE>           Comma separated ports or port ranges like "30000-40000", to refuse all other external ports
+//!       --startup-delay <SECONDS>         Wait the given number of seconds before the first iteration [default: 0]
+//!       --renew-threshold <PERCENT>       Only refresh port mappings whose remaining lease is below this percentage of the duration
+//!       --refresh-once                    Only add each port mapping once, until it changes or vanishes from the router
+//!       --once-per-change                 Only add port mappings if the configuration has changed, or if the leases need renewal
+//!       --renew-interval <SECONDS>        Renew unchanged port mappings after this many seconds, instead of half the shortest lease
+//!       --ssdp-bind-port <PORT>           Local port for the discovery of gateways, 0 for a random port [default: 0]
+//!       --ssdp-address <ADDR>             Send the discovery of gateways to this address, instead of the SSDP multicast address
+//!       --gateway-url <URL>               Use the gateway with this device description URL, without any discovery
+//!       --interface-priority <IFACES>     Comma separated interface names or address ranges, to try those interfaces first
+//!       --exclude-interfaces <IFACES>     Comma separated interface names or address ranges, to never try those interfaces
+//!       --match-policy <POLICY>           The order of interfaces with the same priority that match the address of a port mapping [default: name] [possible values: name, lowest-ip]
+//!       --discovery-timeout <SECONDS>     Seconds to wait for a gateway to answer the discovery [default: 10]
+//!       --discovery-retries <COUNT>       How often to repeat the discovery on an interface if no gateway answers in time [default: 2]
+//!       --debug-search-options            Log the options of each discovery at debug level, for bug reports
+//!       --bind-ip <IP>                    The local address to search for gateways from, if an interface has it
+//!       --gateway-name <NAME>             Only use gateways whose friendly name contains this string
+//!       --protect-port <PORTS>            Comma separated ports whose existing port mappings are never deleted to make room
+//!       --prefer-family <FAMILY>          The address family of the interfaces to try first, only IPv4 is supported so far [default: v4] [possible values: v4, v6]
+//!       --record-soap <FILE>              Append all requests to the gateway and their responses to this file, for debugging
+//!       --log-format <LOG_FORMAT>         The format of the log output [default: text] [possible values: text, json]
+//!       --metrics-textfile <FILE>         Write metrics for the textfile collector of the Prometheus node_exporter to this file
+//!       --summary                         Log one summary line per iteration instead of one line per port mapping
+//!       --report-outcomes                 Look up each port mapping before adding it, to log whether it has been created, refreshed or left unchanged
+//!       --log-dedup-window <SECONDS>      Log repeated identical errors of a port mapping only once in this many seconds, 0 to log all [default: 600]
+//!       --detect-foreign-changes          Warn if the description of a created port mapping has been changed by another client
+//!       --distinct-protocol-ports <MODE>  Check whether the router keeps TCP and UDP mappings of the same port apart [possible values: warn, stagger]
+//!       --respect-manual-removal          Do not add created port mappings again right away if they have been removed on the router
+//!       --removal-cooldown <SECONDS>      Seconds to wait before adding a manually removed port mapping again [default: 3600]
+//!       --require-gateway                 Exit right away if no gateway can be found at startup, instead of retrying each iteration
+//!       --prefetch                        Find the gateway and its external IP once at startup, and reuse them in the first iteration
+//!       --strict                          Skip port mappings with a lease duration shorter than the interval, instead of warning
+//!       --interactive                     Ask before replacing a port mapping of another client, skip it without a terminal
+//!       --on-add <CMD>                    Command to run after each successful addition of a port mapping
+//!       --on-remove <CMD>                 Command to run after each successful removal of a port mapping
+//!       --close-ports-on-exit             Close specified ports on program exit
+//!       --verify-removal                  Check that closed ports are really gone, and try once more if they are not
+//!       --only-close-ports                Only close specified ports and exit
+//!       --stream-stdin                    Apply the port mappings from stdin line by line as they arrive, instead of reading it whole
+//!       --dry-run                         Only show what would be done for each port mapping, without changing anything
+//!       --plan-format <PLAN_FORMAT>       The format of the dry run output [default: text] [possible values: text, json]
+//!       --pid-file <PID_FILE>             Absolute path to PID file for daemon mode [default: /tmp/upnp-daemon.pid]
+//!       --working-dir <DIR>               Working directory for daemon mode, instead of the root directory
+//!       --umask <MASK>                    Octal file mode creation mask for daemon mode, instead of 027
+//!   -h, --help                            Print help
+//!   -V, --version                         Print version
 //! ```
 //!
 //! In the most basic case, a call might look like so:
@@ -100,12 +165,30 @@
 //! beforehand. Also, of course, the user running the application needs to have
 //! write permission to the folder.
 //!
+//! Before starting, the daemon checks whether the PID file already exists. If the
+//! process it names is still running and is an upnp-daemon, the daemon refuses to
+//! start a second time and exits with code 5. If the process is gone, for example
+//! because the daemon has crashed, or its PID now belongs to another program, the
+//! stale PID file is removed and the daemon starts as usual.
+//!
+//! After forking to the background, the daemon changes its working directory to
+//! the root directory and sets its file mode creation mask to `027`. If you rely
+//! on relative paths or on specific permissions of created files, you can change
+//! this with the `--working-dir` and `--umask` options:
+//!
+//! ```shell script
+//! upnp-daemon --working-dir /var/lib/upnp-daemon --umask 022 --file ports.csv
+//! ```
+//!
+//! The working directory needs to exist beforehand. The mask is given in octal
+//! notation. Both options have no effect in foreground mode.
+//!
 //! **A note to Windows users:** The `daemonize` library that is used to send this
 //! program to the background, does only work on Unix like systems. You can still
 //! install and use the program on Windows, but it will behave as if you started
 //! it with the `--foreground` option (see [below](#foreground-operation)).
-//! Therefore, you will also not see the `--pid-file` option on Windows since it
-//! has no use there.
+//! Therefore, you will also not see the `--pid-file`, `--working-dir` and
+//! `--umask` options on Windows since they have no use there.
 //!
 //! ### Reading from standard input
 //!
@@ -128,6 +211,61 @@
 //! upnp-daemon --file ./-
 //! ```
 //!
+//! Normally, stdin is read completely before the first iteration, so that it can
+//! be read again on each iteration. If the generator runs continuously and emits
+//! new port mappings over time, use the `stream-stdin` flag instead:
+//!
+//! ```shell script
+//! generate-configuration | upnp-daemon --stream-stdin --file -
+//! ```
+//!
+//! In this mode, each line is applied as soon as it arrives, and only once. The
+//! input can be CSV, with a header line unless `--no-header` is given, or JSON
+//! Lines, with one JSON object per line. The usual JSON format, with all port
+//! mappings in one array, is not supported, and neither are YAML and TOML. Since
+//! nothing is read again, the leases are not renewed on each iteration: either
+//! use a duration of 0, or let the generator repeat the line to renew it. The
+//! program always runs in the foreground and ends at the end of the input or when
+//! it is interrupted, which is when `--close-ports-on-exit` takes effect. It
+//! cannot be combined with other files or with `--once-per-change`.
+//!
+//! ### Reading from a Named Pipe
+//!
+//! On Unix like systems, the file can also be a named pipe (FIFO), so that
+//! another program can push new configurations to a running daemon:
+//!
+//! ```shell script
+//! mkfifo /run/upnp-daemon.csv
+//! upnp-daemon --file /run/upnp-daemon.csv
+//! generate-configuration > /run/upnp-daemon.csv
+//! ```
+//!
+//! At startup, the program waits for the first configuration to be written to
+//! the pipe. Each write, from opening the pipe until closing it again, is one
+//! complete configuration, which replaces the previous one and is applied right
+//! away. Between writes, the last configuration is applied on each iteration as
+//! usual, so the interval still drives the renewal of the leases. The pipe can be
+//! combined with other files, and empty writes are ignored.
+//!
+//! ### Reading from Environment Variables
+//!
+//! For a single port mapping, for example in a minimal container, no file is
+//! needed at all. With the `--from-env` flag, the port mapping is read from
+//! environment variables, named after the [fields](#fields) in upper case with
+//! the prefix `UPNP_`:
+//!
+//! ```shell script
+//! UPNP_PORT=8080 UPNP_PROTOCOL=TCP UPNP_DURATION=3600 UPNP_COMMENT=Web \
+//!     upnp-daemon --from-env
+//! ```
+//!
+//! `UPNP_PORT` and `UPNP_PROTOCOL` are required, all other variables are
+//! optional, like `UPNP_ADDRESS` for the interface to use. The values are given
+//! just like in a [CSV](#csv) file. If a required variable is missing or a value
+//! is invalid, the program exits with an error before it goes to the background.
+//! The flag can be combined with `--file`, the port mapping from the environment
+//! is then added to the ones from the files.
+//!
 //! ### Foreground Operation
 //!
 //! Some service monitors expect services to start in the foreground, so they can
@@ -139,13 +277,31 @@
 //! ```
 //!
 //! This will leave the program running in the foreground. You can terminate it by
-//! issuing a `SIGINT` (Ctrl-C), for example.
+//! issuing a `SIGINT` (Ctrl-C), for example. If the shutdown takes too long, for
+//! example while closing ports on an unresponsive router, a second `SIGINT` ends
+//! the program immediately, with exit code 1 and without any further cleanup.
 //!
 //! **A note to Windows users:** This option flag does not exist in the Windows
 //! version of this program. Instead, foreground operation is the default
 //! operation mode, since due to technical limitations, it cannot be sent to the
 //! background there.
 //!
+//! ### Pausing
+//!
+//! On Unix systems, the daemon can be paused without stopping it, for example
+//! while the router is under maintenance. Send it the `SIGUSR1` signal to pause
+//! it:
+//!
+//! ```shell script
+//! kill -USR1 $(</tmp/upnp-daemon.pid)
+//! ```
+//!
+//! While paused, the daemon does not touch the router at all, and only logs
+//! that it is paused on each iteration. Everything it remembers, like the port
+//! mappings it created, is kept. Sending `SIGUSR1` again resumes it, starting
+//! with the next iteration. Closing the ports on exit is not affected by the
+//! pause.
+//!
 //! ### Oneshot Mode
 //!
 //! If you just want to test your configuration, without letting the daemon run
@@ -159,6 +315,60 @@
 //! know when the process has finished, which could take some time, depending on
 //! the size of the mapping file.
 //!
+//! Please note that the port mappings are not temporary: they stay open after
+//! the program has finished, until their lease expires. If you only want to
+//! forward ports for as long as the program runs, leave off `oneshot` and use
+//! `--close-ports-on-exit` instead, then stop the daemon when you are done.
+//!
+//! ### Requiring a Gateway
+//!
+//! By default, the daemon tolerates a missing router, and simply tries again in
+//! the next iteration. If running without a reachable router is a
+//! misconfiguration in your setup, use the `--require-gateway` flag:
+//!
+//! ```shell script
+//! upnp-daemon --require-gateway --file ports.csv
+//! ```
+//!
+//! The daemon then searches for a gateway once at startup, on any interface and
+//! with the given discovery options. If none is found, it exits right away with
+//! exit code 3, before going to the background. Once it runs, later failures are
+//! tolerated as before.
+//!
+//! ### Prefetching the Gateway
+//!
+//! Each port mapping searches for its gateway on its own. To find the gateway and
+//! its external IP address only once at startup, and reuse them for all port
+//! mappings of the first iteration, use the `--prefetch` flag:
+//!
+//! ```shell script
+//! upnp-daemon --oneshot --prefetch --file ports.csv
+//! ```
+//!
+//! This makes the first iteration faster, especially with `--oneshot`, and logs
+//! connectivity problems right away. Later iterations search for the gateway
+//! again, in case it changed in the meantime. If the port mappings have different
+//! addresses, or several address candidates, they might need different gateways,
+//! so nothing is prefetched then.
+//!
+//! ### Exit Codes
+//!
+//! For the use in scripts, upnp-daemon reports the kind of failure in its exit
+//! code:
+//!
+//! | Code | Meaning                                                                    |
+//! |------|----------------------------------------------------------------------------|
+//! | 0    | Success                                                                    |
+//! | 1    | Any other failure, for example an aborted `remove-all`                     |
+//! | 2    | Invalid command line arguments, or config files that cannot be read        |
+//! | 3    | No router found, by `probe`, `remove-all`, `export` or `--require-gateway` |
+//! | 4    | Some port mappings failed, in oneshot mode, `dry-run` or `remove-all`      |
+//! | 5    | Another daemon is still running with the same `--pid-file`                 |
+//!
+//! Please note that without `--foreground`, the program exits with 0 as soon as
+//! the daemon has been started, so apart from code 5, the exit codes are only
+//! meaningful in the foreground. In daemon mode, errors are only logged.
+//!
 //! ### Closing Ports
 //!
 //! If you want to close your opened ports when the program exits, you can use the
@@ -184,390 +394,4299 @@
 //! The `foreground` flag here is optional, but it is useful if you need to know
 //! when all ports have been closed, since the program only terminates then.
 //!
-//! ### Logging
+//! Some routers confirm the removal of a port mapping, but keep it anyway. With
+//! the `--verify-removal` flag, the router is asked for each port mapping after
+//! closing it. If it still exists, a warning is logged and the port is closed
+//! once more. If even that does not help, this is logged like other errors while
+//! closing ports:
 //!
-//! If you want to activate logging to have a better understanding what the
-//! program does under the hood, you need to set the environment variable
-//! `RUST_LOG`, like so:
+//! ```shell script
+//! upnp-daemon --close-ports-on-exit --verify-removal --file ports.csv
+//! ```
+//!
+//! ### Cron Schedule
+//!
+//! Instead of waiting a fixed interval between the iterations, the daemon can run
+//! at the times of a cron expression, for example every 5 minutes on the minute:
 //!
 //! ```shell script
-//! RUST_LOG=info upnp-daemon --foreground --file ports.csv
+//! upnp-daemon --cron "*/5 * * * *" --file ports.csv
 //! ```
 //!
-//! To make the logger even more verbose, try to set the log level to `debug`:
+//! This aligns the refreshes to clock boundaries and across machines. The first
+//! iteration still runs right away, and `--interval` is ignored. Besides the five
+//! fields of a classic crontab, a leading field for the seconds and a trailing
+//! field for the years may be given, see the [cron] crate for the syntax. With five
+//! fields, the days of the week are numbered like in a crontab, from 0 for Sunday
+//! to 6 for Saturday, and 7 for Sunday again. Otherwise, they are numbered like in
+//! the cron crate, from 1 for Sunday to 7 for Saturday. The longest time between
+//! two runs stands in for the interval where it matters, for example for the
+//! automatic lease duration. An invalid expression is rejected at startup.
+//!
+//! [cron]: https://docs.rs/cron
+//!
+//! ### Startup Delay
+//!
+//! When started at boot time, the network might not be up yet, so the first
+//! iteration would fail. To give the interfaces some time to get their
+//! addresses, you can delay the first iteration, like so:
 //!
 //! ```shell script
-//! RUST_LOG=debug upnp-daemon --foreground --file ports.csv
+//! upnp-daemon --startup-delay 30 --file ports.csv
 //! ```
 //!
-//! Please note that it does not make sense to activate logging without using
-//! `foreground`, since the output (stdout as well as stderr) will not be saved in
-//! daemon mode. This might change in a future release.
+//! The program can still be terminated during the delay, in which case it exits
+//! immediately without touching any port mappings.
 //!
-//! ## Config File Format
+//! ### Renewing Only Expiring Leases
 //!
-//! The config file can be given as either CSV (default for now) or JSON (with
-//! `--format json`). The names and contents of the fields are always the same.
+//! By default, every port mapping is sent to the router on each iteration. On
+//! big tables, where most leases are still valid, this causes a lot of
+//! unnecessary traffic. With the `--renew-threshold` option, a port mapping will
+//! only be refreshed if its remaining lease, as reported by the router, dropped
+//! below the given percentage of its duration:
 //!
-//! ### CSV
+//! ```shell script
+//! upnp-daemon --renew-threshold 50 --file ports.csv
+//! ```
 //!
-//! The format of the port mapping file is a simple CSV file, like the following
-//! example:
+//! In this example, a port mapping with a duration of 3600 seconds will be
+//! skipped as long as the router reports more than 1800 seconds of remaining
+//! lease time. If the remaining lease cannot be read, for example because the
+//! mapping does not exist (anymore), it forwards to another address, or the
+//! router does not support the query, the port mapping will be refreshed as
+//! usual. The same is true for permanent port mappings.
 //!
-//! ```text
-//! address;port;protocol;duration;comment
-//! 192.168.0.10;12345;UDP;60;Test 1
-//! ;12346;TCP;60;Test 2
+//! If your router honors long or permanent leases, even checking them on each
+//! iteration might be more than needed. With the `--refresh-once` flag, each port
+//! mapping is only added once, and then left alone:
+//!
+//! ```shell script
+//! upnp-daemon --refresh-once --file ports.csv
 //! ```
 //!
-//! Please note that the first line is mandatory at the moment, it is needed to
-//! accurately map the fields to the internal options.
+//! On each iteration, the program lists the port mappings of the router and adds
+//! those again that have vanished from it. If a port mapping is changed in the
+//! configuration, it is added again as well. Port mappings that cannot be
+//! listed, like those via NAT-PMP or with `any_port`, are refreshed as usual.
+//! Together with `--respect-manual-removal`, vanished port mappings are not added
+//! again until the cooldown has passed.
 //!
-//! With the `--csv-delimiter` option, you can choose an arbitrary character to be
-//! used as a field delimiter in your CSV file. By default, we use the semicolon,
-//! but if you instead prefer a usual comma, you can just say so with
-//! `--csv-delimiter ','`.
+//! ### Applying Only Changes
 //!
-//! Please be aware that your shell might interpret the delimiter (for example,
-//! the semicolon is used in bash to separate two commands), so be sure to
-//! correctly escape it.
+//! By default, the daemon re-adds all port mappings on each iteration. For a
+//! configuration that rarely changes, this causes a lot of log output and
+//! traffic to the router. With the `--once-per-change` flag, the port mappings
+//! are only added if the configuration has changed since the last iteration:
 //!
-//! ### JSON
+//! ```shell script
+//! upnp-daemon --once-per-change --file ports.csv
+//! ```
 //!
-//! A config file in JSON format with the above contents could look like this:
+//! The configuration is still read on every iteration, so changes are picked up
+//! as quickly as before. If an address is given as a command, a changed output of
+//! the command counts as a change, too. If not all ports could be opened, the
+//! port mappings will be added again in the next iteration.
 //!
-//! ```json
-//! [
-//!   {
-//!     "address": "192.168.0.10",
-//!     "port": 12345,
-//!     "protocol": "UDP",
-//!     "duration": 60,
-//!     "comment": "Test 1"
-//!   },
-//!   {
-//!     "address": null,
-//!     "port": 12346,
-//!     "protocol": "TCP",
-//!     "duration": 60,
-//!     "comment": "Test 2"
-//!   }
-//! ]
+//! To keep the port mappings from expiring, unchanged port mappings are renewed
+//! after half of the shortest lease duration by default. You can choose another
+//! renewal interval in seconds with `--renew-interval`:
+//!
+//! ```shell script
+//! upnp-daemon --once-per-change --renew-interval 1800 --file ports.csv
 //! ```
 //!
-//! The line breaks and indentations are just for readability, you can format it
-//! any way you like. You can even go as far and feed in a single line, as long as
-//! it is a valid JSON array with all required fields in it.
+//! All waiting is measured on a monotonic clock, so a change of the system time
+//! does not shorten or prolong the interval. If the system clock jumps by more
+//! than 30 seconds between two iterations, for example after a suspend or a
+//! correction by NTP, all port mappings are added again in the next iteration,
+//! because the leases on the router might have expired in the meantime.
 //!
-//! Since `address` is `null` in the second entry, it can also be left out
-//! completely if you prefer. Also, any key that is not documented below will be
-//! silently ignored, so you might use them as internal comments for yourself. So
-//! a config might also look like:
+//! ### Detecting Foreign Changes
 //!
-//! ```json
-//! [
-//!   {
-//!     "rationale": "This port is needed for an awesome application!",
-//!     "may-be-deleted": false,
-//!     "port": 12347,
-//!     "protocol": "TCP",
-//!     "duration": 60,
-//!     "comment": "Test 3"
-//!   }
-//! ]
+//! Other devices in your network might modify port mappings that have been
+//! created by upnp-daemon. With the `--detect-foreign-changes` flag, the daemon
+//! remembers the port mappings it created and compares their description on the
+//! router with the expected comment on each iteration:
+//!
+//! ```shell script
+//! upnp-daemon --detect-foreign-changes --file ports.csv
 //! ```
 //!
-//! The keys `rationale` and `may-be-deleted` will be ignored by the daemon.
+//! If the description differs, a warning will be logged, indicating that another
+//! client touched the port mapping. Please note that the daemon will still
+//! re-add its own port mapping afterwards, as usual.
 //!
-//! Also, please note that even if you want to add just one port mapping, you need
-//! to specify a JSON array.
+//! ### Respecting Manual Removal
 //!
-//! ### Fields
+//! If you remove a port mapping in the web interface of your router, the daemon
+//! would add it again in the next iteration. To be able to intervene by hand, use
+//! the `--respect-manual-removal` flag:
 //!
-//! -   address
+//! ```shell script
+//! upnp-daemon --respect-manual-removal --removal-cooldown 600 --file ports.csv
+//! ```
 //!
-//!     The IP address for which the port mapping should be added. This field can
-//!     be empty, in which case every connected interface will be tried, until one
-//!     gateway reports success. Useful if the IP address is dynamic and not
-//!     consistent over reboots.
+//! The daemon then remembers the port mappings it created, and checks on each
+//! iteration whether they are still present on the router. If one is gone, this
+//! is logged, and the port mapping is only added again after the cooldown, which
+//! is one hour by default. Please note that a port mapping whose lease expired
+//! before it has been renewed looks the same as a removed one, so make sure that
+//! the duration is longer than the interval. Like foreign changes, this is not
+//! supported for NAT-PMP and for port mappings with `any_port`.
 //!
-//!     Fill in an IP address if you want to add a port mapping for a foreign
-//!     device, or if you know your machine's address and want to slightly speed
-//!     up the process.
+//! ### Confirming Replacements
 //!
-//!     You can also enter the IP address in CIDR notation. In that case, the IP
-//!     range is checked against all connected interfaces and only matching ones
-//!     are considered. This is useful if you don't know your current IP address
-//!     (or it might change from time to time), but you know the DHCP
-//!     configuration of your router.
+//! Since the daemon is greedy, a port mapping of another client on the same port
+//! is deleted and replaced. To be asked before that happens, for example while
+//! setting up a new configuration, use the `--interactive` flag:
 //!
-//!     Such an IP address might be `192.168.0.10` or `192.168.0.0/24` or even
-//!     `192.168.0`.
+//! ```shell script
+//! upnp-daemon --foreground --interactive --file ports.csv
+//! ```
 //!
-//!     More examples can be found in the responsible library's documentation:
-//!     <https://docs.rs/cidr-utils/0.5.10/cidr_utils/index.html>
+//! If you decline, the port mapping is left alone for as long as the daemon runs.
+//! Asking is only possible in the foreground, and only if the configuration is not
+//! read from stdin. Otherwise, the flag makes the daemon skip port mappings of
+//! other clients, and checks again on each iteration.
 //!
-//! -   port
+//! ### Protecting Ports
 //!
-//!     The port number to open for the given IP address. Note that upnp-daemon is
-//!     greedy at the moment, if a port mapping is already in place, it will be
-//!     deleted and re-added with the given IP address. This might be configurable
-//!     in a future release.
+//! If you mix port mappings of the daemon with port mappings that you configured
+//! by hand on the same router, you can protect the latter from being replaced with
+//! `--protect-port`. It takes a comma separated list and can be given multiple
+//! times:
 //!
-//! -   protocol
+//! ```shell script
+//! upnp-daemon --protect-port 22,443 --file ports.csv
+//! ```
 //!
-//!     The protocol for which the given port will be opened. Possible values are
-//!     `UDP` and `TCP`.
+//! If one of these ports is already in use by another port mapping, the existing
+//! port mapping is kept and an error is logged. Unlike `--interactive`, this only
+//! affects the listed ports, all other ports are still replaced as usual.
 //!
-//! -   duration
+//! ### Routers Conflating Protocols
 //!
-//!     The lease duration for the port mapping in seconds. Please note that some
-//!     UPnP capable routers might choose to ignore this value, so do not
-//!     exclusively rely on this.
+//! TCP and UDP ports are independent, so there can be a TCP and a UDP port
+//! mapping with the same port number. Some routers mix them up, though, and
+//! replace one of them with the other. To detect this, let upnp-daemon list the
+//! port mappings of the router after adding both of them:
 //!
-//! -   comment
+//! ```shell script
+//! upnp-daemon --distinct-protocol-ports warn --file ports.csv
+//! ```
 //!
-//!     A comment about the reason for the port mapping. Will be stored together
-//!     with the mapping in the router.
-
-use std::error::Error;
-use std::fs::File;
-use std::io::{stdin, BufReader, BufWriter, Seek};
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, RecvTimeoutError};
-use std::time::Duration;
-
-use anyhow::anyhow;
-use clap::{
-    builder::{PathBufValueParser, TypedValueParser},
-    Parser, ValueEnum,
-};
-use csv::Reader;
-#[cfg(unix)]
-use daemonize::Daemonize;
-use log::error;
-use serde_json::Value;
-use tempfile::tempfile;
-
-use easy_upnp::UpnpConfig;
-
-#[derive(Clone)]
-enum CliInput {
-    File(PathBuf),
-    Stdin,
-}
-
-impl TryFrom<PathBuf> for CliInput {
-    type Error = std::io::Error;
-
-    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
-        Ok(if path == PathBuf::from("-") {
-            CliInput::Stdin
-        } else {
-            CliInput::File(path.canonicalize()?)
-        })
-    }
-}
-
-enum Input {
-    File(File),
-    PathBuf(PathBuf),
-}
-
-impl TryFrom<CliInput> for Input {
-    type Error = std::io::Error;
-
-    fn try_from(cli_input: CliInput) -> Result<Self, Self::Error> {
-        Ok(match cli_input {
-            CliInput::File(pathbuf) => Self::PathBuf(pathbuf),
-            CliInput::Stdin => {
-                // Write contents of stdin to temporary file, so we can read it multiple times.
-                let tempfile = tempfile()?;
-                {
-                    let mut reader = BufReader::new(stdin());
-                    let mut writer = BufWriter::new(&tempfile);
-                    std::io::copy(&mut reader, &mut writer)?;
-                }
-                Self::File(tempfile)
-            }
-        })
-    }
-}
-
-fn get_csv_reader(input: &Input, delim: char) -> Result<Reader<File>, std::io::Error> {
-    let mut builder = csv::ReaderBuilder::new();
-    let reader_builder = builder.delimiter(delim as u8);
-
-    Ok(match input {
-        Input::File(file) => {
-            // Clone file handle, so we don't move the original handle away.
-            let mut file = file.try_clone()?;
-
-            // File may have been advanced in previous iteration, so rewind it first.
-            file.rewind()?;
-            reader_builder.from_reader(file)
-        }
-        Input::PathBuf(pathbuf) => reader_builder.from_path(pathbuf)?,
-    })
-}
-
-fn get_configs_from_csv_reader(
-    reader: &mut Reader<File>,
-) -> impl Iterator<Item = anyhow::Result<UpnpConfig>> + '_ {
-    reader
-        .deserialize()
-        .map(|result| result.map_err(anyhow::Error::from))
-}
-
-fn get_configs_from_json(
-    input: &Input,
-) -> anyhow::Result<impl Iterator<Item = anyhow::Result<UpnpConfig>> + '_> {
-    let file = match input {
-        Input::File(file) => {
-            // Clone file handle, so we don't move the original handle away.
-            let mut file = file.try_clone()?;
-
-            // File may have been advanced in previous iteration, so rewind it first.
-            file.rewind()?;
-            file
-        }
-        Input::PathBuf(pathbuf) => File::open(pathbuf)?,
-    };
-
-    let v: Value = serde_json::from_reader(file)?;
-
-    if !v.is_array() {
-        return Err(anyhow!("Input is not a JSON array"));
-    }
-
-    Ok(if let Value::Array(v) = v {
-        v.into_iter()
-            .map(|v| serde_json::from_value::<UpnpConfig>(v).map_err(anyhow::Error::from))
-    } else {
-        unreachable!()
-    })
-}
-
-fn filter_out_and_log_errors(result: anyhow::Result<UpnpConfig>) -> Option<UpnpConfig> {
-    result
-        .map_err(|err| {
-            error!("{}", err);
-            err
-        })
-        .ok()
-}
-
-fn add_ports(configs: impl IntoIterator<Item = UpnpConfig>) {
-    for result in easy_upnp::add_ports(configs) {
-        if let Err(err) = result {
-            error!("{}", err);
-        }
-    }
-}
-
-fn delete_ports(configs: impl IntoIterator<Item = UpnpConfig>) {
-    for result in easy_upnp::delete_ports(configs) {
-        if let Err(err) = result {
-            error!("{}", err);
-        }
-    }
-}
-
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
-enum CliInputFormat {
-    Csv,
-    Json,
-}
-
+//! If one of them has been replaced, a warning is logged and the port mapping
+//! counts as failed. With `stagger` instead of `warn`, the replaced port mapping
+//! is added again on another external port, which the router chooses, like with
+//! the `any_port` field. This is logged as well, and the port mapping stays on
+//! its new port for as long as the daemon runs. Port mappings with a remote host
+//! cannot be staggered. Without this option, the protocols are assumed to be
+//! independent, as the standard requires.
+//!
+//! ### Filtering by Comment
+//!
+//! If one configuration file holds mappings for several logical groups, you can
+//! restrict a run to the mappings whose comment matches a pattern, like so:
+//!
+//! ```shell script
+//! upnp-daemon --filter-comment 'torrent-*' --file ports.csv
+//! ```
+//!
+//! If the pattern contains one of the wildcards `*` (any number of characters)
+//! or `?` (exactly one character), it has to match the whole comment. Otherwise,
+//! it is enough for the comment to contain the pattern.
+//!
+//! The filter applies to opening as well as closing ports. This means that
+//! `--close-ports-on-exit` and `--only-close-ports` will close only the matching
+//! mappings, so you can run multiple instances with different filters on the
+//! same file and manage each group independently:
+//!
+//! ```shell script
+//! upnp-daemon --foreground --only-close-ports --filter-comment 'torrent-*' --file ports.csv
+//! ```
+//!
+//! ### Selecting Entries
+//!
+//! To re-apply or close just one service from a large file, without editing it,
+//! select the entries to process by their position, counted from 1 in the order
+//! of the files. Entries that cannot be parsed count as well, so that the
+//! positions do not shift:
+//!
+//! ```shell script
+//! upnp-daemon --select 2,4-6 --file ports.csv
+//! ```
+//!
+//! Entries can also be selected by their comment, with the same patterns as for
+//! `--filter-comment`, like `--select comment:Webserver`. Such a pattern is taken
+//! as a whole, commas included. Positions and comments can be mixed by giving
+//! `--select` several times, an entry is processed if any of them selects it.
+//! Positions out of range and comments that match no entry are logged as a
+//! warning, once. Like the comment filter, the selection applies to opening as
+//! well as closing ports. It cannot be combined with `--stream-stdin`.
+//!
+//! ### Prefixing Comments
+//!
+//! If several daemons share a router, it helps to see which host created which
+//! port mapping. With `--comment-prefix`, the given prefix is prepended to the
+//! comment of every port mapping:
+//!
+//! ```shell script
+//! upnp-daemon --comment-prefix '[host-a] ' --file ports.csv
+//! ```
+//!
+//! A mapping with the comment `Webserver` then shows up as `[host-a] Webserver`
+//! on the router. Comment filters still match the comments from the
+//! configuration, without the prefix.
+//!
+//! ### Description Templates
+//!
+//! For more information in the table of the router, for example to tell later
+//! which port mappings can be cleaned up, the descriptions can be built from a
+//! template:
+//!
+//! ```shell script
+//! upnp-daemon --description-template '{comment} (upnp-daemon {date})' --file ports.csv
+//! ```
+//!
+//! A mapping with the comment `Webserver` then shows up as
+//! `Webserver (upnp-daemon 2024-05-01)` on the router. The placeholders are
+//! `{comment}` for the comment, including the prefix of `--comment-prefix`,
+//! `{date}` for the local date of the last refresh, `{host}` for the name of this
+//! host, and `{version}` for the version of upnp-daemon. Other placeholders are
+//! rejected at startup. Without a template, the comment is used as is.
+//!
+//! Some routers reject long descriptions or cut them short on their own, so
+//! descriptions from a template are cut to 64 characters. Since the date changes
+//! every day, port mappings whose description contains it are refreshed at least
+//! once a day, even with `--once-per-change`.
+//!
+//! ### Overriding Lease Durations
+//!
+//! To try out other lease durations without editing the config files, for
+//! example shorter ones while testing, you can override the duration of all port
+//! mappings at once:
+//!
+//! ```shell script
+//! upnp-daemon --duration-override 120 --file ports.csv
+//! ```
+//!
+//! The given number of seconds replaces the durations of all entries, including
+//! `auto` and left out durations. Together with `--dry-run`, you can preview the
+//! effect without touching the router.
+//!
+//! ### Filtering by Protocol
+//!
+//! If your network only permits forwarding of one protocol, for example only TCP,
+//! you can skip the port mappings of all other protocols, without editing a
+//! shared configuration file:
+//!
+//! ```shell script
+//! upnp-daemon --protocols tcp --file ports.csv
+//! ```
+//!
+//! Each skipped port mapping is logged at the debug level. By default, both TCP
+//! and UDP are handled. Like the comment filter, this applies to opening as well
+//! as closing ports.
+//!
+//! ### Allowed Ports
+//!
+//! As a safety measure, you can restrict the daemon to a set of external ports,
+//! so that an errant configuration cannot open privileged or unexpected ports:
+//!
+//! ```shell script
+//! upnp-daemon --allowed-ports 30000-40000,8080 --file ports.csv
+//! ```
+//!
+//! Port mappings whose external port is outside of all given ports and ranges are
+//! skipped with a warning, both when opening and when closing ports. The option
+//! can be given multiple times. Without it, all ports are allowed.
+//!
+//! Port mappings with `any_port` are skipped as well, because the router chooses
+//! their external port. For the same reason, `--distinct-protocol-ports stagger`
+//! does not stagger port mappings while this option is given, they stay failed.
+//!
+//! ### NAT-PMP
+//!
+//! Some routers, most notably from Apple, do not speak UPnP, but NAT-PMP instead.
+//! With the `--protocol-backend` option, you can choose which protocol is used to
+//! talk to the router:
+//!
+//! ```shell script
+//! upnp-daemon --protocol-backend natpmp --file ports.csv
+//! ```
+//!
+//! This sets the backend for all port mappings that do not specify one
+//! themselves in the `backend` field, see below. So you can also mix both
+//! protocols in one file.
+//!
+//! Please note that NAT-PMP can only open ports for the machine upnp-daemon is
+//! running on, via its default gateway. The `address` of a port mapping is
+//! therefore ignored with this backend. Also, the router does not store a
+//! comment, so `--detect-foreign-changes` only applies to UPnP port mappings.
+//!
+//! ### Probing for a Gateway
+//!
+//! If you are unsure whether your router supports UPnP at all, you can let
+//! upnp-daemon look for it, without opening any ports:
+//!
+//! ```shell script
+//! upnp-daemon probe
+//! ```
+//!
+//! This will print the address, the friendly name and the external IP address of
+//! the gateway that was found. The program exits with status code 0 if a gateway
+//! was found, and with a non-zero status code otherwise, so it can be used in
+//! scripts. With the `--address` option, only a gateway that is responsible for
+//! the given address will be considered, in the same way as for the `address`
+//! field of a port mapping:
+//!
+//! ```shell script
+//! upnp-daemon probe --address 192.168.0.0/24
+//! ```
+//!
+//! Some routers support UPnP, but lack some of the actions that upnp-daemon uses,
+//! like listing the port mappings. `probe` also reports these unsupported
+//! actions. The daemon itself warns about them once, when the router is first
+//! found, and then quietly skips the features that need them, like the check for
+//! foreign changes. Commands that cannot work without them, like `export`, fail
+//! with an error that names the missing action.
+//!
+//! ### Discovery Port
+//!
+//! To find the router, upnp-daemon sends a search request from a random local
+//! port. If your firewall only allows such requests from a known port, you can
+//! choose a fixed port with the `--ssdp-bind-port` option:
+//!
+//! ```shell script
+//! upnp-daemon --ssdp-bind-port 1901 --file ports.csv
+//! ```
+//!
+//! Please note that the discovery fails if the port is already in use, for
+//! example by another instance of upnp-daemon. The error will be logged for each
+//! port mapping, like any other discovery error.
+//!
+//! The search request is sent to the SSDP multicast address, so that any router
+//! in the network can answer. If multicast is blocked in your network, or if you
+//! want to ask one specific router, you can send the search request directly to
+//! the SSDP port of that router instead:
+//!
+//! ```shell script
+//! upnp-daemon --ssdp-address 192.168.0.1:1900 --file ports.csv
+//! ```
+//!
+//! If the search cannot reach the router at all, but you know the URL of its
+//! device description, you can skip the search completely. The URL is the
+//! `LOCATION` that the router announces, and its host has to be an IPv4 address:
+//!
+//! ```shell script
+//! upnp-daemon --gateway-url http://192.168.0.1:5000/rootDesc.xml --file ports.csv
+//! ```
+//!
+//! This router is then used for all port mappings, regardless of their address,
+//! and the ports are forwarded to the address of the local interface that faces
+//! the router. The options that steer the search, like `--interface-priority`,
+//! are ignored. If the URL is invalid or the router cannot be reached, the error
+//! is logged for each port mapping.
+//!
+//! ### Interface Priority
+//!
+//! If a port mapping has no address, all interfaces are tried until one of them
+//! finds a router. On machines with more than one network connection, this might
+//! not be the router that you intended. Instead of pinning the address of each
+//! port mapping, you can tell upnp-daemon which interfaces to try first:
+//!
+//! ```shell script
+//! upnp-daemon --interface-priority eth0,192.168.1.0/24 --file ports.csv
+//! ```
+//!
+//! Each entry is either the name of an interface or an address range in the same
+//! notation as the address field. Interfaces are tried in the order of the first
+//! entry that they match, interfaces that match no entry are tried last. Without
+//! this option, the first interface that finds a router is used.
+//!
+//! The interfaces are looked up again on each iteration. If an interface that has
+//! been used before is gone, for example when the Wi-Fi drops or a VPN goes down,
+//! a warning names it, and the next matching interface is selected right away.
+//!
+//! Interfaces that should never be used, like the bridge of a container runtime,
+//! can be excluded in the same notation:
+//!
+//! ```shell script
+//! upnp-daemon --exclude-interfaces docker0,172.17.0.0/16 --file ports.csv
+//! ```
+//!
+//! If there is more than one router in reach, you can also pick the one by its
+//! friendly name, as shown by `probe`. Routers whose name does not contain the
+//! given string are ignored:
+//!
+//! ```shell script
+//! upnp-daemon --gateway-name 'FRITZ!Box' --file ports.csv
+//! ```
+//!
+//! Each search waits up to ten seconds for an answer of a router. In networks
+//! where routers answer quickly, a shorter `--discovery-timeout` lets the search
+//! on interfaces without a router give up earlier.
+//!
+//! Since the search and its answers can get lost in the network, a search
+//! without an answer is repeated twice after a short random delay, before the
+//! interface is given up for this iteration. Choose another number of retries
+//! with `--discovery-retries`, or turn them off with `--discovery-retries 0`.
+//! Please note that each retry waits for the full timeout again.
+//!
+//! If no router can be found at all, the exact options of each search help with
+//! the bug report. With `--debug-search-options`, the address that the search is
+//! sent from, the address that it is sent to and the timeout are logged for each
+//! attempt on each interface, at the `debug` level:
+//!
+//! ```shell script
+//! RUST_LOG=debug upnp-daemon --debug-search-options probe
+//! ```
+//!
+//! On machines with several addresses in the same range, the first address that
+//! finds a router is used for port mappings without an address, or with an
+//! address range. To use a specific address instead, give it with `--bind-ip`:
+//!
+//! ```shell script
+//! upnp-daemon --bind-ip 192.168.0.11 --file ports.csv
+//! ```
+//!
+//! The search is then sent from this address, and the ports are forwarded to it.
+//! If no interface has this address, or if it is not in the address range of a
+//! port mapping, that port mapping fails with an error. In this case,
+//! `--interface-priority` and `--exclude-interfaces` are not considered.
+//!
+//! If several interfaces match an address range and have the same priority, they
+//! are tried in the order of their names by default, for example `eth0` before
+//! `eth1` before `wlan0`. With `--match-policy lowest-ip`, they are tried in the
+//! order of their addresses instead. Either way, the order is the same on all
+//! platforms, and the chosen interface is logged on the debug level.
+//!
+//! On dual-stack machines, `--prefer-family v6` will try interfaces with an IPv6
+//! address first, among those with the same priority. Since port mappings are only
+//! supported for IPv4 so far, this has no effect yet, and the default `v4` keeps
+//! the current behavior.
+//!
+//! ### Removing All Port Mappings
+//!
+//! If the table of your router is cluttered with stale port mappings, for
+//! example from past runs with another configuration, you can remove all of
+//! them at once, regardless of any config file:
+//!
+//! ```shell script
+//! upnp-daemon remove-all
+//! ```
+//!
+//! This lists all port mappings of the router and asks for confirmation before
+//! removing them. **This removes port mappings of all devices in your network,**
+//! not only the ones created by upnp-daemon, as far as the router allows it. To
+//! skip the confirmation, for example in scripts, add the `--yes` flag. Like for
+//! `probe`, the `--address` option selects the router that is responsible for
+//! the given address. The program exits with a non-zero status code if a port
+//! mapping could not be removed.
+//!
+//! If you only remember the comment of the port mappings to remove, for example
+//! the prefix that a daemon gives all of its comments, you can restrict the
+//! removal to the port mappings with a matching comment:
+//!
+//! ```shell script
+//! upnp-daemon remove-all --comment '[host-a] *'
+//! ```
+//!
+//! Without the wildcards `*` and `?`, the comment has to match exactly. If no
+//! port mapping matches, nothing is removed, and the program exits
+//! successfully.
+//!
+//! ### Renewing a Single Port Mapping
+//!
+//! To renew the lease of one port mapping right away, for example when another
+//! tool notices that a service has restarted, there is no need to apply a whole
+//! config file:
+//!
+//! ```shell script
+//! upnp-daemon renew --port 8080 --protocol tcp --duration 3600
+//! ```
+//!
+//! The port mapping is added again for this host, with the given duration, and
+//! keeps its comment unless `--comment` is given. If it did not exist before, it
+//! is created, which the output points out. If it is forwarded to another host,
+//! it is left alone and the command fails. Like for `probe`, the `--address`
+//! option selects the router that is responsible for the given address.
+//!
+//! ### Exporting Port Mappings
+//!
+//! To bootstrap a config file from the port mappings that are already in place,
+//! for example ones that you created by hand in the web interface of your
+//! router, you can export them:
+//!
+//! ```shell script
+//! upnp-daemon export --output ports.csv
+//! ```
+//!
+//! The format is chosen by the file extension of `--output`, or explicitly with
+//! `--format csv` or `--format json`. Without `--output`, the config is written
+//! to stdout. Each port mapping is exported with its internal client in the
+//! `internal_client` field, and its remaining lease time as duration. Note that the router reports the port
+//! mappings of all devices in your network, so you might want to edit the file
+//! before using it. Since upnp-daemon always uses the same port internally and
+//! externally, port mappings that forward to a different internal port are
+//! exported with the external port, and a warning is logged.
+//!
+//! ### Dry Run
+//!
+//! To check what upnp-daemon would do with your configuration, without touching
+//! the router, use the `dry-run` flag:
+//!
+//! ```shell script
+//! upnp-daemon --dry-run --file ports.csv
+//! ```
+//!
+//! For each port mapping, this searches for the gateway and asks it for an
+//! existing port mapping, then prints the action that would be taken: `add` for
+//! new port mappings, `refresh` for existing ones, `enable` for existing ones
+//! that have been disabled on the router, `replace` if the port is currently
+//! mapped to another address, and `skip` if the port mapping would not
+//! be touched in this iteration, because its required process is not running or
+//! its lease is not due for renewal. Port mappings that would fail are shown as
+//! `error`, together with the reason. The program exits right afterwards, and
+//! never forks to the background.
+//!
+//! With `--plan-format json`, the plan is printed as a JSON array instead, with
+//! one object per port mapping that also contains the gateway, with its friendly
+//! name and external IP, and the local address that the port is forwarded to.
+//! This is useful in CI, to check that a config resolves sanely across
+//! environments: since NAT-PMP cannot be asked beforehand, port mappings of that
+//! backend are always shown as `add`, without a gateway. The option is not simply
+//! called `--format`, because that one already selects the format of the
+//! configuration files.
+//!
+//! ### Hooks
+//!
+//! To integrate upnp-daemon with other tools, for example to sync firewall rules,
+//! update DNS records or send notifications, you can let it run a command after
+//! each successful addition or removal of a port mapping:
+//!
+//! ```shell script
+//! upnp-daemon --on-add /usr/local/bin/port-added --on-remove /usr/local/bin/port-removed --file ports.csv
+//! ```
+//!
+//! The details of the port mapping are passed to the command in the environment
+//! variables `UPNP_PORT`, `UPNP_PROTOCOL`, `UPNP_ADDRESS` and `UPNP_COMMENT`.
+//! `UPNP_ADDRESS` is empty if no address is configured. The command is not run in
+//! a shell, but arguments separated by whitespace are passed on, so for anything
+//! more complex, please use a script.
+//!
+//! Please note that port mappings are added again on each iteration, so the
+//! `--on-add` command runs on each iteration, too. Removals only happen with
+//! `--close-ports-on-exit` or `--only-close-ports`. The commands run one after
+//! another and the daemon waits for them to finish. If a command fails, a warning
+//! is logged, but the port mapping itself is not affected.
+//!
+//! ### Self-Test
+//!
+//! Having the port mapping in the router's table does not necessarily mean that
+//! the port is actually reachable from the internet. If upnp-daemon is built with
+//! the `selftest` feature, like so:
+//!
+//! ```shell script
+//! cargo install --locked upnp-daemon --features selftest
+//! ```
+//!
+//! you get the additional options `--selftest` and `--selftest-url`. After
+//! opening the ports, each TCP mapping will be verified by asking an external
+//! checker to connect to it:
+//!
+//! ```shell script
+//! upnp-daemon --selftest --selftest-url 'https://checker.example/tcp/{port}' --file ports.csv
+//! ```
+//!
+//! The placeholder `{port}` in the URL will be replaced with the external port
+//! to check, which is the one that the router has chosen for `any_port` mappings.
+//! The checker is expected to connect to the port from the outside, using the
+//! address the request came from, and to answer with a successful HTTP status
+//! code if the connection could be established. If the port is forwarded to this
+//! machine and no service is listening on it yet, upnp-daemon will temporarily
+//! listen on it itself during the check. The result will be logged as reachable
+//! or unreachable for each port.
+//!
+//! Since this feature needs to talk to an external service, it is not compiled
+//! in by default.
+//!
+//! ### Systemd Socket Activation
+//!
+//! For services that are started by systemd on demand, the ports are already
+//! known to systemd, in the form of socket units. If upnp-daemon is built with the
+//! `systemd` feature, like so:
+//!
+//! ```shell script
+//! cargo install --locked upnp-daemon --features systemd
+//! ```
+//!
+//! you get the additional option `--from-systemd-sockets`, which forwards the
+//! ports of the sockets that systemd passes to upnp-daemon, instead of or in
+//! addition to the ports of the config files. Since systemd can pass the same
+//! socket to several services, it is enough to list the socket units of your
+//! services in the unit of upnp-daemon:
+//!
+//! ```ini
+//! [Service]
+//! ExecStart=/usr/local/bin/upnp-daemon --foreground --from-systemd-sockets
+//! Sockets=web.socket game.socket
+//! ```
+//!
+//! Each TCP or UDP socket becomes a port mapping with the default lease duration,
+//! with the name of the socket (by default the name of its socket unit, or the
+//! `FileDescriptorName=` of the socket unit) as comment. A socket that is bound to
+//! a single IPv4 address is forwarded to that address, all others to the address
+//! of this host. Sockets that are only reachable from this host, like the ones on
+//! `127.0.0.1`, are skipped. The sockets are received once at startup, so please
+//! restart upnp-daemon after changing the socket units.
+//!
+//! ### Logging
+//!
+//! If you want to activate logging to have a better understanding what the
+//! program does under the hood, you need to set the environment variable
+//! `RUST_LOG`, like so:
+//!
+//! ```shell script
+//! RUST_LOG=info upnp-daemon --foreground --file ports.csv
+//! ```
+//!
+//! To make the logger even more verbose, try to set the log level to `debug`:
+//!
+//! ```shell script
+//! RUST_LOG=debug upnp-daemon --foreground --file ports.csv
+//! ```
+//!
+//! For log aggregators, the log can also be written as JSON Lines, with one JSON
+//! object per log record, holding its `timestamp`, `level`, `target` and
+//! `message`:
+//!
+//! ```shell script
+//! RUST_LOG=info upnp-daemon --foreground --log-format json --file ports.csv
+//! ```
+//!
+//! When several UPnP devices are on the network, the log tells which one was
+//! chosen: the first time a gateway is used, its friendly name, manufacturer and
+//! model are logged on the `info` level, and the URL that the requests are sent
+//! to on the `debug` level.
+//!
+//! To find out which step is slow with a slow router, the `debug` level also
+//! shows how long the search for the router, each addition and each removal of a
+//! port mapping, and each whole iteration took, like in
+//! `RUST_LOG=debug upnp-daemon --foreground --file ports.csv`. If an iteration
+//! takes longer than the interval, a warning is logged on any level.
+//!
+//! With `--report-outcomes`, the log tells for each port mapping whether it has
+//! been created, refreshed, or left unchanged, like `Port mapping TCP 8080
+//! refreshed`. To tell them apart, the router is asked for the existing port
+//! mapping before each addition. Port mappings with `any_port` and permanent port
+//! mappings are reported like this anyway, since it takes no extra request for
+//! them: a permanent port mapping that already exists with the same comment is
+//! left unchanged, without sending anything else to the router.
+//!
+//! With many port mappings, the `info` level logs quite a lot on each
+//! iteration. The `--summary` flag leaves out the lines about single port
+//! mappings, and logs one line per iteration instead, like `12 added, 1 skipped,
+//! 0 failed in 3.2s`. Skipped port mappings are those that were not due, for
+//! example because their required process is not running. Errors and warnings
+//! are still logged, and so are all details on the `debug` level.
+//!
+//! When the program exits, a summary with the number of iterations, the total
+//! number of added ports and failures, and the uptime will be logged on the
+//! `info` level.
+//!
+//! If a port mapping fails with the same error on every iteration, for example
+//! because UPnP is disabled on the router, the error is logged only once every
+//! 10 minutes, together with the number of suppressed repetitions. You can choose
+//! another window in seconds with `--log-dedup-window`, or log every error with
+//! `--log-dedup-window 0`.
+//!
+//! Please note that it does not make sense to activate logging without using
+//! `foreground`, since the output (stdout as well as stderr) will not be saved in
+//! daemon mode. This might change in a future release.
+//!
+//! If a router rejects a port mapping for reasons that the log does not explain,
+//! the raw exchange with the router can be recorded to a file, to be attached to
+//! a bug report:
+//!
+//! ```shell script
+//! upnp-daemon --foreground --oneshot --record-soap soap.log --file ports.csv
+//! ```
+//!
+//! Each request is appended to the file together with the response of the
+//! router. Nothing is redacted, so please be aware that the file contains the
+//! addresses and comments of your port mappings. Only the search for the router
+//! is not recorded.
+//!
+//! Such a record can be replayed without the router, to reproduce the behaviour
+//! of the router in question. Instead of the router, the daemon then gets its
+//! responses from the record, in the recorded order:
+//!
+//! ```shell script
+//! upnp-daemon --foreground --oneshot --replay-soap soap.log --file ports.csv
+//! ```
+//!
+//! Since only recorded requests can be answered, the configuration has to match
+//! the one of the recording.
+//!
+//! ### Metrics
+//!
+//! For monitoring, the daemon can write metrics for the textfile collector of the
+//! Prometheus node_exporter after each iteration:
+//!
+//! ```shell script
+//! upnp-daemon --metrics-textfile /var/lib/node_exporter/upnp-daemon.prom --file ports.csv
+//! ```
+//!
+//! The file contains the number of iterations, added port mappings and failures
+//! since the daemon started, the time of the last iteration, and the external IP
+//! address of the gateway, if it could be found. The file is replaced as a whole,
+//! so the collector never reads a partially written file.
+//!
+//! ## Config File Format
+//!
+//! The config file can be given as CSV, JSON, YAML or TOML. The names and contents
+//! of the fields are always the same. By default, the format is chosen by the file
+//! extension: `.json` for JSON, `.yaml` or `.yml` for YAML, and `.toml` for TOML.
+//! For all other files and stdin, the format is detected from the content: if the
+//! first character other than whitespace is `[` or `{`, the file is read as JSON,
+//! otherwise as CSV. This detection can also be requested explicitly with
+//! `--format auto`. You can choose the format explicitly with `--format`, for
+//! example `--format json`, this always wins over the file extension.
+//!
+//! ### Multiple Files
+//!
+//! The `--file` option can be given multiple times, for example to keep some
+//! port mappings in CSV and others in JSON:
+//!
+//! ```shell script
+//! upnp-daemon --file ports.csv --file more-ports.json
+//! ```
+//!
+//! If `--format` is given once, it applies to all files. If it is given multiple
+//! times, it has to be given once per file and the formats are assigned to the
+//! files in the same order. All files are read on each iteration and their port
+//! mappings are merged, in the order of the files and of the lines within each
+//! file, before the `priority` is applied. If the same port and protocol are
+//! configured more than once, within one file or across files, a warning will be
+//! logged. Both port mappings will be added in order, so the later one wins.
+//!
+//! ### CSV
+//!
+//! The format of the port mapping file is a simple CSV file, like the following
+//! example:
+//!
+//! ```text
+//! address;port;protocol;duration;comment
+//! 192.168.0.10;12345;UDP;60;Test 1
+//! ;12346;TCP;60;Test 2
+//! ```
+//!
+//! Please note that the first line is mandatory at the moment, it is needed to
+//! accurately map the fields to the internal options.
+//!
+//! Optional fields at the end of a line can be left out, even if they appear in
+//! the first line. So a line like `;12347;TCP;60` is valid and opens a port
+//! mapping without a comment.
+//!
+//! With the `--csv-delimiter` option, you can choose an arbitrary character to be
+//! used as a field delimiter in your CSV file. By default, we use the semicolon,
+//! but if you instead prefer a usual comma, you can just say so with
+//! `--csv-delimiter ','`.
+//!
+//! Please be aware that your shell might interpret the delimiter (for example,
+//! the semicolon is used in bash to separate two commands), so be sure to
+//! correctly escape it.
+//!
+//! If your CSV file comes from a tool that does not write a header line, use the
+//! `--no-header` flag. In that case, the columns are expected in the order shown
+//! above. If the tool uses another order, you can tell upnp-daemon which column
+//! is which, with the field names separated by commas:
+//!
+//! ```shell script
+//! upnp-daemon --no-header --csv-columns port,protocol,address,duration,comment --file ports.csv
+//! ```
+//!
+//! ### JSON
+//!
+//! A config file in JSON format with the above contents could look like this:
+//!
+//! ```json
+//! [
+//!   {
+//!     "address": "192.168.0.10",
+//!     "port": 12345,
+//!     "protocol": "UDP",
+//!     "duration": 60,
+//!     "comment": "Test 1"
+//!   },
+//!   {
+//!     "address": null,
+//!     "port": 12346,
+//!     "protocol": "TCP",
+//!     "duration": 60,
+//!     "comment": "Test 2"
+//!   }
+//! ]
+//! ```
+//!
+//! The line breaks and indentations are just for readability, you can format it
+//! any way you like. You can even go as far and feed in a single line, as long as
+//! it is a valid JSON array with all required fields in it.
+//!
+//! Since `address` is `null` in the second entry, it can also be left out
+//! completely if you prefer. Also, any key that is not documented below will be
+//! silently ignored, so you might use them as internal comments for yourself. So
+//! a config might also look like:
+//!
+//! ```json
+//! [
+//!   {
+//!     "rationale": "This port is needed for an awesome application!",
+//!     "may-be-deleted": false,
+//!     "port": 12347,
+//!     "protocol": "TCP",
+//!     "duration": 60,
+//!     "comment": "Test 3"
+//!   }
+//! ]
+//! ```
+//!
+//! The keys `rationale` and `may-be-deleted` will be ignored by the daemon.
+//!
+//! Also, please note that even if you want to add just one port mapping, you need
+//! to specify a JSON array.
+//!
+//! Content after the array is an error by default, because it usually means that
+//! the file was not written completely. If another program appends to your config
+//! files, you can pass `--lenient-json` to read just the first JSON array of each
+//! file. Any trailing content is then ignored with a warning.
+//!
+//! ### YAML
+//!
+//! In YAML, the config file is a sequence of port mappings, just like the JSON
+//! array:
+//!
+//! ```yaml
+//! - address: 192.168.0.10
+//!   port: 12345
+//!   protocol: UDP
+//!   duration: 60
+//!   comment: Test 1
+//! - port: 12346
+//!   protocol: TCP
+//!   duration: 60
+//!   comment: Test 2
+//! ```
+//!
+//! ### TOML
+//!
+//! In TOML, each port mapping is a table in an array of tables named `mapping`:
+//!
+//! ```toml
+//! [[mapping]]
+//! address = "192.168.0.10"
+//! port = 12345
+//! protocol = "UDP"
+//! duration = 60
+//! comment = "Test 1"
+//!
+//! [[mapping]]
+//! port = 12346
+//! protocol = "TCP"
+//! duration = 60
+//! comment = "Test 2"
+//! ```
+//!
+//! Since TOML has no null value, optional fields like `address` are simply left
+//! out if they are not needed.
+//!
+//! ### Fields
+//!
+//! -   address
+//!
+//!     The IP address for which the port mapping should be added. This field can
+//!     be empty, in which case every connected interface will be tried, until one
+//!     gateway reports success. Useful if the IP address is dynamic and not
+//!     consistent over reboots.
+//!
+//!     Fill in an IP address if you want to add a port mapping for a foreign
+//!     device, or if you know your machine's address and want to slightly speed
+//!     up the process.
+//!
+//!     You can also enter the IP address in CIDR notation. In that case, the IP
+//!     range is checked against all connected interfaces and only matching ones
+//!     are considered. This is useful if you don't know your current IP address
+//!     (or it might change from time to time), but you know the DHCP
+//!     configuration of your router.
+//!
+//!     Such an IP address might be `192.168.0.10` or `192.168.0.0/24` or even
+//!     `192.168.0`.
+//!
+//!     More examples can be found in the responsible library's documentation:
+//!     <https://docs.rs/cidr-utils/0.5.10/cidr_utils/index.html>
+//!
+//!     For setups where the usable address can only be determined at runtime,
+//!     for example the address that a VPN assigned, the field can also contain a
+//!     command in the form `exec:/usr/local/bin/get-vpn-ip`. The command is run
+//!     once per iteration, the first line of its output is used as the address
+//!     and may also be given in CIDR notation. The command is not run in a shell,
+//!     but arguments separated by whitespace are passed on. If the command fails
+//!     or does not print a valid address, an error is logged and the port
+//!     mapping is skipped for this iteration.
+//!
+//!     For devices that move between networks, like laptops, several
+//!     candidates can be given, separated by commas, like
+//!     `192.168.1.0/24,10.0.0.0/8`. In JSON, YAML and TOML files, they can also
+//!     be given as a list. The candidates are tried in the given order, until
+//!     one of them finds a gateway, and the successful one is logged.
+//!
+//! -   port
+//!
+//!     The port number to open for the given IP address. Note that upnp-daemon is
+//!     greedy at the moment, if a port mapping is already in place, it will be
+//!     deleted and re-added with the given IP address. This might be configurable
+//!     in a future release.
+//!
+//!     The port can also be given together with the protocol, like `tcp/8080` or
+//!     `udp/53`. In this case, the protocol field can be left out, or left empty
+//!     in CSV files. If it is given anyway, it has to be the same protocol.
+//!
+//! -   protocol
+//!
+//!     The protocol for which the given port will be opened. Possible values are
+//!     `UDP` and `TCP`.
+//!
+//! -   duration
+//!
+//!     The lease duration for the port mapping in seconds. Please note that some
+//!     UPnP capable routers might choose to ignore this value, so do not
+//!     exclusively rely on this. If the router advertises a maximum lease
+//!     duration, longer values are reduced to that maximum instead of being
+//!     rejected by the router. Some routers reject UDP port mappings with a lease
+//!     duration altogether. Once a router does so, all UDP port mappings on it
+//!     are requested as permanent port mappings.
+//!
+//!     A duration of `0` requests a permanent port mapping, which is never
+//!     reduced to the maximum of the router. Since NAT-PMP has no permanent port
+//!     mappings, the NAT-PMP backend reports an error for it instead.
+//!
+//!     If the field is left out, or left empty in CSV files, the router decides.
+//!     With UPnP, the maximum that the router advertises is requested. If it
+//!     advertises none, or with NAT-PMP, the lifetime of two hours that NAT-PMP
+//!     recommends is requested.
+//!
+//!     Instead of a number, you can also give the special value `auto`. In that
+//!     case, the lease will be slightly longer than the update interval (10
+//!     percent, but at least 30 seconds), so it will be renewed on each
+//!     iteration, but expires shortly after the daemon stops. This way, ports of
+//!     a crashed daemon will clean themselves up.
+//!
+//!     A duration shorter than the update interval, like a typo of `5` instead
+//!     of `500`, means that the port mapping expires long before it is renewed.
+//!     Outside of oneshot mode, such durations are reported with a warning. With
+//!     the `--strict` flag, they are reported as errors, and the port mapping is
+//!     skipped instead.
+//!
+//! -   comment
+//!
+//!     A comment about the reason for the port mapping. Will be stored together
+//!     with the mapping in the router. This field is optional, if it is left out,
+//!     an empty comment is used.
+//!
+//! -   backend
+//!
+//!     The protocol that is used to talk to the router. Possible values are
+//!     `upnp` and `natpmp`. This field is optional, if it is empty or left out,
+//!     the backend given with `--protocol-backend` is used, which defaults to
+//!     `upnp`.
+//!
+//! -   priority
+//!
+//!     The order in which the port mappings are opened, as a whole number. Port
+//!     mappings with a higher priority are opened first, so a critical service
+//!     can be made available even if later port mappings fail or are slow. Port
+//!     mappings with the same priority are opened in the order of the file. This
+//!     field is optional and defaults to `0`. It has no effect on the router
+//!     itself, only on the order of processing.
+//!
+//! -   remote_host
+//!
+//!     The external IP address that may use the port mapping, for example
+//!     `198.51.100.7`. Traffic from other addresses is not forwarded. This field
+//!     is optional, if it is empty or left out, the port mapping is valid for
+//!     everyone. Please note that not all routers honor this value, some reject
+//!     such port mappings, others silently open the port for everyone, so do not
+//!     rely on this as a security measure. This is not supported by the
+//!     `natpmp` backend.
+//!
+//! -   any_port
+//!
+//!     Set this to `true` if the external port does not matter, for example for
+//!     ephemeral services. In that case, `port` is only used as the internal
+//!     port, and the router chooses any free external port, which is logged. On
+//!     subsequent iterations, the existing port mapping is renewed, so the
+//!     external port stays the same as long as the port mapping does not expire.
+//!     This field is optional and defaults to `false`. It cannot be combined with
+//!     `remote_host`.
+//!
+//! -   internal_client
+//!
+//!     The IP address of another device in your network, to which the port
+//!     should be forwarded, for example `192.168.0.20`. This way, one instance of
+//!     upnp-daemon can open ports for all devices in the network. The router is
+//!     still searched for via the interfaces of the machine that runs the daemon,
+//!     so `address` can be used to choose the interface as usual. If the field is
+//!     empty or left out, the port is forwarded to the machine itself. A warning
+//!     is logged if the address is not a private address. Please note that some
+//!     routers only allow port mappings for the requesting device. This is not
+//!     supported by the `natpmp` backend.
+//!
+//! -   requires_process
+//!
+//!     A process that has to be running for the port to be open, either given by
+//!     its name, like `minecraft-server`, or by its PID file, like
+//!     `pidfile:/run/minecraft.pid`. The processes are checked on each iteration:
+//!     while the process is running, the port mapping is added as usual. Once the
+//!     process has ended, the port mapping is removed on the next iteration, and
+//!     it is not added again until the process runs again. So the port might stay
+//!     open for up to one interval after the process has ended. Process names are
+//!     compared exactly, as reported by the operating system, which might shorten
+//!     long names. This field is optional, if it is empty or left out, the port
+//!     mapping is always added.
+//!
+//! -   internal_hostname
+//!
+//!     The hostname of another device in your network, to which the port should
+//!     be forwarded, for devices that get a changing address by DHCP. The
+//!     current address of the hostname is looked up in the lease file of
+//!     dnsmasq, which is `/var/lib/misc/dnsmasq.leases` by default and can be
+//!     changed with `--dhcp-leases`. The lease file is read again on each
+//!     iteration, so that a new address is picked up. If the hostname is not
+//!     leased at the moment, a warning is logged and the port mapping is skipped
+//!     for this iteration. This field is optional, if it is given, it takes
+//!     precedence over `internal_client`.
+//!
+//! -   retries
+//!
+//!     How often the search for the router of this port mapping is repeated if no
+//!     router answers in time. This field is optional, if it is empty or left out,
+//!     the value of `--discovery-retries` is used. Give a higher number for
+//!     critical ports, or 0 for ports that are not worth the wait.
+//!
+//! -   timeout
+//!
+//!     How many seconds to wait for a router to answer the search for this port
+//!     mapping, at least 1. This field is optional, if it is empty or left out,
+//!     the value of `--discovery-timeout` is used.
+//!
+//! -   gateway
+//!
+//!     The router to use for this port mapping, for networks with several
+//!     routers. Either the URL of its device description, like
+//!     `http://192.168.0.1:5000/rootDesc.xml`, or a part of its friendly name, as
+//!     shown by `probe`, like `FRITZ!Box`. For this port mapping, it takes
+//!     precedence over `--gateway-url` and `--gateway-name`. If no such router is
+//!     found, the port mapping fails with an error. This field is optional, if it
+//!     is empty or left out, the router is chosen as usual.
+
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+#[cfg(unix)]
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use chrono::{DateTime, Local, TimeZone};
+use clap::{
+    builder::{PathBufValueParser, PossibleValuesParser, TypedValueParser},
+    error::ErrorKind,
+    CommandFactory, Parser, Subcommand, ValueEnum,
+};
+use cron::Schedule;
+#[cfg(unix)]
+use daemonize::Daemonize;
+use log::{debug, error, info, warn, Level, Log, Metadata, Record};
+use serde::Serialize;
+use tempfile::tempfile;
+use wildmatch::WildMatch;
+
+use easy_upnp::{
+    Address, AddressFamily, Backend, DiscoveryOptions, GatewayInfo, InterfaceSelector, Ipv4Cidr,
+    LeaseDuration, MatchPolicy, PlannedAction, PortMappingEntry, PortMappingProtocol,
+    PrefetchedGateway, StringRecord, UpnpConfig,
+};
+
+mod running;
+#[cfg(feature = "selftest")]
+mod selftest;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+
+#[derive(Clone)]
+enum CliInput {
+    File(PathBuf),
+    Stdin,
+}
+
+impl TryFrom<PathBuf> for CliInput {
+    type Error = std::io::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Ok(if path.as_os_str() == "-" {
+            CliInput::Stdin
+        } else {
+            // The file might not exist yet, if it is generated by another process.
+            match path.canonicalize() {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    CliInput::File(std::env::current_dir()?.join(&path))
+                }
+                result => CliInput::File(result?),
+            }
+        })
+    }
+}
+
+impl CliInput {
+    /// Guess the format from the file extension, or let it be detected from the content.
+    fn format(&self) -> CliInputFormat {
+        let extension = match self {
+            CliInput::File(path) => path.extension().and_then(|extension| extension.to_str()),
+            CliInput::Stdin => None,
+        };
+
+        match extension.map(str::to_lowercase).as_deref() {
+            Some("json") => CliInputFormat::Json,
+            Some("yaml" | "yml") => CliInputFormat::Yaml,
+            Some("toml") => CliInputFormat::Toml,
+            _ => CliInputFormat::Auto,
+        }
+    }
+}
+
+impl fmt::Display for CliInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliInput::File(path) => write!(f, "{}", path.display()),
+            CliInput::Stdin => f.write_str("stdin"),
+        }
+    }
+}
+
+enum Input {
+    File(File),
+    PathBuf(PathBuf),
+
+    /// A named pipe, with the last document that has been written to it.
+    #[cfg(unix)]
+    Fifo(PathBuf, Arc<Mutex<File>>),
+}
+
+/// Prefix of the environment variables that `--from-env` reads, followed by the upper case field
+/// name.
+const ENV_PREFIX: &str = "UPNP_";
+
+/// Fields that `--from-env` cannot do without.
+const REQUIRED_ENV_FIELDS: [&str; 2] = ["port", "protocol"];
+
+/// Build a single port mapping from the environment variables, one for each field that is set.
+///
+/// The values are parsed just like the fields of a CSV file.
+fn read_env_config() -> anyhow::Result<UpnpConfig> {
+    let mut headers = StringRecord::new();
+    let mut record = StringRecord::new();
+
+    for field in UpnpConfig::FIELDS {
+        let name = format!("{}{}", ENV_PREFIX, field.to_uppercase());
+        match std::env::var(&name) {
+            Ok(value) => {
+                headers.push_field(field);
+                record.push_field(&value);
+            }
+            Err(std::env::VarError::NotPresent) if REQUIRED_ENV_FIELDS.contains(field) => {
+                anyhow::bail!("--from-env requires the environment variable {}", name)
+            }
+            Err(std::env::VarError::NotPresent) => {}
+            Err(err) => return Err(err).with_context(|| format!("Could not read {}", name)),
+        }
+    }
+
+    UpnpConfig::from_csv_record(&record, &headers)
+        .context("Invalid port mapping in the environment variables")
+}
+
+/// One of the configuration files, with everything that is needed to read it on each iteration.
+struct Source {
+    name: String,
+    input: Input,
+    format: CliInputFormat,
+}
+
+impl TryFrom<CliInput> for Input {
+    type Error = std::io::Error;
+
+    fn try_from(cli_input: CliInput) -> Result<Self, Self::Error> {
+        Ok(match cli_input {
+            #[cfg(unix)]
+            CliInput::File(pathbuf) if is_fifo(&pathbuf) => {
+                // Wait for the first document, so that there is something to apply.
+                let document = read_document(File::open(&pathbuf)?)?;
+                Self::Fifo(pathbuf, Arc::new(Mutex::new(document)))
+            }
+            CliInput::File(pathbuf) => Self::PathBuf(pathbuf),
+            CliInput::Stdin => Self::File(read_document(stdin())?),
+        })
+    }
+}
+
+/// Write everything from the reader to a temporary file, so we can read it multiple times.
+fn read_document(reader: impl Read) -> std::io::Result<File> {
+    let tempfile = tempfile()?;
+    {
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(&tempfile);
+        std::io::copy(&mut reader, &mut writer)?;
+    }
+    Ok(tempfile)
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+/// Read each document that is written to the named pipe, and wake up the main loop to apply it.
+///
+/// A document ends when the writer closes the pipe. The thread runs until the main loop is gone.
+#[cfg(unix)]
+fn watch_fifo(path: PathBuf, current: Arc<Mutex<File>>, wakeup: Sender<LoopEvent>) {
+    thread::spawn(move || loop {
+        // Opening blocks until the next writer opens the pipe.
+        match File::open(&path).and_then(read_document) {
+            Ok(document)
+                if document
+                    .metadata()
+                    .is_ok_and(|metadata| metadata.len() == 0) =>
+            {
+                debug!("Ignore empty write to {}", path.display());
+            }
+            Ok(document) => {
+                info!("New configuration in {}, apply it", path.display());
+                *current
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = document;
+                if wakeup.send(LoopEvent::Reload).is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                error!("Could not read {}: {}", path.display(), err);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    });
+}
+
+/// What the main loop is woken up for before the interval has passed.
+enum LoopEvent {
+    Quit,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Reload,
+}
+
+impl Input {
+    fn open(&self) -> std::io::Result<File> {
+        match self {
+            Input::File(file) => {
+                // Clone file handle, so we don't move the original handle away.
+                let mut file = file.try_clone()?;
+
+                // File may have been advanced in previous iteration, so rewind it first.
+                file.rewind()?;
+                Ok(file)
+            }
+            Input::PathBuf(pathbuf) => File::open(pathbuf),
+            #[cfg(unix)]
+            Input::Fifo(_, current) => {
+                let mut file = current
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .try_clone()?;
+                file.rewind()?;
+                Ok(file)
+            }
+        }
+    }
+}
+
+/// Detect JSON by its first character other than whitespace, everything else is read as CSV.
+///
+/// The leading whitespace is consumed, the rest of the input is left untouched.
+fn sniff_format(reader: &mut impl BufRead) -> std::io::Result<CliInputFormat> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(CliInputFormat::Csv);
+        }
+
+        match buf.iter().position(|byte| !byte.is_ascii_whitespace()) {
+            Some(position) => {
+                let format = match buf[position] {
+                    b'[' | b'{' => CliInputFormat::Json,
+                    _ => CliInputFormat::Csv,
+                };
+                reader.consume(position);
+                return Ok(format);
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+fn filter_out_and_log_errors(result: easy_upnp::Result<UpnpConfig>) -> Option<UpnpConfig> {
+    result
+        .map_err(|err| {
+            error!("{}", err);
+            err
+        })
+        .ok()
+}
+
+/// Run the address command of the config, if any, so it runs only once per iteration.
+///
+/// Configs whose command fails are logged and skipped.
+fn resolve_address_command(mut config: UpnpConfig) -> Option<UpnpConfig> {
+    if let Some(address @ Address::Command(_)) = &config.address {
+        match address.resolve() {
+            Ok(cidr) => config.address = Some(cidr.into()),
+            Err(err) => {
+                error!("{}", err);
+                return None;
+            }
+        }
+    }
+
+    Some(config)
+}
+
+/// Fill in the internal client of the config by its hostname, if it has one.
+///
+/// Configs whose hostname is not leased at the moment are logged and skipped, just like all
+/// configs with a hostname if there are no `leases`.
+fn resolve_internal_hostname(
+    mut config: UpnpConfig,
+    leases: &Option<BTreeMap<String, Ipv4Addr>>,
+) -> Option<UpnpConfig> {
+    let Some(hostname) = &config.internal_hostname else {
+        return Some(config);
+    };
+
+    match leases.as_ref()?.get(hostname) {
+        Some(&ip) => {
+            debug!("Hostname \"{}\" is leased to {}", hostname, ip);
+            config.internal_client = Some(ip);
+            Some(config)
+        }
+        None => {
+            warn!(
+                "Skip port {} {}, hostname \"{}\" is not leased at the moment",
+                config.protocol, config.port, hostname
+            );
+            None
+        }
+    }
+}
+
+/// Report port mappings whose lease ends before they are renewed in the next iteration.
+///
+/// Each port mapping is reported once per duration. With `strict`, these port mappings are
+/// skipped, otherwise they are kept.
+fn check_durations(
+    configs: &mut Vec<UpnpConfig>,
+    interval: u64,
+    strict: bool,
+    reported: &mut HashSet<(u16, PortMappingProtocol, u32)>,
+) {
+    configs.retain(|config| {
+        let LeaseDuration::Seconds(duration) = config.duration else {
+            return true;
+        };
+
+        // A duration of 0 means that the lease does not expire at all.
+        if duration == 0 || u64::from(duration) >= interval {
+            return true;
+        }
+
+        if reported.insert((config.port, config.protocol, duration)) {
+            let message = format!(
+                "Lease duration of port {} {} is {} seconds, but the interval is {} seconds, so \
+                the port mapping expires before it is renewed; use a duration longer than the \
+                interval, or \"auto\"",
+                config.protocol, config.port, duration, interval
+            );
+            if strict {
+                error!("{}, skipping it", message);
+            } else {
+                warn!("{}", message);
+            }
+        }
+
+        !strict
+    });
+}
+
+fn comment_matches(pattern: &str, comment: &str) -> bool {
+    if pattern.contains(['*', '?']) {
+        WildMatch::new(pattern).matches(comment)
+    } else {
+        comment.contains(pattern)
+    }
+}
+
+/// An entry of `--select`, to pick configs by their position or by their comment.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ConfigSelector {
+    /// The configs in the given ranges of positions, both ends inclusive and counted from 1.
+    Positions(Vec<(usize, usize)>),
+
+    /// The configs whose comment matches the pattern, like for `--filter-comment`.
+    Comment(String),
+}
+
+impl FromStr for ConfigSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Only lists of positions are split, since a pattern may contain commas itself
+        if let Some(pattern) = s.strip_prefix("comment:") {
+            return Ok(ConfigSelector::Comment(pattern.to_string()));
+        }
+
+        let position = |n: &str| match n.trim().parse() {
+            Ok(0) | Err(_) => Err(format!("\"{}\" is not a position counted from 1", n)),
+            Ok(n) => Ok(n),
+        };
+        s.split(',')
+            .map(|range| parse_range(range, position))
+            .collect::<Result<_, _>>()
+            .map(ConfigSelector::Positions)
+    }
+}
+
+/// Parse a range like "4-6" with both ends inclusive, or a single value like "2" as a range of
+/// one, where `bound` parses each end.
+fn parse_range<T: PartialOrd>(
+    s: &str,
+    bound: impl Fn(&str) -> Result<T, String>,
+) -> Result<(T, T), String> {
+    let (first, last) = match s.split_once('-') {
+        Some((first, last)) => (bound(first)?, bound(last)?),
+        None => (bound(s)?, bound(s)?),
+    };
+    if first > last {
+        return Err(format!("range \"{}\" is empty", s));
+    }
+
+    Ok((first, last))
+}
+
+/// Keep only the configs that match any of the selectors. Without selectors, all configs are
+/// kept.
+///
+/// Each config comes with its position among all `rows` of the config files, which includes the
+/// rows that could not be parsed. Selectors that match nothing are warned about once, `warned`
+/// keeps track of them.
+fn select_configs(
+    configs: Vec<(usize, UpnpConfig)>,
+    rows: usize,
+    selectors: &[ConfigSelector],
+    warned: &mut HashSet<ConfigSelector>,
+) -> Vec<UpnpConfig> {
+    for selector in selectors {
+        if warned.contains(selector) {
+            continue;
+        }
+
+        match selector {
+            ConfigSelector::Positions(ranges) => {
+                let last = ranges.iter().map(|&(_, last)| last).max().unwrap_or(0);
+                if last <= rows {
+                    continue;
+                }
+                warn!(
+                    "Selected position {} is out of range, there are only {} entries",
+                    last, rows
+                );
+            }
+            ConfigSelector::Comment(pattern) => {
+                if configs
+                    .iter()
+                    .any(|(_, config)| comment_matches(pattern, &config.comment))
+                {
+                    continue;
+                }
+                warn!("Selected comment \"{}\" matches no port mapping", pattern);
+            }
+        }
+        warned.insert(selector.clone());
+    }
+
+    configs
+        .into_iter()
+        .filter(|(position, config)| {
+            selectors.is_empty()
+                || selectors.iter().any(|selector| match selector {
+                    ConfigSelector::Positions(ranges) => ranges
+                        .iter()
+                        .any(|(first, last)| (first..=last).contains(&position)),
+                    ConfigSelector::Comment(pattern) => comment_matches(pattern, &config.comment),
+                })
+        })
+        .map(|(_, config)| config)
+        .collect()
+}
+
+/// An entry of `--allowed-ports`, a single port or a range of ports with both ends inclusive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |n: &str| {
+            n.trim()
+                .parse()
+                .map_err(|_| format!("\"{}\" is not a port", n))
+        };
+        let (first, last) = parse_range(s, port)?;
+
+        Ok(PortRange { first, last })
+    }
+}
+
+/// The placeholders of `--description-template`.
+const DESCRIPTION_PLACEHOLDERS: [&str; 4] = ["{comment}", "{date}", "{host}", "{version}"];
+
+/// The length that descriptions from `--description-template` are cut to, since some routers
+/// reject longer descriptions or cut them short on their own.
+const MAX_DESCRIPTION_LEN: usize = 64;
+
+/// Check that a description template only uses known placeholders.
+fn parse_description_template(value: &str) -> Result<String, String> {
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end + 1)
+            .ok_or_else(|| format!("placeholder in \"{}\" is not closed", value))?;
+        let placeholder = &rest[start..end];
+        if !DESCRIPTION_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder {}, expected one of {}",
+                placeholder,
+                DESCRIPTION_PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[end..];
+    }
+
+    Ok(value.to_string())
+}
+
+/// Fill in the placeholders of a description template, and cut the result to the maximum length.
+fn render_description(template: &str, comment: &str, date: &str, host: &str) -> String {
+    // The comment comes last, so that braces in it are not taken as placeholders.
+    let description = template
+        .replace("{date}", date)
+        .replace("{host}", host)
+        .replace("{version}", env!("CARGO_PKG_VERSION"))
+        .replace("{comment}", comment);
+
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        debug!(
+            "Description \"{}\" is cut to {} characters",
+            description, MAX_DESCRIPTION_LEN
+        );
+        description.chars().take(MAX_DESCRIPTION_LEN).collect()
+    } else {
+        description
+    }
+}
+
+fn needs_renewal(config: &UpnpConfig, threshold: u8, options: &DiscoveryOptions) -> bool {
+    let LeaseDuration::Seconds(duration) = config.duration else {
+        return true;
+    };
+
+    match config.remaining_lease(Some(options)) {
+        Ok(Some(remaining))
+            if remaining > 0
+                && u64::from(remaining) * 100 > u64::from(duration) * u64::from(threshold) =>
+        {
+            debug!(
+                "Skip port {}, remaining lease of {} seconds is above threshold",
+                config.port, remaining
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(err) => {
+            debug!(
+                "Could not read remaining lease of port {}, refresh anyway: {}",
+                config.port, err
+            );
+            true
+        }
+    }
+}
+
+/// Add the port mappings, and return the ones that have been opened, together with their actual
+/// external ports.
+///
+/// Telling created from refreshed port mappings takes another request for each port mapping with
+/// a fixed port and a lease, so this is only done if asked to `report` the outcome.
+fn add_ports(
+    mut configs: Vec<UpnpConfig>,
+    options: &DiscoveryOptions,
+    errors: &mut ErrorLog,
+    on_add: Option<&str>,
+    report: bool,
+) -> Vec<(UpnpConfig, u16)> {
+    // Use the same order as easy_upnp::apply_ports, even though each port mapping is added on its
+    // own.
+    configs.sort_by_key(|config| Reverse(config.priority));
+
+    let mut opened = Vec::new();
+    for config in configs {
+        let key = (config.port, config.protocol);
+        let result = if report || config.any_port || config.duration == LeaseDuration::Seconds(0) {
+            easy_upnp::apply_ports([config.clone()], Some(options))
+                .map(|result| result.map(|outcome| outcome.port()))
+                .next()
+        } else {
+            easy_upnp::add_ports([config.clone()], Some(options)).next()
+        };
+        let Some(result) = result else {
+            continue;
+        };
+
+        match result {
+            Ok(external_port) => {
+                errors.clear(key);
+                if external_port != config.port {
+                    info!(
+                        "Port {} {} is mapped to external port {}",
+                        config.protocol, config.port, external_port
+                    );
+                }
+                if let Some(command) = on_add {
+                    run_hook(command, &config);
+                }
+                opened.push((config, external_port));
+            }
+            Err(err) => errors.log(key, err.to_string()),
+        }
+    }
+
+    opened
+}
+
+/// The last error message of a port mapping, and how often it has been repeated since.
+struct LoggedError {
+    message: String,
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+/// Error log that reports repeated errors of the same port mapping only once per window.
+struct ErrorLog {
+    window: Duration,
+    errors: HashMap<(u16, PortMappingProtocol), LoggedError>,
+}
+
+impl ErrorLog {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            errors: HashMap::new(),
+        }
+    }
+
+    fn log(&mut self, key: (u16, PortMappingProtocol), message: String) {
+        let now = Instant::now();
+
+        if let Some(logged) = self.errors.get_mut(&key) {
+            if logged.message == message {
+                if now.duration_since(logged.logged_at) < self.window {
+                    logged.suppressed += 1;
+                    return;
+                }
+
+                if logged.suppressed > 0 {
+                    error!(
+                        "{} (repeated {} times since the last report)",
+                        message, logged.suppressed
+                    );
+                } else {
+                    error!("{}", message);
+                }
+
+                logged.logged_at = now;
+                logged.suppressed = 0;
+                return;
+            }
+        }
+
+        error!("{}", message);
+        self.errors.insert(
+            key,
+            LoggedError {
+                message,
+                logged_at: now,
+                suppressed: 0,
+            },
+        );
+    }
+
+    /// Forget the error of a port mapping that succeeded again.
+    fn clear(&mut self, key: (u16, PortMappingProtocol)) {
+        self.errors.remove(&key);
+    }
+}
+
+/// Configuration that has been applied completely in an earlier iteration.
+struct AppliedConfigs {
+    hash: u64,
+    at: Instant,
+}
+
+/// How far the wall clock may drift from the monotonic clock between iterations, before it counts
+/// as a jump.
+const CLOCK_JUMP_TOLERANCE: Duration = Duration::from_secs(30);
+
+/// Detects jumps of the wall clock relative to the monotonic clock.
+///
+/// All scheduling uses the monotonic clock, but on some systems it stands still while the machine
+/// is suspended, while the leases on the router keep running out. So a jump of the wall clock,
+/// after a suspend or a step by NTP, is a sign that the port mappings might be gone.
+struct ClockWatch {
+    wall: SystemTime,
+    monotonic: Instant,
+
+    /// The source of the wall clock, which tests can replace with a stepped clock.
+    now: fn() -> SystemTime,
+}
+
+impl ClockWatch {
+    fn new() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            monotonic: Instant::now(),
+            now: SystemTime::now,
+        }
+    }
+
+    /// The current time of the wall clock.
+    fn now(&self) -> DateTime<Local> {
+        (self.now)().into()
+    }
+
+    /// Return how far the wall clock has jumped since the last check, if it has jumped at all.
+    fn check(&mut self) -> Option<String> {
+        self.check_at((self.now)(), Instant::now())
+    }
+
+    fn check_at(&mut self, wall: SystemTime, monotonic: Instant) -> Option<String> {
+        let expected = monotonic.duration_since(self.monotonic).as_secs_f64();
+        let drift = match wall.duration_since(self.wall) {
+            Ok(elapsed) => elapsed.as_secs_f64() - expected,
+            Err(e) => -e.duration().as_secs_f64() - expected,
+        };
+        let jump = (drift.abs() > CLOCK_JUMP_TOLERANCE.as_secs_f64()).then(|| {
+            let direction = if drift > 0.0 { "forward" } else { "backward" };
+            format!("{} by {:.0}s", direction, drift.abs())
+        });
+
+        self.wall = wall;
+        self.monotonic = monotonic;
+        jump
+    }
+}
+
+fn config_hash(configs: &[UpnpConfig]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    configs.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Half of the shortest lease duration, so unchanged port mappings are renewed before they expire.
+///
+/// Permanent port mappings do not need to be renewed at all. The default lease of the router is not
+/// known beforehand, so it is assumed to be the one that NAT-PMP recommends.
+fn default_renew_interval(configs: &[UpnpConfig]) -> Duration {
+    configs
+        .iter()
+        .filter_map(|config| match config.duration {
+            LeaseDuration::Seconds(duration) if duration > 0 => Some(duration / 2),
+            LeaseDuration::RouterDefault => Some(easy_upnp::NAT_PMP_DEFAULT_LIFETIME / 2),
+            _ => None,
+        })
+        .min()
+        .map_or(Duration::MAX, |seconds| Duration::from_secs(seconds.into()))
+}
+
+/// Port mappings that have been created by the daemon, by port and protocol.
+type CreatedMappings = HashMap<(u16, PortMappingProtocol), UpnpConfig>;
+
+/// Look up the port mappings that have been created by the daemon on their gateways.
+///
+/// The gateway of each port mapping is found like when it was added, with its own address and
+/// [`gateway`](UpnpConfig::gateway). Port mappings whose gateway cannot be asked are left out.
+fn lookup_created(
+    created: &CreatedMappings,
+    options: &DiscoveryOptions,
+) -> Vec<(UpnpConfig, Option<PortMappingEntry>)> {
+    // Port mappings via NAT-PMP cannot be listed, and the external port of port mappings with
+    // any_port is not known here.
+    let created: Vec<_> = created
+        .values()
+        .filter(|config| config.backend != Some(Backend::NatPmp) && !config.any_port)
+        .collect();
+
+    // Port mappings with the same address and gateway are on the same router.
+    let routers: HashSet<_> = created
+        .iter()
+        .map(|config| (&config.address, &config.gateway))
+        .collect();
+
+    let mut mappings = Vec::new();
+    for router in routers {
+        let on_router: Vec<_> = created
+            .iter()
+            .filter(|config| (&config.address, &config.gateway) == router)
+            .collect();
+        let options = on_router[0].discovery_options(options);
+
+        let entries = match router
+            .0
+            .as_ref()
+            .map(Address::resolve)
+            .transpose()
+            .and_then(|address| easy_upnp::list_port_mappings(&address, Some(&options)))
+        {
+            Ok(entries) => entries,
+            // This has already been reported once, when the gateway was found.
+            Err(err @ easy_upnp::UpnpError::UnsupportedAction(_)) => {
+                debug!("Cannot check the created port mappings: {}", err);
+                continue;
+            }
+            Err(err) => {
+                warn!(
+                    "Could not list port mappings to check the created ones: {}",
+                    err
+                );
+                continue;
+            }
+        };
+
+        for config in on_router {
+            let entry = entries.iter().find(|entry| {
+                entry.external_port == config.port && entry.protocol == config.protocol
+            });
+            mappings.push(((*config).clone(), entry.cloned()));
+        }
+    }
+
+    mappings
+}
+
+/// Find port mappings that the router has dropped, because it treats the TCP and UDP ports with the
+/// same number as a single slot.
+///
+/// Only ports that have just been opened for both protocols are checked, by listing the port
+/// mappings of their gateway.
+fn find_conflated_ports(
+    opened: &[(UpnpConfig, u16)],
+    options: &DiscoveryOptions,
+) -> Vec<UpnpConfig> {
+    let candidates: Vec<_> = opened
+        .iter()
+        .map(|(config, _)| config)
+        .filter(|config| config.backend != Some(Backend::NatPmp) && !config.any_port)
+        .filter(|config| {
+            opened.iter().any(|(other, _)| {
+                other.port == config.port
+                    && other.protocol != config.protocol
+                    && other.address == config.address
+            })
+        })
+        .collect();
+
+    let addresses: HashSet<_> = candidates.iter().map(|config| &config.address).collect();
+
+    let mut dropped = Vec::new();
+    for address in addresses {
+        let entries = match address
+            .as_ref()
+            .map(Address::resolve)
+            .transpose()
+            .and_then(|address| easy_upnp::list_port_mappings(&address, Some(options)))
+        {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!(
+                    "Could not list port mappings to check the protocols: {}",
+                    err
+                );
+                continue;
+            }
+        };
+
+        for config in candidates
+            .iter()
+            .filter(|config| &config.address == address)
+        {
+            if !entries.iter().any(|entry| {
+                entry.external_port == config.port && entry.protocol == config.protocol
+            }) {
+                warn!(
+                    "Router seems to treat TCP and UDP port {} as a single slot, the {} port \
+                    mapping has been replaced",
+                    config.port, config.protocol
+                );
+                dropped.push((*config).clone());
+            }
+        }
+    }
+
+    dropped
+}
+
+/// Let the router choose the external ports of port mappings that have been staggered before,
+/// see [find_conflated_ports].
+fn apply_staggered(configs: &mut [UpnpConfig], staggered: &HashSet<(u16, PortMappingProtocol)>) {
+    for config in configs {
+        if staggered.contains(&(config.port, config.protocol)) {
+            config.any_port = true;
+        }
+    }
+}
+
+/// Warn about port mappings whose description has been changed by another client.
+fn detect_foreign_changes(mappings: &[(UpnpConfig, Option<PortMappingEntry>)]) {
+    for (config, entry) in mappings {
+        if let Some(entry) = entry
+            .as_ref()
+            .filter(|entry| entry.description != config.comment)
+        {
+            warn!(
+                "Description of port {} {} changed from \"{}\" to \"{}\", \
+                 it might have been modified by another client",
+                config.protocol, config.port, config.comment, entry.description
+            );
+        }
+    }
+}
+
+/// Remember the time of removal for created port mappings that are gone from their gateway.
+///
+/// Those port mappings are not regarded as created anymore.
+fn detect_manual_removals(
+    mappings: &[(UpnpConfig, Option<PortMappingEntry>)],
+    created: &mut CreatedMappings,
+    removals: &mut HashMap<(u16, PortMappingProtocol), Instant>,
+    cooldown: Duration,
+) {
+    for (config, _) in mappings.iter().filter(|(_, entry)| entry.is_none()) {
+        let key = (config.port, config.protocol);
+        info!(
+            "Port {} {} has been removed from the router, wait {} seconds before adding it again",
+            config.protocol,
+            config.port,
+            cooldown.as_secs()
+        );
+        created.remove(&key);
+        removals.insert(key, Instant::now());
+    }
+}
+
+/// Find the created port mappings that still exist on their router, for `--refresh-once`.
+///
+/// If `forget` is true, the port mappings that are gone from their router are not regarded as
+/// created anymore, so that they are added again.
+fn detect_vanished(
+    mappings: &[(UpnpConfig, Option<PortMappingEntry>)],
+    created: &mut CreatedMappings,
+    forget: bool,
+) -> HashSet<(u16, PortMappingProtocol)> {
+    let mut present = HashSet::new();
+    for (config, entry) in mappings {
+        let key = (config.port, config.protocol);
+        if entry.is_some() {
+            present.insert(key);
+        } else if forget {
+            info!(
+                "Port {} {} has vanished from the router, add it again",
+                config.protocol, config.port
+            );
+            created.remove(&key);
+        }
+    }
+
+    present
+}
+
+/// Find the gateway and its external IP before the first iteration, for `--prefetch`.
+///
+/// This is skipped if the port mappings have different addresses, or several address candidates,
+/// since they might need different gateways then.
+fn prefetch(
+    cli: &Cli,
+    sources: &[Source],
+    options: &DiscoveryOptions,
+) -> Option<PrefetchedGateway> {
+    let configs = cli.read_configs(sources).ok()?;
+    let mut addresses = configs
+        .iter()
+        .filter(|config| config.backend != Some(Backend::NatPmp))
+        .map(|config| &config.address);
+
+    let address = match addresses.next() {
+        Some(first) if addresses.any(|address| address != first) => {
+            debug!("Port mappings have different addresses, skip the prefetch");
+            return None;
+        }
+        Some(Some(Address::Candidates(_))) => {
+            debug!("Port mappings have several address candidates, skip the prefetch");
+            return None;
+        }
+        Some(Some(address)) => Some(address.resolve().ok()?),
+        Some(None) | None => None,
+    };
+
+    let started = Instant::now();
+    match easy_upnp::prefetch_gateway(&address, Some(options)) {
+        Ok(prefetched) => {
+            info!(
+                "Prefetched gateway {} with external IP {} in {:.2?}",
+                prefetched.gateway().addr,
+                prefetched.external_ip(),
+                started.elapsed()
+            );
+            Some(prefetched)
+        }
+        Err(err) => {
+            error!("Could not prefetch the gateway: {}", err);
+            None
+        }
+    }
+}
+
+/// The exit status of the program, as documented in the section about exit codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExitStatus {
+    Success = 0,
+    Failure = 1,
+    ConfigError = 2,
+    NoGateway = 3,
+    PartialFailure = 4,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    AlreadyRunning = 5,
+}
+
+impl ExitStatus {
+    /// The exit status for an error that ended the program.
+    fn of(err: &easy_upnp::UpnpError) -> Self {
+        match err {
+            easy_upnp::UpnpError::NoMatchingGateway
+            | easy_upnp::UpnpError::GatewayNotFound(_)
+            | easy_upnp::UpnpError::IgdSearchError(_)
+            | easy_upnp::UpnpError::CannotGetInterfaceAddress(_) => ExitStatus::NoGateway,
+            _ => ExitStatus::Failure,
+        }
+    }
+
+    fn exit(self) -> ! {
+        process::exit(self as i32)
+    }
+}
+
+/// Report whether a gateway is available and exit with a matching status code.
+fn probe(address: &Option<Ipv4Cidr>, options: &DiscoveryOptions) -> ! {
+    match easy_upnp::probe_gateway(address, Some(options)) {
+        Ok(info) => {
+            println!("Found gateway at {}", info.addr);
+            if let Some(friendly_name) = info.friendly_name {
+                println!("Friendly name: {}", friendly_name);
+            }
+            println!("External IP: {}", info.external_ip);
+            if info.missing_actions.is_empty() {
+                println!("Supported actions: all");
+            } else {
+                println!("Unsupported actions: {}", info.missing_actions.join(", "));
+            }
+            ExitStatus::Success.exit()
+        }
+        Err(err) => {
+            eprintln!("No UPnP gateway available: {}", err);
+            ExitStatus::of(&err).exit()
+        }
+    }
+}
+
+/// Ask the user on the terminal, defaulting to "no".
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    if stdout().flush().is_err() {
+        return false;
+    }
+
+    let mut answer = String::new();
+    match stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Whether the daemon is paused, toggled by `SIGUSR1`.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn toggle_pause(_: libc::c_int) {
+    PAUSED.fetch_xor(true, Ordering::SeqCst);
+}
+
+/// Return the PID of the daemon that is still running with the given PID file.
+///
+/// A PID file whose process has gone away or is not another upnp-daemon, or that does not contain
+/// a PID at all, is left over from a crashed daemon and will be removed.
+#[cfg(unix)]
+fn running_daemon(pid_file: &Path) -> std::io::Result<Option<u32>> {
+    let content = match std::fs::read_to_string(pid_file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let pid = content.trim().parse().ok();
+    if let Some(pid) = pid.filter(|&pid| running::Processes::new().is_pid_running_as_self(pid)) {
+        return Ok(Some(pid));
+    }
+
+    std::fs::remove_file(pid_file)?;
+    info!("Removed stale PID file {}", pid_file.display());
+    Ok(None)
+}
+
+/// Toggle the paused state on `SIGUSR1`.
+#[cfg(unix)]
+fn set_pause_handler() {
+    let handler = toggle_pause as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: The handler only touches an atomic, which is async-signal-safe.
+    let previous = unsafe { libc::signal(libc::SIGUSR1, handler) };
+    if previous == libc::SIG_ERR {
+        warn!("Could not set SIGUSR1 handler, pausing is not available");
+    }
+}
+
+/// Whether the user can be asked on the terminal, which needs the foreground and a free stdin.
+fn can_prompt(cli: &Cli) -> bool {
+    #[cfg(unix)]
+    if !cli.foreground {
+        return false;
+    }
+
+    stdin().is_terminal()
+        && !cli
+            .file
+            .iter()
+            .any(|input| matches!(input, CliInput::Stdin))
+}
+
+/// Ask before replacing port mappings of other clients, and skip the declined ones.
+///
+/// Declined port mappings are remembered and skipped from then on. If the user cannot be asked,
+/// conflicting port mappings are skipped for the current iteration only.
+fn confirm_replacements(
+    configs: &mut Vec<UpnpConfig>,
+    options: &DiscoveryOptions,
+    prompt: bool,
+    declined: &mut HashSet<(u16, PortMappingProtocol)>,
+) {
+    configs.retain(|config| {
+        let key = (config.port, config.protocol);
+        if declined.contains(&key) {
+            debug!(
+                "Skip port {} {}, replacing it has been declined",
+                config.protocol, config.port
+            );
+            return false;
+        }
+
+        if !matches!(config.plan(Some(options)), Ok(plan) if plan.action == PlannedAction::Replace)
+        {
+            return true;
+        }
+
+        let question = format!(
+            "Port {} {} is mapped to another client, replace it?",
+            config.protocol, config.port
+        );
+        if prompt && confirm(&question) {
+            return true;
+        }
+
+        info!(
+            "Skip port {} {}, it is mapped to another client",
+            config.protocol, config.port
+        );
+        if prompt {
+            declined.insert(key);
+        }
+        false
+    });
+}
+
+/// Remove every port mapping of the gateway and exit with a matching status code.
+fn remove_all(
+    address: &Option<Ipv4Cidr>,
+    yes: bool,
+    comment: Option<&str>,
+    options: &DiscoveryOptions,
+) -> ! {
+    let entries = match easy_upnp::list_port_mappings(address, Some(options)) {
+        Ok(entries) => entries
+            .into_iter()
+            .filter(|entry| match comment {
+                Some(pattern) => entry.description_matches(pattern),
+                None => true,
+            })
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            eprintln!("Could not list port mappings: {}", err);
+            ExitStatus::of(&err).exit()
+        }
+    };
+
+    if entries.is_empty() {
+        println!("No port mappings found");
+        ExitStatus::Success.exit()
+    }
+
+    for entry in &entries {
+        println!(
+            "{} {} -> {}:{} \"{}\"",
+            entry.protocol,
+            entry.external_port,
+            entry.internal_client,
+            entry.internal_port,
+            entry.description
+        );
+    }
+
+    if !yes && !confirm(&format!("Remove all {} port mappings?", entries.len())) {
+        println!("Aborted");
+        ExitStatus::Failure.exit()
+    }
+
+    let results = match easy_upnp::delete_port_mappings(address, entries, Some(options)) {
+        Ok(results) => results,
+        Err(err) => {
+            eprintln!("Could not remove port mappings: {}", err);
+            ExitStatus::of(&err).exit()
+        }
+    };
+
+    let mut failed = false;
+    let mut removed = 0;
+    for (entry, result) in results {
+        match result {
+            Ok(()) => {
+                println!("Removed {} {}", entry.protocol, entry.external_port);
+                removed += 1;
+            }
+            Err(err) => {
+                eprintln!(
+                    "Could not remove {} {}: {}",
+                    entry.protocol, entry.external_port, err
+                );
+                failed = true;
+            }
+        }
+    }
+    println!("Removed {} port mappings", removed);
+
+    if failed {
+        ExitStatus::PartialFailure.exit()
+    } else {
+        ExitStatus::Success.exit()
+    }
+}
+
+/// Renew a single port mapping and exit with a matching status code.
+fn renew(
+    port: u16,
+    protocol: PortMappingProtocol,
+    address: &Option<Ipv4Cidr>,
+    duration: u32,
+    comment: Option<String>,
+    options: &DiscoveryOptions,
+) -> ! {
+    let existing = match easy_upnp::list_port_mappings(address, Some(options)) {
+        Ok(entries) => entries
+            .into_iter()
+            .find(|entry| entry.external_port == port && entry.protocol == protocol),
+        Err(err) => {
+            eprintln!("Could not list port mappings: {}", err);
+            ExitStatus::of(&err).exit()
+        }
+    };
+
+    let comment = match (comment, &existing) {
+        (Some(comment), _) => comment,
+        (None, Some(entry)) => entry.description.clone(),
+        (None, None) => String::new(),
+    };
+
+    let config = UpnpConfig {
+        address: address.map(Address::from),
+        duration: LeaseDuration::Seconds(duration),
+        comment,
+        ..UpnpConfig::new(port, protocol)
+    };
+
+    // Do not take over a port that is forwarded to another host
+    if let Some(entry) = &existing {
+        match config.plan(Some(options)) {
+            Ok(plan) if plan.action == PlannedAction::Replace => {
+                eprintln!(
+                    "{} {} is forwarded to {}:{}, not renewing it for this host",
+                    protocol, port, entry.internal_client, entry.internal_port
+                );
+                ExitStatus::Failure.exit()
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Could not renew {} {}: {}", protocol, port, err);
+                ExitStatus::of(&err).exit()
+            }
+        }
+    }
+
+    match easy_upnp::add_ports([config], Some(options)).next() {
+        Some(Ok(_)) if existing.is_some() => {
+            println!("Renewed {} {} for {} seconds", protocol, port, duration)
+        }
+        Some(Ok(_)) => println!(
+            "No port mapping for {} {} existed, created it for {} seconds",
+            protocol, port, duration
+        ),
+        Some(Err(err)) => {
+            eprintln!("Could not renew {} {}: {}", protocol, port, err);
+            ExitStatus::of(&err).exit()
+        }
+        None => unreachable!("add_ports returns one result per config"),
+    }
+
+    ExitStatus::Success.exit()
+}
+
+/// Write the port mappings of the gateway as configs and exit with a matching status code.
+fn export(
+    address: &Option<Ipv4Cidr>,
+    format: CliOutputFormat,
+    output: &Path,
+    delimiter: char,
+    options: &DiscoveryOptions,
+) -> ! {
+    let entries = match easy_upnp::list_port_mappings(address, Some(options)) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Could not list port mappings: {}", err);
+            ExitStatus::of(&err).exit()
+        }
+    };
+
+    let configs: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| {
+            if entry.internal_port != entry.external_port {
+                warn!(
+                    "Port mapping {} {} forwards to internal port {}, exporting it as port {}",
+                    entry.protocol, entry.external_port, entry.internal_port, entry.external_port
+                );
+            }
+
+            UpnpConfig::from_port_mapping_entry(entry)
+                .map_err(|err| {
+                    warn!(
+                        "Skipping {} {}: {}",
+                        entry.protocol, entry.external_port, err
+                    )
+                })
+                .ok()
+        })
+        .collect();
+
+    let writer: Box<dyn Write> = if output.as_os_str() == "-" {
+        Box::new(stdout())
+    } else {
+        match File::create(output) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(err) => {
+                eprintln!("Could not create {}: {}", output.display(), err);
+                ExitStatus::Failure.exit()
+            }
+        }
+    };
+
+    let result = match format {
+        CliOutputFormat::Csv => easy_upnp::write_configs_csv(writer, &configs, delimiter as u8),
+        CliOutputFormat::Json => easy_upnp::write_configs_json(writer, &configs),
+    };
+
+    match result {
+        Ok(()) => {
+            info!("Exported {} port mappings", configs.len());
+            ExitStatus::Success.exit()
+        }
+        Err(err) => {
+            eprintln!("Could not export port mappings: {}", err);
+            ExitStatus::Failure.exit()
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DryRunAction {
+    Add,
+    Refresh,
+    Enable,
+    Replace,
+    Skip,
+    Error,
+}
+
+impl From<PlannedAction> for DryRunAction {
+    fn from(action: PlannedAction) -> Self {
+        match action {
+            PlannedAction::Add => DryRunAction::Add,
+            PlannedAction::Refresh => DryRunAction::Refresh,
+            PlannedAction::Enable => DryRunAction::Enable,
+            PlannedAction::Replace => DryRunAction::Replace,
+        }
+    }
+}
+
+/// What would be done for a single port mapping, as shown by `--dry-run`.
+#[derive(Serialize)]
+struct DryRunEntry {
+    port: u16,
+    protocol: PortMappingProtocol,
+    comment: String,
+    action: DryRunAction,
+    gateway: Option<GatewayInfo>,
+    local_addr: Option<SocketAddrV4>,
+    reason: Option<String>,
+}
+
+impl DryRunEntry {
+    fn new(config: &UpnpConfig, action: DryRunAction, reason: Option<String>) -> Self {
+        Self {
+            port: config.port,
+            protocol: config.protocol,
+            comment: config.comment.clone(),
+            action,
+            gateway: None,
+            local_addr: None,
+            reason,
+        }
+    }
+
+    fn plan(config: &UpnpConfig, cli: &Cli, processes: &mut Option<running::Processes>) -> Self {
+        if let Some(process) = &config.requires_process {
+            if !processes
+                .get_or_insert_with(running::Processes::new)
+                .is_running(process)
+            {
+                let reason = "required process is not running".to_string();
+                return Self::new(config, DryRunAction::Skip, Some(reason));
+            }
+        }
+
+        let options = cli.discovery_options();
+        if let Some(threshold) = cli.renew_threshold {
+            if !needs_renewal(config, threshold, &options) {
+                let reason = "remaining lease is above the renew threshold".to_string();
+                return Self::new(config, DryRunAction::Skip, Some(reason));
+            }
+        }
+
+        match config.plan(Some(&options)) {
+            Ok(plan) => Self {
+                gateway: plan.gateway,
+                local_addr: plan.local_addr,
+                ..Self::new(config, plan.action.into(), None)
+            },
+            Err(err) => Self::new(config, DryRunAction::Error, Some(err.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for DryRunEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            DryRunAction::Add => "add",
+            DryRunAction::Refresh => "refresh",
+            DryRunAction::Enable => "enable",
+            DryRunAction::Replace => "replace",
+            DryRunAction::Skip => "skip",
+            DryRunAction::Error => "error",
+        };
+        write!(f, "{} {} {}", action, self.protocol, self.port)?;
+
+        if let Some(local_addr) = self.local_addr {
+            write!(f, " -> {}", local_addr)?;
+        }
+        if let Some(gateway) = &self.gateway {
+            write!(f, " via {}", gateway.addr)?;
+            if let Some(friendly_name) = &gateway.friendly_name {
+                write!(f, " ({})", friendly_name)?;
+            }
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse one line of streamed input, `headers` holds the names of the CSV columns once known.
+///
+/// Returns [None] for lines that do not contain a config, like empty lines and the CSV header.
+fn parse_stream_line(
+    cli: &Cli,
+    line: &str,
+    format: &mut CliInputFormat,
+    headers: &mut Option<Vec<String>>,
+) -> Option<easy_upnp::Result<UpnpConfig>> {
+    if line.trim().is_empty() {
+        return None;
+    }
+
+    if *format == CliInputFormat::Auto {
+        *format = match line.trim_start().as_bytes()[0] {
+            b'{' | b'[' => CliInputFormat::Json,
+            _ => CliInputFormat::Csv,
+        };
+    }
+
+    if *format == CliInputFormat::Json {
+        return Some(
+            serde_json::from_str(line)
+                .map_err(easy_upnp::UpnpError::from)
+                .and_then(|value| UpnpConfig::from_json_value(&value)),
+        );
+    }
+
+    let Some(headers) = headers else {
+        *headers = Some(
+            line.split(cli.csv_delimiter)
+                .map(ToString::to_string)
+                .collect(),
+        );
+        return None;
+    };
+
+    easy_upnp::parse_configs_csv_with_columns(line.as_bytes(), cli.csv_delimiter as u8, headers)
+        .next()
+}
+
+/// What the reader thread of [stream_stdin] reports.
+enum StreamEvent {
+    /// A line of the input.
+    Line(std::io::Result<String>),
+
+    /// The end of the input.
+    End,
+
+    /// Ctrl-C or a termination signal.
+    Interrupted,
+}
+
+/// Apply the port mappings from stdin one by one, as soon as each line arrives.
+///
+/// Each config is applied only once, the program ends at the end of the input or when it is
+/// interrupted.
+fn stream_stdin(cli: &Cli) -> anyhow::Result<ExitStatus> {
+    let mut format = cli.formats()[0];
+    if matches!(format, CliInputFormat::Yaml | CliInputFormat::Toml) {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--stream-stdin only supports CSV and JSON Lines",
+            )
+            .exit();
+    }
+
+    let options = cli.discovery_options();
+    let mut stats = Stats::new();
+    let mut errors = ErrorLog::new(Duration::from_secs(cli.log_dedup_window));
+    let mut opened = HashMap::new();
+    let mut headers = cli.no_header.then(|| cli.csv_columns());
+
+    let (tx_events, rx_events) = channel();
+
+    {
+        let tx_events = tx_events.clone();
+        let interrupted = AtomicBool::new(false);
+        ctrlc::set_handler(move || {
+            if interrupted.swap(true, Ordering::SeqCst) {
+                warn!("Interrupted again, exit immediately");
+                ExitStatus::Failure.exit();
+            }
+
+            // The receiver is gone if the program is already shutting down
+            let _ = tx_events.send(StreamEvent::Interrupted);
+        })
+        .expect("Error setting Ctrl-C handler");
+    }
+
+    // Read in the background, so that an interrupt does not wait for the next line
+    thread::spawn(move || {
+        for line in stdin().lock().lines() {
+            if tx_events.send(StreamEvent::Line(line)).is_err() {
+                return;
+            }
+        }
+        let _ = tx_events.send(StreamEvent::End);
+    });
+
+    loop {
+        let line = match rx_events.recv() {
+            Ok(StreamEvent::Line(line)) => line?,
+            Ok(StreamEvent::Interrupted) => {
+                debug!("Interrupted, stop reading the input");
+                break;
+            }
+            Ok(StreamEvent::End) | Err(_) => {
+                debug!("End of input reached");
+                break;
+            }
+        };
+
+        let Some(config) = parse_stream_line(cli, &line, &mut format, &mut headers)
+            .and_then(filter_out_and_log_errors)
+        else {
+            continue;
+        };
+
+        stats.ticks += 1;
+        let configs = cli.prepare(vec![config]);
+        let attempted = configs.len();
+        let added = add_ports(
+            configs,
+            &options,
+            &mut errors,
+            cli.on_add.as_deref(),
+            cli.report_outcomes,
+        );
+
+        stats.added += added.len() as u64;
+        stats.failed += (attempted - added.len()) as u64;
+        for (config, _) in added {
+            opened.insert((config.port, config.protocol), config);
+        }
+    }
+
+    stats.log_summary();
+
+    if cli.close_ports_on_exit {
+        delete_ports(
+            opened.into_values().collect(),
+            &options,
+            cli.on_remove.as_deref(),
+        );
+    }
+
+    if stats.failed > 0 {
+        Ok(ExitStatus::PartialFailure)
+    } else {
+        Ok(ExitStatus::Success)
+    }
+}
+
+/// Print what would be done for each configured port mapping, without changing anything.
+fn dry_run(cli: &Cli, sources: &[Source]) -> anyhow::Result<ExitStatus> {
+    let mut processes = None;
+    let entries: Vec<_> = cli
+        .read_configs(sources)?
+        .iter()
+        .map(|config| DryRunEntry::plan(config, cli, &mut processes))
+        .collect();
+
+    match cli.plan_format {
+        CliPlanFormat::Text => entries.iter().for_each(|entry| println!("{}", entry)),
+        CliPlanFormat::Json => {
+            serde_json::to_writer_pretty(stdout(), &entries)?;
+            println!();
+        }
+    }
+
+    if entries
+        .iter()
+        .any(|entry| matches!(entry.action, DryRunAction::Error))
+    {
+        Ok(ExitStatus::PartialFailure)
+    } else {
+        Ok(ExitStatus::Success)
+    }
+}
+
+/// Keep only the configs whose required process is running.
+///
+/// Port mappings that have been opened for a process that is not running anymore are deleted.
+/// `gated` holds the port mappings that have been passed on while their process was running, and
+/// deleted port mappings are removed from `created`.
+fn filter_by_process(
+    configs: Vec<UpnpConfig>,
+    gated: &mut HashSet<(u16, PortMappingProtocol)>,
+    created: &mut CreatedMappings,
+    options: &DiscoveryOptions,
+    on_remove: Option<&str>,
+) -> Vec<UpnpConfig> {
+    if configs
+        .iter()
+        .all(|config| config.requires_process.is_none())
+    {
+        return configs;
+    }
+
+    let processes = running::Processes::new();
+    let (running, stopped): (Vec<_>, Vec<_>) =
+        configs
+            .into_iter()
+            .partition(|config| match &config.requires_process {
+                Some(process) => processes.is_running(process),
+                None => true,
+            });
+
+    let mut closing = Vec::new();
+    for config in stopped {
+        if gated.remove(&(config.port, config.protocol)) {
+            created.remove(&(config.port, config.protocol));
+            info!(
+                "Required process of port {} {} is not running anymore, close the port",
+                config.protocol, config.port
+            );
+            closing.push(config);
+        } else {
+            debug!(
+                "Skip port {} {}, required process is not running",
+                config.protocol, config.port
+            );
+        }
+    }
+
+    if !closing.is_empty() {
+        delete_ports(closing, options, on_remove);
+    }
+
+    gated.extend(
+        running
+            .iter()
+            .filter(|config| config.requires_process.is_some())
+            .map(|config| (config.port, config.protocol)),
+    );
+
+    running
+}
+
+fn delete_ports(configs: Vec<UpnpConfig>, options: &DiscoveryOptions, on_remove: Option<&str>) {
+    for (result, config) in easy_upnp::delete_ports(configs.clone(), Some(options)).zip(configs) {
+        match result {
+            Ok(()) => {
+                if let Some(command) = on_remove {
+                    run_hook(command, &config);
+                }
+            }
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
+/// Run a hook command for a port mapping, passing its details as environment variables.
+///
+/// Failures are only logged, they do not affect the port mapping.
+fn run_hook(command: &str, config: &UpnpConfig) {
+    let mut args = command.split_whitespace();
+    let Some(program) = args.next() else {
+        return;
+    };
+
+    let address = config
+        .resolve_address()
+        .ok()
+        .flatten()
+        .map(|cidr| cidr.to_string())
+        .unwrap_or_default();
+
+    let status = process::Command::new(program)
+        .args(args)
+        .env("UPNP_PORT", config.port.to_string())
+        .env("UPNP_PROTOCOL", config.protocol.to_string())
+        .env("UPNP_ADDRESS", address)
+        .env("UPNP_COMMENT", &config.comment)
+        .status();
+
+    match status {
+        Ok(status) if status.success() => {
+            debug!("Hook \"{}\" for port {} succeeded", command, config.port)
+        }
+        Ok(status) => warn!(
+            "Hook \"{}\" for port {} failed: {}",
+            command, config.port, status
+        ),
+        Err(err) => warn!(
+            "Hook \"{}\" for port {} could not be run: {}",
+            command, config.port, err
+        ),
+    }
+}
+
+/// Counters that are accumulated over the whole runtime of the daemon.
+struct Stats {
+    started: Instant,
+    ticks: u64,
+    added: u64,
+    failed: u64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            ticks: 0,
+            added: 0,
+            failed: 0,
+        }
+    }
+
+    /// Log the outcome of a single iteration, for `--summary`.
+    fn log_tick(added: usize, skipped: usize, failed: usize, started: Instant) {
+        info!(
+            "{} added, {} skipped, {} failed in {:.1}s",
+            added,
+            skipped,
+            failed,
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    fn log_summary(&self) {
+        info!(
+            "Summary: {} iterations, {} ports added, {} failures, uptime {}s",
+            self.ticks,
+            self.added,
+            self.failed,
+            self.started.elapsed().as_secs()
+        );
+    }
+
+    /// Render the counters in the text format of Prometheus, for the textfile collector of the
+    /// node_exporter.
+    fn metrics(&self, external_ip: Option<Ipv4Addr>, now: SystemTime) -> String {
+        let timestamp = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut metrics = format!(
+            "# HELP upnp_daemon_iterations_total Iterations since the daemon started.\n\
+            # TYPE upnp_daemon_iterations_total counter\n\
+            upnp_daemon_iterations_total {}\n\
+            # HELP upnp_daemon_port_mappings_added_total Port mappings that have been added.\n\
+            # TYPE upnp_daemon_port_mappings_added_total counter\n\
+            upnp_daemon_port_mappings_added_total {}\n\
+            # HELP upnp_daemon_port_mapping_failures_total Port mappings that could not be added.\n\
+            # TYPE upnp_daemon_port_mapping_failures_total counter\n\
+            upnp_daemon_port_mapping_failures_total {}\n\
+            # HELP upnp_daemon_last_iteration_timestamp_seconds Time of the last iteration.\n\
+            # TYPE upnp_daemon_last_iteration_timestamp_seconds gauge\n\
+            upnp_daemon_last_iteration_timestamp_seconds {:.3}\n",
+            self.ticks, self.added, self.failed, timestamp
+        );
+        if let Some(external_ip) = external_ip {
+            metrics.push_str(&format!(
+                "# HELP upnp_daemon_external_ip_info The external IP address of the gateway.\n\
+                # TYPE upnp_daemon_external_ip_info gauge\n\
+                upnp_daemon_external_ip_info{{address=\"{}\"}} 1\n",
+                external_ip
+            ));
+        }
+
+        metrics
+    }
+
+    /// Replace the metrics file, via a temporary file in the same directory, so that the
+    /// collector never reads a partially written file.
+    fn write_metrics(&self, path: &Path, external_ip: Option<Ipv4Addr>) -> std::io::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::Builder::new()
+            .prefix(".upnp-daemon")
+            .suffix(".tmp")
+            .tempfile_in(dir)?;
+        file.write_all(self.metrics(external_ip, SystemTime::now()).as_bytes())?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+/// Parse an octal file mode creation mask, like "022" or "0o022".
+#[cfg(unix)]
+fn parse_umask(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+
+    match u32::from_str_radix(digits, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(format!(
+            "\"{}\" is not an octal mask between 000 and 777",
+            value
+        )),
+    }
+}
+
+/// Parse a cron expression for `--cron`, where the leading field for the seconds may be left out.
+///
+/// Without the seconds, the days of the week are numbered like in a crontab, from 0 for Sunday,
+/// and converted to the numbering of the [cron] crate, from 1 for Sunday.
+fn parse_cron(value: &str) -> Result<Schedule, String> {
+    let fields: Vec<_> = value.split_whitespace().collect();
+    let expression = if let [minutes, hours, days, months, days_of_week] = fields[..] {
+        format!(
+            "0 {} {} {} {} {}",
+            minutes,
+            hours,
+            days,
+            months,
+            crontab_days_of_week(days_of_week)
+        )
+    } else {
+        value.to_string()
+    };
+
+    Schedule::from_str(&expression)
+        .map_err(|_| format!("\"{}\" is not a cron expression with 5 to 7 fields", value))
+}
+
+/// Convert the days of the week of a crontab, where Sunday is 0 or 7, to the numbering of the
+/// [cron] crate, where Sunday is 1.
+///
+/// Names and steps of all days are the same in both, so they are kept as they are.
+fn crontab_days_of_week(field: &str) -> String {
+    field
+        .split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, step.parse::<usize>().ok()),
+                None => (item, Some(1)),
+            };
+            let bounds = match range.split_once('-') {
+                Some((first, last)) => first.parse::<u32>().ok().zip(last.parse().ok()),
+                None => range.parse().ok().map(|day| (day, day)),
+            };
+            match (bounds, step) {
+                (Some((first, last)), Some(step)) if step > 0 && first <= last && last <= 7 => {
+                    (first..=last)
+                        .step_by(step)
+                        .map(|day| (day % 7 + 1).to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                }
+                _ => item.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The number of upcoming runs of a cron schedule that are looked at to find the longest gap.
+const CRON_LOOKAHEAD: usize = 32;
+
+/// The time from `now` until the next run of the schedule, if there is one.
+fn until_next_run<Tz: TimeZone>(schedule: &Schedule, now: &DateTime<Tz>) -> Option<Duration> {
+    let next = schedule.after(now).next()?;
+    Some((next - now.clone()).to_std().unwrap_or_default())
+}
+
+/// The longest time between two of the upcoming runs of the schedule.
+fn longest_gap<Tz: TimeZone>(schedule: &Schedule, now: &DateTime<Tz>) -> Option<Duration> {
+    let runs: Vec<_> = schedule.after(now).take(CRON_LOOKAHEAD).collect();
+    runs.windows(2)
+        .filter_map(|runs| (runs[1].clone() - runs[0].clone()).to_std().ok())
+        .max()
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliInputFormat {
+    Auto,
+    Csv,
+    Json,
+    Yaml,
+    Toml,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliLogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliPlanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliOutputFormat {
+    Csv,
+    Json,
+}
+
+impl CliOutputFormat {
+    /// Guess the format from the file extension, CSV is the fallback.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => CliOutputFormat::Json,
+            _ => CliOutputFormat::Csv,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliProtocol {
+    Tcp,
+    Udp,
+}
+
+impl From<CliProtocol> for PortMappingProtocol {
+    fn from(protocol: CliProtocol) -> Self {
+        match protocol {
+            CliProtocol::Tcp => PortMappingProtocol::TCP,
+            CliProtocol::Udp => PortMappingProtocol::UDP,
+        }
+    }
+}
+
+/// What to do if the router treats the TCP and UDP ports with the same number as one.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliProtocolPortsPolicy {
+    Warn,
+    Stagger,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliAddressFamily {
+    V4,
+    V6,
+}
+
+impl From<CliAddressFamily> for AddressFamily {
+    fn from(family: CliAddressFamily) -> Self {
+        match family {
+            CliAddressFamily::V4 => AddressFamily::V4,
+            CliAddressFamily::V6 => AddressFamily::V6,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliMatchPolicy {
+    Name,
+    LowestIp,
+}
+
+impl From<CliMatchPolicy> for MatchPolicy {
+    fn from(policy: CliMatchPolicy) -> Self {
+        match policy {
+            CliMatchPolicy::Name => MatchPolicy::Name,
+            CliMatchPolicy::LowestIp => MatchPolicy::LowestIp,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliBackend {
+    Upnp,
+    Natpmp,
+}
+
+impl From<CliBackend> for Backend {
+    fn from(backend: CliBackend) -> Self {
+        match backend {
+            CliBackend::Upnp => Backend::Upnp,
+            CliBackend::Natpmp => Backend::NatPmp,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Check whether a UPnP gateway is available, without opening any ports
+    Probe {
+        /// Only search for a gateway that is responsible for the given address
+        #[arg(long)]
+        address: Option<Ipv4Cidr>,
+    },
+
+    /// Remove all port mappings of the gateway, regardless of any configuration
+    RemoveAll {
+        /// Only search for a gateway that is responsible for the given address
+        #[arg(long)]
+        address: Option<Ipv4Cidr>,
+
+        /// Do not ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+
+        /// Only remove port mappings whose comment is equal to this, or matches it with * and ?
+        #[arg(long, value_name = "PATTERN")]
+        comment: Option<String>,
+    },
+
+    /// Renew the lease of a single port mapping once, or create it if it does not exist
+    Renew {
+        /// The external port of the port mapping
+        #[arg(long)]
+        port: u16,
+
+        /// The protocol of the port mapping
+        #[arg(long, value_enum)]
+        protocol: CliProtocol,
+
+        /// Only search for a gateway that is responsible for the given address
+        #[arg(long)]
+        address: Option<Ipv4Cidr>,
+
+        /// The new lease duration in seconds
+        #[arg(long, default_value_t = 3600)]
+        duration: u32,
+
+        /// The comment of the port mapping [default: the comment of the existing mapping]
+        #[arg(long)]
+        comment: Option<String>,
+    },
+
+    /// Write the current port mappings of the gateway to a config file
+    Export {
+        /// Only search for a gateway that is responsible for the given address
+        #[arg(long)]
+        address: Option<Ipv4Cidr>,
+
+        /// The format of the config file [default: by file extension]
+        #[arg(long, value_enum)]
+        format: Option<CliOutputFormat>,
+
+        /// The config file to write, or "-" for stdout
+        #[arg(long, short, default_value = "-")]
+        output: PathBuf,
+
+        /// Field delimiter when writing CSV files
+        #[arg(long, short = 'd', default_value_t = ';')]
+        csv_delimiter: char,
+    },
+}
+
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
-    /// The file (or "-" for stdin) with the port descriptions
-    #[arg(long, short, value_parser = PathBufValueParser::new().try_map(CliInput::try_from))]
-    file: CliInput,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    /// The file (or "-" for stdin) with the port descriptions, can be given multiple times
+    #[arg(
+        long,
+        short,
+        value_parser = PathBufValueParser::new().try_map(CliInput::try_from)
+    )]
+    #[cfg_attr(
+        all(unix, feature = "systemd"),
+        arg(required_unless_present_any = ["from_env", "from_systemd_sockets"])
+    )]
+    #[cfg_attr(
+        not(all(unix, feature = "systemd")),
+        arg(required_unless_present = "from_env")
+    )]
+    file: Vec<CliInput>,
+
+    /// Read one more port mapping from the UPNP_* environment variables
+    #[arg(long, conflicts_with = "stream_stdin")]
+    from_env: bool,
+
+    /// Forward the ports of the sockets that systemd passed to this process
+    #[cfg(all(unix, feature = "systemd"))]
+    #[arg(long, conflicts_with = "stream_stdin")]
+    from_systemd_sockets: bool,
+
+    /// The port mappings of the sockets of --from-systemd-sockets, received once at startup
+    #[cfg(all(unix, feature = "systemd"))]
+    #[arg(skip)]
+    systemd_sockets: Vec<UpnpConfig>,
+
+    /// The format of the configuration files, once for all or once per file [default: by file extension, or auto]
+    #[arg(long, value_enum)]
+    format: Vec<CliInputFormat>,
+
+    /// Field delimiter when using CSV files
+    #[arg(long, short = 'd', default_value_t = ';')]
+    csv_delimiter: char,
+
+    /// Treat the first line of CSV files as data instead of a header
+    #[arg(long)]
+    no_header: bool,
+
+    /// Comma separated names of the columns in CSV files without a header
+    #[arg(
+        long,
+        value_name = "SPEC",
+        value_delimiter = ',',
+        value_parser = PossibleValuesParser::new(UpnpConfig::FIELDS),
+        requires = "no_header"
+    )]
+    csv_columns: Vec<String>,
+
+    /// The backend for port mappings that do not specify one
+    #[arg(long, value_name = "BACKEND", value_enum, default_value_t = CliBackend::Upnp)]
+    protocol_backend: CliBackend,
+
+    /// Run in foreground instead of forking to background
+    #[cfg(unix)]
+    #[arg(long, short = 'F')]
+    foreground: bool,
+
+    /// Run just one time instead of continuously
+    #[arg(long, short = '1')]
+    oneshot: bool,
+
+    /// Specify update interval in seconds
+    #[arg(long, short = 'n', default_value_t = 60)]
+    interval: u64,
+
+    /// Run at the times of this cron expression instead of every interval, like "*/5 * * * *"
+    #[arg(long, value_name = "EXPR", value_parser = parse_cron, conflicts_with = "oneshot")]
+    cron: Option<Schedule>,
+
+    /// Only process port mappings whose comment matches the given pattern
+    #[arg(long, value_name = "PATTERN")]
+    filter_comment: Option<String>,
+
+    /// Comma separated positions like "2,4-6", or "comment:PATTERN", to only process those entries
+    #[arg(long, value_name = "SPEC", conflicts_with = "stream_stdin")]
+    select: Vec<ConfigSelector>,
+
+    /// The entries of --select that matched nothing and have been warned about
+    #[arg(skip)]
+    warned_selectors: Mutex<HashSet<ConfigSelector>>,
+
+    /// Ignore content after the array in JSON files, instead of rejecting the whole file
+    #[arg(long)]
+    lenient_json: bool,
+
+    /// The dnsmasq lease file to look up the hostnames of internal clients in
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = "/var/lib/misc/dnsmasq.leases"
+    )]
+    dhcp_leases: PathBuf,
+
+    /// Prefix for the comments of all port mappings, like the name of this host
+    #[arg(long, value_name = "PREFIX")]
+    comment_prefix: Option<String>,
+
+    /// Template for the descriptions on the router, with {comment}, {date}, {host} and {version}
+    #[arg(long, value_name = "FORMAT", value_parser = parse_description_template)]
+    description_template: Option<String>,
+
+    /// Use this lease duration in seconds for all port mappings, instead of the configured ones
+    #[arg(long, value_name = "SECONDS")]
+    duration_override: Option<u32>,
+
+    /// Comma separated protocols to handle, port mappings of other protocols are skipped
+    #[arg(
+        long,
+        value_name = "PROTOCOLS",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [CliProtocol::Tcp, CliProtocol::Udp]
+    )]
+    protocols: Vec<CliProtocol>,
+
+    /// Comma separated ports or port ranges like "30000-40000", to refuse all other external ports
+    #[arg(long, value_name = "RANGE", value_delimiter = ',')]
+    allowed_ports: Vec<PortRange>,
+
+    /// Wait the given number of seconds before the first iteration
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    startup_delay: u64,
+
+    /// Only refresh port mappings whose remaining lease is below this percentage of the duration
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    renew_threshold: Option<u8>,
+
+    /// Only add each port mapping once, until it changes or vanishes from the router
+    #[arg(long)]
+    refresh_once: bool,
+
+    /// Only add port mappings if the configuration has changed, or if the leases need renewal
+    #[arg(long)]
+    once_per_change: bool,
+
+    /// Renew unchanged port mappings after this many seconds, instead of half the shortest lease
+    #[arg(long, value_name = "SECONDS", requires = "once_per_change")]
+    renew_interval: Option<u64>,
+
+    /// Local port for the discovery of gateways, 0 for a random port
+    #[arg(long, value_name = "PORT", default_value_t = 0, global = true)]
+    ssdp_bind_port: u16,
+
+    /// Send the discovery of gateways to this address, instead of the SSDP multicast address
+    #[arg(long, value_name = "ADDR", global = true)]
+    ssdp_address: Option<SocketAddrV4>,
+
+    /// Use the gateway with this device description URL, without any discovery
+    #[arg(long, value_name = "URL", global = true)]
+    gateway_url: Option<String>,
+
+    /// Comma separated interface names or address ranges, to try those interfaces first
+    #[arg(long, value_name = "IFACES", value_delimiter = ',', global = true)]
+    interface_priority: Vec<InterfaceSelector>,
+
+    /// Comma separated interface names or address ranges, to never try those interfaces
+    #[arg(long, value_name = "IFACES", value_delimiter = ',', global = true)]
+    exclude_interfaces: Vec<InterfaceSelector>,
+
+    /// The order of interfaces with the same priority that match the address of a port mapping
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = CliMatchPolicy::Name,
+        global = true
+    )]
+    match_policy: CliMatchPolicy,
+
+    /// Seconds to wait for a gateway to answer the discovery
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = easy_upnp::DEFAULT_SEARCH_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        global = true
+    )]
+    discovery_timeout: u64,
+
+    /// How often to repeat the discovery on an interface if no gateway answers in time
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = easy_upnp::DEFAULT_DISCOVERY_RETRIES,
+        global = true
+    )]
+    discovery_retries: u32,
+
+    /// Log the options of each discovery at debug level, for bug reports
+    #[arg(long, global = true)]
+    debug_search_options: bool,
+
+    /// The local address to search for gateways from, if an interface has it
+    #[arg(long, value_name = "IP", global = true)]
+    bind_ip: Option<Ipv4Addr>,
+
+    /// Only use gateways whose friendly name contains this string
+    #[arg(long, value_name = "NAME", global = true)]
+    gateway_name: Option<String>,
+
+    /// Comma separated ports whose existing port mappings are never deleted to make room
+    #[arg(long, value_name = "PORTS", value_delimiter = ',', global = true)]
+    protect_port: Vec<u16>,
+
+    /// The address family of the interfaces to try first, only IPv4 is supported so far
+    #[arg(
+        long,
+        value_name = "FAMILY",
+        value_enum,
+        default_value_t = CliAddressFamily::V4,
+        global = true
+    )]
+    prefer_family: CliAddressFamily,
+
+    /// Append all requests to the gateway and their responses to this file, for debugging
+    #[arg(long, value_name = "FILE", global = true)]
+    record_soap: Option<PathBuf>,
+
+    /// Answer the requests to the gateway from a file of --record-soap, for reproducing bugs
+    #[arg(long, value_name = "FILE", global = true, hide = true)]
+    replay_soap: Option<PathBuf>,
+
+    /// The format of the log output
+    #[arg(long, value_enum, default_value_t = CliLogFormat::Text, global = true)]
+    log_format: CliLogFormat,
+
+    /// Write metrics for the textfile collector of the Prometheus node_exporter to this file
+    #[arg(long, value_name = "FILE")]
+    metrics_textfile: Option<PathBuf>,
+
+    /// Log one summary line per iteration instead of one line per port mapping
+    #[arg(long)]
+    summary: bool,
+
+    /// Look up each port mapping before adding it, to log whether it has been created, refreshed or left unchanged
+    #[arg(long)]
+    report_outcomes: bool,
+
+    /// Log repeated identical errors of a port mapping only once in this many seconds, 0 to log all
+    #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+    log_dedup_window: u64,
+
+    /// Warn if the description of a created port mapping has been changed by another client
+    #[arg(long)]
+    detect_foreign_changes: bool,
+
+    /// Check whether the router keeps TCP and UDP mappings of the same port apart
+    #[arg(long, value_name = "MODE", value_enum)]
+    distinct_protocol_ports: Option<CliProtocolPortsPolicy>,
+
+    /// Do not add created port mappings again right away if they have been removed on the router
+    #[arg(long)]
+    respect_manual_removal: bool,
+
+    /// Seconds to wait before adding a manually removed port mapping again
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 3600,
+        requires = "respect_manual_removal"
+    )]
+    removal_cooldown: u64,
+
+    /// Exit right away if no gateway can be found at startup, instead of retrying each iteration
+    #[arg(long)]
+    require_gateway: bool,
+
+    /// Find the gateway and its external IP once at startup, and reuse them in the first iteration
+    #[arg(long, conflicts_with = "stream_stdin")]
+    prefetch: bool,
+
+    /// Skip port mappings with a lease duration shorter than the interval, instead of warning
+    #[arg(long)]
+    strict: bool,
+
+    /// Ask before replacing a port mapping of another client, skip it without a terminal
+    #[arg(long)]
+    interactive: bool,
+
+    /// Command to run after each successful addition of a port mapping
+    #[arg(long, value_name = "CMD")]
+    on_add: Option<String>,
+
+    /// Command to run after each successful removal of a port mapping
+    #[arg(long, value_name = "CMD")]
+    on_remove: Option<String>,
+
+    /// Close specified ports on program exit
+    #[arg(long)]
+    close_ports_on_exit: bool,
+
+    /// Check that closed ports are really gone, and try once more if they are not
+    #[arg(long)]
+    verify_removal: bool,
+
+    /// Only close specified ports and exit
+    #[arg(long)]
+    only_close_ports: bool,
+
+    /// Apply the port mappings from stdin line by line as they arrive, instead of reading it whole
+    #[arg(long, conflicts_with_all = ["once_per_change", "only_close_ports", "dry_run"])]
+    stream_stdin: bool,
+
+    /// Only show what would be done for each port mapping, without changing anything
+    #[arg(long, conflicts_with = "only_close_ports")]
+    dry_run: bool,
+
+    /// The format of the dry run output
+    #[arg(long, value_enum, default_value_t = CliPlanFormat::Text, requires = "dry_run")]
+    plan_format: CliPlanFormat,
+
+    /// Absolute path to PID file for daemon mode
+    #[cfg(unix)]
+    #[arg(long, default_value = "/tmp/upnp-daemon.pid")]
+    pid_file: PathBuf,
+
+    /// Working directory for daemon mode, instead of the root directory
+    #[cfg(unix)]
+    #[arg(long, value_name = "DIR")]
+    working_dir: Option<PathBuf>,
+
+    /// Octal file mode creation mask for daemon mode, instead of 027
+    #[cfg(unix)]
+    #[arg(long, value_name = "MASK", value_parser = parse_umask)]
+    umask: Option<u32>,
+
+    /// Check if opened TCP ports are reachable from the outside
+    #[cfg(feature = "selftest")]
+    #[arg(long, requires = "selftest_url")]
+    selftest: bool,
+
+    /// URL of the external checker, "{port}" will be replaced with the port to check
+    #[cfg(feature = "selftest")]
+    #[arg(long)]
+    selftest_url: Option<String>,
+}
+
+impl Cli {
+    fn discovery_options(&self) -> DiscoveryOptions {
+        let mut options = DiscoveryOptions::new()
+            .bind_port(self.ssdp_bind_port)
+            .interface_priority(self.interface_priority.clone())
+            .interface_excludes(self.exclude_interfaces.clone())
+            .match_policy(self.match_policy.into())
+            .timeout(Duration::from_secs(self.discovery_timeout))
+            .retries(self.discovery_retries)
+            .log_search_options(self.debug_search_options)
+            .verify_removal(self.verify_removal)
+            .protected_ports(self.protect_port.clone())
+            .prefer_family(self.prefer_family.into());
+
+        if let Some(ssdp_address) = self.ssdp_address {
+            options = options.ssdp_address(ssdp_address);
+        }
+        if let Some(gateway_name) = &self.gateway_name {
+            options = options.gateway_name(gateway_name);
+        }
+        if let Some(gateway_url) = &self.gateway_url {
+            options = options.gateway_url(gateway_url);
+        }
+        if let Some(bind_ip) = self.bind_ip {
+            options = options.bind_ip(bind_ip);
+        }
+
+        options
+    }
+
+    /// The time between two iterations in seconds, for `--cron` the longest of the upcoming ones.
+    fn effective_interval(&self) -> u64 {
+        self.cron
+            .as_ref()
+            .and_then(|schedule| longest_gap(schedule, &Local::now()))
+            .map_or(self.interval, |gap| gap.as_secs())
+    }
+
+    /// The time to wait from `now` until the next iteration.
+    fn next_wait(&self, now: &DateTime<Local>) -> Duration {
+        let Some(schedule) = &self.cron else {
+            return Duration::from_secs(self.interval);
+        };
+
+        until_next_run(schedule, now).unwrap_or_else(|| {
+            warn!(
+                "The cron schedule has no upcoming run, wait {} seconds instead",
+                self.interval
+            );
+            Duration::from_secs(self.interval)
+        })
+    }
+
+    /// The format of each file, in the same order as the files.
+    fn formats(&self) -> Vec<CliInputFormat> {
+        match self.format.as_slice() {
+            [] => self.file.iter().map(CliInput::format).collect(),
+            [format] => vec![*format; self.file.len()],
+            formats => formats.to_vec(),
+        }
+    }
+
+    fn read_configs(&self, sources: &[Source]) -> anyhow::Result<Vec<UpnpConfig>> {
+        let mut configs = Vec::new();
+        let mut origins = HashMap::new();
+        let mut rows = 0;
+
+        for source in sources {
+            for row in self.parse_source(source)? {
+                rows += 1;
+                let Some(config) = filter_out_and_log_errors(row) else {
+                    continue;
+                };
+
+                if let Some(origin) = origins.insert((config.port, config.protocol), &source.name) {
+                    warn!(
+                        "Port mapping {} {} is configured more than once, in {} and {}",
+                        config.protocol, config.port, origin, source.name
+                    );
+                }
+                configs.push((rows, config));
+            }
+        }
+
+        if self.from_env {
+            let config = read_env_config()?;
+            if let Some(origin) = origins.get(&(config.port, config.protocol)) {
+                warn!(
+                    "Port mapping {} {} is configured more than once, in {} and the environment",
+                    config.protocol, config.port, origin
+                );
+            }
+            rows += 1;
+            configs.push((rows, config));
+        }
+
+        #[cfg(all(unix, feature = "systemd"))]
+        for config in &self.systemd_sockets {
+            if let Some(origin) = origins.get(&(config.port, config.protocol)) {
+                warn!(
+                    "Port mapping {} {} is configured more than once, in {} and socket {}",
+                    config.protocol, config.port, origin, config.comment
+                );
+            }
+            rows += 1;
+            configs.push((rows, config.clone()));
+        }
+
+        Ok(self.prepare(select_configs(
+            configs,
+            rows,
+            &self.select,
+            &mut self.warned_selectors.lock().unwrap(),
+        )))
+    }
+
+    /// Apply the command line options to freshly parsed configs, and resolve their addresses.
+    fn prepare(&self, configs: Vec<UpnpConfig>) -> Vec<UpnpConfig> {
+        let auto_duration =
+            easy_upnp::auto_duration(Duration::from_secs(self.effective_interval()));
+        let description = self.description_template.as_ref().map(|template| {
+            let date = Local::now().format("%Y-%m-%d").to_string();
+            let host = sysinfo::System::host_name().unwrap_or_default();
+            (template, date, host)
+        });
+        let leases = configs
+            .iter()
+            .any(|config| config.internal_hostname.is_some())
+            .then(|| self.read_dhcp_leases())
+            .flatten();
+
+        configs
+            .into_iter()
+            .filter(|config| match &self.filter_comment {
+                Some(pattern) => comment_matches(pattern, &config.comment),
+                None => true,
+            })
+            .filter(|config| {
+                let allowed = self
+                    .protocols
+                    .iter()
+                    .any(|&protocol| PortMappingProtocol::from(protocol) == config.protocol);
+                if !allowed {
+                    debug!(
+                        "Skip port {} {}, protocol is not enabled",
+                        config.protocol, config.port
+                    );
+                }
+                allowed
+            })
+            .filter(|config| {
+                if self.allowed_ports.is_empty() {
+                    return true;
+                }
+                if config.any_port {
+                    warn!(
+                        "Skip port {} {}, the router would choose an external port that is not \
+                         checked against the allowed ports",
+                        config.protocol, config.port
+                    );
+                    return false;
+                }
+                let allowed = self
+                    .allowed_ports
+                    .iter()
+                    .any(|range| range.contains(config.port));
+                if !allowed {
+                    warn!(
+                        "Skip port {} {}, it is outside of the allowed ports",
+                        config.protocol, config.port
+                    );
+                }
+                allowed
+            })
+            .filter_map(resolve_address_command)
+            .filter_map(|config| resolve_internal_hostname(config, &leases))
+            .map(|mut config| {
+                if let Some(duration) = self.duration_override {
+                    config.duration = LeaseDuration::Seconds(duration);
+                }
+                config.duration = config.duration.resolve(auto_duration);
+                config.backend.get_or_insert(self.protocol_backend.into());
+                if let Some(prefix) = &self.comment_prefix {
+                    config.comment.insert_str(0, prefix);
+                }
+                if let Some((template, date, host)) = &description {
+                    config.comment = render_description(template, &config.comment, date, host);
+                }
+                config
+            })
+            .collect()
+    }
+
+    /// Read the DHCP leases, logging any error.
+    fn read_dhcp_leases(&self) -> Option<BTreeMap<String, Ipv4Addr>> {
+        File::open(&self.dhcp_leases)
+            .map_err(easy_upnp::UpnpError::from)
+            .and_then(easy_upnp::parse_dnsmasq_leases)
+            .map_err(|err| {
+                error!(
+                    "Could not read DHCP leases from {}: {}",
+                    self.dhcp_leases.display(),
+                    err
+                )
+            })
+            .ok()
+    }
+
+    /// The names of the CSV columns if there is no header line.
+    fn csv_columns(&self) -> Vec<String> {
+        if self.csv_columns.is_empty() {
+            UpnpConfig::FIELDS.iter().map(ToString::to_string).collect()
+        } else {
+            self.csv_columns.clone()
+        }
+    }
+
+    fn parse_source(&self, source: &Source) -> anyhow::Result<Vec<easy_upnp::Result<UpnpConfig>>> {
+        let file = source
+            .input
+            .open()
+            .with_context(|| format!("Could not open {}", source.name))?;
+        let mut reader = BufReader::new(file);
+
+        let format = match source.format {
+            CliInputFormat::Auto => sniff_format(&mut reader)?,
+            format => format,
+        };
 
-    /// The format of the configuration file
-    #[arg(long, value_enum, default_value_t = CliInputFormat::Csv)]
-    format: CliInputFormat,
+        Ok(match format {
+            CliInputFormat::Auto => unreachable!("format has been detected"),
+            CliInputFormat::Csv if self.no_header => easy_upnp::parse_configs_csv_with_columns(
+                reader,
+                self.csv_delimiter as u8,
+                &self.csv_columns(),
+            )
+            .collect(),
+            CliInputFormat::Csv => {
+                easy_upnp::parse_configs_csv(reader, self.csv_delimiter as u8).collect()
+            }
+            CliInputFormat::Json if self.lenient_json => {
+                easy_upnp::parse_configs_json_lenient(reader)?.collect()
+            }
+            CliInputFormat::Json => easy_upnp::parse_configs_json(reader)?.collect(),
+            CliInputFormat::Yaml => easy_upnp::parse_configs_yaml(reader)?.collect(),
+            CliInputFormat::Toml => easy_upnp::parse_configs_toml(reader)?.collect(),
+        })
+    }
 
-    /// Field delimiter when using CSV files
-    #[arg(long, short = 'd', default_value_t = ';')]
-    csv_delimiter: char,
+    /// Run the program, returning the status to exit with.
+    ///
+    /// All errors that are returned concern the configuration, all other errors are either logged
+    /// or end the program directly.
+    fn run() -> Result<ExitStatus, Box<dyn Error>> {
+        let cli = Cli::parse();
+        init_logger(cli.log_format, cli.summary);
 
-    /// Run in foreground instead of forking to background
-    #[cfg(unix)]
-    #[arg(long, short = 'F')]
-    foreground: bool,
+        if let Some(path) = &cli.record_soap {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Could not open {}", path.display()))?;
+            easy_upnp::record_soap(file);
+        }
 
-    /// Run just one time instead of continuously
-    #[arg(long, short = '1')]
-    oneshot: bool,
+        if let Some(path) = &cli.replay_soap {
+            let file =
+                File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+            easy_upnp::replay_soap(file)
+                .with_context(|| format!("Could not replay {}", path.display()))?;
+        }
 
-    /// Specify update interval in seconds
-    #[arg(long, short = 'n', default_value_t = 60)]
-    interval: u64,
+        match &cli.command {
+            Some(CliCommand::Probe { address }) => probe(address, &cli.discovery_options()),
+            Some(CliCommand::RemoveAll {
+                address,
+                yes,
+                comment,
+            }) => remove_all(address, *yes, comment.as_deref(), &cli.discovery_options()),
+            Some(CliCommand::Renew {
+                port,
+                protocol,
+                address,
+                duration,
+                comment,
+            }) => renew(
+                *port,
+                (*protocol).into(),
+                address,
+                *duration,
+                comment.clone(),
+                &cli.discovery_options(),
+            ),
+            Some(CliCommand::Export {
+                address,
+                format,
+                output,
+                csv_delimiter,
+            }) => export(
+                address,
+                format.unwrap_or_else(|| CliOutputFormat::from_path(output)),
+                output,
+                *csv_delimiter,
+                &cli.discovery_options(),
+            ),
+            None => {}
+        }
 
-    /// Close specified ports on program exit
-    #[arg(long)]
-    close_ports_on_exit: bool,
+        if cli.format.len() > 1 && cli.format.len() != cli.file.len() {
+            Cli::command()
+                .error(
+                    ErrorKind::WrongNumberOfValues,
+                    "--format must be given once for all files or once per file",
+                )
+                .exit();
+        }
 
-    /// Only close specified ports and exit
-    #[arg(long)]
-    only_close_ports: bool,
+        if cli.require_gateway {
+            let options = cli.discovery_options();
+            if let Err(err) = easy_upnp::probe_gateway(&None, Some(&options)) {
+                eprintln!("No UPnP gateway found at startup: {}", err);
+                ExitStatus::of(&err).exit();
+            }
+        }
 
-    /// Absolute path to PID file for daemon mode
-    #[cfg(unix)]
-    #[arg(long, default_value = "/tmp/upnp-daemon.pid")]
-    pid_file: PathBuf,
-}
+        if cli.stream_stdin {
+            if !matches!(cli.file.as_slice(), [CliInput::Stdin]) {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--stream-stdin requires \"--file -\" as the only file",
+                    )
+                    .exit();
+            }
 
-impl Cli {
-    fn run() -> Result<(), Box<dyn Error>> {
-        let cli = Cli::parse();
+            return Ok(stream_stdin(&cli)?);
+        }
 
-        // Handle file here, because reading from stdin will fail in daemon mode.
-        let file = cli.file.try_into()?;
+        // Handle files here, because reading from stdin will fail in daemon mode.
+        let sources = cli
+            .file
+            .iter()
+            .zip(cli.formats())
+            .map(|(cli_input, format)| {
+                Ok(Source {
+                    name: cli_input.to_string(),
+                    input: cli_input.clone().try_into()?,
+                    format,
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        // Check the environment before going to the background, so that missing variables are
+        // reported on the terminal.
+        if cli.from_env {
+            read_env_config()?;
+        }
+
+        // The sockets are only passed to this very process, so they have to be received before
+        // going to the background.
+        #[cfg(all(unix, feature = "systemd"))]
+        let cli = Cli {
+            systemd_sockets: match cli.from_systemd_sockets {
+                true => systemd::socket_configs()?,
+                false => Vec::new(),
+            },
+            ..cli
+        };
+
+        if cli.dry_run {
+            return Ok(dry_run(&cli, &sources)?);
+        }
 
         #[cfg(unix)]
         if !cli.foreground {
-            Daemonize::new()
-                .pid_file(cli.pid_file)
-                .start()
-                .expect("Failed to daemonize.");
+            let running = running_daemon(&cli.pid_file)
+                .with_context(|| format!("Could not check {}", cli.pid_file.display()))?;
+            if let Some(pid) = running {
+                eprintln!("upnp-daemon is already running (pid {})", pid);
+                return Ok(ExitStatus::AlreadyRunning);
+            }
+
+            let mut daemonize = Daemonize::new().pid_file(&cli.pid_file);
+
+            if let Some(working_dir) = &cli.working_dir {
+                daemonize = daemonize.working_directory(working_dir);
+            }
+
+            if let Some(umask) = cli.umask {
+                daemonize = daemonize.umask(umask);
+            }
+
+            daemonize.start().expect("Failed to daemonize.");
         }
 
         let (tx_quitter, rx_quitter) = channel();
 
         {
             let tx_quitter = tx_quitter.clone();
+            let interrupted = AtomicBool::new(false);
             ctrlc::set_handler(move || {
-                tx_quitter.send(true).unwrap();
+                if interrupted.swap(true, Ordering::SeqCst) {
+                    warn!("Interrupted again, exit immediately");
+                    ExitStatus::Failure.exit();
+                }
+
+                // The receiver is gone if the program is already shutting down
+                let _ = tx_quitter.send(LoopEvent::Quit);
             })
             .expect("Error setting Ctrl-C handler");
         }
 
+        #[cfg(unix)]
+        set_pause_handler();
+
+        #[cfg(unix)]
+        for source in &sources {
+            if let Input::Fifo(path, current) = &source.input {
+                watch_fifo(path.clone(), Arc::clone(current), tx_quitter.clone());
+            }
+        }
+
+        if cli.startup_delay > 0 {
+            debug!("Wait {} seconds before starting", cli.startup_delay);
+            let deadline = Instant::now() + Duration::from_secs(cli.startup_delay);
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                match rx_quitter.recv_timeout(timeout) {
+                    // Quit signal received during the delay, nothing has been done yet
+                    Ok(LoopEvent::Quit) => return Ok(ExitStatus::Success),
+                    // The first iteration reads the configuration anyway
+                    Ok(LoopEvent::Reload) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+
+        let mut options = cli.discovery_options();
+        let mut prefetched = false;
+        if cli.prefetch && !cli.only_close_ports {
+            if let Some(gateway) = prefetch(&cli, &sources, &options) {
+                options = options.resolver(gateway);
+                prefetched = true;
+            }
+        }
+        let mut stats = Stats::new();
+        let mut errors = ErrorLog::new(Duration::from_secs(cli.log_dedup_window));
+        let mut created = HashMap::new();
+        let mut removals = HashMap::new();
+        let removal_cooldown = Duration::from_secs(cli.removal_cooldown);
+        let mut gated = HashSet::new();
+        let prompt = cli.interactive && can_prompt(&cli);
+        if cli.interactive && !prompt {
+            warn!("Cannot ask on a terminal, port mappings of other clients will be skipped");
+        }
+        let mut declined = HashSet::new();
+        let mut short_durations = HashSet::new();
+        let mut staggered = HashSet::new();
+        let mut applied: Option<AppliedConfigs> = None;
+        let mut clock = ClockWatch::new();
+        let mut paused = false;
+
         loop {
-            if !cli.only_close_ports {
-                match cli.format {
-                    CliInputFormat::Csv => {
-                        let mut rdr = get_csv_reader(&file, cli.csv_delimiter)?;
-                        let configs = get_configs_from_csv_reader(&mut rdr)
-                            .filter_map(filter_out_and_log_errors);
-                        add_ports(configs);
+            if let Some(jump) = clock.check() {
+                info!(
+                    "The clock jumped {}, maybe after a suspend, apply all port mappings again",
+                    jump
+                );
+                applied = None;
+            }
+
+            if PAUSED.load(Ordering::SeqCst) != paused {
+                paused = !paused;
+                info!("{}", if paused { "Paused" } else { "Resumed" });
+            }
+
+            if paused && !cli.only_close_ports {
+                info!("Paused, skip this iteration");
+            } else if !cli.only_close_ports {
+                let tick_started = Instant::now();
+                let interval = cli.effective_interval();
+
+                // Port mappings that are known to still exist on their router.
+                let mut present = HashSet::new();
+
+                if cli.detect_foreign_changes || cli.respect_manual_removal || cli.refresh_once {
+                    let mappings = lookup_created(&created, &options);
+                    if cli.detect_foreign_changes {
+                        detect_foreign_changes(&mappings);
+                    }
+                    if cli.respect_manual_removal {
+                        detect_manual_removals(
+                            &mappings,
+                            &mut created,
+                            &mut removals,
+                            removal_cooldown,
+                        );
+                    }
+                    if cli.refresh_once {
+                        present =
+                            detect_vanished(&mappings, &mut created, !cli.respect_manual_removal);
+                    }
+                }
+
+                let configs = match cli.read_configs(&sources) {
+                    Ok(configs) => Some(configs),
+                    Err(err) if cli.oneshot => return Err(err.into()),
+                    Err(err) => {
+                        error!(
+                            "Could not read the configuration, trying again in the next iteration: {:#}",
+                            err
+                        );
+                        None
+                    }
+                };
+
+                if let Some(configs) = configs {
+                    let read = configs.len();
+                    let mut configs = filter_by_process(
+                        configs,
+                        &mut gated,
+                        &mut created,
+                        &options,
+                        cli.on_remove.as_deref(),
+                    );
+
+                    removals.retain(|_, removed_at: &mut Instant| {
+                        removed_at.elapsed() < removal_cooldown
+                    });
+                    configs.retain(|config| {
+                        let removed = removals.contains_key(&(config.port, config.protocol));
+                        if removed {
+                            debug!(
+                                "Skip port {} {}, it has been removed from the router",
+                                config.protocol, config.port
+                            );
+                        }
+                        !removed
+                    });
+
+                    if !cli.oneshot {
+                        check_durations(&mut configs, interval, cli.strict, &mut short_durations);
+                    }
+
+                    if cli.interactive {
+                        confirm_replacements(&mut configs, &options, prompt, &mut declined);
+                    }
+
+                    apply_staggered(&mut configs, &staggered);
+
+                    let hash = config_hash(&configs);
+
+                    stats.ticks += 1;
+
+                    let renew_interval = cli
+                        .renew_interval
+                        .map(Duration::from_secs)
+                        .unwrap_or_else(|| default_renew_interval(&configs));
+                    let mut added = 0;
+                    let mut failed = 0;
+                    if cli.once_per_change
+                        && applied.as_ref().is_some_and(|applied| {
+                            applied.hash == hash && applied.at.elapsed() < renew_interval
+                        })
+                    {
+                        debug!("Configuration has not changed, skip adding ports");
+                    } else {
+                        if let Some(threshold) = cli.renew_threshold {
+                            configs.retain(|config| needs_renewal(config, threshold, &options));
+                        }
+                        if cli.refresh_once {
+                            configs.retain(|config| {
+                                let key = (config.port, config.protocol);
+                                let unchanged = present.contains(&key)
+                                    && created.get(&key).is_some_and(|created| created == config);
+                                if unchanged {
+                                    debug!(
+                                        "Skip port {} {}, it has been added already",
+                                        config.protocol, config.port
+                                    );
+                                }
+                                !unchanged
+                            });
+                        }
+
+                        let attempted = configs.len();
+                        let mut opened = add_ports(
+                            configs,
+                            &options,
+                            &mut errors,
+                            cli.on_add.as_deref(),
+                            cli.report_outcomes,
+                        );
+
+                        if let Some(policy) = cli.distinct_protocol_ports {
+                            let dropped = find_conflated_ports(&opened, &options);
+                            opened.retain(|(config, _)| {
+                                !dropped.iter().any(|dropped| {
+                                    dropped.port == config.port
+                                        && dropped.protocol == config.protocol
+                                })
+                            });
+
+                            if policy == CliProtocolPortsPolicy::Stagger {
+                                let dropped: Vec<_> = dropped
+                                    .into_iter()
+                                    .filter(|config| {
+                                        config.remote_host.is_none() && cli.allowed_ports.is_empty()
+                                    })
+                                    .map(|mut config| {
+                                        staggered.insert((config.port, config.protocol));
+                                        config.any_port = true;
+                                        config
+                                    })
+                                    .collect();
+                                opened.extend(add_ports(
+                                    dropped,
+                                    &options,
+                                    &mut errors,
+                                    cli.on_add.as_deref(),
+                                    cli.report_outcomes,
+                                ));
+                            }
+                        }
+
+                        added = opened.len();
+                        failed = attempted - opened.len();
+                        stats.added += added as u64;
+                        stats.failed += failed as u64;
+
+                        // Only remember fully applied configurations, so that failed ports are
+                        // retried in the next iteration.
+                        applied = (opened.len() == attempted).then(|| AppliedConfigs {
+                            hash,
+                            at: Instant::now(),
+                        });
+
+                        if cli.detect_foreign_changes
+                            || cli.respect_manual_removal
+                            || cli.refresh_once
+                        {
+                            for (config, _) in &opened {
+                                created.insert((config.port, config.protocol), config.clone());
+                            }
+                        }
+
+                        #[cfg(feature = "selftest")]
+                        if let Some(url) = cli.selftest_url.as_deref().filter(|_| cli.selftest) {
+                            opened
+                                .iter()
+                                .filter(|(config, _)| {
+                                    matches!(config.protocol, PortMappingProtocol::TCP)
+                                })
+                                .for_each(|(config, external_port)| {
+                                    selftest::check_port(url, config, *external_port)
+                                });
+                        }
+                    }
+
+                    if cli.summary {
+                        Stats::log_tick(added, read - added - failed, failed, tick_started);
                     }
-                    CliInputFormat::Json => {
-                        let configs =
-                            get_configs_from_json(&file)?.filter_map(filter_out_and_log_errors);
-                        add_ports(configs);
+                }
+
+                if let Some(path) = &cli.metrics_textfile {
+                    let external_ip = match easy_upnp::probe_gateway(&None, Some(&options)) {
+                        Ok(info) => Some(info.external_ip),
+                        Err(err) => {
+                            debug!("Could not get the external IP address: {}", err);
+                            None
+                        }
+                    };
+                    if let Err(err) = stats.write_metrics(path, external_ip) {
+                        error!("Could not write metrics to {}: {}", path.display(), err);
                     }
                 }
+
+                if prefetched {
+                    // Search again from now on, in case the gateway changes while running.
+                    options = cli.discovery_options();
+                    prefetched = false;
+                }
+
+                let took = tick_started.elapsed();
+                debug!("Iteration took {:.2?}", took);
+                if !cli.oneshot && took > Duration::from_secs(interval) {
+                    warn!(
+                        "Iteration took {:.1?}, longer than the interval of {} seconds",
+                        took, interval
+                    );
+                }
             }
 
             if cli.oneshot || cli.only_close_ports {
-                tx_quitter.send(true)?;
+                tx_quitter
+                    .send(LoopEvent::Quit)
+                    .expect("Quit channel is closed while still in use");
+            }
+
+            let wait = cli.next_wait(&clock.now());
+            if cli.cron.is_some() {
+                debug!("Wait {:.0?} until the next run of the cron schedule", wait);
             }
 
-            match rx_quitter.recv_timeout(Duration::from_secs(cli.interval)) {
+            match rx_quitter.recv_timeout(wait) {
                 Err(RecvTimeoutError::Timeout) => {
                     // Timeout reached without being interrupted, continue with loop
                 }
@@ -575,23 +4694,18 @@ impl Cli {
                     // Something bad happened
                     panic!("{}", e);
                 }
-                Ok(_) => {
+                Ok(LoopEvent::Reload) => {
+                    // New configuration from a named pipe, apply it right away
+                }
+                Ok(LoopEvent::Quit) => {
                     // Quit signal received, break loop and quit nicely
 
+                    stats.log_summary();
+
                     if cli.close_ports_on_exit || cli.only_close_ports {
-                        match cli.format {
-                            CliInputFormat::Csv => {
-                                let mut rdr = get_csv_reader(&file, cli.csv_delimiter)?;
-                                let configs = get_configs_from_csv_reader(&mut rdr)
-                                    .filter_map(filter_out_and_log_errors);
-                                delete_ports(configs);
-                            }
-                            CliInputFormat::Json => {
-                                let configs = get_configs_from_json(&file)?
-                                    .filter_map(filter_out_and_log_errors);
-                                delete_ports(configs);
-                            }
-                        }
+                        let mut closing = cli.read_configs(&sources)?;
+                        apply_staggered(&mut closing, &staggered);
+                        delete_ports(closing, &options, cli.on_remove.as_deref());
                     }
 
                     break;
@@ -599,25 +4713,509 @@ impl Cli {
             }
         }
 
-        Ok(())
+        if cli.oneshot && stats.added > 0 && !cli.close_ports_on_exit {
+            info!(
+                "The added port mappings stay open until their leases expire; to close them when \
+                the program exits, run it without --oneshot and with --close-ports-on-exit"
+            );
+        }
+
+        if cli.oneshot && stats.failed > 0 {
+            return Ok(ExitStatus::PartialFailure);
+        }
+
+        Ok(ExitStatus::Success)
+    }
+}
+
+/// A logger that leaves out the `info` records of the library, which describe every single port
+/// mapping, unless `debug` is enabled for them anyway.
+struct SummaryLogger(env_logger::Logger);
+
+impl SummaryLogger {
+    fn is_detail(&self, record: &Record) -> bool {
+        let debug = Metadata::builder()
+            .level(Level::Debug)
+            .target(record.target())
+            .build();
+
+        record.level() == Level::Info
+            && record.target().starts_with("easy_upnp")
+            && !self.0.enabled(&debug)
+    }
+}
+
+impl Log for SummaryLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.is_detail(record) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+/// Set up the logger, configured by `RUST_LOG` like the default logger of env_logger.
+fn init_logger(format: CliLogFormat, summary: bool) {
+    let mut builder = env_logger::Builder::from_default_env();
 
-    Cli::run()?;
+    if format == CliLogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    if summary {
+        let logger = builder.build();
+        log::set_max_level(logger.filter());
+        log::set_boxed_logger(Box::new(SummaryLogger(logger)))
+            .expect("Logger is only initialized once");
+    } else {
+        builder.init();
+    }
+}
 
-    Ok(())
+fn main() -> ExitCode {
+    match Cli::run() {
+        Ok(status) => ExitCode::from(status as u8),
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(ExitStatus::ConfigError as u8)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use easy_upnp::RequiredProcess;
+
     use super::*;
 
     #[test]
     fn verify_app() {
-        use clap::CommandFactory;
         Cli::command().debug_assert()
     }
+
+    #[test]
+    fn short_durations_are_reported() {
+        let config = |duration| UpnpConfig {
+            duration,
+            ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+        };
+        let configs = vec![
+            config(LeaseDuration::Seconds(5)),
+            config(LeaseDuration::Seconds(0)),
+            config(LeaseDuration::Seconds(60)),
+        ];
+
+        let mut lenient = configs.clone();
+        let mut reported = HashSet::new();
+        check_durations(&mut lenient, 60, false, &mut reported);
+        assert_eq!(lenient, configs);
+        assert_eq!(reported.len(), 1);
+
+        let mut strict = configs.clone();
+        check_durations(&mut strict, 60, true, &mut HashSet::new());
+        assert_eq!(strict, configs[1..]);
+    }
+
+    #[test]
+    fn format_is_sniffed() {
+        fn sniff(input: &str) -> (CliInputFormat, &[u8]) {
+            let mut reader = input.as_bytes();
+            let format = sniff_format(&mut reader).unwrap();
+            (format, reader)
+        }
+
+        assert!(matches!(sniff("\n  [{}]"), (CliInputFormat::Json, b"[{}]")));
+        assert!(matches!(sniff("{}"), (CliInputFormat::Json, b"{}")));
+        assert!(matches!(
+            sniff("port;protocol"),
+            (CliInputFormat::Csv, b"port;protocol")
+        ));
+        assert!(matches!(sniff(" \n"), (CliInputFormat::Csv, b"")));
+    }
+
+    #[test]
+    fn required_process_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("test.pid");
+        std::fs::write(&pid_file, format!("{}\n", process::id())).unwrap();
+
+        let processes = running::Processes::new();
+        assert!(processes.is_running(&RequiredProcess::PidFile(pid_file)));
+        assert!(!processes.is_running(&RequiredProcess::PidFile(dir.path().join("missing.pid"))));
+        assert!(!processes.is_running(&RequiredProcess::Name("no-such-process-name".to_string())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_pid_file_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let pid_file = dir.path().join("running.pid");
+        std::fs::write(&pid_file, format!("{}\n", process::id())).unwrap();
+        assert_eq!(running_daemon(&pid_file).unwrap(), Some(process::id()));
+        assert!(pid_file.exists());
+
+        let pid_file = dir.path().join("stale.pid");
+        std::fs::write(&pid_file, format!("{}\n", u32::MAX)).unwrap();
+        assert_eq!(running_daemon(&pid_file).unwrap(), None);
+        assert!(!pid_file.exists());
+
+        let mut other = process::Command::new("sleep").arg("60").spawn().unwrap();
+        let pid_file = dir.path().join("reused.pid");
+        std::fs::write(&pid_file, format!("{}\n", other.id())).unwrap();
+        let running = running_daemon(&pid_file).unwrap();
+        other.kill().unwrap();
+        other.wait().unwrap();
+        assert_eq!(running, None);
+        assert!(!pid_file.exists());
+
+        assert_eq!(
+            running_daemon(&dir.path().join("missing.pid")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn comment_filter() {
+        assert!(comment_matches("torrent-*", "torrent-web"));
+        assert!(!comment_matches("torrent-*", "my torrent-web"));
+        assert!(comment_matches("torrent", "my torrent-web"));
+        assert!(comment_matches("web?", "web1"));
+        assert!(!comment_matches("web?", "web10"));
+    }
+
+    #[test]
+    fn metrics_are_rendered_for_the_textfile_collector() {
+        let mut stats = Stats::new();
+        stats.ticks = 3;
+        stats.added = 5;
+        stats.failed = 1;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+
+        let metrics = stats.metrics(None, now);
+        assert!(metrics.contains("\nupnp_daemon_iterations_total 3\n"));
+        assert!(metrics.contains("\nupnp_daemon_port_mappings_added_total 5\n"));
+        assert!(metrics.contains("\nupnp_daemon_port_mapping_failures_total 1\n"));
+        assert!(metrics.contains("\nupnp_daemon_last_iteration_timestamp_seconds 1700000000.500\n"));
+        assert!(!metrics.contains("external_ip"));
+
+        let metrics = stats.metrics(Some(Ipv4Addr::new(203, 0, 113, 1)), now);
+        assert!(metrics.ends_with("upnp_daemon_external_ip_info{address=\"203.0.113.1\"} 1\n"));
+    }
+
+    #[test]
+    fn allowed_ports_are_parsed_as_ranges() {
+        let range: PortRange = "30000-40000".parse().unwrap();
+        assert!(!range.contains(29999));
+        assert!(range.contains(30000));
+        assert!(range.contains(40000));
+        assert!(!range.contains(40001));
+
+        let single: PortRange = "8080".parse().unwrap();
+        assert_eq!(
+            single,
+            PortRange {
+                first: 8080,
+                last: 8080
+            }
+        );
+
+        assert!("40000-30000".parse::<PortRange>().is_err());
+        assert!("1-70000".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn cron_schedule_determines_the_wait() {
+        use chrono::Utc;
+
+        assert!(parse_cron("*/5 * * *").is_err());
+        assert!(parse_cron("every five minutes").is_err());
+
+        let schedule = parse_cron("*/5 * * * *").unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 3, 30).unwrap();
+        assert_eq!(
+            until_next_run(&schedule, &now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(longest_gap(&schedule, &now), Some(Duration::from_secs(300)));
+
+        let schedule = parse_cron("30 0 2,3 * * *").unwrap();
+        assert_eq!(
+            until_next_run(&schedule, &now),
+            Some(Duration::from_secs(13 * 3600 + 57 * 60))
+        );
+        assert_eq!(
+            longest_gap(&schedule, &now),
+            Some(Duration::from_secs(23 * 3600))
+        );
+
+        let schedule = parse_cron("0 0 0 1 1 * 2000").unwrap();
+        assert_eq!(until_next_run(&schedule, &now), None);
+        assert_eq!(longest_gap(&schedule, &now), None);
+    }
+
+    #[test]
+    fn crontab_days_of_week_start_on_sunday() {
+        use chrono::Utc;
+
+        // A Saturday.
+        let now = Utc.with_ymd_and_hms(2024, 5, 4, 12, 0, 0).unwrap();
+        let sunday = Utc.with_ymd_and_hms(2024, 5, 5, 3, 0, 0).unwrap();
+        let monday = Utc.with_ymd_and_hms(2024, 5, 6, 3, 0, 0).unwrap();
+        let next_run = |expression| parse_cron(expression).unwrap().after(&now).next();
+
+        assert_eq!(next_run("0 3 * * 1-5"), Some(monday));
+        assert_eq!(next_run("0 3 * * 0"), Some(sunday));
+        assert_eq!(next_run("0 3 * * 7"), Some(sunday));
+        assert_eq!(next_run("0 3 * * 5-7"), Some(sunday));
+        assert_eq!(next_run("0 3 * * 1-5/2"), Some(monday));
+        assert_eq!(next_run("0 3 * * MON-FRI"), Some(monday));
+
+        // With the seconds, the numbering of the cron crate applies.
+        assert_eq!(next_run("0 0 3 * * 2"), Some(monday));
+        assert!(parse_cron("0 3 * * 8").is_err());
+    }
+
+    #[test]
+    fn descriptions_are_rendered_from_the_template() {
+        let template = parse_description_template("{comment} (upnp-daemon {date})").unwrap();
+        assert_eq!(
+            render_description(&template, "Web", "2024-05-01", "host-a"),
+            "Web (upnp-daemon 2024-05-01)"
+        );
+
+        let template = parse_description_template("[{host}] {comment} v{version}").unwrap();
+        assert_eq!(
+            render_description(&template, "{date}", "2024-05-01", "host-a"),
+            format!("[host-a] {{date}} v{}", env!("CARGO_PKG_VERSION"))
+        );
+
+        let long = "x".repeat(100);
+        let description = render_description("{comment}", &long, "2024-05-01", "host-a");
+        assert_eq!(description.chars().count(), MAX_DESCRIPTION_LEN);
+
+        assert!(parse_description_template("{comment} {time}").is_err());
+        assert!(parse_description_template("{comment").is_err());
+    }
+
+    #[test]
+    fn clock_jumps_are_detected() {
+        let mut clock = ClockWatch::new();
+        let (wall, monotonic) = (clock.wall, clock.monotonic);
+        let minute = Duration::from_secs(60);
+
+        // A normal iteration, both clocks advance together.
+        assert_eq!(clock.check_at(wall + minute, monotonic + minute), None);
+
+        // A suspend, only the wall clock advanced.
+        assert_eq!(
+            clock.check_at(wall + 61 * minute, monotonic + 2 * minute),
+            Some("forward by 3540s".to_string())
+        );
+
+        // A step backwards, to before the start.
+        assert_eq!(
+            clock.check_at(wall - minute, monotonic + 3 * minute),
+            Some("backward by 3780s".to_string())
+        );
+
+        // After the jump, the new wall clock is the reference.
+        assert_eq!(clock.check_at(wall, monotonic + 4 * minute), None);
+    }
+
+    #[test]
+    fn backward_clock_step_is_handled_once() {
+        fn an_hour_ago() -> SystemTime {
+            SystemTime::now() - Duration::from_secs(3600)
+        }
+
+        let mut clock = ClockWatch::new();
+        clock.now = an_hour_ago;
+
+        // The next iteration applies all port mappings again, and the ones after it go on as usual,
+        // with the stepped clock as the reference.
+        assert!(clock
+            .check()
+            .is_some_and(|jump| jump.starts_with("backward")));
+        assert_eq!(clock.check(), None);
+    }
+
+    #[test]
+    fn backward_clock_step_does_not_delay_the_next_iteration() {
+        fn three_o_clock() -> SystemTime {
+            Local.with_ymd_and_hms(2024, 5, 6, 3, 0, 0).unwrap().into()
+        }
+        fn half_past_two() -> SystemTime {
+            Local.with_ymd_and_hms(2024, 5, 6, 2, 30, 0).unwrap().into()
+        }
+
+        let cli =
+            Cli::try_parse_from(["upnp-daemon", "--cron", "0 * * * *", "-f", "ports.csv"]).unwrap();
+        let mut clock = ClockWatch {
+            wall: three_o_clock(),
+            monotonic: Instant::now(),
+            now: half_past_two,
+        };
+
+        // The last iteration ran at 3:00, and the clock has been stepped back by half an hour since.
+        // The port mappings are applied again right away, and the wait is measured from the stepped
+        // clock, so the next run is the one at 3:00 again, not the one at 4:00.
+        assert_eq!(clock.check(), Some("backward by 1800s".to_string()));
+        assert_eq!(cli.next_wait(&clock.now()), Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn configs_are_selected_by_position_or_comment() {
+        let config = |port, comment: &str| UpnpConfig {
+            duration: LeaseDuration::Seconds(3600),
+            comment: comment.to_string(),
+            ..UpnpConfig::new(port, PortMappingProtocol::TCP)
+        };
+        let configs: Vec<_> = (1..=6)
+            .map(|port| (port, config(port as u16, "Game")))
+            .collect();
+        let ports = |configs: Vec<UpnpConfig>| -> Vec<u16> {
+            configs.into_iter().map(|config| config.port).collect()
+        };
+        let mut warned = HashSet::new();
+
+        let selectors: Vec<ConfigSelector> = ["2,4-6", "9"]
+            .into_iter()
+            .map(|spec| spec.parse().unwrap())
+            .collect();
+        assert_eq!(
+            ports(select_configs(configs.clone(), 6, &selectors, &mut warned)),
+            [2, 4, 5, 6]
+        );
+        assert_eq!(warned, HashSet::from([selectors[1].clone()]));
+        assert_eq!(
+            ports(select_configs(configs, 6, &[], &mut warned)),
+            [1, 2, 3, 4, 5, 6]
+        );
+
+        // The second row could not be parsed, but still counts
+        let configs = vec![(1, config(1, "Webserver")), (3, config(2, "Game"))];
+        let selectors = ["3".parse().unwrap()];
+        assert_eq!(
+            ports(select_configs(configs.clone(), 3, &selectors, &mut warned)),
+            [2]
+        );
+
+        let selectors = ["comment:Web".parse().unwrap()];
+        assert_eq!(
+            ports(select_configs(configs, 3, &selectors, &mut warned)),
+            [1]
+        );
+
+        assert_eq!(
+            "comment:a,b".parse(),
+            Ok(ConfigSelector::Comment("a,b".to_string()))
+        );
+        assert_eq!(
+            "2,4-6".parse(),
+            Ok(ConfigSelector::Positions(vec![(2, 2), (4, 6)]))
+        );
+        for spec in ["0", "3-1", "x", "2-", "2,,3"] {
+            assert!(spec.parse::<ConfigSelector>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn renew_interval_is_half_of_shortest_lease() {
+        let config = |duration| UpnpConfig {
+            duration,
+            comment: "Webserver".to_string(),
+            ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+        };
+
+        let configs = [
+            config(LeaseDuration::Seconds(3600)),
+            config(LeaseDuration::Seconds(0)),
+            config(LeaseDuration::Seconds(600)),
+        ];
+        assert_eq!(default_renew_interval(&configs), Duration::from_secs(300));
+
+        let configs = [config(LeaseDuration::Seconds(0))];
+        assert_eq!(default_renew_interval(&configs), Duration::MAX);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hook_gets_details() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("hook");
+        let output = dir.path().join("output");
+
+        std::fs::write(
+            &script,
+            "#!/bin/sh\necho \"$1 $UPNP_PORT $UPNP_PROTOCOL $UPNP_ADDRESS $UPNP_COMMENT\" > \"$2\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = UpnpConfig {
+            address: Some(Ipv4Cidr::from_str("192.168.0.10").unwrap().into()),
+            duration: LeaseDuration::Seconds(60),
+            comment: "Webserver".to_string(),
+            ..UpnpConfig::new(80, PortMappingProtocol::TCP)
+        };
+
+        run_hook(
+            &format!("{} added {}", script.display(), output.display()),
+            &config,
+        );
+
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "added 80 TCP 192.168.0.10/32 Webserver\n"
+        );
+    }
+
+    #[test]
+    fn repeated_errors_are_suppressed() {
+        let key = (80, PortMappingProtocol::TCP);
+        let mut errors = ErrorLog::new(Duration::from_secs(600));
+
+        errors.log(key, "No matching gateway found".to_string());
+        errors.log(key, "No matching gateway found".to_string());
+        assert_eq!(errors.errors[&key].suppressed, 1);
+
+        errors.log(key, "Another error".to_string());
+        assert_eq!(errors.errors[&key].suppressed, 0);
+
+        errors.clear(key);
+        assert!(errors.errors.is_empty());
+
+        let mut errors = ErrorLog::new(Duration::ZERO);
+        errors.log(key, "No matching gateway found".to_string());
+        errors.log(key, "No matching gateway found".to_string());
+        assert_eq!(errors.errors[&key].suppressed, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn umask_is_octal() {
+        assert_eq!(parse_umask("022"), Ok(0o022));
+        assert_eq!(parse_umask("0o077"), Ok(0o077));
+        assert!(parse_umask("8").is_err());
+        assert!(parse_umask("1000").is_err());
+    }
 }
@@ -0,0 +1,57 @@
+use std::ffi::OsStr;
+use std::fs;
+
+use easy_upnp::RequiredProcess;
+use log::debug;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Snapshot of the running processes, taken once per iteration.
+pub struct Processes {
+    system: System,
+}
+
+impl Processes {
+    pub fn new() -> Self {
+        let mut system = System::new();
+        system.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing(),
+        );
+        Self { system }
+    }
+
+    /// Check whether the given process was running when the snapshot was taken.
+    ///
+    /// A PID file that cannot be read or does not contain a PID counts as not running.
+    pub fn is_running(&self, process: &RequiredProcess) -> bool {
+        match process {
+            RequiredProcess::Name(name) => self
+                .system
+                .processes_by_exact_name(OsStr::new(name))
+                .next()
+                .is_some(),
+            RequiredProcess::PidFile(path) => {
+                let pid = fs::read_to_string(path)
+                    .map_err(|e| debug!("Could not read PID file {}: {}", path.display(), e))
+                    .ok()
+                    .and_then(|content| content.trim().parse().ok());
+
+                pid.is_some_and(|pid| self.is_pid_running(pid))
+            }
+        }
+    }
+
+    /// Check whether a process with the given PID was running when the snapshot was taken.
+    pub fn is_pid_running(&self, pid: u32) -> bool {
+        self.system.process(Pid::from_u32(pid)).is_some()
+    }
+
+    /// Check whether a process with the given PID was running when the snapshot was taken, with
+    /// the same name as this process.
+    pub fn is_pid_running_as_self(&self, pid: u32) -> bool {
+        let name = |pid| self.system.process(pid).map(|process| process.name());
+        let own_name = sysinfo::get_current_pid().ok().and_then(name);
+        own_name.is_some() && name(Pid::from_u32(pid)) == own_name
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use easy_upnp::UpnpConfig;
+use log::{debug, info, warn};
+
+/// Placeholder in the checker URL that will be replaced with the port number.
+const PORT_PLACEHOLDER: &str = "{port}";
+
+/// Time to wait for the external checker before giving up.
+const CHECKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn accept_until_done(listener: &TcpListener, done: &AtomicBool) {
+    while !done.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((_, peer)) => debug!("Self-test connection from {}", peer),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+            Err(e) => {
+                warn!("Self-test listener failed: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Whether the address belongs to this host, which is the case if a socket can be bound to it.
+fn is_local(ip: Ipv4Addr) -> bool {
+    UdpSocket::bind((ip, 0)).is_ok()
+}
+
+/// Bind a temporary listener on the given port, unless a service is listening there already.
+fn bind_listener(port: u16) -> Option<TcpListener> {
+    match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)) {
+        Ok(listener) => listener.set_nonblocking(true).map(|_| listener).ok(),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            debug!(
+                "Port {} is already in use, relying on the running service",
+                port
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Could not bind self-test listener on port {}: {}", port, e);
+            None
+        }
+    }
+}
+
+fn ask_checker(url: &str) -> Result<bool, attohttpc::Error> {
+    let response = attohttpc::get(url).timeout(CHECKER_TIMEOUT).send()?;
+    Ok(response.is_success())
+}
+
+/// Ask the external checker whether the external port of the given TCP port mapping is reachable
+/// from the outside.
+///
+/// If the port mapping forwards to this host and no service is listening on the port yet, a
+/// temporary listener is bound for the duration of the check, so that the checker has something
+/// to connect to.
+pub fn check_port(url_template: &str, config: &UpnpConfig, external_port: u16) {
+    let port = external_port;
+    let url = url_template.replace(PORT_PLACEHOLDER, &port.to_string());
+
+    let listener = match config.internal_client {
+        Some(client) if !is_local(client) => {
+            debug!(
+                "Port {} is forwarded to {}, relying on the service there",
+                port, client
+            );
+            None
+        }
+        _ => bind_listener(config.port),
+    };
+
+    let done = AtomicBool::new(false);
+    let result = thread::scope(|scope| {
+        if let Some(listener) = &listener {
+            scope.spawn(|| accept_until_done(listener, &done));
+        }
+
+        let result = ask_checker(&url);
+        done.store(true, Ordering::Relaxed);
+        result
+    });
+
+    match result {
+        Ok(true) => info!("Port {} is reachable from the outside", port),
+        Ok(false) => warn!("Port {} is not reachable from the outside", port),
+        Err(e) => warn!("Self-test for port {} failed: {}", port, e),
+    }
+}
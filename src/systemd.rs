@@ -0,0 +1,99 @@
+use std::mem::{self, ManuallyDrop};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+
+use anyhow::Context;
+use easy_upnp::{Address, Ipv4Cidr, PortMappingProtocol, UpnpConfig};
+use libsystemd::activation::{self, IsType};
+use log::{info, warn};
+
+/// The protocol of a socket, if it is a stream or datagram socket.
+fn socket_protocol(fd: RawFd) -> Option<PortMappingProtocol> {
+    let mut socket_type: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    // SAFETY: `socket_type` and `len` are valid for writes, and `len` holds the size of
+    // `socket_type`.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match (result, socket_type) {
+        (0, libc::SOCK_STREAM) => Some(PortMappingProtocol::TCP),
+        (0, libc::SOCK_DGRAM) => Some(PortMappingProtocol::UDP),
+        _ => None,
+    }
+}
+
+/// The address that a socket is bound to.
+fn local_addr(fd: RawFd) -> std::io::Result<SocketAddr> {
+    // The socket still belongs to the activated service, so it must not be closed here. Asking for
+    // the local address works the same for every type of socket.
+    // SAFETY: The file descriptor is an open internet socket that systemd passed to this process.
+    let socket = ManuallyDrop::new(unsafe { UdpSocket::from_raw_fd(fd) });
+    socket.local_addr()
+}
+
+/// Build a port mapping for each internet socket that systemd passed to this process.
+///
+/// The protocol and port are taken from the socket, the name of the socket (by default the name
+/// of its socket unit) becomes the comment. Sockets that are bound to a single IPv4 address are
+/// forwarded to that address, all others to the address of this host. Sockets that are only
+/// reachable from this host are skipped.
+pub fn socket_configs() -> anyhow::Result<Vec<UpnpConfig>> {
+    let descriptors = activation::receive_descriptors_with_names(true)
+        .context("Could not receive the sockets from systemd")?;
+
+    if descriptors.is_empty() {
+        anyhow::bail!(
+            "--from-systemd-sockets requires sockets that systemd passed to this process"
+        );
+    }
+
+    let mut configs = Vec::new();
+
+    for (descriptor, name) in descriptors {
+        if !descriptor.is_inet() {
+            warn!("Skip socket {}, it is no internet socket", name);
+            continue;
+        }
+
+        let fd = descriptor.into_raw_fd();
+        let Some(protocol) = socket_protocol(fd) else {
+            warn!(
+                "Skip socket {}, it is neither a stream nor a datagram socket",
+                name
+            );
+            continue;
+        };
+        let addr = local_addr(fd)
+            .with_context(|| format!("Could not get the address of socket {}", name))?;
+
+        let address = match addr.ip() {
+            ip if ip.is_loopback() => {
+                warn!("Skip socket {}, it is bound to {}", name, ip);
+                continue;
+            }
+            IpAddr::V4(ip) if !ip.is_unspecified() => {
+                Some(Address::from(Ipv4Cidr::from_prefix_and_bits(ip, 32)?))
+            }
+            _ => None,
+        };
+
+        info!("Found socket {} for {} {}", name, protocol, addr.port());
+
+        configs.push(UpnpConfig {
+            address,
+            comment: name,
+            ..UpnpConfig::new(addr.port(), protocol)
+        });
+    }
+
+    Ok(configs)
+}
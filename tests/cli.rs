@@ -50,6 +50,22 @@ fn empty_json_input_fails() {
     command.assert().failure();
 }
 
+#[test]
+fn missing_file_fails_in_oneshot() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f").arg(dir.path().join("missing.csv"));
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Could not open"));
+}
+
 #[test]
 fn empty_json_array_input_passes() {
     let mut command = Command::new(&*BIN_PATH);
@@ -60,3 +76,334 @@ fn empty_json_array_input_passes() {
 
     command.write_stdin("[]").assert().success();
 }
+
+#[test]
+fn from_env_requires_the_port() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.args(["-1", "--from-env"]);
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .env_remove("UPNP_PORT")
+        .env("UPNP_PROTOCOL", "TCP")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("UPNP_PORT"));
+}
+
+#[test]
+fn invalid_cron_expression_is_rejected() {
+    Command::new(&*BIN_PATH)
+        .args(["--file=-", "--cron", "*/5 * * *"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "\"*/5 * * *\" is not a cron expression with 5 to 7 fields",
+        ));
+}
+
+#[test]
+fn trailing_json_content_needs_lenient_json() {
+    let input = "[]\n{\"port\": ";
+
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").arg("--format=json");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command.write_stdin(input).assert().code(2);
+    command
+        .arg("--lenient-json")
+        .write_stdin(input)
+        .assert()
+        .success();
+}
+
+#[test]
+fn json_on_stdin_is_detected() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .write_stdin(" {}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not a JSON array"));
+}
+
+#[test]
+fn dry_run_prints_json_plan() {
+    let mut command = Command::new(&*BIN_PATH);
+    command
+        .arg("-f-")
+        .arg("--dry-run")
+        .arg("--plan-format=json");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .write_stdin(
+            "address;port;protocol;duration;comment;requires_process\n\
+             203.0.113.0/24;80;TCP;60;Web;\n\
+             ;81;TCP;60;Game;no-such-process-name\n",
+        )
+        .assert()
+        .code(4)
+        .stdout(predicate::str::contains(r#""action": "error""#))
+        .stdout(predicate::str::contains(
+            r#""reason": "required process is not running""#,
+        ));
+}
+
+#[test]
+fn stream_stdin_requires_only_stdin() {
+    Command::new(&*BIN_PATH)
+        .arg("--stream-stdin")
+        .arg("-f-")
+        .arg("-fports.csv")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("requires \"--file -\""));
+}
+
+#[test]
+fn json_log_lines_are_structured() {
+    let mut command = Command::new(&*BIN_PATH);
+    command
+        .arg("-1f-")
+        .arg("--log-format=json")
+        .env("RUST_LOG", "error");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    let output = command
+        .write_stdin("address;port;protocol;duration;comment\n203.0.113.0/24;80;TCP;60;Web\n")
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let record: serde_json::Value = serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+
+    assert_eq!(record["level"], "ERROR");
+    assert_eq!(record["message"], "No matching gateway found");
+    assert!(record["target"].is_string());
+    assert!(record["timestamp"].is_string());
+}
+
+#[test]
+fn empty_yaml_sequence_input_passes() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").arg("--format=yaml");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command.write_stdin("[]").assert().success();
+}
+
+#[test]
+fn toml_input_without_mappings_fails() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").arg("--format=toml");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command.write_stdin("").assert().failure();
+}
+
+#[test]
+fn summary_is_logged_on_exit() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").env("RUST_LOG", "info");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Summary: 1 iterations"));
+}
+
+#[test]
+fn ports_are_processed_by_priority() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").env("RUST_LOG", "info");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    // Addresses outside of all local networks, so no gateway search is performed.
+    let input = "address;port;protocol;duration;comment;priority\n\
+                 203.0.113.1;1001;TCP;60;low;\n\
+                 203.0.113.1;1002;TCP;60;high;10\n\
+                 203.0.113.1;1003;TCP;60;low;\n";
+
+    let output = command.write_stdin(input).output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let ports: Vec<_> = ["port: 1002", "port: 1001", "port: 1003"]
+        .iter()
+        .map(|port| stderr.find(port).expect("Port has not been processed"))
+        .collect();
+
+    assert!(ports.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn unknown_csv_column_fails() {
+    Command::new(&*BIN_PATH)
+        .args([
+            "-1f-",
+            "--no-header",
+            "--csv-columns",
+            "port,protocol,bogus",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value 'bogus'"));
+}
+
+#[test]
+fn unknown_protocol_backend_fails() {
+    Command::new(&*BIN_PATH)
+        .args(["-1f-", "--protocol-backend", "bogus"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value 'bogus'"));
+}
+
+#[test]
+fn probe_without_matching_interface_fails() {
+    Command::new(&*BIN_PATH)
+        .args(["probe", "--address", "203.0.113.0/24"])
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("No UPnP gateway available"));
+}
+
+#[test]
+fn required_gateway_is_missing() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.args([
+        "-1f-",
+        "--require-gateway",
+        "--ssdp-address=127.0.0.1:9",
+        "--discovery-timeout=1",
+    ]);
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .write_stdin("")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("No UPnP gateway found at startup"));
+}
+
+#[cfg(unix)]
+#[test]
+fn address_command_is_resolved() {
+    Command::new(&*BIN_PATH)
+        .args(["-F1f-"])
+        .env("RUST_LOG", "info")
+        .write_stdin(
+            "address;port;protocol;duration;comment\nexec:echo 203.0.113.1;1001;TCP;60;Test\n",
+        )
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("prefix: 203.0.113.1,"));
+}
+
+#[cfg(unix)]
+#[test]
+fn failing_address_command_is_skipped() {
+    Command::new(&*BIN_PATH)
+        .args(["-F1f-"])
+        .env("RUST_LOG", "info")
+        .write_stdin("address;port;protocol;duration;comment\nexec:false;1001;TCP;60;Test\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Address command \"false\" failed"))
+        .stderr(predicate::str::contains("Add port").not());
+}
+
+#[test]
+fn comment_is_optional() {
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").env("RUST_LOG", "info");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .write_stdin("address;port;protocol;duration;comment\n203.0.113.1;1001;TCP;60\n")
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("comment: \"\""));
+}
+
+#[test]
+fn remove_all_without_matching_interface_fails() {
+    Command::new(&*BIN_PATH)
+        .args(["remove-all", "--yes", "--address", "203.0.113.0/24"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Could not list port mappings"));
+}
+
+#[test]
+fn export_without_matching_interface_fails() {
+    Command::new(&*BIN_PATH)
+        .args(["export", "--format", "json", "--address", "203.0.113.0/24"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Could not list port mappings"));
+}
+
+#[test]
+fn format_count_must_match_files() {
+    Command::new(&*BIN_PATH)
+        .args(["-1f-", "-f-", "-f-", "--format=csv", "--format=json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--format must be given once"));
+}
+
+#[cfg(unix)]
+#[test]
+fn running_daemon_is_not_started_twice() {
+    // Waits for input in the foreground, without touching the network
+    let mut running = std::process::Command::new(&*BIN_PATH)
+        .args(["--stream-stdin", "-f-"])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("upnp-daemon.pid");
+    std::fs::write(&pid_file, format!("{}\n", running.id())).unwrap();
+
+    let assert = Command::new(&*BIN_PATH)
+        .arg("-f-")
+        .arg("--pid-file")
+        .arg(&pid_file)
+        .assert();
+
+    running.kill().unwrap();
+    running.wait().unwrap();
+
+    assert.code(5).stderr(predicate::str::contains(format!(
+        "already running (pid {})",
+        running.id()
+    )));
+}
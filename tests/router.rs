@@ -0,0 +1,2165 @@
+//! End-to-end tests against a fake UPnP gateway.
+//!
+//! The fake gateway answers the SSDP search and the SOAP actions that upnp-daemon uses, on an
+//! address of the local machine. The daemon is pointed at it with `--ssdp-address`, so neither a
+//! real router nor network access is needed.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::Command;
+use easy_upnp::{
+    AddressResolver, DefaultResolver, DiscoveryOptions, Gateway, Ipv4Cidr, LeaseDuration,
+    PortMappingProtocol, UpnpConfig, UpnpError,
+};
+use lazy_static::lazy_static;
+use predicates::prelude::*;
+
+lazy_static! {
+    static ref BIN_PATH: PathBuf = assert_cmd::cargo::cargo_bin("upnp-daemon");
+}
+
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+/// The maximum lease duration that the fake gateway advertises.
+const MAX_LEASE_DURATION: u32 = 604800;
+
+const EXTERNAL_IP: &str = "203.0.113.7";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Mapping {
+    remote_host: String,
+    external_port: u16,
+    protocol: String,
+    internal_port: u16,
+    internal_client: String,
+    enabled: bool,
+    description: String,
+    lease_duration: u32,
+}
+
+#[derive(Default)]
+struct State {
+    mappings: Vec<Mapping>,
+
+    /// Number of HTTP requests received so far, including those for the descriptions.
+    requests: u32,
+
+    /// The SOAP actions received so far, like "AddPortMapping TCP 8080".
+    actions: Vec<String>,
+
+    /// Error codes to answer the next requests for an action with, instead of performing it.
+    failures: VecDeque<(&'static str, u16)>,
+
+    /// Actions to leave out of the service description, like a router with an incomplete service.
+    missing_actions: Vec<&'static str>,
+
+    /// Treat the TCP and UDP ports with the same number as one, like some buggy routers.
+    conflate_protocols: bool,
+
+    /// Number of next deletions to acknowledge without removing the port mapping.
+    ignored_deletions: u32,
+
+    /// Reject UDP port mappings with a lease time, like some routers.
+    permanent_udp_only: bool,
+
+    /// Advertise no maximum lease duration, like routers of the first version of the standard.
+    unlimited_leases: bool,
+}
+
+type SoapResult = Result<String, (u16, &'static str)>;
+
+impl State {
+    fn find(&self, remote_host: &str, protocol: &str, external_port: u16) -> Option<usize> {
+        self.mappings.iter().position(|m| {
+            m.remote_host == remote_host && {
+                (self.conflate_protocols || m.protocol == protocol)
+                    && m.external_port == external_port
+            }
+        })
+    }
+
+    fn handle(&mut self, action: &str, body: &str) -> SoapResult {
+        let remote_host = arg(body, "NewRemoteHost");
+        let protocol = arg(body, "NewProtocol");
+        let external_port = arg(body, "NewExternalPort").parse().unwrap_or(0);
+
+        self.actions.push(match action {
+            "AddPortMapping" | "DeletePortMapping" => {
+                format!("{} {} {}", action, protocol, external_port)
+            }
+            _ => action.to_string(),
+        });
+
+        if let Some(&(_, code)) = self.failures.front().filter(|(a, _)| *a == action) {
+            self.failures.pop_front();
+            return Err((code, "InjectedFailure"));
+        }
+
+        match action {
+            "GetExternalIPAddress" => Ok(field("NewExternalIPAddress", EXTERNAL_IP)),
+            "AddPortMapping" => {
+                let mapping = mapping_from_request(body, external_port);
+                if self.permanent_udp_only && protocol == "UDP" && mapping.lease_duration > 0 {
+                    return Err((725, "OnlyPermanentLeasesSupported"));
+                }
+                match self.find(&remote_host, &protocol, external_port) {
+                    Some(i) if self.mappings[i].internal_client != mapping.internal_client => {
+                        Err((718, "ConflictInMappingEntry"))
+                    }
+                    Some(i) => {
+                        self.mappings[i] = mapping;
+                        Ok(String::new())
+                    }
+                    None => {
+                        self.mappings.push(mapping);
+                        Ok(String::new())
+                    }
+                }
+            }
+            "AddAnyPortMapping" => {
+                let port = (external_port..=u16::MAX)
+                    .chain(50000..=u16::MAX)
+                    .find(|&port| port != 0 && self.find(&remote_host, &protocol, port).is_none())
+                    .ok_or((728, "NoPortMapsAvailable"))?;
+                self.mappings.push(mapping_from_request(body, port));
+                Ok(field("NewReservedPort", &port.to_string()))
+            }
+            "DeletePortMapping" if self.ignored_deletions > 0 => {
+                self.ignored_deletions -= 1;
+                Ok(String::new())
+            }
+            "DeletePortMapping" => match self.find(&remote_host, &protocol, external_port) {
+                Some(i) => {
+                    self.mappings.remove(i);
+                    Ok(String::new())
+                }
+                None => Err((714, "NoSuchEntryInArray")),
+            },
+            "GetSpecificPortMappingEntry" => {
+                match self.find(&remote_host, &protocol, external_port) {
+                    Some(i) => Ok(entry_fields(&self.mappings[i], false)),
+                    None => Err((714, "NoSuchEntryInArray")),
+                }
+            }
+            "GetGenericPortMappingEntry" => {
+                let index: usize = arg(body, "NewPortMappingIndex")
+                    .parse()
+                    .unwrap_or(usize::MAX);
+                match self.mappings.get(index) {
+                    Some(mapping) => Ok(entry_fields(mapping, true)),
+                    None => Err((713, "SpecifiedArrayIndexInvalid")),
+                }
+            }
+            _ => Err((401, "InvalidAction")),
+        }
+    }
+}
+
+/// Get the text of the first element with the given name, or an empty string.
+fn arg(body: &str, name: &str) -> String {
+    let start = format!("<{}>", name);
+    let end = format!("</{}>", name);
+
+    body.find(&start)
+        .map(|i| &body[i + start.len()..])
+        .and_then(|rest| rest.find(&end).map(|j| rest[..j].trim().to_string()))
+        .unwrap_or_default()
+}
+
+fn field(name: &str, value: &str) -> String {
+    format!("<{name}>{value}</{name}>")
+}
+
+fn mapping_from_request(body: &str, external_port: u16) -> Mapping {
+    Mapping {
+        remote_host: arg(body, "NewRemoteHost"),
+        external_port,
+        protocol: arg(body, "NewProtocol"),
+        internal_port: arg(body, "NewInternalPort").parse().unwrap_or(0),
+        internal_client: arg(body, "NewInternalClient"),
+        enabled: arg(body, "NewEnabled") == "1",
+        description: arg(body, "NewPortMappingDescription"),
+        lease_duration: arg(body, "NewLeaseDuration").parse().unwrap_or(0),
+    }
+}
+
+fn entry_fields(mapping: &Mapping, generic: bool) -> String {
+    let mut fields = vec![
+        field("NewInternalPort", &mapping.internal_port.to_string()),
+        field("NewInternalClient", &mapping.internal_client),
+        field("NewEnabled", if mapping.enabled { "1" } else { "0" }),
+        field("NewPortMappingDescription", &mapping.description),
+        field("NewLeaseDuration", &mapping.lease_duration.to_string()),
+    ];
+
+    if generic {
+        fields.extend([
+            field("NewRemoteHost", &mapping.remote_host),
+            field("NewExternalPort", &mapping.external_port.to_string()),
+            field("NewProtocol", &mapping.protocol),
+        ]);
+    }
+
+    fields.concat()
+}
+
+fn soap_response(action: &str, result: SoapResult) -> (&'static str, String) {
+    let (status, body) = match result {
+        Ok(fields) => (
+            "200 OK",
+            format!(
+                r#"<u:{action}Response xmlns:u="{SERVICE_TYPE}">{fields}</u:{action}Response>"#
+            ),
+        ),
+        Err((code, description)) => (
+            "500 Internal Server Error",
+            format!(
+                "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+                 <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+                 <errorCode>{code}</errorCode><errorDescription>{description}</errorDescription>\
+                 </UPnPError></detail></s:Fault>"
+            ),
+        ),
+    };
+
+    (
+        status,
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>{body}</s:Body>
+</s:Envelope>"#
+        ),
+    )
+}
+
+fn device_description() -> String {
+    format!(
+        r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<friendlyName>Fake Gateway</friendlyName>
+<manufacturer>Fake Inc.</manufacturer>
+<modelName>Fake Router 1</modelName>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<serviceList><service>
+<serviceType>{SERVICE_TYPE}</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<SCPDURL>/WANIPCn.xml</SCPDURL>
+<controlURL>/ctl/IPConn</controlURL>
+<eventSubURL>/evt/IPConn</eventSubURL>
+</service></serviceList>
+</device></deviceList>
+</device></deviceList>
+</device>
+</root>"#
+    )
+}
+
+fn service_description(state: &State) -> String {
+    let mapping_args = [
+        "NewRemoteHost",
+        "NewExternalPort",
+        "NewProtocol",
+        "NewInternalPort",
+        "NewInternalClient",
+        "NewEnabled",
+        "NewPortMappingDescription",
+        "NewLeaseDuration",
+    ];
+    let actions: [(&str, &[&str]); 6] = [
+        ("GetExternalIPAddress", &[]),
+        ("AddPortMapping", &mapping_args),
+        ("AddAnyPortMapping", &mapping_args),
+        (
+            "DeletePortMapping",
+            &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+        ),
+        (
+            "GetSpecificPortMappingEntry",
+            &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+        ),
+        ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+    ];
+
+    let actions: String = actions
+        .iter()
+        .filter(|(name, _)| !state.missing_actions.contains(name))
+        .map(|(name, args)| {
+            let args: String = args
+                .iter()
+                .map(|arg| {
+                    format!("<argument><name>{arg}</name><direction>in</direction></argument>")
+                })
+                .collect();
+            format!("<action><name>{name}</name><argumentList>{args}</argumentList></action>")
+        })
+        .collect();
+
+    let range = if state.unlimited_leases {
+        String::new()
+    } else {
+        format!(
+            "<allowedValueRange><minimum>0</minimum><maximum>{MAX_LEASE_DURATION}</maximum>\
+             </allowedValueRange>"
+        )
+    };
+
+    format!(
+        r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<actionList>{actions}</actionList>
+<serviceStateTable>
+<stateVariable sendEvents="no">
+<name>PortMappingLeaseDuration</name>
+<dataType>ui4</dataType>
+{range}
+</stateVariable>
+</serviceStateTable>
+</scpd>"#
+    )
+}
+
+fn serve_http(mut stream: TcpStream, state: &Mutex<State>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut content_length = 0;
+    let mut soap_action = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                "soapaction" => soap_action = value.trim().trim_matches('"').to_string(),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = String::from_utf8_lossy(&body);
+
+    state.lock().unwrap().requests += 1;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, content) = match path {
+        "/rootDesc.xml" => ("200 OK", device_description()),
+        "/WANIPCn.xml" => ("200 OK", service_description(&state.lock().unwrap())),
+        "/ctl/IPConn" => {
+            let action = soap_action.rsplit('#').next().unwrap_or_default();
+            let result = state.lock().unwrap().handle(action, &body);
+            soap_response(action, result)
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content.len(),
+        content
+    )
+}
+
+/// A fake gateway, running in background threads until the test ends.
+struct FakeGateway {
+    ip: Ipv4Addr,
+    http_addr: SocketAddrV4,
+    ssdp_addr: SocketAddrV4,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeGateway {
+    /// Start the fake gateway on the first non-loopback IPv4 address of this machine.
+    ///
+    /// The daemon never searches for gateways via the loopback interface. So if there is no other
+    /// interface, [None] is returned and the test should be skipped.
+    fn start() -> Option<Self> {
+        let ip = get_if_addrs::get_if_addrs()
+            .ok()?
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .find_map(|iface| match iface.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            });
+
+        let Some(ip) = ip else {
+            eprintln!("No non-loopback IPv4 interface, skipping test");
+            return None;
+        };
+
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let http = TcpListener::bind((ip, 0)).unwrap();
+        let http_port = http.local_addr().unwrap().port();
+        {
+            let state = state.clone();
+            thread::spawn(move || {
+                for stream in http.incoming().flatten() {
+                    let _ = serve_http(stream, &state);
+                }
+            });
+        }
+
+        let ssdp = UdpSocket::bind((ip, 0)).unwrap();
+        let ssdp_port = ssdp.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            while let Ok((read, peer)) = ssdp.recv_from(&mut buf) {
+                if buf[..read].starts_with(b"M-SEARCH") {
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                         CACHE-CONTROL: max-age=120\r\n\
+                         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                         USN: uuid:fake-gateway\r\n\
+                         LOCATION: http://{}:{}/rootDesc.xml\r\n\r\n",
+                        ip, http_port
+                    );
+                    let _ = ssdp.send_to(response.as_bytes(), peer);
+                }
+            }
+        });
+
+        Some(Self {
+            ip,
+            http_addr: SocketAddrV4::new(ip, http_port),
+            ssdp_addr: SocketAddrV4::new(ip, ssdp_port),
+            state,
+        })
+    }
+
+    fn add_mapping(&self, external_port: u16, protocol: &str, internal_client: &str) {
+        self.state.lock().unwrap().mappings.push(Mapping {
+            remote_host: String::new(),
+            external_port,
+            protocol: protocol.to_string(),
+            internal_port: external_port,
+            internal_client: internal_client.to_string(),
+            enabled: true,
+            description: "Existing".to_string(),
+            lease_duration: 0,
+        });
+    }
+
+    /// Disable all port mappings, like a user would in the web interface of the router.
+    fn disable_mappings(&self) {
+        for mapping in &mut self.state.lock().unwrap().mappings {
+            mapping.enabled = false;
+        }
+    }
+
+    fn fail_next(&self, action: &'static str, code: u16) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .push_back((action, code));
+    }
+
+    fn mappings(&self) -> Vec<Mapping> {
+        self.state.lock().unwrap().mappings.clone()
+    }
+
+    fn requests(&self) -> u32 {
+        self.state.lock().unwrap().requests
+    }
+
+    /// The received actions that add or delete port mappings.
+    fn changes(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .actions
+            .iter()
+            .filter(|action| action.starts_with("Add") || action.starts_with("Delete"))
+            .cloned()
+            .collect()
+    }
+
+    /// The arguments for the daemon to only find this gateway.
+    fn search_args(&self) -> [String; 4] {
+        [
+            "--ssdp-address".to_string(),
+            self.ssdp_addr.to_string(),
+            "--interface-priority".to_string(),
+            format!("{}/32", self.ip),
+        ]
+    }
+
+    /// A command for the daemon that only finds this gateway.
+    fn command(&self) -> Command {
+        let mut command = Command::new(&*BIN_PATH);
+        command.args(self.search_args());
+
+        command
+    }
+
+    /// A oneshot command for the daemon that reads the configuration from stdin.
+    fn oneshot(&self) -> Command {
+        let mut command = self.command();
+        command.arg("-1f-");
+
+        #[cfg(unix)]
+        command.arg("-F");
+
+        command
+    }
+}
+
+#[test]
+fn ports_are_mapped_to_the_local_address() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        gateway.mappings(),
+        [
+            Mapping {
+                remote_host: String::new(),
+                external_port: 8080,
+                protocol: "TCP".to_string(),
+                internal_port: 8080,
+                internal_client: gateway.ip.to_string(),
+                enabled: true,
+                description: "Web".to_string(),
+                lease_duration: 3600,
+            },
+            Mapping {
+                remote_host: String::new(),
+                external_port: 8081,
+                protocol: "UDP".to_string(),
+                internal_port: 8081,
+                internal_client: gateway.ip.to_string(),
+                enabled: true,
+                description: "Game".to_string(),
+                lease_duration: 3600,
+            },
+        ]
+    );
+}
+
+#[test]
+fn other_protocols_are_skipped() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--protocols=tcp")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn ports_outside_of_the_allowlist_are_refused() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--allowed-ports=30000-40000,8080")
+        .env("RUST_LOG", "warn")
+        .write_stdin(
+            "port;protocol;duration;comment\n8080;TCP;3600;Web\n22;TCP;3600;SSH\n30000;UDP;3600;Game\n",
+        )
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Skip port TCP 22, it is outside of the allowed ports",
+        ));
+
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "AddPortMapping UDP 30000"]
+    );
+}
+
+#[test]
+fn any_port_is_refused_with_an_allowlist() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--allowed-ports=8080-8081")
+        .env("RUST_LOG", "warn")
+        .write_stdin(
+            "port;protocol;duration;comment;any_port\n8080;TCP;3600;Web;true\n8081;TCP;3600;Game;false\n",
+        )
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Skip port TCP 8080, the router would choose an external port",
+        ));
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8081"]);
+}
+
+#[test]
+fn ports_are_closed_on_exit() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--close-ports-on-exit")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        gateway.changes(),
+        [
+            "AddPortMapping TCP 8080",
+            "AddPortMapping UDP 8081",
+            "DeletePortMapping TCP 8080",
+            "DeletePortMapping UDP 8081",
+        ]
+    );
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[test]
+fn conflicting_port_mapping_is_replaced() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        gateway.changes(),
+        [
+            "AddPortMapping TCP 8080",
+            "DeletePortMapping TCP 8080",
+            "AddPortMapping TCP 8080",
+        ]
+    );
+    assert_eq!(
+        gateway.mappings()[0].internal_client,
+        gateway.ip.to_string()
+    );
+}
+
+#[test]
+fn conflicting_port_mapping_is_kept_without_terminal() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+
+    gateway
+        .oneshot()
+        .arg("--interactive")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert!(gateway.changes().is_empty());
+    assert_eq!(gateway.mappings()[0].internal_client, "192.0.2.99");
+}
+
+#[test]
+fn protected_port_mapping_is_not_deleted() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+
+    gateway
+        .oneshot()
+        .arg("--protect-port=22,8080")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("protected"));
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+    assert_eq!(gateway.mappings()[0].internal_client, "192.0.2.99");
+}
+
+#[test]
+fn ipv6_preference_still_maps_via_ipv4() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--prefer-family=v6")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn gateway_url_skips_the_discovery() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut command = Command::new(&*BIN_PATH);
+    command
+        .arg(format!(
+            "--gateway-url=http://{}/rootDesc.xml",
+            gateway.http_addr
+        ))
+        .args([
+            "--ssdp-address=127.0.0.1:9",
+            "--discovery-timeout=1",
+            "-1f-",
+        ]);
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    let mappings = gateway.mappings();
+    assert_eq!(mappings[0].external_port, 8080);
+    assert_eq!(mappings[0].internal_client, gateway.ip.to_string());
+}
+
+#[test]
+fn comment_prefix_is_prepended() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--comment-prefix=[host-a] ")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings()[0].description, "[host-a] Web");
+}
+
+#[test]
+fn descriptions_are_built_from_the_template() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--comment-prefix=[host-a] ")
+        .arg("--description-template={comment} (upnp-daemon {version})")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(
+        gateway.mappings()[0].description,
+        format!("[host-a] Web (upnp-daemon {})", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn required_gateway_is_found() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--require-gateway")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn address_candidates_are_tried_in_order() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let config = serde_json::json!([{
+        "address": ["203.0.113.0/24", format!("{}/24", gateway.ip)],
+        "port": 8080,
+        "protocol": "TCP",
+        "duration": 3600,
+    }]);
+    gateway
+        .oneshot()
+        .write_stdin(config.to_string())
+        .assert()
+        .success();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn gateways_are_filtered_by_name() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let config = "port;protocol;duration;comment\n8080;TCP;3600;Web\n";
+
+    gateway
+        .oneshot()
+        .arg("--gateway-name=Other")
+        .write_stdin(config)
+        .assert()
+        .code(4);
+    assert!(gateway.changes().is_empty());
+
+    gateway
+        .oneshot()
+        .arg("--gateway-name=Fake")
+        .write_stdin(config)
+        .assert()
+        .success();
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn port_mapping_can_be_pinned_to_a_gateway() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--gateway-name=Other")
+        .write_stdin(format!(
+            "port;protocol;duration;comment;gateway\n\
+             8080;TCP;3600;Web;Fake\n\
+             8081;TCP;3600;Web;Other\n\
+             8082;TCP;3600;Web;http://{}/rootDesc.xml\n",
+            gateway.http_addr
+        ))
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("Gateway \"Other\" not found"));
+
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "AddPortMapping TCP 8082"]
+    );
+}
+
+#[test]
+fn refresh_once_looks_up_pinned_port_mappings_on_their_gateway() {
+    let (Some(searched), Some(pinned)) = (FakeGateway::start(), FakeGateway::start()) else {
+        return;
+    };
+
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        config,
+        "port;protocol;duration;comment;gateway\n8080;TCP;0;Web;http://{}/rootDesc.xml\n",
+        pinned.http_addr
+    )
+    .unwrap();
+
+    let mut command = std::process::Command::new(&*BIN_PATH);
+    command
+        .args(searched.search_args())
+        .arg("--interval=1")
+        .arg("--refresh-once")
+        .arg("-f")
+        .arg(config.path());
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    let mut daemon = command
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let started = Instant::now();
+    while pinned.mappings().is_empty() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    thread::sleep(Duration::from_secs(3));
+
+    daemon.kill().unwrap();
+    let output = daemon.wait_with_output().unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!log.contains("has vanished"), "{}", log);
+    assert!(searched.changes().is_empty());
+    assert_eq!(pinned.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn soap_requests_are_recorded() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let record = tempfile::NamedTempFile::new().unwrap();
+
+    gateway
+        .oneshot()
+        .arg("--record-soap")
+        .arg(record.path())
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    let record = std::fs::read_to_string(record.path()).unwrap();
+    assert!(record.contains("AddPortMapping"));
+    assert!(record.contains("<NewExternalPort>8080</NewExternalPort>"));
+    assert!(record.contains("<<< 200"));
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn listing_of_port_mappings_is_recorded() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+    let record = tempfile::NamedTempFile::new().unwrap();
+
+    gateway
+        .command()
+        .arg("--record-soap")
+        .arg(record.path())
+        .arg("remove-all")
+        .arg("--yes")
+        .assert()
+        .success();
+
+    let record = std::fs::read_to_string(record.path()).unwrap();
+    assert!(record.contains("GetGenericPortMappingEntry"));
+    assert!(record.contains("DeletePortMapping"));
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[test]
+fn metrics_are_written_after_each_iteration() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let metrics = dir.path().join("upnp-daemon.prom");
+
+    gateway
+        .oneshot()
+        .arg("--metrics-textfile")
+        .arg(&metrics)
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    let metrics = std::fs::read_to_string(metrics).unwrap();
+    assert!(metrics.contains("\nupnp_daemon_iterations_total 1\n"));
+    assert!(metrics.contains("\nupnp_daemon_port_mappings_added_total 1\n"));
+    assert!(metrics.contains("\nupnp_daemon_port_mapping_failures_total 0\n"));
+    assert!(metrics.contains(&format!(
+        "upnp_daemon_external_ip_info{{address=\"{}\"}} 1",
+        EXTERNAL_IP
+    )));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn gateway_is_prefetched_at_startup() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--prefetch")
+        .env("RUST_LOG", "info")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "with external IP {} in",
+            EXTERNAL_IP
+        )));
+
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "AddPortMapping UDP 8081"]
+    );
+}
+
+#[test]
+fn recorded_exchange_is_replayed_without_the_gateway() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let record = tempfile::NamedTempFile::new().unwrap();
+    let input = "port;protocol;duration;comment\n8080;TCP;3600;Web\n";
+
+    gateway
+        .oneshot()
+        .arg("--report-outcomes")
+        .arg("--record-soap")
+        .arg(record.path())
+        .write_stdin(input)
+        .assert()
+        .success();
+
+    let mut command = Command::new(&*BIN_PATH);
+    command
+        .arg("-1f-")
+        .arg("--report-outcomes")
+        .arg("--replay-soap")
+        .arg(record.path());
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .env("RUST_LOG", "info")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Port mapping TCP 8080 created"));
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn replay_sends_no_requests_to_the_gateway() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let added = tempfile::NamedTempFile::new().unwrap();
+    let removed = tempfile::NamedTempFile::new().unwrap();
+    let input = "port;protocol;any_port\n8080;TCP;true\n";
+
+    gateway
+        .oneshot()
+        .arg("--record-soap")
+        .arg(added.path())
+        .write_stdin(input)
+        .assert()
+        .success();
+    gateway
+        .command()
+        .arg("--record-soap")
+        .arg(removed.path())
+        .arg("remove-all")
+        .arg("--yes")
+        .assert()
+        .success();
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+    let requests = gateway.requests();
+
+    let mut command = Command::new(&*BIN_PATH);
+    command.arg("-1f-").arg("--replay-soap").arg(added.path());
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command.write_stdin(input).assert().success();
+    Command::new(&*BIN_PATH)
+        .arg("--replay-soap")
+        .arg(removed.path())
+        .arg("remove-all")
+        .arg("--yes")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.requests(), requests);
+    assert_eq!(gateway.mappings().len(), 1);
+}
+
+#[test]
+fn recorded_refusal_is_replayed_from_fixture() {
+    let mut command = Command::new(&*BIN_PATH);
+    command
+        .arg("-1f-")
+        .arg("--replay-soap")
+        .arg("tests/fixtures/not-authorized.soap");
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .env("RUST_LOG", "info")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("refused to authorize"));
+}
+
+#[test]
+fn internal_hostname_is_looked_up_in_dhcp_leases() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut leases = tempfile::NamedTempFile::new().unwrap();
+    writeln!(
+        leases,
+        "1700000000 00:11:22:33:44:55 192.168.0.20 nas 01:00:11:22:33:44:55"
+    )
+    .unwrap();
+
+    gateway
+        .oneshot()
+        .arg("--dhcp-leases")
+        .arg(leases.path())
+        .write_stdin(
+            "port;protocol;duration;comment;internal_hostname\n\
+            8080;TCP;3600;NAS;nas\n\
+            8081;TCP;3600;Printer;printer\n",
+        )
+        .assert()
+        .success();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+    assert_eq!(gateway.mappings()[0].internal_client, "192.168.0.20");
+}
+
+#[test]
+fn oneshot_mode_notes_that_port_mappings_stay_open() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .env("RUST_LOG", "info")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "stay open until their leases expire",
+        ));
+}
+
+#[test]
+fn disabled_port_mapping_is_enabled_in_place() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", &gateway.ip.to_string());
+    gateway.state.lock().unwrap().mappings[0].lease_duration = 3000;
+    gateway.disable_mappings();
+
+    // Most of the lease is left, so only the disabled state makes the mapping due for renewal.
+    gateway
+        .oneshot()
+        .arg("--renew-threshold=50")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+    assert!(gateway.mappings()[0].enabled);
+}
+
+#[test]
+fn manually_removed_port_mapping_is_not_added_again() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        config,
+        "port;protocol;duration;comment\n8080;TCP;3600;Web\n"
+    )
+    .unwrap();
+
+    let mut command = std::process::Command::new(&*BIN_PATH);
+    command
+        .args(gateway.search_args())
+        .arg("--interval=1")
+        .arg("--respect-manual-removal")
+        .arg("-f")
+        .arg(config.path());
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    let mut daemon = command.spawn().unwrap();
+
+    let started = Instant::now();
+    while gateway.mappings().is_empty() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    gateway.state.lock().unwrap().mappings.clear();
+    thread::sleep(Duration::from_secs(3));
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[cfg(unix)]
+#[test]
+fn paused_daemon_leaves_the_router_alone() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        config,
+        "port;protocol;duration;comment\n8080;TCP;3600;Web\n"
+    )
+    .unwrap();
+
+    let mut daemon = std::process::Command::new(&*BIN_PATH)
+        .args(gateway.search_args())
+        .arg("--interval=1")
+        .arg("-F")
+        .arg("-f")
+        .arg(config.path())
+        .spawn()
+        .unwrap();
+
+    let toggle_pause = || {
+        std::process::Command::new("kill")
+            .arg("-USR1")
+            .arg(daemon.id().to_string())
+            .status()
+            .unwrap()
+    };
+
+    let wait_for_mapping = || {
+        let started = Instant::now();
+        while gateway.mappings().is_empty() && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    wait_for_mapping();
+    toggle_pause();
+    gateway.state.lock().unwrap().mappings.clear();
+    thread::sleep(Duration::from_secs(3));
+    let paused_changes = gateway.changes();
+
+    toggle_pause();
+    wait_for_mapping();
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    assert_eq!(paused_changes, ["AddPortMapping TCP 8080"]);
+    assert_eq!(gateway.mappings().len(), 1);
+}
+
+#[test]
+fn refused_port_mapping_fails() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.fail_next("AddPortMapping", 606);
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .code(4);
+
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[test]
+fn failed_removal_of_conflicting_port_mapping_is_reported() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.168.0.99");
+    gateway.fail_next("DeletePortMapping", 606);
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;TCP;3600;Web\n")
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains(
+            "the existing mapping could not be deleted",
+        ));
+
+    assert_eq!(gateway.mappings()[0].internal_client, "192.168.0.99");
+    assert_eq!(gateway.mappings()[1].external_port, 8081);
+}
+
+#[test]
+fn long_lease_is_reduced_to_the_maximum() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;99999999;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings()[0].lease_duration, MAX_LEASE_DURATION);
+}
+
+#[test]
+fn zero_duration_requests_a_permanent_lease() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;0;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings()[0].lease_duration, 0);
+}
+
+#[test]
+fn outcome_of_each_port_mapping_is_logged() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", &gateway.ip.to_string());
+    gateway.add_mapping(8081, "TCP", &gateway.ip.to_string());
+
+    gateway
+        .oneshot()
+        .arg("--report-outcomes")
+        .env("RUST_LOG", "info")
+        .write_stdin(
+            "port;protocol;duration;comment\n\
+             8080;TCP;0;Existing\n\
+             8081;TCP;3600;Existing\n\
+             8082;TCP;3600;New\n",
+        )
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Port mapping TCP 8080 unchanged"))
+        .stderr(predicate::str::contains("Port mapping TCP 8081 refreshed"))
+        .stderr(predicate::str::contains("Port mapping TCP 8082 created"));
+
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8081", "AddPortMapping TCP 8082"]
+    );
+}
+
+#[test]
+fn outcome_is_only_looked_up_on_request() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .env("RUST_LOG", "info")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+
+    assert!(!gateway
+        .state
+        .lock()
+        .unwrap()
+        .actions
+        .iter()
+        .any(|action| action == "GetSpecificPortMappingEntry"));
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn omitted_duration_uses_the_router_default() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;comment\n8080;TCP;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings()[0].lease_duration, MAX_LEASE_DURATION);
+}
+
+#[test]
+fn omitted_duration_is_limited_without_a_maximum() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.state.lock().unwrap().unlimited_leases = true;
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;comment\n8080;TCP;Web\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings()[0].lease_duration, 7200);
+}
+
+#[test]
+fn duration_override_replaces_configured_durations() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--duration-override=120")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;auto;Game\n")
+        .assert()
+        .success();
+
+    let durations: Vec<_> = gateway
+        .mappings()
+        .iter()
+        .map(|mapping| mapping.lease_duration)
+        .collect();
+    assert_eq!(durations, [120, 120]);
+}
+
+#[test]
+fn conflated_protocols_are_detected() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.state.lock().unwrap().conflate_protocols = true;
+
+    gateway
+        .oneshot()
+        .arg("--distinct-protocol-ports=warn")
+        .env("RUST_LOG", "warn")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8080;UDP;3600;Game\n")
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("as a single slot"));
+
+    assert_eq!(gateway.mappings().len(), 1);
+}
+
+#[test]
+fn conflated_protocols_are_staggered() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.state.lock().unwrap().conflate_protocols = true;
+
+    gateway
+        .oneshot()
+        .arg("--distinct-protocol-ports=stagger")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8080;UDP;3600;Game\n")
+        .assert()
+        .success();
+
+    let mappings = gateway.mappings();
+    assert_eq!(mappings.len(), 2);
+    assert!(mappings
+        .iter()
+        .any(|mapping| mapping.protocol == "UDP" && mapping.external_port == 8080));
+    assert!(mappings
+        .iter()
+        .any(|mapping| mapping.protocol == "TCP" && mapping.external_port != 8080));
+}
+
+#[test]
+fn staggered_ports_are_closed_on_exit() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.state.lock().unwrap().conflate_protocols = true;
+
+    gateway
+        .oneshot()
+        .args(["--distinct-protocol-ports=stagger", "--close-ports-on-exit"])
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8080;UDP;3600;Game\n")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[test]
+fn streamed_ports_are_applied_line_by_line() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut command = gateway.command();
+    command.arg("--stream-stdin").arg("-f-");
+
+    command
+        .write_stdin(
+            "{\"port\": 8080, \"protocol\": \"TCP\", \"duration\": 0, \"comment\": \"Web\"}\n\
+             \n\
+             {\"port\": 8081, \"protocol\": \"UDP\", \"duration\": 0, \"comment\": \"Game\"}\n",
+        )
+        .assert()
+        .success();
+
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "AddPortMapping UDP 8081"]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_stream_closes_the_opened_ports() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut daemon = std::process::Command::new(&*BIN_PATH)
+        .arg("--ssdp-address")
+        .arg(gateway.ssdp_addr.to_string())
+        .arg("--interface-priority")
+        .arg(format!("{}/32", gateway.ip))
+        .arg("--stream-stdin")
+        .arg("--close-ports-on-exit")
+        .arg("-f-")
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Keep stdin open, so that only the interrupt can end the stream
+    let mut stdin = daemon.stdin.take().unwrap();
+    writeln!(
+        stdin,
+        "{{\"port\": 8080, \"protocol\": \"TCP\", \"duration\": 0, \"comment\": \"Web\"}}"
+    )
+    .unwrap();
+
+    let started = Instant::now();
+    while gateway.mappings().is_empty() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    std::process::Command::new("kill")
+        .arg("-INT")
+        .arg(daemon.id().to_string())
+        .status()
+        .unwrap();
+    let status = daemon.wait().unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "DeletePortMapping TCP 8080"]
+    );
+}
+
+#[test]
+fn remove_all_by_comment_keeps_other_mappings() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+    gateway.add_mapping(8081, "UDP", "192.0.2.98");
+    gateway.state.lock().unwrap().mappings[0].description = "[host-a] Web".to_string();
+
+    gateway
+        .command()
+        .args(["remove-all", "--yes", "--comment=[host-a] *"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Removed 1 port mappings"));
+
+    assert_eq!(gateway.changes(), ["DeletePortMapping TCP 8080"]);
+    assert_eq!(gateway.mappings()[0].external_port, 8081);
+
+    gateway
+        .command()
+        .args(["remove-all", "--yes", "--comment=[host-b] *"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No port mappings found"));
+}
+
+#[test]
+fn renew_refreshes_a_single_port_mapping() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", &gateway.ip.to_string());
+    gateway.add_mapping(8081, "TCP", &gateway.ip.to_string());
+
+    gateway
+        .command()
+        .args(["renew", "--port=8080", "--protocol=tcp", "--duration=600"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Renewed TCP 8080"));
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+    let mappings = gateway.mappings();
+    assert_eq!(mappings[0].description, "Existing");
+    assert_eq!(mappings[0].lease_duration, 600);
+    assert_eq!(mappings[1].lease_duration, 0);
+}
+
+#[test]
+fn renew_creates_a_missing_port_mapping() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .command()
+        .args(["renew", "--port=8080", "--protocol=udp", "--comment=Game"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "No port mapping for UDP 8080 existed",
+        ));
+
+    let mappings = gateway.mappings();
+    assert_eq!(mappings[0].description, "Game");
+    assert_eq!(mappings[0].lease_duration, 3600);
+}
+
+#[test]
+fn renew_leaves_the_port_mapping_of_another_host_alone() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+
+    gateway
+        .command()
+        .args(["renew", "--port=8080", "--protocol=tcp"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("forwarded to 192.0.2.99:8080"));
+
+    assert!(gateway.changes().is_empty());
+}
+
+#[test]
+fn probe_reports_unsupported_actions() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .command()
+        .arg("probe")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Supported actions: all"));
+
+    gateway.state.lock().unwrap().missing_actions = vec!["GetGenericPortMappingEntry"];
+
+    gateway
+        .command()
+        .arg("probe")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Unsupported actions: GetGenericPortMappingEntry",
+        ));
+
+    gateway
+        .command()
+        .arg("export")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "does not support the GetGenericPortMappingEntry action",
+        ));
+}
+
+#[test]
+fn remove_all_clears_the_gateway() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", "192.0.2.99");
+    gateway.add_mapping(8081, "UDP", "192.0.2.98");
+
+    gateway
+        .command()
+        .arg("remove-all")
+        .arg("--yes")
+        .assert()
+        .success();
+
+    assert_eq!(
+        gateway.changes(),
+        ["DeletePortMapping TCP 8080", "DeletePortMapping UDP 8081"]
+    );
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[test]
+fn remove_all_clears_port_mappings_for_a_remote_host() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .write_stdin("port;protocol;remote_host\n8080;TCP;198.51.100.1\n")
+        .assert()
+        .success();
+    assert_eq!(gateway.mappings()[0].remote_host, "198.51.100.1");
+
+    gateway
+        .command()
+        .arg("remove-all")
+        .arg("--yes")
+        .assert()
+        .success();
+
+    assert_eq!(gateway.mappings(), []);
+}
+
+#[test]
+fn from_env_maps_a_single_port() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut command = gateway.command();
+    command.args(["-1", "--from-env"]);
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    command
+        .env("UPNP_PORT", "8080")
+        .env("UPNP_PROTOCOL", "UDP")
+        .env("UPNP_DURATION", "600")
+        .env("UPNP_COMMENT", "Game")
+        .assert()
+        .success();
+
+    let mappings = gateway.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].external_port, 8080);
+    assert_eq!(mappings[0].description, "Game");
+    assert_eq!(mappings[0].lease_duration, 600);
+}
+
+#[test]
+fn chosen_gateway_is_logged() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .env("RUST_LOG", "debug")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;TCP;3600;Web\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "manufacturer: Fake Inc., model: Fake Router 1",
+        ))
+        .stderr(predicate::str::contains("/ctl/IPConn"))
+        .stderr(predicate::function(|stderr: &str| {
+            stderr.matches("Using gateway").count() == 1
+        }));
+}
+
+#[test]
+fn search_options_are_logged_on_request() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let input = "port;protocol;duration;comment\n8080;TCP;3600;Web\n";
+
+    gateway
+        .oneshot()
+        .env("RUST_LOG", "debug")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Search options").not());
+
+    gateway
+        .oneshot()
+        .arg("--debug-search-options")
+        .env("RUST_LOG", "debug")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "Search options of attempt 1 of 3: bind address {}:0, broadcast address {}",
+            gateway.ip, gateway.ssdp_addr
+        )));
+}
+
+#[test]
+fn durations_of_operations_are_logged() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .env("RUST_LOG", "debug")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Finding the gateway for port 8080 took",
+        ))
+        .stderr(predicate::str::contains("Adding port TCP 8080 took"))
+        .stderr(predicate::str::contains("Iteration took"));
+}
+
+#[test]
+fn bind_ip_must_be_in_the_address_range() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg(format!("--bind-ip={}", gateway.ip))
+        .write_stdin("address;port;protocol;duration;comment\n;8080;TCP;3600;Web\n")
+        .assert()
+        .success();
+    assert_eq!(
+        gateway.mappings()[0].internal_client,
+        gateway.ip.to_string()
+    );
+
+    gateway
+        .oneshot()
+        .arg(format!("--bind-ip={}", gateway.ip))
+        .write_stdin("address;port;protocol;duration;comment\n203.0.113.0/24;8081;TCP;3600;Web\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Bind IP {} is not the address of any matching interface",
+            gateway.ip
+        )));
+    assert_eq!(gateway.mappings().len(), 1);
+}
+
+#[test]
+fn ignored_removal_is_retried() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", &gateway.ip.to_string());
+    gateway.state.lock().unwrap().ignored_deletions = 1;
+
+    gateway
+        .oneshot()
+        .args(["--only-close-ports", "--verify-removal"])
+        .env("RUST_LOG", "warn")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Port mapping TCP 8080 still exists after removing it, trying again",
+        ));
+
+    assert_eq!(
+        gateway.changes(),
+        ["DeletePortMapping TCP 8080", "DeletePortMapping TCP 8080"]
+    );
+    assert!(gateway.mappings().is_empty());
+}
+
+#[test]
+fn failed_removal_skips_the_hook() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.add_mapping(8080, "TCP", &gateway.ip.to_string());
+    gateway.state.lock().unwrap().ignored_deletions = 2;
+
+    gateway
+        .oneshot()
+        .args(["--only-close-ports", "--verify-removal"])
+        .args(["--on-remove", "/nonexistent/port-removed"])
+        .env("RUST_LOG", "warn")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Port mapping TCP 8080 still exists after removing it twice",
+        ))
+        .stderr(predicate::str::contains("port-removed").not());
+
+    assert_eq!(gateway.mappings().len(), 1);
+}
+
+#[test]
+fn udp_falls_back_to_a_permanent_lease() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    gateway.state.lock().unwrap().permanent_udp_only = true;
+
+    gateway
+        .oneshot()
+        .env("RUST_LOG", "info")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Gateway rejected the lease of 3600 seconds for UDP port 8081",
+        ));
+
+    assert_eq!(
+        gateway.changes(),
+        [
+            "AddPortMapping TCP 8080",
+            "AddPortMapping UDP 8081",
+            "AddPortMapping UDP 8081"
+        ]
+    );
+    let mappings = gateway.mappings();
+    assert_eq!(mappings[0].lease_duration, 3600);
+    assert_eq!(mappings[1].lease_duration, 0);
+}
+
+#[test]
+fn summary_replaces_the_lines_per_port_mapping() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    gateway
+        .oneshot()
+        .arg("--summary")
+        .env("RUST_LOG", "info")
+        .write_stdin("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("2 added, 0 skipped, 0 failed in"))
+        .stderr(predicate::str::contains("Add port").not());
+}
+
+#[cfg(unix)]
+#[test]
+fn fifo_writes_are_applied_right_away() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("ports.csv");
+    let created = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !created {
+        return;
+    }
+
+    let mut daemon = std::process::Command::new(&*BIN_PATH)
+        .args(gateway.search_args())
+        .arg("--interval=3600")
+        .arg("-F")
+        .arg("-f")
+        .arg(&fifo)
+        .spawn()
+        .unwrap();
+
+    let write_fifo = |content: &str| std::fs::write(&fifo, content).unwrap();
+    let wait_for_mappings = |count: usize| {
+        let started = Instant::now();
+        while gateway.mappings().len() < count && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    write_fifo("port;protocol;duration;comment\n8080;TCP;3600;Web\n");
+    wait_for_mappings(1);
+    write_fifo("port;protocol;duration;comment\n8080;TCP;3600;Web\n8081;UDP;3600;Game\n");
+    wait_for_mappings(2);
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    assert_eq!(
+        gateway.changes(),
+        [
+            "AddPortMapping TCP 8080",
+            "AddPortMapping TCP 8080",
+            "AddPortMapping UDP 8081"
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn fifo_write_during_the_startup_delay_is_applied() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("ports.csv");
+    let created = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !created {
+        return;
+    }
+
+    let mut daemon = std::process::Command::new(&*BIN_PATH)
+        .args(gateway.search_args())
+        .arg("--interval=3600")
+        .arg("--startup-delay=1")
+        .arg("-F")
+        .arg("-f")
+        .arg(&fifo)
+        .spawn()
+        .unwrap();
+
+    std::fs::write(&fifo, "port;protocol;duration;comment\n8080;TCP;3600;Web\n").unwrap();
+
+    let started = Instant::now();
+    while gateway.mappings().is_empty() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    assert_eq!(gateway.changes(), ["AddPortMapping TCP 8080"]);
+}
+
+#[test]
+fn refresh_once_only_adds_vanished_port_mappings_again() {
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    write!(config, "port;protocol;duration;comment\n8080;TCP;0;Web\n").unwrap();
+
+    let mut command = std::process::Command::new(&*BIN_PATH);
+    command
+        .args(gateway.search_args())
+        .arg("--interval=1")
+        .arg("--refresh-once")
+        .arg("-f")
+        .arg(config.path());
+
+    #[cfg(unix)]
+    command.arg("-F");
+
+    let mut daemon = command.spawn().unwrap();
+
+    let wait_for_mapping = || {
+        let started = Instant::now();
+        while gateway.mappings().is_empty() && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    wait_for_mapping();
+    thread::sleep(Duration::from_secs(3));
+    let untouched_changes = gateway.changes();
+
+    gateway.state.lock().unwrap().mappings.clear();
+    wait_for_mapping();
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    assert_eq!(untouched_changes, ["AddPortMapping TCP 8080"]);
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "AddPortMapping TCP 8080"]
+    );
+}
+
+#[test]
+fn panic_of_one_port_mapping_does_not_stop_the_others() {
+    #[derive(Debug)]
+    struct PanicsOn8081;
+
+    impl AddressResolver for PanicsOn8081 {
+        fn resolve(
+            &self,
+            address: &Option<Ipv4Cidr>,
+            port: u16,
+            options: &DiscoveryOptions,
+        ) -> easy_upnp::Result<(Gateway, SocketAddrV4)> {
+            if port == 8081 {
+                panic!("resolver failed on port {}", port);
+            }
+            DefaultResolver.resolve(address, port, options)
+        }
+    }
+
+    let Some(gateway) = FakeGateway::start() else {
+        return;
+    };
+    let options = DiscoveryOptions::new()
+        .gateway_url(format!("http://{}/rootDesc.xml", gateway.http_addr))
+        .resolver(PanicsOn8081);
+    let configs = [8080, 8081, 8082].map(|port| UpnpConfig {
+        duration: LeaseDuration::Seconds(600),
+        ..UpnpConfig::new(port, PortMappingProtocol::TCP)
+    });
+
+    let results: Vec<_> = easy_upnp::add_ports(configs, Some(&options)).collect();
+
+    assert!(matches!(
+        results[..],
+        [Ok(8080), Err(UpnpError::Panicked(_)), Ok(8082)]
+    ));
+    assert_eq!(
+        gateway.changes(),
+        ["AddPortMapping TCP 8080", "AddPortMapping TCP 8082"]
+    );
+}